@@ -0,0 +1,53 @@
+//! Compares the byte interpreter with the threaded engine on loop-heavy programs.
+//! Run with `cargo bench`, adding `--features jit` to include native code.
+
+use std::time::{Duration, Instant};
+
+use corrode::{
+    code::{parse::parse_code, threaded::ThreadedCode},
+    stack::Stack,
+};
+
+fn time<F: FnMut() -> i64>(runs: u32, mut body: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..runs {
+        std::hint::black_box(body());
+    }
+    start.elapsed() / runs
+}
+
+fn bench(file: &str, runs: u32) {
+    let code = parse_code(file, &[]).unwrap();
+    let threaded = ThreadedCode::new(&code);
+
+    let interpreted = time(runs, || Stack::<i64>::new().execute(&code).unwrap());
+    let predecoded = time(runs, || {
+        Stack::<i64>::new().execute_threaded(&threaded).unwrap()
+    });
+
+    println!("{file}");
+    println!("    execute          {:>12?}", interpreted);
+    println!("    execute_threaded {:>12?}", predecoded);
+    println!(
+        "    speedup          {:>11.2}x",
+        interpreted.as_secs_f64() / predecoded.as_secs_f64()
+    );
+
+    #[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+    {
+        let program = corrode::code::jit::JitProgram::compile(&code).unwrap();
+        let native = time(runs, || {
+            Stack::<i64>::new().execute_compiled(&program).unwrap()
+        });
+        println!("    execute_compiled {:>12?}", native);
+        println!(
+            "    speedup          {:>11.2}x",
+            interpreted.as_secs_f64() / native.as_secs_f64()
+        );
+    }
+}
+
+fn main() {
+    bench("benches/countdown.cor", 100_000);
+    bench("benches/nested_countdown.cor", 200);
+}
@@ -0,0 +1,864 @@
+/*!Transpiler from bytecode to a self contained C program
+ *
+ * The stack is a fixed size array of `int64_t`, each instruction becomes a
+ * statement and JMP/JNZ targets become `goto` labels. Frames opened by ENTER
+ * are kept in arrays beside it, and so are the handlers installed by TRY.
+ * Underflow, unknown ops, division by zero, arithmetic overflow and a full
+ * stack are checked at run time. Inside a TRY they `longjmp` back to `main`,
+ * which jumps on to the handler. Otherwise they are reported like the matching
+ * `StackError` with exit status 1. RET and EXIT return their value from `main`,
+ * so the low byte of it is the exit status.
+ *
+ * Coroutines that are not running keep copies of their stack, frames and
+ * handlers in `saved`, and are switched in through the same `switch` in `main`
+ * that sends caught faults to their handler. Channels are ring buffers of
+ * `CORRODE_CHANNEL_SIZE` values.
+ */
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use crate::code::instruction::{Instruction, reachable};
+
+const PRELUDE: &str = r#"#include <inttypes.h>
+#include <setjmp.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#ifndef CORRODE_STACK_SIZE
+#define CORRODE_STACK_SIZE 4096
+#endif
+
+#ifndef CORRODE_FRAMES
+#define CORRODE_FRAMES 256
+#endif
+
+#ifndef CORRODE_HANDLERS
+#define CORRODE_HANDLERS 256
+#endif
+
+#ifndef CORRODE_COROUTINES
+#define CORRODE_COROUTINES 16
+#endif
+
+#ifndef CORRODE_CHANNEL_SIZE
+#define CORRODE_CHANNEL_SIZE 64
+#endif
+
+static int64_t stack[CORRODE_STACK_SIZE];
+static size_t depth = 0;
+static size_t frame_base[CORRODE_FRAMES];
+static size_t frame_size[CORRODE_FRAMES];
+static size_t frames = 0;
+static size_t handler_address[CORRODE_HANDLERS];
+static size_t handler_depth[CORRODE_HANDLERS];
+static size_t handler_frames[CORRODE_HANDLERS];
+static size_t handlers = 0;
+static size_t resume_at;
+static jmp_buf catch_point;
+
+static inline void fault(int code, const char *error, size_t idx, unsigned op);
+
+static inline void push(int64_t item, size_t idx, unsigned op) {
+    if (depth == CORRODE_STACK_SIZE) fault(3, "Not enough capacity on stack", idx, op);
+    stack[depth++] = item;
+}
+
+static inline int64_t pop(size_t idx, unsigned op) {
+    if (depth == 0) fault(1, "Cannot pop empty stack", idx, op);
+    return stack[--depth];
+}
+
+static inline int64_t top(size_t idx, unsigned op) {
+    if (depth == 0) fault(1, "Cannot pop empty stack", idx, op);
+    return stack[depth - 1];
+}
+
+/* Unwind to the depth and frames of the innermost TRY and go on at its handler with `code` on top */
+static void catch_code(int64_t code, size_t idx, unsigned op) {
+    handlers--;
+    if (depth > handler_depth[handlers]) depth = handler_depth[handlers];
+    if (frames > handler_frames[handlers]) frames = handler_frames[handlers];
+    resume_at = handler_address[handlers];
+    push(code, idx, op);
+    longjmp(catch_point, 1);
+}
+
+static inline void fault(int code, const char *error, size_t idx, unsigned op) {
+    if (handlers > 0) catch_code(code, idx, op);
+    fprintf(stderr, "%s (idx: %zu, op: 0x%02x)\n", error, idx, op);
+    exit(1);
+}
+
+static inline void unknown(size_t idx, unsigned byte) {
+    if (handlers > 0) catch_code(2, idx, byte);
+    fprintf(stderr, "Unknown operation: %zu at index: %u\n", idx, byte);
+    exit(1);
+}
+
+static inline void try_handler(size_t address, size_t idx, unsigned op) {
+    if (handlers == CORRODE_HANDLERS) fault(3, "Not enough capacity on stack", idx, op);
+    handler_address[handlers] = address;
+    handler_depth[handlers] = depth;
+    handler_frames[handlers++] = frames;
+}
+
+static inline void end_try(size_t idx, unsigned op) {
+    if (handlers == 0) fault(8, "ENDTRY without a matching TRY", idx, op);
+    handlers--;
+}
+
+static inline void throw_code(int64_t code, size_t idx, unsigned op) {
+    if (handlers > 0) catch_code(code, idx, op);
+    fprintf(stderr, "Uncaught exception %" PRId64 " (idx: %zu, op: 0x%02x)\n", code, idx, op);
+    exit(1);
+}
+
+static inline void not_equal(int64_t left, int64_t right, size_t idx, unsigned op) {
+    if (handlers > 0) catch_code(11, idx, op);
+    fprintf(stderr, "Assertion failed: %" PRId64 " is not equal to %" PRId64 " (idx: %zu, op: 0x%02x)\n",
+            left, right, idx, op);
+    exit(1);
+}
+
+static inline int64_t *slot(size_t index, size_t idx, unsigned op) {
+    if (index >= depth) {
+        if (handlers > 0) catch_code(5, idx, op);
+        fprintf(stderr, "Stack slot %zu is out of range (idx: %zu, op: 0x%02x)\n", index, idx, op);
+        exit(1);
+    }
+    return &stack[index];
+}
+
+static inline void enter(size_t size, size_t idx, unsigned op) {
+    if (frames == CORRODE_FRAMES) fault(3, "Not enough capacity on stack", idx, op);
+    frame_base[frames] = depth;
+    frame_size[frames++] = size;
+    for (size_t i = 0; i < size; i++) push(0, idx, op);
+}
+
+static inline int64_t *local(size_t index, size_t idx, unsigned op) {
+    if (frames == 0) fault(6, "No frame to leave or to hold locals", idx, op);
+    size_t base = frame_base[frames - 1];
+    if (index >= frame_size[frames - 1] || base + index >= depth) {
+        if (handlers > 0) catch_code(7, idx, op);
+        fprintf(stderr, "Local %zu is out of range of the current frame (idx: %zu, op: 0x%02x)\n", index, idx, op);
+        exit(1);
+    }
+    return &stack[base + index];
+}
+
+static inline void leave(size_t idx, unsigned op) {
+    if (frames == 0) fault(6, "No frame to leave or to hold locals", idx, op);
+    size_t base = frame_base[frames - 1], size = frame_size[frames - 1];
+    if (depth < base + size) fault(1, "Cannot pop empty stack", idx, op);
+    for (size_t i = base + size; i < depth; i++) stack[i - size] = stack[i];
+    depth -= size;
+    frames--;
+}
+
+/* Put the values at `order`, counted from the lowest of the `taken` on top, in place of those */
+static inline void shuffle(size_t taken, const unsigned char *order, size_t count, size_t idx, unsigned op) {
+    int64_t moved[257];
+    if (depth < taken) fault(1, "Cannot pop empty stack", idx, op);
+    if (depth - taken + count > CORRODE_STACK_SIZE) fault(3, "Not enough capacity on stack", idx, op);
+    for (size_t i = 0; i < count; i++) moved[i] = stack[depth - taken + order[i]];
+    memcpy(&stack[depth - taken], moved, count * sizeof *moved);
+    depth = depth - taken + count;
+}
+
+static inline void out_of_reach(size_t n, size_t idx, unsigned op) {
+    if (depth > n) return;
+    if (handlers > 0) catch_code(12, idx, op);
+    fprintf(stderr, "Cannot reach value %zu below the top of a stack of %zu values (idx: %zu, op: 0x%02x)\n",
+            n, depth, idx, op);
+    exit(1);
+}
+
+static inline void clear(void) {
+    size_t floor = frames > 0 ? frame_base[frames - 1] + frame_size[frames - 1] : 0;
+    if (depth > floor) depth = floor;
+}
+
+static inline int valid_utf8(const unsigned char *text, size_t length) {
+    size_t i = 0;
+    while (i < length) {
+        unsigned char lead = text[i];
+        size_t size;
+        uint32_t point;
+        if (lead < 0x80) {
+            i++;
+            continue;
+        } else if (lead >= 0xC2 && lead <= 0xDF) {
+            size = 2;
+            point = lead & 0x1F;
+        } else if (lead >= 0xE0 && lead <= 0xEF) {
+            size = 3;
+            point = lead & 0x0F;
+        } else if (lead >= 0xF0 && lead <= 0xF4) {
+            size = 4;
+            point = lead & 0x07;
+        } else {
+            return 0;
+        }
+        if (i + size > length) return 0;
+        for (size_t k = 1; k < size; k++) {
+            if ((text[i + k] & 0xC0) != 0x80) return 0;
+            point = (point << 6) | (text[i + k] & 0x3F);
+        }
+        if (size == 3 && (point < 0x800 || (point >= 0xD800 && point <= 0xDFFF))) return 0;
+        if (size == 4 && (point < 0x10000 || point > 0x10FFFF)) return 0;
+        i += size;
+    }
+    return 1;
+}
+
+static inline void pchar(size_t idx, unsigned op) {
+    static unsigned char text[CORRODE_STACK_SIZE];
+    size_t length = 0;
+    while (depth > 0) {
+        int64_t character = stack[--depth];
+        if (character == 0) break;
+        if (character >= 0 && character <= 0xFF) text[length++] = (unsigned char)character;
+    }
+    for (size_t i = 0; i < length / 2; i++) {
+        unsigned char swap = text[i];
+        text[i] = text[length - 1 - i];
+        text[length - 1 - i] = swap;
+    }
+    if (valid_utf8(text, length)) {
+        fwrite(text, 1, length, stdout);
+        putchar('\n');
+    } else {
+        puts("Could not parse stack to string.");
+    }
+    for (size_t i = 0; i < length; i++) push(text[i], idx, op);
+}
+
+/* A coroutine that is not running, with everything it needs to go on at `resume_at` */
+struct coroutine {
+    size_t id, resume_at, depth, frames, handlers;
+    int blocked;
+    int64_t stack[CORRODE_STACK_SIZE];
+    size_t frame_base[CORRODE_FRAMES], frame_size[CORRODE_FRAMES];
+    size_t handler_address[CORRODE_HANDLERS], handler_depth[CORRODE_HANDLERS], handler_frames[CORRODE_HANDLERS];
+};
+
+static struct coroutine saved[CORRODE_COROUTINES];
+static int saved_used[CORRODE_COROUTINES];
+/* Slots in `saved` of the coroutines waiting for their turn, the next one first */
+static size_t queue[CORRODE_COROUTINES];
+static size_t waiting = 0;
+static size_t current = 0;
+static size_t next_id = 1;
+static int64_t channel[256][CORRODE_CHANNEL_SIZE];
+static size_t channel_head[256], channel_count[256];
+
+static inline size_t free_slot(void) {
+    size_t slot = 0;
+    while (saved_used[slot]) slot++;
+    return slot;
+}
+
+static inline void spawn(size_t address, size_t idx, unsigned op) {
+    if (waiting + 1 == CORRODE_COROUTINES) fault(3, "Not enough capacity on stack", idx, op);
+    size_t slot = free_slot();
+    saved_used[slot] = 1;
+    saved[slot].id = next_id++;
+    saved[slot].resume_at = address;
+    saved[slot].depth = saved[slot].frames = saved[slot].handlers = 0;
+    saved[slot].blocked = -1;
+    queue[waiting++] = slot;
+}
+
+static inline void send(unsigned number, int64_t value, size_t idx, unsigned op) {
+    if (channel_count[number] == CORRODE_CHANNEL_SIZE) fault(3, "Not enough capacity on stack", idx, op);
+    channel[number][(channel_head[number] + channel_count[number]++) % CORRODE_CHANNEL_SIZE] = value;
+}
+
+static inline int receive(unsigned number, size_t idx, unsigned op) {
+    if (channel_count[number] == 0) return 0;
+    int64_t value = channel[number][channel_head[number]];
+    channel_head[number] = (channel_head[number] + 1) % CORRODE_CHANNEL_SIZE;
+    channel_count[number]--;
+    push(value, idx, op);
+    return 1;
+}
+
+static inline void deadlock(int blocked, size_t idx, unsigned op) {
+    size_t ids[CORRODE_COROUTINES];
+    int channels[CORRODE_COROUTINES];
+    size_t count = 0;
+    if (handlers > 0) catch_code(10, idx, op);
+    if (blocked >= 0) {
+        ids[count] = current;
+        channels[count++] = blocked;
+    }
+    for (size_t i = 0; i < waiting; i++) {
+        ids[count] = saved[queue[i]].id;
+        channels[count++] = saved[queue[i]].blocked;
+    }
+    for (size_t i = 1; i < count; i++) {
+        for (size_t k = i; k > 0 && ids[k - 1] > ids[k]; k--) {
+            size_t id = ids[k];
+            int number = channels[k];
+            ids[k] = ids[k - 1];
+            channels[k] = channels[k - 1];
+            ids[k - 1] = id;
+            channels[k - 1] = number;
+        }
+    }
+    fprintf(stderr, "Deadlock, every coroutine is waiting to receive: ");
+    for (size_t i = 0; i < count; i++) {
+        fprintf(stderr, "%scoroutine %zu on channel %d", i > 0 ? ", " : "", ids[i], channels[i]);
+    }
+    fprintf(stderr, " (idx: %zu, op: 0x%02x)\n", idx, op);
+    exit(1);
+}
+
+/* Swap in the first waiting coroutine that can run, queueing the current one to go on at `resume`
+   unless it has `finished`. Returns 0 when the current one only yields and no other can run. */
+static inline int reschedule(size_t resume, int blocked, int finished, size_t idx, unsigned op) {
+    size_t position = 0;
+    while (position < waiting) {
+        int on = saved[queue[position]].blocked;
+        if (on < 0 || channel_count[on] > 0) break;
+        position++;
+    }
+    if (position == waiting) {
+        if (blocked < 0 && !finished) return 0;
+        /* A finished coroutine is not waiting, so report the RECV the first waiting one runs again */
+        if (blocked < 0) deadlock(blocked, saved[queue[0]].resume_at, 0x53);
+        deadlock(blocked, idx, op);
+    }
+    size_t next = queue[position];
+    for (; position + 1 < waiting; position++) queue[position] = queue[position + 1];
+    waiting--;
+
+    if (!finished) {
+        size_t slot = free_slot();
+        struct coroutine *out = &saved[slot];
+        saved_used[slot] = 1;
+        out->id = current;
+        out->resume_at = resume;
+        out->blocked = blocked;
+        out->depth = depth;
+        out->frames = frames;
+        out->handlers = handlers;
+        memcpy(out->stack, stack, depth * sizeof *stack);
+        memcpy(out->frame_base, frame_base, frames * sizeof *frame_base);
+        memcpy(out->frame_size, frame_size, frames * sizeof *frame_size);
+        memcpy(out->handler_address, handler_address, handlers * sizeof *handler_address);
+        memcpy(out->handler_depth, handler_depth, handlers * sizeof *handler_depth);
+        memcpy(out->handler_frames, handler_frames, handlers * sizeof *handler_frames);
+        queue[waiting++] = slot;
+    }
+
+    struct coroutine *in = &saved[next];
+    current = in->id;
+    resume_at = in->resume_at;
+    depth = in->depth;
+    frames = in->frames;
+    handlers = in->handlers;
+    memcpy(stack, in->stack, depth * sizeof *stack);
+    memcpy(frame_base, in->frame_base, frames * sizeof *frame_base);
+    memcpy(frame_size, in->frame_size, frames * sizeof *frame_size);
+    memcpy(handler_address, in->handler_address, handlers * sizeof *handler_address);
+    memcpy(handler_depth, in->handler_depth, handlers * sizeof *handler_depth);
+    memcpy(handler_frames, in->handler_frames, handlers * sizeof *handler_frames);
+    saved_used[next] = 0;
+    return 1;
+}
+
+"#;
+
+///Translate bytecode into the source of a C program that runs it
+pub fn transpile_c(code: &[u8]) -> String {
+    let instructions = reachable(code);
+
+    // Labels are needed for jump targets and for fallthrough into an
+    // instruction that was not decoded right after this one
+    let mut targets: BTreeSet<usize> = BTreeSet::new();
+    let mut following = instructions.keys().skip(1);
+    for (&offset, instruction) in &instructions {
+        let next = following.next().copied();
+        match instruction {
+            Instruction::Jmp(target)
+            | Instruction::Jnz(target)
+            | Instruction::Try(target)
+            | Instruction::Spawn(target) => {
+                targets.insert(*target);
+            }
+            // A coroutine switched out here goes on with the next instruction
+            Instruction::Yield => {
+                targets.insert(offset + 1);
+            }
+            // or runs the RECV again
+            Instruction::Recv(_) => {
+                targets.insert(offset);
+            }
+            _ => (),
+        }
+        if !instruction.ends_block() && next != Some(offset + instruction.size()) {
+            targets.insert(offset + instruction.size());
+        }
+    }
+
+    let mut out = String::from(PRELUDE);
+    out.push_str(
+        "int main(void) {\n    int64_t lhs = 0, rhs = 0;\n    (void)lhs;\n    (void)rhs;\n\n",
+    );
+
+    // Faults and THROWs inside a TRY come back here to find their handler, and
+    // coroutines that are switched in to find where they left off
+    let coroutines = instructions.values().any(|instruction| {
+        matches!(
+            instruction,
+            Instruction::Spawn(_) | Instruction::Yield | Instruction::Recv(_)
+        )
+    });
+    let resume_points: BTreeSet<usize> = instructions
+        .iter()
+        .filter_map(|(&offset, instruction)| match instruction {
+            Instruction::Try(target) | Instruction::Spawn(target) => Some(*target),
+            Instruction::Yield => Some(offset + 1),
+            Instruction::Recv(_) => Some(offset),
+            _ => None,
+        })
+        .collect();
+    if !resume_points.is_empty() {
+        out.push_str("    if (setjmp(catch_point)) {\n");
+        if coroutines {
+            out.push_str("dispatch:\n");
+        }
+        out.push_str("        switch (resume_at) {\n");
+        for resume_point in resume_points {
+            writeln!(
+                out,
+                "        case {resume_point}: {}",
+                goto(&instructions, coroutines, resume_point)
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "        default: {}\n        }}\n    }}\n",
+            finish(coroutines, "0", code.len(), 0)
+        )
+        .unwrap();
+    }
+
+    let mut following = instructions.keys().skip(1);
+    for (&offset, &instruction) in &instructions {
+        if targets.contains(&offset) {
+            writeln!(out, "L{offset}:").unwrap();
+        }
+        statement(&mut out, &instructions, coroutines, offset, instruction);
+
+        let next = offset + instruction.size();
+        let decoded_next = following.next();
+        if !instruction.ends_block() && decoded_next != Some(&next) {
+            writeln!(out, "    {}", goto(&instructions, coroutines, next)).unwrap();
+        }
+    }
+
+    writeln!(out, "    {}\n}}", finish(coroutines, "0", code.len(), 0)).unwrap();
+    out
+}
+
+///Jump to the instruction at `offset`, or finish when there is none
+fn goto(instructions: &BTreeMap<usize, Instruction>, coroutines: bool, offset: usize) -> String {
+    if instructions.contains_key(&offset) {
+        format!("goto L{offset};")
+    } else {
+        finish(coroutines, "0", offset, 0)
+    }
+}
+
+///Return `value` from `main`, or switch to the next coroutine when a spawned one finishes
+fn finish(coroutines: bool, value: &str, idx: usize, op: u8) -> String {
+    if coroutines {
+        format!(
+            "if (current != 0) {{ reschedule(0, -1, 1, {idx}, {op:#04x}); goto dispatch; }} return {value};"
+        )
+    } else {
+        format!("return {value};")
+    }
+}
+
+fn statement(
+    out: &mut String,
+    instructions: &BTreeMap<usize, Instruction>,
+    coroutines: bool,
+    offset: usize,
+    instruction: Instruction,
+) {
+    let op = instruction.opcode();
+    let at = format!("{offset}, {op:#04x}");
+    // SWP and DUP report the index after themselves, like the interpreter
+    let after = format!("{}, {op:#04x}", offset + 1);
+
+    let body = match instruction {
+        Instruction::Nop => String::from(";"),
+        Instruction::Add => format!(
+            "rhs = pop({at}); lhs = pop({at}); \
+             if (__builtin_add_overflow(lhs, rhs, &lhs)) fault(13, \"Arithmetic overflow\", {at}); \
+             push(lhs, {at});"
+        ),
+        Instruction::Sub => format!(
+            "rhs = pop({at}); lhs = pop({at}); \
+             if (__builtin_sub_overflow(lhs, rhs, &lhs)) fault(13, \"Arithmetic overflow\", {at}); \
+             push(lhs, {at});"
+        ),
+        Instruction::Mul => format!(
+            "rhs = pop({at}); lhs = pop({at}); \
+             if (__builtin_mul_overflow(lhs, rhs, &lhs)) fault(13, \"Arithmetic overflow\", {at}); \
+             push(lhs, {at});"
+        ),
+        Instruction::Div => format!(
+            "rhs = pop({at}); lhs = pop({at}); \
+             if (rhs == 0) fault(4, \"Division by zero\", {at}); \
+             if (lhs == INT64_MIN && rhs == -1) fault(13, \"Arithmetic overflow\", {at}); \
+             push(lhs / rhs, {at});"
+        ),
+        Instruction::Mod => format!(
+            "rhs = pop({at}); lhs = pop({at}); \
+             if (rhs == 0) fault(4, \"Division by zero\", {at}); \
+             if (lhs == INT64_MIN && rhs == -1) fault(13, \"Arithmetic overflow\", {at}); \
+             push(lhs % rhs, {at});"
+        ),
+        Instruction::Lt => format!("rhs = pop({at}); lhs = pop({at}); push(lhs < rhs, {at});"),
+        Instruction::Print => format!("printf(\"%\" PRId64 \"\\n\", top({at}));"),
+        Instruction::PChar => format!("pchar({at});"),
+        Instruction::Ret => format!(
+            "lhs = top({at}); {}",
+            finish(coroutines, "(int)(lhs & 0xFF)", offset, op)
+        ),
+        Instruction::Push(value) => format!("push({value}, {at});"),
+        Instruction::Swp => {
+            format!(
+                "rhs = pop({after}); lhs = pop({after}); push(rhs, {after}); push(lhs, {after});"
+            )
+        }
+        Instruction::Pop => String::from("if (depth > 0) depth--;"),
+        Instruction::Dup => format!("lhs = top({after}); push(lhs, {after});"),
+        Instruction::Load(slot) => format!("lhs = *slot({slot}, {at}); push(lhs, {at});"),
+        Instruction::Store(slot) => format!("lhs = pop({at}); *slot({slot}, {at}) = lhs;"),
+        Instruction::Enter(size) => format!("enter({size}, {at});"),
+        Instruction::LoadLocal(local) => format!("lhs = *local({local}, {at}); push(lhs, {at});"),
+        Instruction::StoreLocal(local) => format!("lhs = pop({at}); *local({local}, {at}) = lhs;"),
+        Instruction::Leave => format!("leave({at});"),
+        Instruction::Jmp(target) => goto(instructions, coroutines, target),
+        Instruction::Jnz(target) => format!(
+            "if (depth > 0 && stack[depth - 1] != 0) {{ {} }}",
+            goto(instructions, coroutines, target)
+        ),
+        Instruction::Try(target) => format!("try_handler({target}, {at});"),
+        Instruction::EndTry => format!("end_try({at});"),
+        Instruction::Throw => format!("lhs = pop({at}); throw_code(lhs, {at});"),
+        Instruction::Assert => {
+            format!("if (pop({at}) == 0) fault(11, \"Assertion failed\", {at});")
+        }
+        Instruction::AssertEq => {
+            format!("rhs = pop({at}); lhs = pop({at}); if (lhs != rhs) not_equal(lhs, rhs, {at});")
+        }
+        Instruction::Spawn(target) => format!("spawn({target}, {at});"),
+        Instruction::Yield => format!(
+            "if (reschedule({}, -1, 0, {at})) goto dispatch;",
+            offset + 1
+        ),
+        Instruction::Send(number) => format!("lhs = pop({at}); send({number}, lhs, {at});"),
+        Instruction::Recv(number) => format!(
+            "if (!receive({number}, {at})) {{ reschedule({offset}, {number}, 0, {at}); goto dispatch; }}"
+        ),
+        Instruction::Over
+        | Instruction::Rot
+        | Instruction::Nip
+        | Instruction::Tuck
+        | Instruction::Pick(_)
+        | Instruction::Roll(_)
+        | Instruction::TwoDup
+        | Instruction::TwoSwap => {
+            let (taken, order) = instruction.shuffle().unwrap();
+            let reach = match instruction {
+                Instruction::Pick(n) | Instruction::Roll(n) => format!("out_of_reach({n}, {at}); "),
+                _ => String::new(),
+            };
+            let order = order
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "{{ static const unsigned char order[] = {{{order}}}; {reach}shuffle({taken}, order, sizeof order, {at}); }}"
+            )
+        }
+        Instruction::Depth => format!("push((int64_t)depth, {at});"),
+        Instruction::Clear => String::from("clear();"),
+        Instruction::Exit => finish(coroutines, "0xFF", offset, op),
+        Instruction::Truncated(_) => finish(coroutines, "0", offset, op),
+        Instruction::Unknown(byte) => format!("unknown({offset}, {byte});"),
+    };
+    writeln!(out, "    {body}").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+    use crate::{
+        code::{
+            link::link,
+            parse::{assemble_source, parse_code},
+        },
+        stack::Stack,
+    };
+
+    ///Compile the C output with the system `cc` and return its stdout, stderr and exit status
+    fn run_c(name: &str, code: &[u8]) -> (String, String, i32) {
+        let dir = std::env::temp_dir().join(format!("corrode_c_{}_{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("program.c");
+        let binary = dir.join("program");
+        std::fs::write(&source, transpile_c(code)).unwrap();
+
+        let status = Command::new("cc")
+            .args(["-std=c99", "-Wall", "-Werror", "-o"])
+            .arg(&binary)
+            .arg(&source)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let output = Command::new(&binary).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        (
+            String::from_utf8(output.stdout).unwrap(),
+            String::from_utf8(output.stderr).unwrap(),
+            output.status.code().unwrap(),
+        )
+    }
+
+    fn assert_exit_matches(name: &str, code: &[u8]) {
+        let expected = Stack::<i64>::new().execute(code).unwrap();
+        let (_, _, status) = run_c(name, code);
+        assert_eq!(status as i64, expected & 0xFF);
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_exit_matches("add", &[0x20, 0x05, 0x20, 0x06, 0x01, 0x12]);
+        assert_exit_matches("mul", &[0x20, 0x05, 0x20, 0x06, 0x03, 0x12]);
+        assert_exit_matches("div", &[0x20, 0x06, 0x20, 0x03, 0x04, 0x12]);
+        assert_exit_matches("mod", &[0x20, 0x05, 0x20, 0x03, 0x05, 0x12]);
+        assert_exit_matches("lt", &[0x20, 0x05, 0x20, 0x06, 0x06, 0x12]);
+    }
+    #[test]
+    fn slots() {
+        assert_exit_matches(
+            "load_store",
+            &[0x20, 0x05, 0x20, 0x06, 0x24, 0x00, 0x01, 0x25, 0x00, 0x12],
+        );
+        let (_, stderr, status) = run_c("out_of_range", &[0x20, 0x05, 0x25, 0x00]);
+        assert_eq!(stderr, "Stack slot 0 is out of range (idx: 2, op: 0x25)\n");
+        assert_eq!(status, 1);
+    }
+    #[test]
+    fn frames() {
+        assert_exit_matches(
+            "frames",
+            &[
+                0x20, 0x09, 0x26, 0x02, 0x20, 0x04, 0x28, 0x01, 0x27, 0x01, 0x27, 0x00, 0x01, 0x29,
+                0x12,
+            ],
+        );
+        let (_, stderr, status) = run_c("local_out_of_range", &[0x26, 0x01, 0x27, 0x01]);
+        assert_eq!(
+            stderr,
+            "Local 1 is out of range of the current frame (idx: 2, op: 0x27)\n"
+        );
+        assert_eq!(status, 1);
+        let (_, stderr, _) = run_c("no_frame", &[0x29]);
+        assert_eq!(
+            stderr,
+            "No frame to leave or to hold locals (idx: 0, op: 0x29)\n"
+        );
+    }
+    #[test]
+    fn jumps() {
+        assert_exit_matches("jmp", &[0x20, 0x05, 0x30, 0x06, 0x20, 0x03, 0x12]);
+        assert_exit_matches(
+            "jnz",
+            &[0x20, 0x05, 0x20, 0x01, 0x02, 0x31, 0x02, 0x20, 0x03, 0x12],
+        );
+        assert_exit_matches("operand", &[0x20, 0x12, 0x30, 0x01, 0xFF]);
+        assert_exit_matches("past_end", &[0x20, 0x05, 0x30, 0xF0]);
+    }
+    #[test]
+    fn countdown_output() {
+        // PUSH 3, loop: PRINT, PUSH 1, SUB, JNZ loop, EXIT
+        let code: Vec<u8> = vec![0x20, 0x03, 0x10, 0x20, 0x01, 0x02, 0x31, 0x02, 0xFF];
+        let (stdout, _, status) = run_c("countdown", &code);
+        assert_eq!(stdout, "3\n2\n1\n");
+        assert_eq!(status, 0xFF);
+    }
+    #[test]
+    fn hello_world() {
+        let (stdout, _, status) = run_c("hello", &parse_code("./hello_world.cor", &[]).unwrap());
+        assert_eq!(stdout, "Hello, world!\n");
+        assert_eq!(status, 0xFF);
+    }
+    #[test]
+    fn errors() {
+        let (_, stderr, status) = run_c("underflow", &[0x20, 0x05, 0x01]);
+        assert_eq!(stderr, "Cannot pop empty stack (idx: 2, op: 0x01)\n");
+        assert_eq!(status, 1);
+
+        let (_, stderr, status) = run_c("unknown", &[0x20, 0x05, 0x42]);
+        assert_eq!(stderr, "Unknown operation: 2 at index: 66\n");
+        assert_eq!(status, 1);
+
+        let (_, stderr, status) = run_c("by_zero", &[0x20, 0x05, 0x20, 0x00, 0x04]);
+        assert_eq!(stderr, "Division by zero (idx: 4, op: 0x04)\n");
+        assert_eq!(status, 1);
+    }
+    #[test]
+    fn overflow() {
+        let build = |source: &str| {
+            link(&[assemble_source::<i64>("overflow.cor", source, &[]).unwrap()]).unwrap()
+        };
+        let operations = [
+            ("add", "push 9223372036854775807\npush 1\nadd"),
+            ("sub", "push -9223372036854775808\npush 1\nsub"),
+            ("mul", "push 4611686018427387904\npush 2\nmul"),
+            ("div", "push -9223372036854775808\npush -1\ndiv"),
+            ("mod", "push -9223372036854775808\npush -1\nmod"),
+        ];
+        for (name, operation) in operations {
+            // Caught by a handler, which returns the code of the fault
+            let code = build(&format!("try $caught\n{operation}\nret\ncaught: ret\n"));
+            assert_exit_matches(&format!("caught_{name}"), &code);
+
+            let code = build(&format!("{operation}\nret\n"));
+            let error = Stack::<i64>::new().execute(&code).unwrap_err();
+            let (_, stderr, status) = run_c(&format!("uncaught_{name}"), &code);
+            assert_eq!(
+                stderr,
+                format!(
+                    "{error} (idx: {}, op: {:#04x})\n",
+                    error.idx().unwrap(),
+                    error.op().unwrap()
+                )
+            );
+            assert_eq!(status, 1);
+        }
+    }
+    #[test]
+    fn try_throw() {
+        assert_exit_matches(
+            "throw",
+            &[
+                0x20, 0x01, 0x32, 0x09, 0x20, 0x02, 0x20, 0x07, 0x34, 0x20, 0x03, 0x01, 0x12,
+            ],
+        );
+        assert_exit_matches(
+            "caught_fault",
+            &[0x32, 0x09, 0x26, 0x01, 0x20, 0x01, 0x20, 0x00, 0x04, 0x12],
+        );
+        // TRY 6, PUSH 1, PUSH 0x42, handler: RET
+        assert_exit_matches(
+            "caught_unknown",
+            &[0x32, 0x06, 0x20, 0x01, 0x42, 0x00, 0x12],
+        );
+        assert_exit_matches("handler_past_end", &[0x32, 0xF0, 0x34]);
+
+        let (_, stderr, status) = run_c("uncaught", &[0x20, 0x07, 0x34]);
+        assert_eq!(stderr, "Uncaught exception 7 (idx: 2, op: 0x34)\n");
+        assert_eq!(status, 1);
+        let (_, stderr, _) = run_c("end_try", &[0x32, 0x03, 0x33, 0x33]);
+        assert_eq!(stderr, "ENDTRY without a matching TRY (idx: 3, op: 0x33)\n");
+    }
+    #[test]
+    fn forth_words() {
+        assert_exit_matches(
+            "shuffles",
+            &[
+                0x20, 0x01, 0x20, 0x02, 0x20, 0x03, 0x60, 0x61, 0x62, 0x63, 0x68, 0x69, 0x64, 0x04,
+                0x65, 0x05, 0x66, 0x12,
+            ],
+        );
+        assert_exit_matches(
+            "clear",
+            &[0x20, 0x01, 0x26, 0x01, 0x20, 0x02, 0x67, 0x66, 0x12],
+        );
+        assert_exit_matches("caught_reach", &[0x32, 0x04, 0x64, 0x00, 0x12]);
+        let (_, stderr, status) = run_c("out_of_reach", &[0x20, 0x01, 0x65, 0x01]);
+        assert_eq!(
+            stderr,
+            "Cannot reach value 1 below the top of a stack of 1 values (idx: 2, op: 0x65)\n"
+        );
+        assert_eq!(status, 1);
+        let (_, stderr, _) = run_c("rot_empty", &[0x20, 0x01, 0x61]);
+        assert_eq!(stderr, "Cannot pop empty stack (idx: 2, op: 0x61)\n");
+    }
+    #[test]
+    fn assertions() {
+        assert_exit_matches(
+            "asserts",
+            &[
+                0x20, 0x01, 0x40, 0x20, 0x04, 0x20, 0x04, 0x41, 0x20, 0x09, 0x12,
+            ],
+        );
+        assert_exit_matches(
+            "caught_assert",
+            &[0x32, 0x07, 0x20, 0x02, 0x20, 0x03, 0x41, 0x12],
+        );
+        let (_, stderr, status) = run_c("assert", &[0x20, 0x00, 0x40]);
+        assert_eq!(stderr, "Assertion failed (idx: 2, op: 0x40)\n");
+        assert_eq!(status, 1);
+        let (_, stderr, _) = run_c("assert_eq", &[0x20, 0x02, 0x20, 0x03, 0x41]);
+        assert_eq!(
+            stderr,
+            "Assertion failed: 2 is not equal to 3 (idx: 4, op: 0x41)\n"
+        );
+    }
+    #[test]
+    fn coroutines() {
+        assert_exit_matches(
+            "channels",
+            &[
+                0x50, 0x0A, 0x50, 0x0F, 0x53, 0x02, 0x53, 0x02, 0x01, 0x12, 0x20, 0x03, 0x52, 0x01,
+                0xFF, 0x53, 0x01, 0x23, 0x51, 0x52, 0x02, 0x52, 0x02,
+            ],
+        );
+        assert_exit_matches(
+            "yield",
+            &[0x50, 0x07, 0x51, 0x51, 0x20, 0x04, 0x12, 0x20, 0x09],
+        );
+        assert_exit_matches(
+            "caught_deadlock",
+            &[
+                0x50, 0x06, 0x53, 0x01, 0x12, 0x00, 0x32, 0x0A, 0x53, 0x02, 0x52, 0x01,
+            ],
+        );
+
+        let (_, stderr, status) = run_c("deadlock", &[0x50, 0x04, 0x53, 0x01, 0x53, 0x02]);
+        assert_eq!(
+            stderr,
+            "Deadlock, every coroutine is waiting to receive: coroutine 0 on channel 1, \
+             coroutine 1 on channel 2 (idx: 4, op: 0x53)\n"
+        );
+        assert_eq!(status, 1);
+        let (_, stderr, _) = run_c("finished", &[0x50, 0x05, 0x53, 0x01, 0x12, 0x20, 0x01]);
+        assert_eq!(
+            stderr,
+            "Deadlock, every coroutine is waiting to receive: coroutine 0 on channel 1 \
+             (idx: 2, op: 0x53)\n"
+        );
+    }
+}
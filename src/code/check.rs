@@ -0,0 +1,519 @@
+/*!Static stack-effect checker of `corrode check`
+ *
+ * The stack effects documented at the top of `code/mod.rs` are run along every
+ * control flow path from the start, counting only how many values are on the
+ * stack. Reported are
+ *
+ * push 1
+ * add          \\ places where the stack can underflow
+ * loop: dup
+ * jnz $loop    \\ loops growing the stack on every iteration
+ *
+ * and labels reached with different depths. A TRY handler starts one deeper
+ * than its TRY, a SPAWNed coroutine with an empty stack. After PCHAR the depth
+ * depends on the values, so a path is not followed any further.
+ *
+ * A label can be annotated with the values it takes and leaves:
+ *
+ * square: ( a -- b ) dup
+ * mul
+ *
+ * Its code, up to the next label, is then checked to be reached with at least
+ * `a` on the stack, to use nothing below it and to leave exactly `b` in its
+ * place wherever it jumps out, returns or runs into the next label.
+ */
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+    sync::LazyLock,
+};
+
+use num::traits::{CheckedAdd, CheckedMul, CheckedSub};
+
+use crate::code::{
+    check_error::CheckError, code_execution::compile_debug, instruction::Instruction,
+    link::DebugInfo,
+};
+
+///Stack effects as documented at the top of `code/mod.rs`, by lower case mnemonic
+pub(crate) static EFFECTS: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
+    include_str!("mod.rs")
+        .lines()
+        .take_while(|line| !line.contains("*/"))
+        .filter_map(|line| line.strip_prefix(" * "))
+        .filter_map(|line| {
+            let name = line.split_once(" => ")?.0.split_whitespace().next()?;
+            let mnemonic = name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+            mnemonic.then(|| (name.to_lowercase(), line.to_string()))
+        })
+        .collect()
+});
+
+///How many values something takes from the top of the stack and leaves in their place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl Signature {
+    ///Read a documented effect such as `( a b -- a + b )`, `None` when it has a variable
+    ///number of values
+    pub fn parse(effect: &str) -> Option<Self> {
+        let effect = effect.trim().strip_prefix('(')?.strip_suffix(')')?;
+        if effect.contains("...") {
+            return None;
+        }
+        let Some((inputs, outputs)) = effect.split_once("--") else {
+            return effect.trim().is_empty().then_some(Signature {
+                inputs: 0,
+                outputs: 0,
+            });
+        };
+        // Every operator joins two values into one
+        let count = |side: &str| {
+            let (operators, values): (Vec<&str>, Vec<&str>) = side
+                .split_whitespace()
+                .partition(|token| matches!(*token, "+" | "-" | "*" | "/" | "%" | "<"));
+            values.len().checked_sub(operators.len())
+        };
+        Some(Signature {
+            inputs: count(inputs)?,
+            outputs: count(outputs)?,
+        })
+    }
+}
+
+///Documented stack effect of an opcode
+pub fn documented(op: u8) -> Option<Signature> {
+    let line = EFFECTS.get(&Instruction::mnemonic(op).to_lowercase())?;
+    let effect = line.split_once(" => ")?.1;
+    Signature::parse(effect.split(" \\\\").next()?)
+}
+
+///What is known about the stack at an instruction
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct State {
+    depth: usize,
+    ///Depth below the locals and number of locals of every open frame, innermost last
+    frames: Vec<(usize, usize)>,
+}
+
+///Values an instruction needs on the stack, and where execution can go on with which state
+struct Step {
+    needs: usize,
+    successors: Vec<(usize, State)>,
+    ///TRY handlers and SPAWNed coroutines, which are no way out of a range
+    entries: Vec<(usize, State)>,
+    ///Whether the stack is left as it is, as on RET and EXIT
+    leaves: bool,
+}
+
+///Effect of the instruction at `idx` on `state`, `None` when the path cannot be followed
+fn step(instruction: Instruction, idx: usize, state: &State) -> Option<Step> {
+    let next = idx + instruction.size();
+    let mut after = state.clone();
+    let mut needs = 0;
+    let mut successors = Vec::new();
+    let mut entries = Vec::new();
+    let mut leaves = false;
+    match instruction {
+        Instruction::Enter(size) => {
+            after.frames.push((after.depth, size as usize));
+            after.depth += size as usize;
+            successors.push((next, after));
+        }
+        Instruction::Leave => {
+            needs = after.frames.pop()?.1;
+            after.depth -= needs.min(after.depth);
+            successors.push((next, after));
+        }
+        Instruction::Clear => {
+            let floor = after.frames.last().map_or(0, |&(base, size)| base + size);
+            after.depth = after.depth.min(floor);
+            successors.push((next, after));
+        }
+        // PICK and ROLL take as many values as they reach, which no documented effect says
+        _ if instruction.shuffle().is_some() => {
+            let (taken, order) = instruction.shuffle()?;
+            needs = taken;
+            if state.depth >= needs {
+                after.depth = state.depth - taken + order.len();
+            }
+            successors.push((next, after));
+        }
+        Instruction::Throw => needs = 1,
+        Instruction::Ret | Instruction::Exit => {
+            needs = documented(instruction.opcode())?.inputs;
+            leaves = true;
+        }
+        Instruction::PChar | Instruction::Truncated(_) | Instruction::Unknown(_) => return None,
+        _ => {
+            let signature = documented(instruction.opcode())?;
+            needs = match instruction {
+                Instruction::Load(slot) => slot as usize + 1,
+                Instruction::Store(slot) => slot as usize + 2,
+                _ => signature.inputs,
+            };
+            if state.depth >= needs {
+                after.depth = state.depth - signature.inputs + signature.outputs;
+            }
+            match instruction {
+                Instruction::Jmp(target) => successors.push((target, after)),
+                Instruction::Jnz(target) => {
+                    successors.push((next, after.clone()));
+                    successors.push((target, after));
+                }
+                Instruction::Try(handler) => {
+                    let mut handling = after.clone();
+                    handling.depth += 1;
+                    successors.push((next, after));
+                    entries.push((handler, handling));
+                }
+                Instruction::Spawn(entry) => {
+                    successors.push((next, after));
+                    entries.push((entry, State::default()));
+                }
+                _ => successors.push((next, after)),
+            }
+        }
+    }
+    Some(Step {
+        needs,
+        successors,
+        entries,
+        leaves,
+    })
+}
+
+///Name of the label at `idx`, or the address itself
+fn name(info: &DebugInfo, idx: usize) -> String {
+    info.labels
+        .iter()
+        .find(|(address, _)| *address == idx)
+        .map_or_else(|| format!("%{idx}"), |(_, name)| name.clone())
+}
+
+///Result of following every path through a range of the code
+#[derive(Default)]
+struct Paths {
+    ///State each instruction is first reached with
+    states: BTreeMap<usize, State>,
+    errors: Vec<CheckError>,
+    ///Index and depth of every way out of the range
+    exits: Vec<(usize, usize)>,
+}
+
+///Follow every path from `start` that stays within `within`
+fn follow(
+    code: &[u8],
+    info: &DebugInfo,
+    start: usize,
+    depth: usize,
+    within: Range<usize>,
+) -> Paths {
+    let mut paths = Paths::default();
+    let mut mismatched = HashSet::new();
+    let state = State {
+        depth,
+        frames: Vec::new(),
+    };
+    paths.states.insert(start, state);
+    let mut worklist = vec![start];
+
+    while let Some(idx) = worklist.pop() {
+        let state = paths.states[&idx].clone();
+        let Some(instruction) = Instruction::decode(code, idx) else {
+            paths.exits.push((idx, state.depth));
+            continue;
+        };
+        let Some(step) = step(instruction, idx, &state) else {
+            continue;
+        };
+        if state.depth < step.needs {
+            paths.errors.push(CheckError::Underflow {
+                idx,
+                op: instruction.opcode(),
+                needs: step.needs,
+                depth: state.depth,
+            });
+            continue;
+        }
+        if step.leaves {
+            paths.exits.push((idx, state.depth));
+        }
+        let successors = step.successors.into_iter().map(|next| (next, true));
+        let entries = step.entries.into_iter().map(|entry| (entry, false));
+        for ((target, after), flows) in successors.chain(entries) {
+            if !within.contains(&target) {
+                if flows {
+                    paths.exits.push((idx, after.depth));
+                }
+                continue;
+            }
+            let Some(reached) = paths.states.get(&target) else {
+                paths.states.insert(target, after);
+                worklist.push(target);
+                continue;
+            };
+            if reached.depth == after.depth || !mismatched.insert(target) {
+                continue;
+            }
+            let label = name(info, target);
+            paths
+                .errors
+                .push(if target <= idx && after.depth > reached.depth {
+                    CheckError::UnboundedLoop {
+                        idx,
+                        label,
+                        growth: after.depth - reached.depth,
+                    }
+                } else {
+                    CheckError::DepthMismatch {
+                        idx: target,
+                        label,
+                        first: reached.depth,
+                        second: after.depth,
+                    }
+                });
+        }
+    }
+    paths
+}
+
+///Check the stack effects of every path through `code`, and the annotated labels
+pub fn check(code: &[u8], info: &DebugInfo) -> Vec<CheckError> {
+    let paths = follow(code, info, 0, 0, 0..code.len());
+    let mut errors = paths.errors;
+
+    for (address, signature) in &info.signatures {
+        let label = name(info, *address);
+        if let Some(state) = paths.states.get(address)
+            && state.depth < signature.inputs
+        {
+            errors.push(CheckError::SignatureInputs {
+                idx: *address,
+                label: label.clone(),
+                inputs: signature.inputs,
+                depth: state.depth,
+            });
+        }
+
+        // Run the section on its own, with only its inputs on the stack
+        let end = info
+            .labels
+            .iter()
+            .map(|(other, _)| *other)
+            .find(|other| other > address)
+            .unwrap_or(code.len());
+        let section = follow(code, info, *address, signature.inputs, *address..end);
+        if let Some(CheckError::Underflow { idx, .. }) = section
+            .errors
+            .iter()
+            .find(|error| matches!(error, CheckError::Underflow { .. }))
+        {
+            errors.push(CheckError::SignatureBelow {
+                idx: *idx,
+                label: label.clone(),
+                inputs: signature.inputs,
+            });
+        }
+        if let Some((idx, found)) = section
+            .exits
+            .into_iter()
+            .find(|(_, depth)| *depth != signature.outputs)
+        {
+            errors.push(CheckError::SignatureOutputs {
+                idx,
+                label,
+                outputs: signature.outputs,
+                found,
+            });
+        }
+    }
+    errors.sort_by_key(CheckError::idx);
+    errors
+}
+
+///Check .cor files for stack values of type `T`, printing every error with where it is.
+///Fails when there are any.
+pub fn check_files<T>(input_files: &[&str], defines: &[(&str, i64)]) -> anyhow::Result<()>
+where
+    T: From<u8> + CheckedAdd + CheckedSub + CheckedMul,
+{
+    let (code, info) = compile_debug::<T>(input_files, defines)?;
+    let errors = check(&code, &info);
+    for error in &errors {
+        match info.line(error.idx()) {
+            Some(at) => println!("{at}: {error}"),
+            None => println!("%{}: {error}", error.idx()),
+        }
+    }
+    if !errors.is_empty() {
+        anyhow::bail!("{} stack effect errors", errors.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::{parse::link_files_debug, preprocess::MNEMONICS};
+
+    use super::*;
+
+    fn labelled(labels: &[(usize, &str)]) -> DebugInfo {
+        DebugInfo {
+            labels: labels
+                .iter()
+                .map(|(address, name)| (*address, name.to_string()))
+                .collect(),
+            ..DebugInfo::default()
+        }
+    }
+
+    #[test]
+    fn documented_signatures() {
+        let signature = |inputs, outputs| Some(Signature { inputs, outputs });
+        assert_eq!(documented(0x01), signature(2, 1));
+        assert_eq!(documented(0x06), signature(2, 1));
+        assert_eq!(documented(0x21), signature(2, 2));
+        assert_eq!(documented(0x20), signature(0, 1));
+        assert_eq!(documented(0x00), signature(0, 0));
+        assert_eq!(documented(0x41), signature(2, 0));
+        assert_eq!(documented(0x26), None);
+        assert_eq!(documented(0x11), None);
+        assert_eq!(documented(0x61), signature(3, 3));
+        assert_eq!(documented(0x69), signature(4, 4));
+        assert_eq!(documented(0x66), signature(0, 1));
+        assert_eq!(documented(0x64), None);
+        assert_eq!(EFFECTS.len(), MNEMONICS.len());
+    }
+    #[test]
+    fn paths() {
+        let info = DebugInfo::default();
+        assert!(check(&[0x20, 10, 0x20, 20, 0x01, 0x10, 0xFF], &info).is_empty());
+        assert_eq!(
+            check(&[0x20, 1, 0x01], &info),
+            [CheckError::Underflow {
+                idx: 2,
+                op: 0x01,
+                needs: 2,
+                depth: 1,
+            }]
+        );
+        // PICK needs as many values as it reaches, and CLEAR keeps the locals
+        assert_eq!(
+            check(&[0x20, 1, 0x64, 1], &info),
+            [CheckError::Underflow {
+                idx: 2,
+                op: 0x64,
+                needs: 2,
+                depth: 1,
+            }]
+        );
+        assert!(check(&[0x26, 1, 0x20, 5, 0x67, 0x29, 0x66, 0x12], &info).is_empty());
+        // A frame's locals count, and the handler of a TRY gets the error code on top
+        let code = [0x26, 2, 0x32, 7, 0x29, 0x33, 0xFF, 0x01, 0x12];
+        assert!(check(&code, &info).is_empty());
+
+        assert_eq!(
+            check(&[0x20, 1, 0x31, 6, 0x20, 2, 0x12], &info),
+            [CheckError::DepthMismatch {
+                idx: 6,
+                label: String::from("%6"),
+                first: 1,
+                second: 2,
+            }]
+        );
+        assert_eq!(
+            check(&[0x20, 1, 0x23, 0x31, 2], &labelled(&[(2, "loop")])),
+            [CheckError::UnboundedLoop {
+                idx: 3,
+                label: String::from("loop"),
+                growth: 1,
+            }]
+        );
+    }
+    #[test]
+    fn repo_programs() {
+        for file in [
+            "hello_world.cor",
+            "benches/countdown.cor",
+            "benches/nested_countdown.cor",
+        ] {
+            let (code, info) = link_files_debug::<i64>(&[file], &[]).unwrap();
+            assert_eq!(check(&code, &info), [], "{file}");
+        }
+    }
+    #[test]
+    fn annotations() {
+        let dir = std::env::temp_dir().join(format!("corrode_check_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("prog.cor");
+        let file = file.to_str().unwrap();
+        let errors = |source: &str| {
+            std::fs::write(file, source).unwrap();
+            let (code, info) = link_files_debug::<i64>(&[file], &[]).unwrap();
+            check(&code, &info)
+        };
+
+        let square = "push 3\nsquare: ( a -- b ) dup\nmul\nret\n";
+        assert_eq!(errors(square), []);
+        let (_, info) = link_files_debug::<i64>(&[file], &[]).unwrap();
+        assert_eq!(
+            info.signatures,
+            [(
+                2,
+                Signature {
+                    inputs: 1,
+                    outputs: 1
+                }
+            )]
+        );
+        assert!(check_files::<i64>(&[file], &[]).is_ok());
+
+        assert_eq!(
+            errors("push 3\nsquare: ( a b -- c ) mul\nret\n"),
+            [
+                CheckError::Underflow {
+                    idx: 2,
+                    op: 0x03,
+                    needs: 2,
+                    depth: 1,
+                },
+                CheckError::SignatureInputs {
+                    idx: 2,
+                    label: String::from("square"),
+                    inputs: 2,
+                    depth: 1,
+                }
+            ]
+        );
+        assert_eq!(
+            errors("push 3\npush 3\nsquare: ( a -- b ) mul\nret\n"),
+            [CheckError::SignatureBelow {
+                idx: 4,
+                label: String::from("square"),
+                inputs: 1,
+            }]
+        );
+        assert_eq!(
+            errors("push 3\nsquare: ( a -- b ) dup\njmp $done\ndone: ret\n"),
+            [CheckError::SignatureOutputs {
+                idx: 3,
+                label: String::from("square"),
+                outputs: 1,
+                found: 2,
+            }]
+        );
+        assert_eq!(
+            errors("push 3\nsquare: ( a -- b ) dup\nret\n")[0].to_string(),
+            "square is annotated to leave 1 values but leaves 2"
+        );
+        assert!(check_files::<i64>(&[file], &[]).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
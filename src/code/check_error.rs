@@ -0,0 +1,61 @@
+use thiserror::Error;
+
+use crate::code::instruction::Instruction;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CheckError {
+    #[error("Stack can underflow: {} needs {needs} values but {depth} can be on the stack", Instruction::mnemonic(*.op))]
+    Underflow {
+        idx: usize,
+        op: u8,
+        needs: usize,
+        depth: usize,
+    },
+    #[error("{label} is reached with stack depths {first} and {second}")]
+    DepthMismatch {
+        idx: usize,
+        label: String,
+        first: usize,
+        second: usize,
+    },
+    #[error("Loop back to {label} grows the stack by {growth} on every iteration")]
+    UnboundedLoop {
+        idx: usize,
+        label: String,
+        growth: usize,
+    },
+    #[error("{label} takes {inputs} values but is reached with {depth} on the stack")]
+    SignatureInputs {
+        idx: usize,
+        label: String,
+        inputs: usize,
+        depth: usize,
+    },
+    #[error("{label} takes {inputs} values but uses more")]
+    SignatureBelow {
+        idx: usize,
+        label: String,
+        inputs: usize,
+    },
+    #[error("{label} is annotated to leave {outputs} values but leaves {found}")]
+    SignatureOutputs {
+        idx: usize,
+        label: String,
+        outputs: usize,
+        found: usize,
+    },
+}
+
+impl CheckError {
+    ///Index of the instruction the error is about
+    pub fn idx(&self) -> usize {
+        match self {
+            CheckError::Underflow { idx, .. }
+            | CheckError::DepthMismatch { idx, .. }
+            | CheckError::UnboundedLoop { idx, .. }
+            | CheckError::SignatureInputs { idx, .. }
+            | CheckError::SignatureBelow { idx, .. }
+            | CheckError::SignatureOutputs { idx, .. } => *idx,
+        }
+    }
+}
@@ -1,85 +1,115 @@
-use std::fmt::{Debug, Display};
+use std::{
+    fmt::{Debug, Display},
+    path::Path,
+};
 
 use colored::{ColoredString, Colorize};
-use num::{Integer, ToPrimitive, traits::NumOps};
+use num::{
+    Integer, ToPrimitive,
+    traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, NumOps},
+};
 
 use crate::{
-    code::parse::parse_code,
-    stack::{Stack, stack_error::StackError},
+    code::{instruction::Instruction, link::DebugInfo, parse::link_files_debug},
+    forth::{self, is_forth_file},
+    lang::compile_file,
+    stack::{Frame, Handler, Stack, scheduler::Coroutine, stack_error::StackError},
 };
 
-impl<T: ToPrimitive + NumOps + Display + TryInto<u8> + From<u8> + Integer + Clone> Stack<T> {
+impl<T> Stack<T>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    ///Execute until the code returns, exits or runs out, sending faults to the innermost TRY handler.
+    ///Coroutines started by SPAWN only end themselves that way; the first one ends them all.
     pub fn execute(&mut self, code: &[u8]) -> Result<T, StackError> {
+        loop {
+            if let Some(result) = self.execute_for(code, usize::MAX)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    ///Execute at most `steps` instructions like `execute`, or `None` when they run out first.
+    ///Executing again, on this stack or on one restored from its snapshot, carries on from there.
+    pub fn execute_for(&mut self, code: &[u8], steps: usize) -> Result<Option<T>, StackError> {
+        self.execute_keeping(code, steps, &mut None)
+    }
+
+    ///Like `execute_for`, handing a coroutine other than the first that finishes to `finished`
+    ///instead of dropping it
+    pub(crate) fn execute_keeping(
+        &mut self,
+        code: &[u8],
+        steps: usize,
+        finished: &mut Option<Coroutine<T>>,
+    ) -> Result<Option<T>, StackError> {
+        let mut steps = steps;
+        loop {
+            match self.execute_unguarded(code, &mut steps) {
+                Err(error) => self.catch(error)?,
+                Ok(Some(_)) if self.scheduler.current != 0 => {
+                    *finished = self.reschedule(None, true)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn execute_unguarded(
+        &mut self,
+        code: &[u8],
+        steps: &mut usize,
+    ) -> Result<Option<T>, StackError> {
         while let Some(&op) = code.get(self.idx) {
+            if *steps == 0 {
+                return Ok(None);
+            }
+            *steps -= 1;
             self.op = op;
             match op {
                 0x00 => {
                     self.idx += 1;
                 }
-                0x01 => {
-                    let rhs = self.pop()?;
-                    let lhs = self.pop()?;
-                    self.push(lhs + rhs)?;
-                    self.idx += 1;
-                }
-                0x02 => {
-                    let rhs = self.pop()?;
-                    let lhs = self.pop()?;
-                    self.push(lhs - rhs)?;
-                    self.idx += 1;
-                }
-                0x03 => {
-                    let rhs = self.pop()?;
-                    let lhs = self.pop()?;
-                    self.push(lhs * rhs)?;
-                    self.idx += 1;
-                }
-                0x04 => {
-                    let rhs = self.pop()?;
-                    let lhs = self.pop()?;
-                    self.push(lhs / rhs)?;
-                    self.idx += 1;
-                }
-                0x05 => {
+                0x01..=0x06 => {
                     let rhs = self.pop()?;
                     let lhs = self.pop()?;
-                    self.push(lhs % rhs)?;
+                    self.push(arithmetic(op, lhs, rhs, self.idx)?)?;
                     self.idx += 1;
                 }
                 0x10 => {
-                    println!("{}", self.peek().unwrap());
+                    let top = self.peek().ok_or(StackError::EmptyStack {
+                        idx: self.idx,
+                        op: self.op,
+                    })?;
+                    let line = top.to_string();
+                    self.write_line(&line);
 
                     self.idx += 1;
                 }
                 0x11 => {
-                    let mut string_data: Vec<u8> = Vec::new();
-
-                    while let Ok(character) = self.pop() {
-                        if character == 0.into() {
-                            break;
-                        } else if let Ok(to_push) = character.try_into() {
-                            string_data.push(to_push)
-                        }
-                    }
-                    string_data.reverse();
-                    if let Ok(out_string) = String::from_utf8(string_data.clone()) {
-                        println!("{}", out_string)
-                    } else {
-                        println!(
-                            "{}",
-                            ColoredString::from("Could not parse stack to string.").red()
-                        )
-                    }
-                    for c in string_data {
-                        self.push(c.into())?;
-                    }
+                    self.print_chars()?;
                     self.idx += 1
                 }
                 0x12 => {
-                    return self.peek().cloned().ok_or(StackError::EmptyStack {
-                        idx: self.idx,
-                        op: self.op,
-                    });
+                    return self
+                        .peek()
+                        .cloned()
+                        .map(Some)
+                        .ok_or(StackError::EmptyStack {
+                            idx: self.idx,
+                            op: self.op,
+                        });
                 }
                 0x20 => {
                     self.idx += 1;
@@ -106,13 +136,153 @@ impl<T: ToPrimitive + NumOps + Display + TryInto<u8> + From<u8> + Integer + Clon
                     self.push(top.clone())?;
                     self.push(top)?;
                 }
-                0x30 => self.idx = code[self.idx + 1] as usize,
+                0x24 => {
+                    if let Some(&slot) = code.get(self.idx + 1) {
+                        let item = self.slot(slot)?.clone();
+                        self.push(item)?;
+                        self.idx += 2;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x25 => {
+                    if let Some(&slot) = code.get(self.idx + 1) {
+                        let item = self.pop()?;
+                        *self.slot(slot)? = item;
+                        self.idx += 2;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x26 => {
+                    if let Some(&size) = code.get(self.idx + 1) {
+                        self.frames.push(Frame {
+                            base: self.state.len(),
+                            size: size as usize,
+                        });
+                        for _ in 0..size {
+                            self.push(0.into())?;
+                        }
+                        self.idx += 2;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x27 => {
+                    if let Some(&local) = code.get(self.idx + 1) {
+                        let item = self.local(local)?.clone();
+                        self.push(item)?;
+                        self.idx += 2;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x28 => {
+                    if let Some(&local) = code.get(self.idx + 1) {
+                        let item = self.pop()?;
+                        *self.local(local)? = item;
+                        self.idx += 2;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x29 => {
+                    self.leave()?;
+                    self.idx += 1;
+                }
+                0x30 => self.idx = jump_target(code, self.idx),
                 0x31 => match self.peek() {
-                    Some(top) if top != &0.into() => self.idx = code[self.idx + 1] as usize,
+                    Some(top) if top != &0.into() => self.idx = jump_target(code, self.idx),
                     _ => self.idx += 2,
                 },
+                0x32 => {
+                    if let Some(&address) = code.get(self.idx + 1) {
+                        self.handlers.push(Handler {
+                            address: address as usize,
+                            depth: self.state.len(),
+                            frames: self.frames.len(),
+                        });
+                        self.idx += 2;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x33 => {
+                    self.handlers.pop().ok_or(StackError::NoHandler {
+                        idx: self.idx,
+                        op: self.op,
+                    })?;
+                    self.idx += 1;
+                }
+                0x34 => {
+                    let code = self.pop()?;
+                    self.throw(code)?;
+                }
+                0x40 => {
+                    if self.pop()?.is_zero() {
+                        return Err(StackError::AssertionFailed {
+                            idx: self.idx,
+                            op: self.op,
+                        });
+                    }
+                    self.idx += 1;
+                }
+                0x41 => {
+                    let rhs = self.pop()?;
+                    let lhs = self.pop()?;
+                    if lhs != rhs {
+                        return Err(StackError::NotEqual {
+                            idx: self.idx,
+                            op: self.op,
+                            left: lhs.to_string(),
+                            right: rhs.to_string(),
+                        });
+                    }
+                    self.idx += 1;
+                }
+                0x50 => {
+                    if let Some(&address) = code.get(self.idx + 1) {
+                        self.spawn(address as usize);
+                        self.idx += 2;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x51 => {
+                    self.idx += 1;
+                    self.reschedule(None, false)?;
+                }
+                0x52 => {
+                    if let Some(&channel) = code.get(self.idx + 1) {
+                        self.send(channel)?;
+                        self.idx += 2;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x53 => {
+                    if let Some(&channel) = code.get(self.idx + 1) {
+                        self.receive(channel, self.idx + 2)?;
+                    } else {
+                        self.idx += 1;
+                    }
+                }
+                0x60..=0x65 | 0x68 | 0x69 => {
+                    let instruction =
+                        Instruction::decode(code, self.idx).unwrap_or(Instruction::Truncated(op));
+                    shuffle(&mut self.state, instruction, self.idx)?;
+                    self.idx += instruction.size();
+                }
+                0x66 => {
+                    self.push(from_count(self.state.len()))?;
+                    self.idx += 1;
+                }
+                0x67 => {
+                    self.state.truncate(floor(&self.frames));
+                    self.idx += 1;
+                }
 
-                0xFF => return Ok(0xFF.into()),
+                0xFF => return Ok(Some(0xFF.into())),
 
                 _ => {
                     return Err(StackError::UnknownOp {
@@ -122,37 +292,422 @@ impl<T: ToPrimitive + NumOps + Display + TryInto<u8> + From<u8> + Integer + Clon
                 }
             }
         }
-        Ok(0.into())
+        Ok(Some(0.into()))
+    }
+
+    ///Send a fault to the innermost handler with its error code, or give it back when there is none
+    pub(crate) fn catch(&mut self, error: StackError) -> Result<(), StackError> {
+        match self.handlers.pop() {
+            Some(handler) => self.unwind(handler, error.code().into()),
+            None => Err(error),
+        }
     }
+
+    ///THROW: send `code` to the innermost handler
+    pub(crate) fn throw(&mut self, code: T) -> Result<(), StackError> {
+        match self.handlers.pop() {
+            Some(handler) => self.unwind(handler, code),
+            None => Err(StackError::Uncaught {
+                idx: self.idx,
+                op: self.op,
+                code: code.to_string(),
+            }),
+        }
+    }
+
+    ///Drop what was pushed and the frames opened since the TRY, then continue at its handler with `code` on top
+    fn unwind(&mut self, handler: Handler, code: T) -> Result<(), StackError> {
+        self.state.truncate(handler.depth);
+        self.frames.truncate(handler.frames);
+        self.idx = handler.address;
+        self.push(code)
+    }
+
+    ///Stack slot counted from the bottom, for LOAD and STORE
+    fn slot(&mut self, slot: u8) -> Result<&mut T, StackError> {
+        let (idx, op) = (self.idx, self.op);
+        self.state
+            .get_mut(slot as usize)
+            .ok_or(StackError::SlotOutOfRange {
+                idx,
+                op,
+                slot: slot as usize,
+            })
+    }
+
+    ///Local of the innermost frame, for LOADLOCAL and STORELOCAL
+    fn local(&mut self, local: u8) -> Result<&mut T, StackError> {
+        let (idx, op) = (self.idx, self.op);
+        let frame = *self.frames.last().ok_or(StackError::NoFrame { idx, op })?;
+        let out_of_range = StackError::LocalOutOfRange {
+            idx,
+            op,
+            local: local as usize,
+        };
+        if local as usize >= frame.size {
+            return Err(out_of_range);
+        }
+        self.state
+            .get_mut(frame.base + local as usize)
+            .ok_or(out_of_range)
+    }
+
+    ///LEAVE: drop the locals of the innermost frame, keeping what was pushed above them
+    pub(crate) fn leave(&mut self) -> Result<(), StackError> {
+        let (idx, op) = (self.idx, self.op);
+        let frame = *self.frames.last().ok_or(StackError::NoFrame { idx, op })?;
+        if self.state.len() < frame.base + frame.size {
+            return Err(StackError::EmptyStack { idx, op });
+        }
+        self.state.drain(frame.base..frame.base + frame.size);
+        self.frames.pop();
+        Ok(())
+    }
+
+    ///Print a line of output, or add it to `captured` while that is `Some`
+    fn write_line(&mut self, line: &str) {
+        write_line(&mut self.captured, line)
+    }
+
+    ///PCHAR: print the stack down to the first 0 as UTF-8, consuming the 0
+    pub(crate) fn print_chars(&mut self) -> Result<(), StackError> {
+        let mut string_data: Vec<u8> = Vec::new();
+
+        while let Ok(character) = self.pop() {
+            if character == 0.into() {
+                break;
+            } else if let Ok(to_push) = character.try_into() {
+                string_data.push(to_push)
+            }
+        }
+        string_data.reverse();
+        if let Ok(out_string) = String::from_utf8(string_data.clone()) {
+            self.write_line(&out_string)
+        } else if self.captured.is_some() {
+            self.write_line("Could not parse stack to string.")
+        } else {
+            println!(
+                "{}",
+                ColoredString::from("Could not parse stack to string.").red()
+            )
+        }
+        for c in string_data {
+            self.push(c.into())?;
+        }
+        Ok(())
+    }
+}
+
+///Rearrange the top of `state` for a stack word of `Instruction::shuffle`, leaving it unchanged
+///when there are not enough values
+pub(crate) fn shuffle<T: Clone>(
+    state: &mut Vec<T>,
+    instruction: Instruction,
+    idx: usize,
+) -> Result<(), StackError> {
+    let Some((taken, order)) = instruction.shuffle() else {
+        return Ok(());
+    };
+    let (op, depth) = (instruction.opcode(), state.len());
+    if depth < taken {
+        return Err(match instruction {
+            Instruction::Pick(n) | Instruction::Roll(n) => StackError::OutOfReach {
+                idx,
+                op,
+                n: n as usize,
+                depth,
+            },
+            _ => StackError::EmptyStack { idx, op },
+        });
+    }
+    state
+        .try_reserve(order.len().saturating_sub(taken))
+        .map_err(|source| StackError::ReserveError { source })?;
+    let values = state.split_off(depth - taken);
+    state.extend(order.iter().map(|&i| values[i].clone()));
+    Ok(())
+}
+
+///A count as a stack value, built from its bytes so that it works for any `T`
+pub(crate) fn from_count<T: From<u8> + NumOps + Clone>(count: usize) -> T {
+    let bytes = count.to_be_bytes();
+    let start = bytes
+        .iter()
+        .position(|&byte| byte != 0)
+        .unwrap_or(bytes.len() - 1);
+    bytes[start + 1..]
+        .iter()
+        .fold(T::from(bytes[start]), |value, &byte| {
+            value * 16.into() * 16.into() + byte.into()
+        })
+}
+
+///Lowest stack index CLEAR may drop, just above the locals of the innermost frame
+pub(crate) fn floor(frames: &[Frame]) -> usize {
+    frames.last().map_or(0, |frame| frame.base + frame.size)
 }
 
-///Compile and execute .cor file returning any output to the caller
+///Address operand of the jump at `idx`, or the end of the code when it is missing
+fn jump_target(code: &[u8], idx: usize) -> usize {
+    code.get(idx + 1)
+        .map_or(code.len(), |&address| address as usize)
+}
+
+///ADD, SUB, MUL, DIV, MOD or LT, by opcode, failing at `idx` on division by zero and on overflow
+pub(crate) fn arithmetic<T>(op: u8, lhs: T, rhs: T, idx: usize) -> Result<T, StackError>
+where
+    T: Integer + From<u8> + CheckedAdd + CheckedSub + CheckedMul + CheckedDiv,
+{
+    if matches!(op, 0x04 | 0x05) && rhs.is_zero() {
+        return Err(StackError::DivisionByZero { idx, op });
+    }
+    let result = match op {
+        0x01 => lhs.checked_add(&rhs),
+        0x02 => lhs.checked_sub(&rhs),
+        0x03 => lhs.checked_mul(&rhs),
+        0x04 => lhs.checked_div(&rhs),
+        // The remainder overflows exactly where the quotient does
+        0x05 => lhs.checked_div(&rhs).map(|_| lhs % rhs),
+        _ => Some(u8::from(lhs < rhs).into()),
+    };
+    result.ok_or(StackError::Overflow { idx, op })
+}
+
+///Compile and execute a .cor file, a .crd file in the structured language or a .fs file in Forth,
+///returning any output to the caller. A runtime error dumps the stack to a `.core` snapshot beside it.
 pub fn run<T>(input_file: &str) -> anyhow::Result<T>
 where
-    T: Debug + ToPrimitive + NumOps + Display + TryInto<u8> + From<u8> + Integer + Clone,
+    T: Debug
+        + ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
 {
-    let mut stack: Stack<T> = Stack::new();
-    let code_stack: Stack<u8> = Stack::from(&parse_code(input_file)?);
+    run_files(&[input_file], &[])
+}
 
-    let result = stack.execute(&code_stack.state);
+///Like `run`, linking a .cor file with the .cor modules after it and assembling them with `defines`.
+///Fails when `input_files` is empty.
+pub fn run_files<T>(input_files: &[&str], defines: &[(&str, i64)]) -> anyhow::Result<T>
+where
+    T: Debug
+        + ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let (code, info) = compile_debug::<T>(input_files, defines)?;
+    execute_dumping(Stack::new(), &code, &info, input_files[0])
+}
+
+///Carry on running the program in `input_files` from the snapshot in `snapshot_file`,
+///which may be the core dump of an earlier run
+pub fn resume<T>(
+    snapshot_file: &str,
+    input_files: &[&str],
+    defines: &[(&str, i64)],
+) -> anyhow::Result<T>
+where
+    T: Debug
+        + ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let (code, info) = compile_debug::<T>(input_files, defines)?;
+    let stack = Stack::restore(&std::fs::read_to_string(snapshot_file)?, &code)?;
+    execute_dumping(stack, &code, &info, input_files[0])
+}
 
-    if let Err(error) = result.clone() {
-        let mut error_location = None;
-        if let StackError::UnknownOp { idx, .. } = error {
-            error_location = Some(idx);
-        } else if let StackError::EmptyStack { idx, .. } = error {
-            error_location = Some(idx);
+///Compile the files `run_files` takes, also returning where the labels and instructions of .cor files
+///and the instructions of .crd and Forth files came from
+pub(crate) fn compile_debug<T>(
+    input_files: &[&str],
+    defines: &[(&str, i64)],
+) -> anyhow::Result<(Vec<u8>, DebugInfo)>
+where
+    T: From<u8> + CheckedAdd + CheckedSub + CheckedMul,
+{
+    Ok(match input_files {
+        [] => anyhow::bail!("No input file given"),
+        [input_file] if input_file.ends_with(".crd") => compile_file(input_file)?,
+        [input_file] if is_forth_file(input_file) => forth::compile_file(input_file)?,
+        _ if input_files
+            .iter()
+            .any(|file| file.ends_with(".crd") || is_forth_file(file)) =>
+        {
+            anyhow::bail!("Only .cor files can be linked together")
         }
-        eprintln!("{}", format!("{:?}", result).red());
-        println!("Call Stack");
+        _ => link_files_debug::<T>(input_files, defines)?,
+    })
+}
+
+///Instructions `execute_dumping` runs between the copies of the stack a failure is replayed from
+const CHECKPOINT_STEPS: usize = 1 << 16;
 
-        code_stack.trace(error_location);
-        println!("current execution stack state");
-        println!("{:?}", stack.state);
+///Execute `code`, describing a runtime error and dumping the stack as it was right before the
+///failed instruction to a snapshot beside `main_file`, so resuming from it runs that instruction
+///again. The bytecode is traced instead when the source map has nothing for the failed instruction.
+fn execute_dumping<T>(
+    mut stack: Stack<T>,
+    code: &[u8],
+    info: &DebugInfo,
+    main_file: &str,
+) -> anyhow::Result<T>
+where
+    T: Debug
+        + ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let result = loop {
+        let checkpoint = stack.clone();
+        match stack.execute_for(code, CHECKPOINT_STEPS) {
+            Ok(Some(value)) => break Ok(value),
+            Ok(None) => (),
+            Err(error) => {
+                let captured = stack.captured.take();
+                stack = before_failure(&checkpoint, code);
+                stack.captured = captured;
+                break Err(error);
+            }
+        }
+    };
+
+    if let Err(error) = &result {
+        eprintln!("{}", describe_error(error, code, info, &stack.state).red());
+        if failed_at(code, error)
+            .and_then(|idx| info.line(idx))
+            .is_none()
+        {
+            println!("Call Stack");
+            Stack::<u8>::from(code).trace(error.idx());
+        }
+        stack.trace_frame();
+
+        let core = Path::new(main_file).with_extension("core");
+        std::fs::write(&core, stack.snapshot(code))?;
+        eprintln!("Core dumped to {}", core.display());
     }
     Ok(result?)
 }
 
+///Replay `code` from `checkpoint` up to the instruction that fails, without printing again
+fn before_failure<T>(checkpoint: &Stack<T>, code: &[u8]) -> Stack<T>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let mut probe = checkpoint.clone();
+    probe.captured = Some(String::new());
+    let mut steps = 0;
+    while let Ok(None) = probe.execute_for(code, 1) {
+        steps += 1;
+    }
+    let mut stack = checkpoint.clone();
+    stack.captured = Some(String::new());
+    // The same steps ran without failing a moment ago
+    let _ = stack.execute_for(code, steps);
+    stack
+}
+
+///Print a line of output, or add it to `captured` while that is `Some`. Every engine writes
+///through here so that `Stack::captured` collects output whichever one runs.
+pub(crate) fn write_line(captured: &mut Option<String>, line: &str) {
+    match captured {
+        Some(captured) => {
+            captured.push_str(line);
+            captured.push('\n');
+        }
+        None => println!("{line}"),
+    }
+}
+
+///Address of the instruction `error` comes from. SWP and DUP count past their opcode before they
+///pop, so they fail at the address after theirs.
+pub(crate) fn failed_at(code: &[u8], error: &StackError) -> Option<usize> {
+    let idx = error.idx()?;
+    match error.op() {
+        Some(op @ (0x21 | 0x23)) if idx > 0 && code.get(idx - 1) == Some(&op) => Some(idx - 1),
+        _ => Some(idx),
+    }
+}
+
+///A runtime error with the source line of the failed instruction when the source map has it,
+///the instruction and what is left on the stack
+pub(crate) fn describe_error<T: Debug>(
+    error: &StackError,
+    code: &[u8],
+    info: &DebugInfo,
+    state: &[T],
+) -> String {
+    let idx = failed_at(code, error);
+    let mut out = match idx.and_then(|idx| info.line(idx)) {
+        Some(at) => {
+            let mut out = format!("{}: {error}\n", at.with_column());
+            let source = std::fs::read_to_string(&at.file).unwrap_or_default();
+            if let Some(line) = source.lines().nth(at.line - 1) {
+                // Tabs before the column stay tabs, so the caret lines up however they are shown
+                let indent: String = line
+                    .chars()
+                    .take(at.column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+                out.push_str(&format!("{line}\n{indent}^\n"));
+            }
+            out
+        }
+        None => format!("{error}\n"),
+    };
+    if let Some(idx) = idx {
+        let instruction = Instruction::decode(code, idx).map_or_else(
+            || String::from("end of code"),
+            |instruction| instruction.to_string(),
+        );
+        out.push_str(&format!("in {instruction} at %{idx}\n"));
+    }
+    out.push_str(&format!("stack: {state:?}"));
+    out
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -198,6 +753,29 @@ mod tests {
         assert_eq!(stack.state, [2]);
     }
     #[test]
+    fn overflow() {
+        // PUSH 16, DUP, MUL, DUP, MUL, DUP, MUL, DUP, MUL: 2^64
+        let code: Vec<u8> = vec![
+            0x20, 0x10, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x12,
+        ];
+        let error = Stack::<i64>::new().execute(&code).unwrap_err();
+        assert!(matches!(error, StackError::Overflow { idx: 9, op: 0x03 }));
+        assert_eq!(error.code(), 13);
+        let retval = Stack::<num::BigInt>::new().execute(&code).unwrap();
+        assert_eq!(retval.to_string(), "18446744073709551616");
+
+        // 2^32 / 2 * -2^32 is i64::MIN, then PUSH 0, PUSH 1, SUB, DIV or MOD
+        let mut code: Vec<u8> = vec![
+            0x20, 0x10, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x23, 0x20, 0x02, 0x04, 0x21, 0x20,
+            0x00, 0x21, 0x02, 0x03, 0x20, 0x00, 0x20, 0x01, 0x02, 0x04, 0x12,
+        ];
+        let error = Stack::<i64>::new().execute(&code).unwrap_err();
+        assert!(matches!(error, StackError::Overflow { idx: 23, op: 0x04 }));
+        code[23] = 0x05;
+        let error = Stack::<i64>::new().execute(&code).unwrap_err();
+        assert!(matches!(error, StackError::Overflow { idx: 23, op: 0x05 }));
+    }
+    #[test]
     fn jmp() {
         let code: Vec<u8> = vec![0x20, 0x05, 0x30, 0x06, 0x20, 0x03, 0x12];
         let mut stack = Stack::<i64>::new();
@@ -213,4 +791,312 @@ mod tests {
         assert_eq!(retval, 3);
         assert_eq!(stack.state, [0, 3]);
     }
+    #[test]
+    fn lt() {
+        let code: Vec<u8> = vec![
+            0x20, 0x05, 0x20, 0x06, 0x06, 0x20, 0x06, 0x20, 0x05, 0x06, 0x12,
+        ];
+        let mut stack = Stack::<i64>::new();
+        let retval = stack.execute(&code).unwrap();
+        assert_eq!(retval, 0);
+        assert_eq!(stack.state, [1, 0]);
+    }
+    #[test]
+    fn load_store() {
+        // PUSH 5, PUSH 6, LOAD 0, ADD, STORE 0, RET
+        let code: Vec<u8> = vec![0x20, 0x05, 0x20, 0x06, 0x24, 0x00, 0x01, 0x25, 0x00, 0x12];
+        let mut stack = Stack::<i64>::new();
+        let retval = stack.execute(&code).unwrap();
+        assert_eq!(retval, 11);
+        assert_eq!(stack.state, [11]);
+
+        let mut stack = Stack::<i64>::new();
+        let error = stack.execute(&[0x20, 0x05, 0x24, 0x01]).unwrap_err();
+        assert!(matches!(
+            error,
+            StackError::SlotOutOfRange {
+                idx: 2,
+                op: 0x24,
+                slot: 1
+            }
+        ));
+    }
+    #[test]
+    fn frames() {
+        // PUSH 9, ENTER 2, PUSH 4, STORELOCAL 1, LOADLOCAL 1, LOADLOCAL 0, ADD, LEAVE, RET
+        let code: Vec<u8> = vec![
+            0x20, 0x09, 0x26, 0x02, 0x20, 0x04, 0x28, 0x01, 0x27, 0x01, 0x27, 0x00, 0x01, 0x29,
+            0x12,
+        ];
+        let mut stack = Stack::<i64>::new();
+        let retval = stack.execute(&code).unwrap();
+        assert_eq!(retval, 4);
+        assert_eq!(stack.state, [9, 4]);
+        assert!(stack.frames.is_empty());
+
+        let mut stack = Stack::<i64>::new();
+        let error = stack.execute(&[0x26, 0x01, 0x27, 0x01]).unwrap_err();
+        assert!(matches!(
+            error,
+            StackError::LocalOutOfRange {
+                idx: 2,
+                op: 0x27,
+                local: 1
+            }
+        ));
+        assert_eq!(stack.frames, [Frame { base: 0, size: 1 }]);
+
+        let mut stack = Stack::<i64>::new();
+        let error = stack.execute(&[0x26, 0x02, 0x22, 0x29]).unwrap_err();
+        assert!(matches!(error, StackError::EmptyStack { idx: 3, .. }));
+        let error = Stack::<i64>::new().execute(&[0x29]).unwrap_err();
+        assert!(matches!(error, StackError::NoFrame { idx: 0, op: 0x29 }));
+    }
+    #[test]
+    fn no_input_files() {
+        let error = run_files::<i64>(&[], &[]).unwrap_err();
+        assert_eq!(error.to_string(), "No input file given");
+        assert!(resume::<i64>("prog.core", &[], &[]).is_err());
+    }
+    #[test]
+    fn source_mapped_errors() {
+        let dir = std::env::temp_dir().join(format!("corrode_errors_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let describe_file = |name: &str, source: &str| {
+            let file = dir.join(name);
+            std::fs::write(&file, source).unwrap();
+            let (code, info) = compile_debug::<i64>(&[file.to_str().unwrap()], &[]).unwrap();
+            let mut stack = Stack::<i64>::new();
+            let error = stack.execute(&code).unwrap_err();
+            let described = describe_error(&error, &code, &info, &stack.state);
+            described.replace(file.to_str().unwrap(), name)
+        };
+        let describe = |source: &str| describe_file("main.cor", source);
+
+        assert_eq!(
+            describe("push 7\npush 1\n\tpush 0 ; divisor\n\tdiv\n"),
+            "main.cor:4:2: Division by zero\n\tdiv\n\t^\nin DIV at %6\nstack: [7]"
+        );
+        // SWP fails past its opcode, but the source map still finds it
+        assert_eq!(
+            describe("push 1\nloop: swp\n"),
+            "main.cor:2:7: Cannot pop empty stack\nloop: swp\n      ^\nin SWP at %2\nstack: []"
+        );
+        assert_eq!(
+            describe_file("main.crd", "let x = 7;\nlet y = 0;\nprint x / y;\n"),
+            "main.crd:3:9: Division by zero\nprint x / y;\n        ^\nin DIV at %8\nstack: [7, 0]"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let error = Stack::<i64>::new().execute(&[0x01]).unwrap_err();
+        assert_eq!(
+            describe_error(&error, &[0x01], &DebugInfo::default(), &[0i64; 0]),
+            "Cannot pop empty stack\nin ADD at %0\nstack: []"
+        );
+    }
+    #[test]
+    fn forth_words() {
+        let state = |code: &[u8]| {
+            let mut stack = Stack::<i64>::from(&[1, 2, 3, 4]);
+            stack.execute(code).unwrap();
+            stack.state
+        };
+        assert_eq!(state(&[0x60]), [1, 2, 3, 4, 3]);
+        assert_eq!(state(&[0x61]), [1, 3, 4, 2]);
+        assert_eq!(state(&[0x62]), [1, 2, 4]);
+        assert_eq!(state(&[0x63]), [1, 2, 4, 3, 4]);
+        assert_eq!(state(&[0x64, 0x03]), [1, 2, 3, 4, 1]);
+        assert_eq!(state(&[0x64, 0x00]), [1, 2, 3, 4, 4]);
+        assert_eq!(state(&[0x65, 0x03]), [2, 3, 4, 1]);
+        assert_eq!(state(&[0x65, 0x00]), [1, 2, 3, 4]);
+        assert_eq!(state(&[0x66]), [1, 2, 3, 4, 4]);
+        assert_eq!(state(&[0x67]), [0; 0]);
+        assert_eq!(state(&[0x68]), [1, 2, 3, 4, 3, 4]);
+        assert_eq!(state(&[0x69]), [3, 4, 1, 2]);
+        assert_eq!(state(&[0x64]), [1, 2, 3, 4]);
+        // CLEAR keeps the locals of the innermost frame
+        assert_eq!(state(&[0x26, 0x01, 0x20, 0x07, 0x67]), [1, 2, 3, 4, 0]);
+
+        // Out of reach, leaving the stack as it was
+        let mut stack = Stack::<i64>::from(&[1, 2]);
+        let error = stack.execute(&[0x64, 0x02]).unwrap_err();
+        assert!(matches!(
+            error,
+            StackError::OutOfReach {
+                idx: 0,
+                op: 0x64,
+                n: 2,
+                depth: 2
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Cannot reach value 2 below the top of a stack of 2 values"
+        );
+        assert_eq!(stack.state, [1, 2]);
+        let error = stack.execute(&[0x61]).unwrap_err();
+        assert!(matches!(error, StackError::EmptyStack { idx: 0, op: 0x61 }));
+        assert_eq!(stack.state, [1, 2]);
+
+        assert_eq!(from_count::<u8>(200), 200);
+        assert_eq!(from_count::<i64>(70000), 70000);
+        assert_eq!(from_count::<i64>(0), 0);
+    }
+    #[test]
+    fn try_throw() {
+        // PUSH 1, TRY 9, PUSH 2, PUSH 7, THROW, RET, handler: PUSH 3, ADD, RET
+        let code: Vec<u8> = vec![
+            0x20, 0x01, 0x32, 0x09, 0x20, 0x02, 0x20, 0x07, 0x34, 0x20, 0x03, 0x01, 0x12,
+        ];
+        let mut stack = Stack::<i64>::new();
+        assert_eq!(stack.execute(&code).unwrap(), 10);
+        assert_eq!(stack.state, [1, 10]);
+        assert!(stack.handlers.is_empty());
+
+        // TRY 9, ENTER 1, PUSH 1, PUSH 0, DIV, handler: RET
+        let code: Vec<u8> = vec![0x32, 0x09, 0x26, 0x01, 0x20, 0x01, 0x20, 0x00, 0x04, 0x12];
+        let mut stack = Stack::<i64>::new();
+        assert_eq!(stack.execute(&code).unwrap(), 4);
+        assert_eq!(stack.state, [4]);
+        assert!(stack.frames.is_empty());
+
+        // TRY 3, ENDTRY, handler: RET
+        let error = Stack::<i64>::new()
+            .execute(&[0x32, 0x03, 0x33, 0x12])
+            .unwrap_err();
+        assert!(matches!(error, StackError::EmptyStack { idx: 3, op: 0x12 }));
+        let error = Stack::<i64>::new()
+            .execute(&[0x20, 0x07, 0x34])
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            StackError::Uncaught {
+                idx: 2,
+                ref code,
+                ..
+            } if code == "7"
+        ));
+        let error = Stack::<i64>::new().execute(&[0x33]).unwrap_err();
+        assert!(matches!(error, StackError::NoHandler { idx: 0, op: 0x33 }));
+    }
+    #[test]
+    fn assertions() {
+        // PUSH 1, ASSERT, PUSH 4, PUSH 4, ASSERTEQ, PUSH 9, RET
+        let code: Vec<u8> = vec![
+            0x20, 0x01, 0x40, 0x20, 0x04, 0x20, 0x04, 0x41, 0x20, 0x09, 0x12,
+        ];
+        let mut stack = Stack::<i64>::new();
+        assert_eq!(stack.execute(&code).unwrap(), 9);
+        assert_eq!(stack.state, [9]);
+
+        let error = Stack::<i64>::new()
+            .execute(&[0x20, 0x00, 0x40])
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            StackError::AssertionFailed { idx: 2, op: 0x40 }
+        ));
+        let error = Stack::<i64>::new()
+            .execute(&[0x20, 0x02, 0x20, 0x03, 0x41])
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Assertion failed: 2 is not equal to 3");
+        assert_eq!(error.code(), 11);
+
+        // TRY 7, PUSH 2, PUSH 3, ASSERTEQ, handler: RET
+        let code: Vec<u8> = vec![0x32, 0x07, 0x20, 0x02, 0x20, 0x03, 0x41, 0x12];
+        assert_eq!(Stack::<i64>::new().execute(&code).unwrap(), 11);
+
+        // PRINT and PCHAR write to the captured output instead of stdout
+        let mut stack = Stack::<i64>::new();
+        stack.captured = Some(String::new());
+        stack
+            .execute(&[
+                0x20, 0x07, 0x10, 0x20, 0x00, 0x20, 0x48, 0x20, 0x69, 0x11, 0x12,
+            ])
+            .unwrap();
+        assert_eq!(stack.captured.unwrap(), "7\nHi\n");
+    }
+    #[test]
+    fn bignum() {
+        // PUSH 255, DUP, MUL, DUP, MUL, DUP, MUL, DUP, MUL, PRINT, PUSH 0, PUSH 72, PUSH 105, PCHAR, POP, RET
+        let code: Vec<u8> = vec![
+            0x20, 0xFF, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x10, 0x20, 0x00, 0x20,
+            0x48, 0x20, 0x69, 0x11, 0x22, 0x22, 0x12,
+        ];
+        let mut stack = Stack::<num::BigInt>::new();
+        stack.captured = Some(String::new());
+        let retval = stack.execute(&code).unwrap();
+        assert_eq!(retval, num::BigInt::from(255).pow(16));
+        assert_eq!(
+            stack.captured.unwrap(),
+            "319626579315078487616775634918212890625\nHi\n"
+        );
+
+        // 2^64 compared with 1, then thrown
+        let mut code: Vec<u8> = vec![
+            0x20, 0x10, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x20, 0x01, 0x41,
+        ];
+        let error = Stack::<num::BigInt>::new().execute(&code).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Assertion failed: 18446744073709551616 is not equal to 1"
+        );
+        code[12] = 0x22;
+        code.push(0x34);
+        let error = Stack::<num::BigInt>::new().execute(&code).unwrap_err();
+        assert_eq!(error.to_string(), "Uncaught exception 18446744073709551616");
+    }
+    #[test]
+    fn coroutines() {
+        // SPAWN 10, SPAWN 15, RECV 2, RECV 2, ADD, RET,
+        // 10: PUSH 3, SEND 1, EXIT, 15: RECV 1, DUP, YIELD, SEND 2, SEND 2
+        let code: Vec<u8> = vec![
+            0x50, 0x0A, 0x50, 0x0F, 0x53, 0x02, 0x53, 0x02, 0x01, 0x12, 0x20, 0x03, 0x52, 0x01,
+            0xFF, 0x53, 0x01, 0x23, 0x51, 0x52, 0x02, 0x52, 0x02,
+        ];
+        let mut stack = Stack::<i64>::new();
+        assert_eq!(stack.execute(&code).unwrap(), 6);
+        assert_eq!(stack.state, [6]);
+        assert!(stack.scheduler.queue.is_empty());
+
+        // RECV 1 with nothing that could ever send on it
+        let error = Stack::<i64>::new()
+            .execute(&[0x50, 0x04, 0x53, 0x01, 0x53, 0x02])
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            StackError::Deadlock { ref blocked, .. } if blocked == &[(0, 1), (1, 2)]
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Deadlock, every coroutine is waiting to receive: coroutine 0 on channel 1, coroutine 1 on channel 2"
+        );
+        // The spawned coroutine returns while the first one waits, which is where it fails
+        let error = Stack::<i64>::new()
+            .execute(&[0x50, 0x05, 0x53, 0x01, 0x12, 0x20, 0x01])
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            StackError::Deadlock { idx: 2, op: 0x53, ref blocked } if blocked == &[(0, 1)]
+        ));
+    }
+    #[test]
+    fn core_dump() {
+        let dir = std::env::temp_dir().join(format!("corrode_core_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("divide.cor");
+        std::fs::write(&file, "push 7\nenter 1\npush 0\ndiv\nret\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        assert!(run::<i64>(file).is_err());
+        let core = std::fs::read_to_string(dir.join("divide.core")).unwrap();
+        // The dump holds the operands of the DIV, and running it again from there retries it
+        assert!(core.contains("\nidx 6\nop 32\nstate 7 0 0\nframe 1 1\n"));
+        let error = resume::<i64>(dir.join("divide.core").to_str().unwrap(), &[file], &[]);
+        assert!(matches!(
+            error.unwrap_err().downcast_ref::<StackError>(),
+            Some(StackError::DivisionByZero { idx: 6, .. })
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
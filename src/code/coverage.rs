@@ -0,0 +1,264 @@
+/*!Code coverage, mapping the instructions a run executed back to the lines they came from
+ *
+ * A line is covered when an instruction assembled from it ran, its count is
+ * the most any of them ran. Every JNZ is a branch with two outcomes, falling
+ * through (0) and jumping (1). Running several inputs into the same `Profile`
+ * adds up their coverage. Only .cor files have lines, the structured
+ * language has none.
+ *
+ * lcov  \\ TN, SF, DA, BRDA, LF, LH, BRF and BRH records, up to end_of_record for every file
+ * json  \\ {"files": [{"file", "lines": [{"line", "count"}], "branches": [{"line", "offset", "fell_through", "jumped"}]}]}
+ */
+
+use std::{
+    collections::BTreeMap,
+    fmt::{Debug, Display, Write},
+    path::Path,
+};
+
+use num::{
+    Integer, ToPrimitive,
+    traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, NumOps},
+};
+use serde_json::json;
+
+use crate::{
+    code::{code_execution::compile_debug, link::DebugInfo, profiler::Profile},
+    stack::Stack,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    ///Coverage of every file that instructions were assembled from, by name
+    pub files: BTreeMap<String, FileCoverage>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileCoverage {
+    ///Executions of every line with instructions
+    pub lines: BTreeMap<usize, u64>,
+    ///Times every JNZ fell through and jumped, by line and offset, `None` when it never ran
+    pub branches: BTreeMap<(usize, usize), Option<[u64; 2]>>,
+}
+
+impl Coverage {
+    ///Coverage of `code` after running it with `profile`, with the lines in `info`
+    pub fn new(profile: &Profile, code: &[u8], info: &DebugInfo) -> Self {
+        let mut coverage = Coverage::default();
+        for (offset, at) in &info.lines {
+            let count = profile.counts.get(*offset).copied().unwrap_or(0);
+            let file = coverage.files.entry(at.file.clone()).or_default();
+            let line = file.lines.entry(at.line).or_default();
+            *line = (*line).max(count);
+            if code.get(*offset) == Some(&0x31) {
+                let outcomes =
+                    (count > 0).then(|| profile.branches.get(offset).copied().unwrap_or_default());
+                file.branches.insert((at.line, *offset), outcomes);
+            }
+        }
+        coverage
+    }
+
+    ///Lines hit and lines, branch outcomes hit and branch outcomes
+    pub fn totals(&self) -> (usize, usize, usize, usize) {
+        self.files.values().fold((0, 0, 0, 0), |totals, file| {
+            let (lines_hit, lines, branches_hit, branches) = file.totals();
+            (
+                totals.0 + lines_hit,
+                totals.1 + lines,
+                totals.2 + branches_hit,
+                totals.3 + branches,
+            )
+        })
+    }
+
+    ///Report in the lcov tracefile format of genhtml and coverage viewers
+    pub fn lcov(&self) -> String {
+        let mut out = String::from("TN:\n");
+        for (name, file) in &self.files {
+            writeln!(out, "SF:{name}").unwrap();
+            for (line, count) in &file.lines {
+                writeln!(out, "DA:{line},{count}").unwrap();
+            }
+            for ((line, offset), outcomes) in &file.branches {
+                for branch in 0..2 {
+                    match outcomes {
+                        Some(outcomes) => {
+                            writeln!(out, "BRDA:{line},{offset},{branch},{}", outcomes[branch])
+                        }
+                        None => writeln!(out, "BRDA:{line},{offset},{branch},-"),
+                    }
+                    .unwrap();
+                }
+            }
+            let (lines_hit, lines, branches_hit, branches) = file.totals();
+            writeln!(out, "LF:{lines}\nLH:{lines_hit}").unwrap();
+            writeln!(out, "BRF:{branches}\nBRH:{branches_hit}").unwrap();
+            out.push_str("end_of_record\n");
+        }
+        out
+    }
+
+    ///Report as JSON, with `null` outcomes for branches that never ran
+    pub fn json(&self) -> String {
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .map(|(name, file)| {
+                let lines: Vec<_> = file
+                    .lines
+                    .iter()
+                    .map(|(line, count)| json!({"line": line, "count": count}))
+                    .collect();
+                let branches: Vec<_> = file
+                    .branches
+                    .iter()
+                    .map(|((line, offset), outcomes)| {
+                        json!({
+                            "line": line,
+                            "offset": offset,
+                            "fell_through": outcomes.map(|outcomes| outcomes[0]),
+                            "jumped": outcomes.map(|outcomes| outcomes[1]),
+                        })
+                    })
+                    .collect();
+                json!({"file": name, "lines": lines, "branches": branches})
+            })
+            .collect();
+        let (lines_hit, lines, branches_hit, branches) = self.totals();
+        let report = json!({
+            "files": files,
+            "lines": lines,
+            "lines_hit": lines_hit,
+            "branches": branches,
+            "branches_hit": branches_hit,
+        });
+        serde_json::to_string_pretty(&report).unwrap()
+    }
+}
+
+impl FileCoverage {
+    ///Lines hit and lines, branch outcomes hit and branch outcomes
+    pub fn totals(&self) -> (usize, usize, usize, usize) {
+        let lines_hit = self.lines.values().filter(|&&count| count > 0).count();
+        let branches_hit = self
+            .branches
+            .values()
+            .flatten()
+            .flatten()
+            .filter(|&&count| count > 0)
+            .count();
+        (
+            lines_hit,
+            self.lines.len(),
+            branches_hit,
+            2 * self.branches.len(),
+        )
+    }
+}
+
+///Run a program recording coverage, and write it beside the first file as lcov with a `.lcov`
+///extension and as JSON with a `.coverage.json` one
+pub fn coverage<T>(input_files: &[&str], defines: &[(&str, i64)]) -> anyhow::Result<T>
+where
+    T: Debug
+        + ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let (code, info) = compile_debug::<T>(input_files, defines)?;
+    let mut profile = Profile::new(code.len());
+    let result = Stack::<T>::new().execute_profiled(&code, &mut profile);
+
+    let coverage = Coverage::new(&profile, &code, &info);
+    let main_file = Path::new(input_files[0]);
+    std::fs::write(main_file.with_extension("lcov"), coverage.lcov())?;
+    std::fs::write(main_file.with_extension("coverage.json"), coverage.json())?;
+    let (lines_hit, lines, branches_hit, branches) = coverage.totals();
+    println!("Lines {lines_hit}/{lines}, branches {branches_hit}/{branches}");
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::preprocess::Location;
+
+    use super::*;
+
+    fn at(file: &str, line: usize) -> Location {
+        Location {
+            file: file.into(),
+            line,
+            column: 1,
+        }
+    }
+
+    ///PUSH 1, JNZ over, PUSH 2, over: PUSH 0, JNZ end, RET, with a JNZ that never runs
+    fn covered() -> Coverage {
+        let code = [
+            0x20, 0x01, 0x31, 0x06, 0x20, 0x02, 0x20, 0x00, 0x31, 0x0B, 0x12, 0x31, 0x00,
+        ];
+        let info = DebugInfo {
+            labels: Vec::new(),
+            lines: vec![
+                (0, at("main.cor", 1)),
+                (2, at("main.cor", 2)),
+                (4, at("main.cor", 3)),
+                (6, at("lib.cor", 1)),
+                (8, at("lib.cor", 1)),
+                (10, at("lib.cor", 2)),
+                (11, at("lib.cor", 3)),
+            ],
+            signatures: Vec::new(),
+        };
+        let mut profile = Profile::new(code.len());
+        let result = Stack::<i64>::new().execute_profiled(&code, &mut profile);
+        assert_eq!(result.unwrap(), 0);
+        Coverage::new(&profile, &code, &info)
+    }
+
+    #[test]
+    fn lines_and_branches() {
+        let coverage = covered();
+        let main = &coverage.files["main.cor"];
+        assert_eq!(main.lines, BTreeMap::from([(1, 1), (2, 1), (3, 0)]));
+        assert_eq!(main.branches, BTreeMap::from([((2, 2), Some([0, 1]))]));
+        let lib = &coverage.files["lib.cor"];
+        assert_eq!(lib.lines, BTreeMap::from([(1, 1), (2, 1), (3, 0)]));
+        assert_eq!(
+            lib.branches,
+            BTreeMap::from([((1, 8), Some([1, 0])), ((3, 11), None)])
+        );
+        assert_eq!(coverage.totals(), (4, 6, 2, 6));
+    }
+    #[test]
+    fn reports() {
+        let coverage = covered();
+        assert_eq!(
+            coverage.lcov(),
+            "TN:\n\
+             SF:lib.cor\nDA:1,1\nDA:2,1\nDA:3,0\n\
+             BRDA:1,8,0,1\nBRDA:1,8,1,0\nBRDA:3,11,0,-\nBRDA:3,11,1,-\n\
+             LF:3\nLH:2\nBRF:4\nBRH:1\nend_of_record\n\
+             SF:main.cor\nDA:1,1\nDA:2,1\nDA:3,0\n\
+             BRDA:2,2,0,0\nBRDA:2,2,1,1\n\
+             LF:3\nLH:2\nBRF:2\nBRH:1\nend_of_record\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&coverage.json()).unwrap();
+        assert_eq!(json["lines_hit"], 4);
+        assert_eq!(json["files"][0]["file"], "lib.cor");
+        assert_eq!(
+            json["files"][0]["branches"][1],
+            json!({"line": 3, "offset": 11, "fell_through": null, "jumped": null})
+        );
+        assert_eq!(json["files"][1]["lines"][2], json!({"line": 3, "count": 0}));
+    }
+}
@@ -0,0 +1,600 @@
+/*!Reverse debugger, executing code one instruction at a time and recording what each changed
+ *
+ * Every step keeps a `Delta` with the `idx` and `op` it started from and the
+ * stack slots it popped and pushed, found from how deep its instruction reaches
+ * into the stack. The frames and handlers are kept when it changed them, and of
+ * the coroutines and channels only the one it spawned, sent to, received from
+ * or switched away from. Undoing the deltas steps backwards; stepping forwards
+ * again executes the code again.
+ *
+ * Commands of `corrode debug`:
+ * step, s               \\ execute one instruction
+ * continue, c           \\ execute until a breakpoint or the end
+ * reverse-step, rs      \\ undo one instruction
+ * reverse-continue, rc  \\ undo instructions until a breakpoint or the start
+ * break N, b N          \\ stop before the instruction at index N
+ * delete N, d N         \\ remove the breakpoint at index N
+ * last-write S, w S     \\ the step that wrote the value in stack slot S
+ * quit, q
+ */
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fmt::{Debug, Display},
+    io::{self, BufRead, Write},
+    mem,
+};
+
+use num::{
+    Integer, ToPrimitive,
+    traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, NumOps},
+};
+
+use crate::{
+    code::{
+        code_execution::{compile_debug, floor},
+        instruction::Instruction,
+        link::DebugInfo,
+    },
+    stack::{Frame, Handler, Stack, scheduler::Coroutine, stack_error::StackError},
+};
+
+pub struct Debugger<'a, T: ToPrimitive + NumOps + Display + From<u8> + Integer> {
+    pub stack: Stack<T>,
+    code: &'a [u8],
+    ///Where the instructions came from, to show with the position
+    pub info: DebugInfo,
+    ///What each step changed, the last one last
+    pub history: Vec<Delta<T>>,
+    ///Indices to stop at before executing the instruction there
+    pub breakpoints: BTreeSet<usize>,
+    ///What the code returned or failed with, once it has finished
+    pub finished: Option<Result<T, StackError>>,
+}
+
+///What one step changed, enough to undo it
+#[derive(Debug, Clone)]
+pub struct Delta<T> {
+    ///`idx` and `op` before the step
+    pub idx: usize,
+    pub op: u8,
+    ///Number of stack slots at the bottom the step left alone
+    pub base: usize,
+    ///Slots from `base` up before the step
+    pub popped: Vec<T>,
+    ///Slots from `base` up after the step
+    pub pushed: Vec<T>,
+    ///Frames and handlers before the step, when it changed them
+    pub frames: Option<Vec<Frame>>,
+    pub handlers: Option<Vec<Handler>>,
+    ///Coroutine the step started, or channel it sent to or received from
+    pub scheduled: Option<Scheduled<T>>,
+    ///Coroutine the step switched to at its end
+    pub switched: Option<Switch<T>>,
+}
+
+///Change a SPAWN, SEND or RECV made to the coroutines and channels
+#[derive(Debug, Clone)]
+pub enum Scheduled<T> {
+    ///Queued a new coroutine last
+    Spawned,
+    ///Added a value last on `channel`, which did not exist before when `opened`
+    Sent { channel: u8, opened: bool },
+    ///Took `value` first off `channel`
+    Received { channel: u8, value: T },
+}
+
+///Switch from the coroutine a step ran in to another one
+#[derive(Debug, Clone)]
+pub struct Switch<T> {
+    ///Position in the queue the coroutine switched to was taken from
+    pub from: usize,
+    ///Channel the coroutine switched to was waiting on
+    pub blocked: Option<u8>,
+    ///The coroutine switched from when it finished, and so is in the queue no longer
+    pub finished: Option<Coroutine<T>>,
+}
+
+impl<'a, T> Debugger<'a, T>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + Debug
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    pub fn new(stack: Stack<T>, code: &'a [u8]) -> Self {
+        Debugger {
+            stack,
+            code,
+            info: DebugInfo::default(),
+            history: Vec::new(),
+            breakpoints: BTreeSet::new(),
+            finished: None,
+        }
+    }
+
+    ///Execute one instruction. Returns false once the code has finished.
+    pub fn step(&mut self) -> bool {
+        if self.finished.is_some() {
+            return false;
+        }
+        let stack = &self.stack;
+        let (idx, op) = (stack.idx, stack.op);
+        let instruction = Instruction::decode(self.code, idx);
+        let base = self.reach(instruction);
+        let popped = stack.state[base..].to_vec();
+        // A fault that a handler catches can unwind frames and handlers along with the stack
+        let unwinds = !stack.handlers.is_empty()
+            || matches!(
+                instruction,
+                Some(Instruction::Enter(_) | Instruction::Leave | Instruction::Try(_))
+            );
+        let frames = unwinds.then(|| stack.frames.clone());
+        let handlers = unwinds.then(|| stack.handlers.clone());
+        // STORE and STORELOCAL write a slot even when it keeps its value
+        let stored = match instruction {
+            Some(Instruction::Store(slot)) => Some(slot as usize),
+            Some(Instruction::StoreLocal(local)) => {
+                stack.frames.last().map(|frame| frame.base + local as usize)
+            }
+            _ => None,
+        };
+        let channel = match instruction {
+            Some(Instruction::Send(channel) | Instruction::Recv(channel)) => Some(channel),
+            _ => None,
+        };
+        let queued = channel.and_then(|channel| stack.scheduler.channels.get(&channel));
+        let queued = queued.map(VecDeque::len);
+        let (current, next_id) = (stack.scheduler.current, stack.scheduler.next_id);
+        let waiting: Vec<(usize, Option<u8>)> = stack
+            .scheduler
+            .queue
+            .iter()
+            .map(|coroutine| (coroutine.id, coroutine.blocked))
+            .collect();
+
+        let mut finished = None;
+        let result = self.stack.execute_keeping(self.code, 1, &mut finished);
+
+        let stack = &self.stack;
+        let switched = (stack.scheduler.current != current).then(|| {
+            let from = waiting
+                .iter()
+                .position(|(id, _)| *id == stack.scheduler.current)
+                .unwrap();
+            Switch {
+                from,
+                blocked: waiting[from].1,
+                finished,
+            }
+        });
+        // What the instruction ran on is queued last, or finished, when it switched away
+        let ran_on = match &switched {
+            None => None,
+            Some(Switch {
+                finished: Some(coroutine),
+                ..
+            }) => Some(coroutine),
+            Some(_) => stack.scheduler.queue.back(),
+        };
+        let (after, after_frames, after_handlers) = match ran_on {
+            Some(coroutine) => (&coroutine.state, &coroutine.frames, &coroutine.handlers),
+            None => (&stack.state, &stack.frames, &stack.handlers),
+        };
+        let kept = popped
+            .iter()
+            .zip(&after[base..])
+            .take_while(|(before, after)| before == after)
+            .count();
+        let kept = match (stored, &result) {
+            (Some(slot), Ok(_)) => kept.min(slot.saturating_sub(base)),
+            _ => kept,
+        };
+        let scheduled = if stack.scheduler.next_id != next_id {
+            Some(Scheduled::Spawned)
+        } else {
+            let now = channel.and_then(|channel| stack.scheduler.channels.get(&channel));
+            match (channel, now.map(VecDeque::len).unwrap_or(0), queued) {
+                (Some(channel), length, _) if length > queued.unwrap_or(0) => {
+                    Some(Scheduled::Sent {
+                        channel,
+                        opened: queued.is_none(),
+                    })
+                }
+                (Some(channel), length, Some(before)) if length < before => {
+                    Some(Scheduled::Received {
+                        channel,
+                        value: after.last().unwrap().clone(),
+                    })
+                }
+                _ => None,
+            }
+        };
+        let delta = Delta {
+            idx,
+            op,
+            base: base + kept,
+            pushed: after[base + kept..].to_vec(),
+            popped: popped[kept..].to_vec(),
+            frames: frames.filter(|frames| frames != after_frames),
+            handlers: handlers.filter(|handlers| handlers != after_handlers),
+            scheduled,
+            switched,
+        };
+        self.history.push(delta);
+        match result {
+            Ok(None) => return true,
+            Ok(Some(value)) => self.finished = Some(Ok(value)),
+            Err(error) => self.finished = Some(Err(error)),
+        }
+        false
+    }
+
+    ///Lowest stack slot `instruction` can change. A fault a handler catches unwinds the stack
+    ///down to where the TRY left it.
+    fn reach(&self, instruction: Option<Instruction>) -> usize {
+        let stack = &self.stack;
+        let len = stack.state.len();
+        let frame = stack.frames.last();
+        let reach = match instruction {
+            Some(Instruction::Store(slot)) => slot as usize,
+            Some(Instruction::StoreLocal(local)) => {
+                frame.map_or(len, |frame| frame.base + local as usize)
+            }
+            Some(Instruction::Leave) => frame.map_or(len, |frame| frame.base),
+            Some(Instruction::Clear) => floor(&stack.frames),
+            Some(Instruction::Roll(n)) => len.saturating_sub(n as usize + 1),
+            // PCHAR pops down to the first 0
+            Some(Instruction::PChar) => stack
+                .state
+                .iter()
+                .rposition(|value| value.is_zero())
+                .unwrap_or(0),
+            // Every other instruction takes at most 4 values, for 2SWAP
+            _ => len.saturating_sub(4),
+        };
+        let unwound = stack.handlers.last().map_or(len, |handler| handler.depth);
+        reach.min(unwound).min(len)
+    }
+
+    ///Execute until the next instruction has a breakpoint. Returns false once the code has finished.
+    pub fn continue_execution(&mut self) -> bool {
+        while self.step() {
+            if self.breakpoints.contains(&self.stack.idx) {
+                return true;
+            }
+        }
+        false
+    }
+
+    ///Undo the last step. Returns false when there is none.
+    pub fn reverse_step(&mut self) -> bool {
+        let Some(delta) = self.history.pop() else {
+            return false;
+        };
+        let stack = &mut self.stack;
+        if let Some(switch) = delta.switched {
+            let previous = match switch.finished {
+                Some(coroutine) => coroutine,
+                None => stack.scheduler.queue.pop_back().unwrap(),
+            };
+            let next = Coroutine {
+                id: mem::replace(&mut stack.scheduler.current, previous.id),
+                state: mem::replace(&mut stack.state, previous.state),
+                idx: stack.idx,
+                frames: mem::replace(&mut stack.frames, previous.frames),
+                handlers: mem::replace(&mut stack.handlers, previous.handlers),
+                blocked: switch.blocked,
+            };
+            stack.scheduler.queue.insert(switch.from, next);
+        }
+        match delta.scheduled {
+            Some(Scheduled::Spawned) => {
+                stack.scheduler.queue.pop_back();
+                stack.scheduler.next_id -= 1;
+            }
+            Some(Scheduled::Sent { channel, opened }) => {
+                let channels = &mut stack.scheduler.channels;
+                channels.get_mut(&channel).and_then(VecDeque::pop_back);
+                if opened {
+                    channels.remove(&channel);
+                }
+            }
+            Some(Scheduled::Received { channel, value }) => {
+                let channel = stack.scheduler.channels.entry(channel).or_default();
+                channel.push_front(value);
+            }
+            None => (),
+        }
+        stack.state.truncate(delta.base);
+        stack.state.extend(delta.popped);
+        stack.idx = delta.idx;
+        stack.op = delta.op;
+        if let Some(frames) = delta.frames {
+            stack.frames = frames;
+        }
+        if let Some(handlers) = delta.handlers {
+            stack.handlers = handlers;
+        }
+        self.finished = None;
+        true
+    }
+
+    ///Undo steps back to one that started at a breakpoint. Returns false when the history runs
+    ///out first.
+    pub fn reverse_continue(&mut self) -> bool {
+        while self.reverse_step() {
+            if self.breakpoints.contains(&self.stack.idx) {
+                return true;
+            }
+        }
+        false
+    }
+
+    ///Position in `history` of the step that wrote the value now in stack slot `slot`,
+    ///or `None` when it was there before the history starts
+    pub fn last_write(&self, slot: usize) -> Option<usize> {
+        if slot >= self.stack.state.len() {
+            return None;
+        }
+        self.history
+            .iter()
+            .rposition(|delta| (delta.base..delta.base + delta.pushed.len()).contains(&slot))
+    }
+
+    ///Where execution is: the next instruction and the stack, or how the code finished
+    pub fn position(&self) -> String {
+        match &self.finished {
+            Some(Ok(value)) => format!("finished: {value} {:?}", self.stack.state),
+            Some(Err(error)) => format!("finished: {error} {:?}", self.stack.state),
+            None => {
+                let instruction = Instruction::decode(self.code, self.stack.idx);
+                let at = match self.info.line(self.stack.idx) {
+                    Some(at) => format!(" at {}", at.with_column()),
+                    None => String::new(),
+                };
+                match instruction {
+                    Some(instruction) => format!(
+                        "{:>4} {instruction}{at} {:?}",
+                        self.stack.idx, self.stack.state
+                    ),
+                    None => format!("{:>4} end {:?}", self.stack.idx, self.stack.state),
+                }
+            }
+        }
+    }
+
+    ///Read commands from `input` until it ends or says quit, reporting to `output`
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        writeln!(output, "{}", self.position())?;
+        for line in input.lines() {
+            let line = line?;
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let number = words.get(1).and_then(|word| word.parse::<usize>().ok());
+            match (words.first().copied(), number) {
+                (None, _) => continue,
+                (Some("step" | "s"), _) => {
+                    self.step();
+                }
+                (Some("continue" | "c"), _) => {
+                    self.continue_execution();
+                }
+                (Some("reverse-step" | "rs"), _) => {
+                    if !self.reverse_step() {
+                        writeln!(output, "at the start of the history")?;
+                    }
+                }
+                (Some("reverse-continue" | "rc"), _) => {
+                    if !self.reverse_continue() {
+                        writeln!(output, "at the start of the history")?;
+                    }
+                }
+                (Some("break" | "b"), Some(idx)) => {
+                    self.breakpoints.insert(idx);
+                    continue;
+                }
+                (Some("delete" | "d"), Some(idx)) => {
+                    self.breakpoints.remove(&idx);
+                    continue;
+                }
+                (Some("last-write" | "w"), Some(slot)) => {
+                    match self.last_write(slot) {
+                        _ if slot >= self.stack.state.len() => {
+                            writeln!(output, "stack slot {slot} is out of range")?
+                        }
+                        Some(step) => writeln!(
+                            output,
+                            "slot {slot} was written by step {step} at {}",
+                            self.history[step].idx
+                        )?,
+                        None => writeln!(output, "slot {slot} was not written in the history")?,
+                    }
+                    continue;
+                }
+                (Some("quit" | "q"), _) => break,
+                _ => {
+                    writeln!(output, "unknown command: {line}")?;
+                    continue;
+                }
+            }
+            writeln!(output, "{}", self.position())?;
+        }
+        Ok(())
+    }
+}
+
+///Debug a .cor file linked with the .cor modules after it, or a .crd or .fs file, on stdin and stdout
+pub fn debug<T>(input_files: &[&str], defines: &[(&str, i64)]) -> anyhow::Result<()>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + Debug
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let (code, info) = compile_debug::<T>(input_files, defines)?;
+    let mut debugger: Debugger<T> = Debugger::new(Stack::new(), &code);
+    debugger.info = info;
+    debugger.repl(io::stdin().lock(), io::stdout())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PUSH 3, loop: PUSH 1, SUB, DUP, STORE 0, JNZ loop, POP, ADD
+    const COUNTDOWN: [u8; 13] = [
+        0x20, 0x03, 0x20, 0x01, 0x02, 0x23, 0x25, 0x00, 0x31, 0x02, 0x22, 0x01, 0x12,
+    ];
+
+    #[test]
+    fn reverse_from_error() {
+        let mut debugger: Debugger<i64> = Debugger::new(Stack::new(), &COUNTDOWN);
+        assert!(!debugger.continue_execution());
+        assert!(matches!(
+            debugger.finished,
+            Some(Err(StackError::EmptyStack { idx: 11, .. }))
+        ));
+        // Back to right before the ADD that failed, and the POP before it
+        assert!(debugger.reverse_step());
+        assert_eq!(debugger.stack.idx, 11);
+        assert!(debugger.stack.state.is_empty());
+        assert!(debugger.finished.is_none());
+        assert!(debugger.reverse_step());
+        assert_eq!(
+            (debugger.stack.idx, &debugger.stack.state[..]),
+            (10, &[0][..])
+        );
+
+        debugger.breakpoints.insert(5);
+        assert!(debugger.reverse_continue());
+        assert_eq!(
+            (debugger.stack.idx, &debugger.stack.state[..]),
+            (5, &[0][..])
+        );
+        assert!(debugger.reverse_continue());
+        assert_eq!(
+            (debugger.stack.idx, &debugger.stack.state[..]),
+            (5, &[1][..])
+        );
+
+        // Undoing everything gets back to the start, and running again ends the same way
+        while debugger.reverse_step() {}
+        assert_eq!((debugger.stack.idx, debugger.stack.state.len()), (0, 0));
+        debugger.breakpoints.clear();
+        assert!(!debugger.continue_execution());
+        assert!(matches!(
+            debugger.finished,
+            Some(Err(StackError::EmptyStack { idx: 11, .. }))
+        ));
+    }
+    #[test]
+    fn last_write() {
+        let mut debugger: Debugger<i64> = Debugger::new(Stack::new(), &COUNTDOWN);
+        debugger.breakpoints.insert(10);
+        assert!(debugger.continue_execution());
+        // The last STORE wrote the 0 the SUB before it had left in slot 0
+        let step = debugger.last_write(0).unwrap();
+        assert_eq!(debugger.history[step].idx, 6);
+        assert_eq!(step, debugger.history.len() - 2);
+        assert_eq!(debugger.last_write(1), None);
+
+        // Slots that were there before the history are not written by it
+        let mut stack = Stack::new();
+        stack.state = vec![4, 2];
+        let mut debugger: Debugger<i64> = Debugger::new(stack, &[0x20, 0x01, 0x01, 0x12]);
+        debugger.step();
+        assert_eq!(debugger.last_write(0), None);
+        assert_eq!(debugger.last_write(2), Some(0));
+        debugger.step();
+        assert_eq!(debugger.last_write(1), Some(1));
+    }
+    #[test]
+    fn coroutines_and_frames() {
+        // ENTER 1, TRY 10, SPAWN 11, RECV 1, RET, handler: NOP, RET,
+        // 11: PUSH 7, SEND 1, TRY 22, PUSH 0, PUSH 0, DIV, handler: PUSH 5
+        let code = [
+            0x26, 0x01, 0x32, 0x0A, 0x50, 0x0B, 0x53, 0x01, 0x12, 0x00, 0x12, 0x20, 0x07, 0x52,
+            0x01, 0x32, 0x16, 0x20, 0x00, 0x20, 0x00, 0x04, 0x20, 0x05,
+        ];
+        let mut stack = Stack::new();
+        stack.state = vec![1, 2, 3, 4, 5, 6];
+        let mut debugger: Debugger<i64> = Debugger::new(stack, &code);
+        let mut before = Vec::new();
+        loop {
+            let stack = &debugger.stack;
+            before.push((
+                stack.state.clone(),
+                stack.idx,
+                stack.frames.clone(),
+                stack.handlers.clone(),
+                stack.scheduler.clone(),
+            ));
+            if !debugger.step() {
+                break;
+            }
+        }
+        assert!(matches!(debugger.finished, Some(Ok(7))));
+        // Only what an instruction took and left is kept, not the whole stack
+        assert!(debugger.history.iter().all(|delta| delta.popped.len() <= 4));
+        assert!(
+            debugger
+                .history
+                .iter()
+                .any(|delta| delta.switched.is_some())
+        );
+
+        while let Some((state, idx, frames, handlers, scheduler)) = before.pop() {
+            assert!(debugger.reverse_step());
+            let stack = &debugger.stack;
+            assert_eq!(stack.state, state);
+            assert_eq!(stack.idx, idx);
+            assert_eq!(stack.frames, frames);
+            assert_eq!(stack.handlers, handlers);
+            assert_eq!(stack.scheduler, scheduler);
+        }
+        assert!(!debugger.reverse_step());
+    }
+    #[test]
+    fn commands() {
+        let mut debugger: Debugger<i64> = Debugger::new(Stack::new(), &COUNTDOWN);
+        let mut output = Vec::new();
+        debugger
+            .repl(&b"b 11\nc\nrs\nw 0\nw 1\nbogus\nq\ns\n"[..], &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "   0 PUSH 3 []\n  11 ADD []\n  10 POP [0]\nslot 0 was written by step 14 at 6\n\
+             stack slot 1 is out of range\nunknown command: bogus\n"
+        );
+
+        debugger.info.lines = vec![(
+            10,
+            crate::code::preprocess::Location {
+                file: String::from("countdown.cor"),
+                line: 7,
+                column: 1,
+            },
+        )];
+        assert_eq!(debugger.position(), "  10 POP at countdown.cor:7:1 [0]");
+        debugger.breakpoints.clear();
+        debugger.continue_execution();
+        assert_eq!(debugger.position(), "finished: Cannot pop empty stack []");
+    }
+}
@@ -0,0 +1,293 @@
+/*!Formatter giving .cor files one canonical layout
+ *
+ * Lines are read with the `line` rule of `InputParser`. Preprocessor directives
+ * and macro calls, which the grammar does not know, are split into a label,
+ * a name and the rest by hand.
+ *
+ * loop:  push  WIDTH * 2  ; comment
+ * \\ labels, mnemonics, operands and trailing comments each start in a column of their own
+ *
+ * Mnemonics and directives are lower case, operand expressions have a space
+ * around every binary operator, comments on lines of their own start in the
+ * mnemonic column and runs of blank lines become one.
+ */
+
+use pest::{Parser, iterators::Pair};
+
+use crate::code::{
+    parse::{InputParser, Rule},
+    preprocess::{MNEMONICS, split_comment, split_label, split_word},
+};
+
+///A line of source, split into columns
+#[derive(Debug, Default)]
+struct Columns {
+    label: Option<String>,
+    ///`( in -- out )` annotation of the label
+    signature: Option<String>,
+    mnemonic: Option<String>,
+    operand: Option<String>,
+    comment: Option<String>,
+}
+
+impl Columns {
+    fn is_blank(&self) -> bool {
+        self.label.is_none() && self.mnemonic.is_none() && self.comment.is_none()
+    }
+
+    ///Text of the label column, with the annotation of the label if it has one
+    fn label(&self) -> Option<String> {
+        let label = self.label.as_ref()?;
+        Some(match &self.signature {
+            Some(signature) => format!("{label}: {signature}"),
+            None => format!("{label}:"),
+        })
+    }
+
+    ///Read a line with the grammar, or by hand when it is not an instruction
+    fn new(text: &str) -> Self {
+        let Ok(mut parsed) = InputParser::parse(Rule::line, text) else {
+            return Columns::directive(text);
+        };
+        let line = parsed.next().unwrap();
+        // A trailing comment can end up inside the operand expression before it
+        let mut columns = Columns {
+            comment: line
+                .clone()
+                .into_inner()
+                .flatten()
+                .find(|pair| pair.as_rule() == Rule::COMMENT)
+                .map(|comment| comment.as_str().trim_end().to_string()),
+            ..Columns::default()
+        };
+        for pair in line.into_inner() {
+            match pair.as_rule() {
+                Rule::label => columns.label = Some(pair.into_inner().as_str().to_string()),
+                Rule::signature => {
+                    let sides: Vec<String> = pair
+                        .into_inner()
+                        .map(|side| {
+                            side.into_inner()
+                                .map(|word| format!("{} ", word.as_str()))
+                                .collect()
+                        })
+                        .collect();
+                    columns.signature = Some(format!("( {}-- {})", sides[0], sides[1]));
+                }
+                Rule::COMMENT | Rule::EOI => (),
+                _ => {
+                    let start = pair.as_span().start();
+                    let mut inner = pair
+                        .clone()
+                        .into_inner()
+                        .filter(|operand| operand.as_rule() != Rule::COMMENT)
+                        .peekable();
+                    let end = inner
+                        .peek()
+                        .map_or(pair.as_span().end(), |operand| operand.as_span().start());
+                    let mnemonic = text[start..end].trim_end_matches('%').trim().to_lowercase();
+                    let separator = match pair.as_rule() {
+                        Rule::export | Rule::import => ", ",
+                        _ => " ",
+                    };
+                    let operands: Vec<String> = inner.map(operand).collect();
+                    columns.mnemonic = Some(mnemonic);
+                    columns.operand = (!operands.is_empty()).then(|| operands.join(separator));
+                }
+            }
+        }
+        columns
+    }
+
+    ///Split a directive or macro call the grammar does not parse
+    fn directive(text: &str) -> Self {
+        let (code, comment) = split_comment(text);
+        let (label, rest) = split_label(code);
+        let (name, operand) = split_word(rest);
+        // Instructions of macro bodies end up here when they use a parameter, macro names keep
+        // their case and cannot be mnemonics
+        let lower = name.to_lowercase();
+        let mnemonic = match name {
+            "" => None,
+            _ if name.starts_with('.') || MNEMONICS.contains(&lower.as_str()) => Some(lower),
+            _ => Some(name.to_string()),
+        };
+        Columns {
+            label: label.map(String::from),
+            signature: None,
+            mnemonic,
+            operand: (!operand.is_empty()).then(|| operand.to_string()),
+            comment: comment.map(|comment| comment.trim_end().to_string()),
+        }
+    }
+}
+
+///Text of an operand of an instruction or directive
+fn operand(pair: Pair<Rule>) -> String {
+    match pair.as_rule() {
+        Rule::expr => expression(pair),
+        // Absolute addresses of jumps, the % is not part of the pair
+        Rule::number => format!("%{}", pair.as_str()),
+        _ => pair.as_str().to_string(),
+    }
+}
+
+///Text of an operand expression, with a space around every binary operator
+fn expression(expr: Pair<Rule>) -> String {
+    expr.into_inner()
+        .filter(|pair| pair.as_rule() != Rule::COMMENT)
+        .map(|pair| match pair.as_rule() {
+            Rule::expr => format!("({})", expression(pair)),
+            Rule::word => format!("${}", pair.as_str()),
+            Rule::negate => String::from("-"),
+            Rule::plus | Rule::minus | Rule::times | Rule::divided | Rule::remainder => {
+                format!(" {} ", pair.as_str())
+            }
+            _ => pair.as_str().to_string(),
+        })
+        .collect()
+}
+
+///Source of a .cor file in the canonical layout
+pub fn format_source(source: &str) -> String {
+    let mut lines: Vec<Columns> = Vec::new();
+    for columns in source.lines().map(Columns::new) {
+        let repeated_blank = columns.is_blank() && lines.last().is_none_or(Columns::is_blank);
+        if !repeated_blank {
+            lines.push(columns);
+        }
+    }
+    if lines.last().is_some_and(Columns::is_blank) {
+        lines.pop();
+    }
+
+    let label_width = lines
+        .iter()
+        .filter_map(Columns::label)
+        .map(|label| label.len() + 1)
+        .max()
+        .unwrap_or(0);
+    let mnemonic_width = lines
+        .iter()
+        .filter(|columns| columns.operand.is_some())
+        .filter_map(|columns| columns.mnemonic.as_ref())
+        .map(|mnemonic| mnemonic.len() + 1)
+        .max()
+        .unwrap_or(0);
+
+    let code: Vec<String> = lines
+        .iter()
+        .map(|columns| {
+            let label = columns.label().unwrap_or_default();
+            let code = match (&columns.mnemonic, &columns.operand) {
+                (Some(mnemonic), Some(operand)) => {
+                    format!("{label:label_width$}{mnemonic:mnemonic_width$}{operand}")
+                }
+                (Some(mnemonic), None) => format!("{label:label_width$}{mnemonic}"),
+                (None, _) => label,
+            };
+            code.trim_end().to_string()
+        })
+        .collect();
+    let comment_column = lines
+        .iter()
+        .zip(&code)
+        .filter(|(columns, code)| columns.comment.is_some() && !code.is_empty())
+        .map(|(_, code)| code.len() + 1)
+        .max()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .zip(code)
+        .map(|(columns, code)| match &columns.comment {
+            Some(comment) if code.is_empty() => format!("{:label_width$}{comment}\n", ""),
+            Some(comment) => format!("{code:comment_column$}{comment}\n"),
+            None => format!("{code}\n"),
+        })
+        .collect()
+}
+
+///Format .cor files in place, or with `check` only list those that are not formatted and fail
+///when there are any
+pub fn format_files(input_files: &[&str], check: bool) -> anyhow::Result<()> {
+    let mut unformatted = Vec::new();
+    for input_file in input_files {
+        let source = std::fs::read_to_string(input_file)?;
+        let formatted = format_source(&source);
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{input_file} is not formatted");
+            unformatted.push(*input_file);
+        } else {
+            std::fs::write(input_file, formatted)?;
+        }
+    }
+    if !unformatted.is_empty() {
+        match unformatted.len() {
+            1 => anyhow::bail!("1 file is not formatted"),
+            count => anyhow::bail!("{count} files are not formatted"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::parse::parse_code;
+
+    use super::*;
+
+    const MESSY: &str = "\n\n; count down from WIDTH\n  .EQU WIDTH 2*3\nPUSH WIDTH   ;start\n\n\n\nloop:   Push 1\n    sub\n  JNZ $loop+0 ; again\n count 3,4\nfinished: .Export   loop,finished\n    .ifdef   DEBUG\n  push -( 'a'+1 )%4\n.endif\njmp %2\nRET\n\n";
+
+    const FORMATTED: &str = "          ; count down from WIDTH
+          .equ    WIDTH 2 * 3
+          push    WIDTH     ;start
+
+loop:     push    1
+          sub
+          jnz     $loop + 0 ; again
+          count   3,4
+finished: .export loop, finished
+          .ifdef  DEBUG
+          push    -('a' + 1) % 4
+          .endif
+          jmp     %2
+          ret
+";
+
+    #[test]
+    fn layout() {
+        assert_eq!(format_source(MESSY), FORMATTED);
+        assert_eq!(format_source(FORMATTED), FORMATTED);
+        assert_eq!(format_source("\n\n"), "");
+        assert_eq!(format_source("Nop\n"), "nop\n");
+        assert_eq!(
+            format_source(".MACRO twice n\nPUSH \\n\nDUP\nloop: JNZ $loop\n.ENDM\ntwice 2\n"),
+            "      .macro twice n\n      push   \\n\n      dup\nloop: jnz    $loop\n      .endm\n      twice  2\n"
+        );
+    }
+    #[test]
+    fn check_and_assemble() {
+        let dir = std::env::temp_dir().join(format!("corrode_fmt_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("countdown.cor");
+        let file = file.to_str().unwrap();
+        let source = "PUSH 3 ; from 3\nloop: push 1 ;\n  SUB\n  jnz $loop\npush ';'\nADD\nret\n";
+        std::fs::write(file, source).unwrap();
+        let code = parse_code(file, &[]).unwrap();
+        assert_eq!(code[..5], [0x20, 3, 0x20, 1, 0x02]);
+
+        assert_eq!(
+            format_files(&[file], true).unwrap_err().to_string(),
+            "1 file is not formatted"
+        );
+        assert!(format_files(&[file], false).is_ok());
+        assert!(format_files(&[file], true).is_ok());
+        // Formatting does not change what the file assembles to
+        assert_eq!(parse_code(file, &[]).unwrap(), code);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,407 @@
+/*!Decoded form of the bytecode, shared by the execution engines that work on
+ * whole instructions instead of raw bytes
+ */
+
+use std::{collections::BTreeMap, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Lt,
+    Print,
+    PChar,
+    Ret,
+    Push(u8),
+    Swp,
+    Pop,
+    Dup,
+    Load(u8),
+    Store(u8),
+    Enter(u8),
+    LoadLocal(u8),
+    StoreLocal(u8),
+    Leave,
+    Jmp(usize),
+    Jnz(usize),
+    Try(usize),
+    EndTry,
+    Throw,
+    Assert,
+    AssertEq,
+    Spawn(usize),
+    Yield,
+    Send(u8),
+    Recv(u8),
+    Over,
+    Rot,
+    Nip,
+    Tuck,
+    Pick(u8),
+    Roll(u8),
+    Depth,
+    Clear,
+    TwoDup,
+    TwoSwap,
+    Exit,
+    /// 2 byte opcode whose operand is missing at the end of the code
+    Truncated(u8),
+    Unknown(u8),
+}
+
+impl Instruction {
+    ///Decode the instruction starting at `idx`, or `None` past the end of the code
+    pub fn decode(code: &[u8], idx: usize) -> Option<Self> {
+        let op = *code.get(idx)?;
+        let operand = code.get(idx + 1).copied();
+
+        Some(match (op, operand) {
+            (0x00, _) => Self::Nop,
+            (0x01, _) => Self::Add,
+            (0x02, _) => Self::Sub,
+            (0x03, _) => Self::Mul,
+            (0x04, _) => Self::Div,
+            (0x05, _) => Self::Mod,
+            (0x06, _) => Self::Lt,
+            (0x10, _) => Self::Print,
+            (0x11, _) => Self::PChar,
+            (0x12, _) => Self::Ret,
+            (0x20, Some(value)) => Self::Push(value),
+            (0x21, _) => Self::Swp,
+            (0x22, _) => Self::Pop,
+            (0x23, _) => Self::Dup,
+            (0x24, Some(slot)) => Self::Load(slot),
+            (0x25, Some(slot)) => Self::Store(slot),
+            (0x26, Some(size)) => Self::Enter(size),
+            (0x27, Some(local)) => Self::LoadLocal(local),
+            (0x28, Some(local)) => Self::StoreLocal(local),
+            (0x29, _) => Self::Leave,
+            (0x30, Some(address)) => Self::Jmp(address as usize),
+            (0x31, Some(address)) => Self::Jnz(address as usize),
+            (0x32, Some(address)) => Self::Try(address as usize),
+            (0x33, _) => Self::EndTry,
+            (0x34, _) => Self::Throw,
+            (0x40, _) => Self::Assert,
+            (0x41, _) => Self::AssertEq,
+            (0x50, Some(address)) => Self::Spawn(address as usize),
+            (0x51, _) => Self::Yield,
+            (0x52, Some(channel)) => Self::Send(channel),
+            (0x53, Some(channel)) => Self::Recv(channel),
+            (0x60, _) => Self::Over,
+            (0x61, _) => Self::Rot,
+            (0x62, _) => Self::Nip,
+            (0x63, _) => Self::Tuck,
+            (0x64, Some(n)) => Self::Pick(n),
+            (0x65, Some(n)) => Self::Roll(n),
+            (0x66, _) => Self::Depth,
+            (0x67, _) => Self::Clear,
+            (0x68, _) => Self::TwoDup,
+            (0x69, _) => Self::TwoSwap,
+            (0x20 | 0x24..=0x28 | 0x30..=0x32 | 0x50 | 0x52 | 0x53 | 0x64 | 0x65, None) => {
+                Self::Truncated(op)
+            }
+            (0xFF, _) => Self::Exit,
+            _ => Self::Unknown(op),
+        })
+    }
+
+    ///Number of bytes the instruction occupies in the bytecode
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Push(_)
+            | Self::Load(_)
+            | Self::Store(_)
+            | Self::Enter(_)
+            | Self::LoadLocal(_)
+            | Self::StoreLocal(_)
+            | Self::Jmp(_)
+            | Self::Jnz(_)
+            | Self::Try(_)
+            | Self::Spawn(_)
+            | Self::Send(_)
+            | Self::Recv(_)
+            | Self::Pick(_)
+            | Self::Roll(_) => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Self::Nop => 0x00,
+            Self::Add => 0x01,
+            Self::Sub => 0x02,
+            Self::Mul => 0x03,
+            Self::Div => 0x04,
+            Self::Mod => 0x05,
+            Self::Lt => 0x06,
+            Self::Print => 0x10,
+            Self::PChar => 0x11,
+            Self::Ret => 0x12,
+            Self::Push(_) => 0x20,
+            Self::Swp => 0x21,
+            Self::Pop => 0x22,
+            Self::Dup => 0x23,
+            Self::Load(_) => 0x24,
+            Self::Store(_) => 0x25,
+            Self::Enter(_) => 0x26,
+            Self::LoadLocal(_) => 0x27,
+            Self::StoreLocal(_) => 0x28,
+            Self::Leave => 0x29,
+            Self::Jmp(_) => 0x30,
+            Self::Jnz(_) => 0x31,
+            Self::Try(_) => 0x32,
+            Self::EndTry => 0x33,
+            Self::Throw => 0x34,
+            Self::Assert => 0x40,
+            Self::AssertEq => 0x41,
+            Self::Spawn(_) => 0x50,
+            Self::Yield => 0x51,
+            Self::Send(_) => 0x52,
+            Self::Recv(_) => 0x53,
+            Self::Over => 0x60,
+            Self::Rot => 0x61,
+            Self::Nip => 0x62,
+            Self::Tuck => 0x63,
+            Self::Pick(_) => 0x64,
+            Self::Roll(_) => 0x65,
+            Self::Depth => 0x66,
+            Self::Clear => 0x67,
+            Self::TwoDup => 0x68,
+            Self::TwoSwap => 0x69,
+            Self::Exit => 0xFF,
+            Self::Truncated(op) | Self::Unknown(op) => *op,
+        }
+    }
+
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(
+            self,
+            Self::Add | Self::Sub | Self::Mul | Self::Div | Self::Mod | Self::Lt
+        )
+    }
+
+    ///Append the bytes of the instruction to `code`.
+    ///Jump targets must fit the 1 byte address operand.
+    pub fn encode(&self, code: &mut Vec<u8>) {
+        code.push(self.opcode());
+        match self {
+            Self::Push(operand)
+            | Self::Load(operand)
+            | Self::Store(operand)
+            | Self::Enter(operand)
+            | Self::LoadLocal(operand)
+            | Self::StoreLocal(operand)
+            | Self::Send(operand)
+            | Self::Recv(operand)
+            | Self::Pick(operand)
+            | Self::Roll(operand) => code.push(*operand),
+            Self::Jmp(target) | Self::Jnz(target) | Self::Try(target) | Self::Spawn(target) => {
+                code.push(*target as u8)
+            }
+            _ => (),
+        }
+    }
+
+    ///Assembly name of an opcode, `UNKNOWN` for bytes that are none
+    pub fn mnemonic(op: u8) -> &'static str {
+        match op {
+            0x00 => "NOP",
+            0x01 => "ADD",
+            0x02 => "SUB",
+            0x03 => "MUL",
+            0x04 => "DIV",
+            0x05 => "MOD",
+            0x06 => "LT",
+            0x10 => "PRINT",
+            0x11 => "PCHAR",
+            0x12 => "RET",
+            0x20 => "PUSH",
+            0x21 => "SWP",
+            0x22 => "POP",
+            0x23 => "DUP",
+            0x24 => "LOAD",
+            0x25 => "STORE",
+            0x26 => "ENTER",
+            0x27 => "LOADLOCAL",
+            0x28 => "STORELOCAL",
+            0x29 => "LEAVE",
+            0x30 => "JMP",
+            0x31 => "JNZ",
+            0x32 => "TRY",
+            0x33 => "ENDTRY",
+            0x34 => "THROW",
+            0x40 => "ASSERT",
+            0x41 => "ASSERTEQ",
+            0x50 => "SPAWN",
+            0x51 => "YIELD",
+            0x52 => "SEND",
+            0x53 => "RECV",
+            0x60 => "OVER",
+            0x61 => "ROT",
+            0x62 => "NIP",
+            0x63 => "TUCK",
+            0x64 => "PICK",
+            0x65 => "ROLL",
+            0x66 => "DEPTH",
+            0x67 => "CLEAR",
+            0x68 => "2DUP",
+            0x69 => "2SWAP",
+            0xFF => "EXIT",
+            _ => "UNKNOWN",
+        }
+    }
+
+    ///For the words that only rearrange the top of the stack, how many values they take and
+    ///which of them they leave in their place, bottom first, counted from the lowest taken.
+    ///SWP and DUP are not among them, as they report faults at the index after their own.
+    pub fn shuffle(&self) -> Option<(usize, Vec<usize>)> {
+        Some(match self {
+            Self::Over => (2, vec![0, 1, 0]),
+            Self::Rot => (3, vec![1, 2, 0]),
+            Self::Nip => (2, vec![1]),
+            Self::Tuck => (2, vec![1, 0, 1]),
+            Self::Pick(n) => {
+                let n = *n as usize;
+                (n + 1, (0..=n).chain([0]).collect())
+            }
+            Self::Roll(n) => {
+                let n = *n as usize;
+                (n + 1, (1..=n).chain([0]).collect())
+            }
+            Self::TwoDup => (2, vec![0, 1, 0, 1]),
+            Self::TwoSwap => (4, vec![2, 3, 0, 1]),
+            _ => return None,
+        })
+    }
+
+    ///Whether execution can never continue with the following instruction
+    pub fn ends_block(&self) -> bool {
+        matches!(
+            self,
+            Self::Ret
+                | Self::Jmp(_)
+                | Self::Throw
+                | Self::Exit
+                | Self::Truncated(_)
+                | Self::Unknown(_)
+        )
+    }
+}
+
+///Assembly syntax, with addresses as %int
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Self::mnemonic(self.opcode());
+        match self {
+            Self::Push(operand)
+            | Self::Load(operand)
+            | Self::Store(operand)
+            | Self::Enter(operand)
+            | Self::LoadLocal(operand)
+            | Self::StoreLocal(operand)
+            | Self::Send(operand)
+            | Self::Recv(operand)
+            | Self::Pick(operand)
+            | Self::Roll(operand) => write!(f, "{name} {operand}"),
+            Self::Jmp(target) | Self::Jnz(target) | Self::Try(target) | Self::Spawn(target) => {
+                write!(f, "{name} %{target}")
+            }
+            Self::Truncated(_) => write!(f, "{name} (missing operand)"),
+            Self::Unknown(op) => write!(f, "{name} {op:#04x}"),
+            _ => f.write_str(name),
+        }
+    }
+}
+
+///Decode every instruction reachable from index 0, keyed by index.
+///Jump targets, TRY handlers and SPAWN entries inside another instruction's operand are decoded
+///in their own right.
+pub fn reachable(code: &[u8]) -> BTreeMap<usize, Instruction> {
+    let mut decoded: BTreeMap<usize, Instruction> = BTreeMap::new();
+    let mut worklist = vec![0];
+
+    while let Some(mut idx) = worklist.pop() {
+        while let Some(instruction) = Instruction::decode(code, idx) {
+            if decoded.insert(idx, instruction).is_some() {
+                break;
+            }
+            if let Instruction::Jmp(target)
+            | Instruction::Jnz(target)
+            | Instruction::Try(target)
+            | Instruction::Spawn(target) = instruction
+            {
+                worklist.push(target);
+            }
+            if instruction.ends_block() {
+                break;
+            }
+            idx += instruction.size();
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn decode_program() {
+        let code: Vec<u8> = vec![0x20, 0x05, 0x23, 0x31, 0x00, 0x42, 0x30];
+        let mut idx = 0;
+        let mut decoded = Vec::new();
+        while let Some(instruction) = Instruction::decode(&code, idx) {
+            idx += instruction.size();
+            decoded.push(instruction);
+        }
+        assert_eq!(
+            decoded,
+            [
+                Instruction::Push(5),
+                Instruction::Dup,
+                Instruction::Jnz(0),
+                Instruction::Unknown(0x42),
+                Instruction::Truncated(0x30)
+            ]
+        );
+        assert_eq!(
+            decoded.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "PUSH 5",
+                "DUP",
+                "JNZ %0",
+                "UNKNOWN 0x42",
+                "JMP (missing operand)"
+            ]
+        );
+    }
+    #[test]
+    fn encode_roundtrip() {
+        let code: Vec<u8> = vec![
+            0x20, 0x05, 0x24, 0x00, 0x06, 0x25, 0x01, 0x26, 0x02, 0x27, 0x00, 0x28, 0x01, 0x29,
+            0x31, 0x00, 0x32, 0x02, 0x33, 0x34, 0x40, 0x41, 0x50, 0x00, 0x51, 0x52, 0x01, 0x53,
+            0x01, 0x60, 0x61, 0x62, 0x63, 0x64, 0x02, 0x65, 0x03, 0x66, 0x67, 0x68, 0x69, 0xFF,
+        ];
+        let mut encoded = Vec::new();
+        let mut idx = 0;
+        while let Some(instruction) = Instruction::decode(&code, idx) {
+            idx += instruction.size();
+            instruction.encode(&mut encoded);
+        }
+        assert_eq!(encoded, code);
+    }
+    #[test]
+    fn reachable_from_jumps() {
+        // JMP 3 skips the unknown byte and lands on the operand of the PUSH at 2
+        let code: Vec<u8> = vec![0x30, 0x03, 0x20, 0x12];
+        let decoded = reachable(&code);
+        assert_eq!(
+            decoded.into_iter().collect::<Vec<_>>(),
+            [(0, Instruction::Jmp(3)), (3, Instruction::Ret)]
+        );
+    }
+}
@@ -0,0 +1,656 @@
+/*!Native code generation with Cranelift, behind the `jit` feature
+ *
+ * A program is first verified: every reachable instruction must be entered with
+ * the same stack depth along all paths. With the depth known, each stack slot
+ * becomes a Cranelift variable and the stack itself disappears into SSA values.
+ *
+ * Anything the native code does not handle, such as PCHAR, frames, TRY and
+ * THROW, coroutines, a stack underflow, an unknown op or arithmetic that would
+ * overflow or divide by zero, is a side exit: the native code writes the stack
+ * out and the byte interpreter resumes at that instruction, so results and
+ * `StackError`s are those of `Stack::execute`.
+ */
+
+use std::{collections::BTreeMap, ffi::c_void};
+
+use cranelift_codegen::{
+    Context,
+    ir::{AbiParam, Block, FuncRef, InstBuilder, MemFlags, Value, condcodes::IntCC, types::I64},
+    settings::{self, Configurable},
+};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module, ModuleError, default_libcall_names};
+use thiserror::Error;
+
+use crate::{
+    code::{code_execution::write_line, instruction::Instruction},
+    stack::{Stack, stack_error::StackError},
+};
+
+#[derive(Debug, Error)]
+pub enum JitError {
+    #[error("Stack depth at index {idx} is both {first} and {second}")]
+    UnbalancedJoin {
+        idx: usize,
+        first: usize,
+        second: usize,
+    },
+    #[error("Could not set up native code generation: {0}")]
+    Host(String),
+    #[error("Cranelift rejected the program")]
+    Module(#[source] Box<ModuleError>),
+}
+
+impl From<ModuleError> for JitError {
+    fn from(error: ModuleError) -> Self {
+        JitError::Module(Box::new(error))
+    }
+}
+
+///How the native code stopped, written to `ExitInfo::kind`
+const RETURN: i64 = 0;
+const END: i64 = 1;
+const SIDE_EXIT: i64 = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct ExitInfo {
+    kind: i64,
+    idx: i64,
+    op: i64,
+    depth: i64,
+    value: i64,
+}
+
+///Stack slots, exit information and the `Stack::captured` PRINT writes to
+type Entry = unsafe extern "C" fn(*mut i64, *mut ExitInfo, *mut c_void);
+
+extern "C" fn corrode_jit_print(captured: *mut c_void, value: i64) {
+    // SAFETY: `execute_compiled` passes its own `captured`, which outlives the native code
+    let captured = unsafe { &mut *captured.cast::<Option<String>>() };
+    write_line(captured, &value.to_string());
+}
+
+///Reachable instructions with the stack depth they are entered with
+struct Analysis {
+    instructions: BTreeMap<usize, (Instruction, usize)>,
+    max_depth: usize,
+}
+
+impl Analysis {
+    fn new(code: &[u8]) -> Result<Self, JitError> {
+        let mut instructions: BTreeMap<usize, (Instruction, usize)> = BTreeMap::new();
+        let mut max_depth = 0;
+        let mut worklist = vec![(0, 0)];
+
+        while let Some((offset, depth)) = worklist.pop() {
+            let Some(instruction) = Instruction::decode(code, offset) else {
+                continue;
+            };
+            if let Some(&(_, first)) = instructions.get(&offset) {
+                if first != depth {
+                    return Err(JitError::UnbalancedJoin {
+                        idx: offset,
+                        first,
+                        second: depth,
+                    });
+                }
+                continue;
+            }
+            instructions.insert(offset, (instruction, depth));
+            max_depth = max_depth.max(depth);
+
+            let next = offset + instruction.size();
+            match instruction {
+                _ if depth < inputs(&instruction) => (),
+                Instruction::Nop | Instruction::Print | Instruction::Swp => {
+                    worklist.push((next, depth))
+                }
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Mod
+                | Instruction::Lt
+                | Instruction::Store(_)
+                | Instruction::Assert => worklist.push((next, depth - 1)),
+                Instruction::AssertEq => worklist.push((next, depth - 2)),
+                Instruction::Push(_) | Instruction::Dup | Instruction::Load(_) => {
+                    worklist.push((next, depth + 1))
+                }
+                Instruction::Pop => worklist.push((next, depth.saturating_sub(1))),
+                Instruction::Depth => worklist.push((next, depth + 1)),
+                Instruction::Clear => worklist.push((next, 0)),
+                _ if instruction.shuffle().is_some() => {
+                    // 2DUP leaves one more than the slot above the deepest entry
+                    let (taken, order) = instruction.shuffle().unwrap();
+                    let after = depth - taken + order.len();
+                    max_depth = max_depth.max(after);
+                    worklist.push((next, after))
+                }
+                Instruction::Jmp(target) => worklist.push((target, depth)),
+                Instruction::Jnz(target) => {
+                    worklist.push((next, depth));
+                    if depth > 0 {
+                        worklist.push((target, depth));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Analysis {
+            instructions,
+            max_depth: max_depth + 1,
+        })
+    }
+}
+
+///Stack slots an instruction needs before native code can run it
+fn inputs(instruction: &Instruction) -> usize {
+    match instruction {
+        Instruction::Add
+        | Instruction::Sub
+        | Instruction::Mul
+        | Instruction::Div
+        | Instruction::Mod
+        | Instruction::Lt
+        | Instruction::Swp
+        | Instruction::AssertEq => 2,
+        Instruction::Print | Instruction::Ret | Instruction::Dup | Instruction::Assert => 1,
+        Instruction::Load(slot) => *slot as usize + 1,
+        // The slot must still be there once the value is popped
+        Instruction::Store(slot) => *slot as usize + 2,
+        _ => instruction.shuffle().map_or(0, |(taken, _)| taken),
+    }
+}
+
+pub struct JitProgram {
+    module: Option<JITModule>,
+    entry: Entry,
+    max_depth: usize,
+    code: Vec<u8>,
+}
+
+impl JitProgram {
+    ///Verify and compile a program that starts at index 0 on an empty stack
+    pub fn compile(code: &[u8]) -> Result<Self, JitError> {
+        let analysis = Analysis::new(code)?;
+
+        let mut flags = settings::builder();
+        flags
+            .set("use_colocated_libcalls", "false")
+            .and_then(|_| flags.set("is_pic", "false"))
+            .and_then(|_| flags.set("opt_level", "speed"))
+            .map_err(|e| JitError::Host(e.to_string()))?;
+        let isa = cranelift_native::builder()
+            .map_err(|e| JitError::Host(e.to_string()))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| JitError::Host(e.to_string()))?;
+
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("corrode_jit_print", corrode_jit_print as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let pointer = module.target_config().pointer_type();
+        let mut print_signature = module.make_signature();
+        print_signature.params.push(AbiParam::new(pointer));
+        print_signature.params.push(AbiParam::new(I64));
+        let print =
+            module.declare_function("corrode_jit_print", Linkage::Import, &print_signature)?;
+
+        let mut ctx = module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(pointer));
+        ctx.func.signature.params.push(AbiParam::new(pointer));
+        ctx.func.signature.params.push(AbiParam::new(pointer));
+        let main = module.declare_function("corrode_main", Linkage::Export, &ctx.func.signature)?;
+
+        Translator::translate(&mut module, &mut ctx, print, &analysis);
+
+        module.define_function(main, &mut ctx)?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions()?;
+
+        // SAFETY: the function was declared with the `Entry` signature above
+        let entry =
+            unsafe { std::mem::transmute::<*const u8, Entry>(module.get_finalized_function(main)) };
+
+        Ok(JitProgram {
+            module: Some(module),
+            entry,
+            max_depth: analysis.max_depth,
+            code: code.to_vec(),
+        })
+    }
+}
+
+impl Drop for JitProgram {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: `entry` points into the module and dies with `self`
+            unsafe { module.free_memory() }
+        }
+    }
+}
+
+struct Translator<'a, 'b> {
+    builder: FunctionBuilder<'b>,
+    print: FuncRef,
+    stack: Value,
+    exit: Value,
+    captured: Value,
+    blocks: &'a BTreeMap<usize, Block>,
+}
+
+impl Translator<'_, '_> {
+    fn translate(module: &mut JITModule, ctx: &mut Context, print: FuncId, analysis: &Analysis) {
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut builder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let print = module.declare_func_in_func(print, builder.func);
+
+        let start = builder.create_block();
+        builder.append_block_params_for_function_params(start);
+        builder.switch_to_block(start);
+        let stack = builder.block_params(start)[0];
+        let exit = builder.block_params(start)[1];
+        let captured = builder.block_params(start)[2];
+
+        for slot in 0..analysis.max_depth {
+            builder.declare_var(Variable::from_u32(slot as u32), I64);
+        }
+        let blocks: BTreeMap<usize, Block> = analysis
+            .instructions
+            .keys()
+            .map(|&offset| (offset, builder.create_block()))
+            .collect();
+
+        let mut translator = Translator {
+            builder,
+            print,
+            stack,
+            exit,
+            captured,
+            blocks: &blocks,
+        };
+        translator.goto(0, 0, 0);
+
+        for (&offset, &(instruction, depth)) in &analysis.instructions {
+            translator.builder.switch_to_block(blocks[&offset]);
+            translator.instruction(offset, instruction, depth);
+        }
+
+        translator.builder.seal_all_blocks();
+        translator.builder.finalize();
+    }
+
+    fn slot(&mut self, slot: usize) -> Value {
+        self.builder.use_var(Variable::from_u32(slot as u32))
+    }
+
+    fn set(&mut self, slot: usize, value: Value) {
+        self.builder.def_var(Variable::from_u32(slot as u32), value)
+    }
+
+    ///Continue at `offset`, or leave the native code when it is past the end
+    fn goto(&mut self, offset: usize, depth: usize, from: u8) {
+        match self.blocks.get(&offset) {
+            Some(&block) => {
+                self.builder.ins().jump(block, &[]);
+            }
+            None => self.leave(END, offset, from, depth, None),
+        }
+    }
+
+    ///Write the stack and exit information out and return to the caller
+    fn leave(&mut self, kind: i64, idx: usize, op: u8, depth: usize, value: Option<Value>) {
+        for slot in 0..depth {
+            let item = self.slot(slot);
+            self.builder
+                .ins()
+                .store(MemFlags::trusted(), item, self.stack, (slot * 8) as i32);
+        }
+        let fields = [kind, idx as i64, op as i64, depth as i64];
+        for (field, number) in fields.into_iter().enumerate() {
+            let number = self.builder.ins().iconst(I64, number);
+            self.builder
+                .ins()
+                .store(MemFlags::trusted(), number, self.exit, (field * 8) as i32);
+        }
+        if let Some(value) = value {
+            self.builder
+                .ins()
+                .store(MemFlags::trusted(), value, self.exit, 32);
+        }
+        self.builder.ins().return_(&[]);
+    }
+
+    ///Hand the instruction at `offset` to the interpreter when `condition` is set
+    fn side_exit_if(&mut self, condition: Value, offset: usize, op: u8, depth: usize) {
+        let side_exit = self.builder.create_block();
+        let resume = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, side_exit, &[], resume, &[]);
+
+        self.builder.switch_to_block(side_exit);
+        self.leave(SIDE_EXIT, offset, op, depth, None);
+        self.builder.switch_to_block(resume);
+    }
+
+    fn instruction(&mut self, offset: usize, instruction: Instruction, depth: usize) {
+        let op = instruction.opcode();
+        let next = offset + instruction.size();
+
+        if depth < inputs(&instruction) {
+            return self.leave(SIDE_EXIT, offset, op, depth, None);
+        }
+
+        match instruction {
+            Instruction::Nop => self.goto(next, depth, op),
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::Mod => {
+                let lhs = self.slot(depth - 2);
+                let rhs = self.slot(depth - 1);
+                let (result, fault) = match instruction {
+                    Instruction::Add => self.builder.ins().sadd_overflow(lhs, rhs),
+                    Instruction::Sub => self.builder.ins().ssub_overflow(lhs, rhs),
+                    Instruction::Mul => self.builder.ins().smul_overflow(lhs, rhs),
+                    _ => {
+                        let ins = &mut self.builder;
+                        let by_zero = ins.ins().icmp_imm(IntCC::Equal, rhs, 0);
+                        let min = ins.ins().icmp_imm(IntCC::Equal, lhs, i64::MIN);
+                        let minus_one = ins.ins().icmp_imm(IntCC::Equal, rhs, -1);
+                        let overflow = ins.ins().band(min, minus_one);
+                        let fault = ins.ins().bor(by_zero, overflow);
+                        self.side_exit_if(fault, offset, op, depth);
+
+                        let result = match instruction {
+                            Instruction::Div => self.builder.ins().sdiv(lhs, rhs),
+                            _ => self.builder.ins().srem(lhs, rhs),
+                        };
+                        self.set(depth - 2, result);
+                        return self.goto(next, depth - 1, op);
+                    }
+                };
+                self.side_exit_if(fault, offset, op, depth);
+                self.set(depth - 2, result);
+                self.goto(next, depth - 1, op);
+            }
+            Instruction::Lt => {
+                let lhs = self.slot(depth - 2);
+                let rhs = self.slot(depth - 1);
+                let less = self.builder.ins().icmp(IntCC::SignedLessThan, lhs, rhs);
+                let result = self.builder.ins().uextend(I64, less);
+                self.set(depth - 2, result);
+                self.goto(next, depth - 1, op);
+            }
+            Instruction::Print => {
+                let top = self.slot(depth - 1);
+                self.builder.ins().call(self.print, &[self.captured, top]);
+                self.goto(next, depth, op);
+            }
+            Instruction::Ret => {
+                let top = self.slot(depth - 1);
+                self.leave(RETURN, offset, op, depth, Some(top));
+            }
+            Instruction::Push(value) => {
+                let value = self.builder.ins().iconst(I64, value as i64);
+                self.set(depth, value);
+                self.goto(next, depth + 1, op);
+            }
+            Instruction::Swp => {
+                let top = self.slot(depth - 1);
+                let under = self.slot(depth - 2);
+                self.set(depth - 1, under);
+                self.set(depth - 2, top);
+                self.goto(next, depth, op);
+            }
+            Instruction::Pop => self.goto(next, depth.saturating_sub(1), op),
+            Instruction::Dup => {
+                let top = self.slot(depth - 1);
+                self.set(depth, top);
+                self.goto(next, depth + 1, op);
+            }
+            Instruction::Load(slot) => {
+                let item = self.slot(slot as usize);
+                self.set(depth, item);
+                self.goto(next, depth + 1, op);
+            }
+            Instruction::Store(slot) => {
+                let top = self.slot(depth - 1);
+                self.set(slot as usize, top);
+                self.goto(next, depth - 1, op);
+            }
+            // A failing assertion leaves it to the interpreter to report
+            Instruction::Assert => {
+                let top = self.slot(depth - 1);
+                let fault = self.builder.ins().icmp_imm(IntCC::Equal, top, 0);
+                self.side_exit_if(fault, offset, op, depth);
+                self.goto(next, depth - 1, op);
+            }
+            Instruction::AssertEq => {
+                let lhs = self.slot(depth - 2);
+                let rhs = self.slot(depth - 1);
+                let fault = self.builder.ins().icmp(IntCC::NotEqual, lhs, rhs);
+                self.side_exit_if(fault, offset, op, depth);
+                self.goto(next, depth - 2, op);
+            }
+            Instruction::Jmp(target) => self.goto(target, depth, op),
+            Instruction::Jnz(target) if depth > 0 => {
+                let top = self.slot(depth - 1);
+                let taken = self.builder.create_block();
+                let not_taken = self.builder.create_block();
+                self.builder.ins().brif(top, taken, &[], not_taken, &[]);
+
+                self.builder.switch_to_block(taken);
+                self.goto(target, depth, op);
+                self.builder.switch_to_block(not_taken);
+                self.goto(next, depth, op);
+            }
+            Instruction::Jnz(_) => self.goto(next, depth, op),
+            Instruction::Over
+            | Instruction::Rot
+            | Instruction::Nip
+            | Instruction::Tuck
+            | Instruction::Pick(_)
+            | Instruction::Roll(_)
+            | Instruction::TwoDup
+            | Instruction::TwoSwap => {
+                let (taken, order) = instruction.shuffle().unwrap();
+                let below = depth - taken;
+                let values: Vec<Value> = (below..depth).map(|slot| self.slot(slot)).collect();
+                for (position, &from) in order.iter().enumerate() {
+                    self.set(below + position, values[from]);
+                }
+                self.goto(next, below + order.len(), op);
+            }
+            Instruction::Depth => {
+                let value = self.builder.ins().iconst(I64, depth as i64);
+                self.set(depth, value);
+                self.goto(next, depth + 1, op);
+            }
+            // Native code never runs inside a frame, so everything goes
+            Instruction::Clear => self.goto(next, 0, op),
+            Instruction::Exit => {
+                let value = self.builder.ins().iconst(I64, 0xFF);
+                self.leave(RETURN, offset, op, depth, Some(value));
+            }
+            // Frames, handlers and coroutines live outside the native stack slots, so the
+            // interpreter handles them
+            Instruction::PChar
+            | Instruction::Enter(_)
+            | Instruction::LoadLocal(_)
+            | Instruction::StoreLocal(_)
+            | Instruction::Leave
+            | Instruction::Try(_)
+            | Instruction::EndTry
+            | Instruction::Throw
+            | Instruction::Spawn(_)
+            | Instruction::Yield
+            | Instruction::Send(_)
+            | Instruction::Recv(_)
+            | Instruction::Truncated(_)
+            | Instruction::Unknown(_) => self.leave(SIDE_EXIT, offset, op, depth, None),
+        }
+    }
+}
+
+impl Stack<i64> {
+    ///Run natively compiled code, behaving exactly like `execute`
+    pub fn execute_compiled(&mut self, program: &JitProgram) -> Result<i64, StackError> {
+        // The compiled code assumes it starts at index 0 on an empty stack, outside any frame or
+        // TRY, as the only coroutine
+        if self.idx != 0
+            || !self.state.is_empty()
+            || !self.frames.is_empty()
+            || !self.handlers.is_empty()
+            || !self.scheduler.queue.is_empty()
+        {
+            return self.execute(&program.code);
+        }
+        if program.code.is_empty() {
+            return Ok(0);
+        }
+
+        let mut stack = vec![0i64; program.max_depth];
+        let mut exit = ExitInfo::default();
+        let captured = (&mut self.captured as *mut Option<String>).cast::<c_void>();
+        // SAFETY: the verifier bounds every slot the code touches by `max_depth`
+        unsafe { (program.entry)(stack.as_mut_ptr(), &mut exit, captured) };
+
+        stack.truncate(exit.depth as usize);
+        self.state = stack;
+        self.idx = exit.idx as usize;
+        self.op = exit.op as u8;
+        match exit.kind {
+            RETURN => Ok(exit.value),
+            END => Ok(0),
+            _ => self.execute(&program.code),
+        }
+    }
+
+    ///Compile and run `code` natively, using the interpreter when it cannot be compiled
+    pub fn execute_jit(&mut self, code: &[u8]) -> Result<i64, StackError> {
+        match JitProgram::compile(code) {
+            Ok(program) => self.execute_compiled(&program),
+            Err(_) => self.execute(code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(code: &[u8]) {
+        let mut interpreted = Stack::<i64>::new();
+        interpreted.captured = Some(String::new());
+        let expected = interpreted.execute(code);
+
+        let mut compiled = Stack::<i64>::new();
+        compiled.captured = Some(String::new());
+        let retval = compiled.execute_compiled(&JitProgram::compile(code).unwrap());
+
+        assert_eq!(format!("{:?}", retval), format!("{:?}", expected));
+        assert_eq!(compiled.state, interpreted.state);
+        assert_eq!(compiled.idx, interpreted.idx);
+        assert_eq!(compiled.op, interpreted.op);
+        assert_eq!(compiled.captured, interpreted.captured);
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x01, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x02, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x03, 0x12]);
+        assert_same(&[0x20, 0x06, 0x20, 0x03, 0x04, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x03, 0x05, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x06, 0x12]);
+    }
+    #[test]
+    fn stack_words() {
+        assert_same(&[0x20, 0x01, 0x20, 0x02, 0x21, 0x23, 0x22, 0xFF]);
+        assert_same(&[0x22, 0x20, 0x01, 0x21]);
+        assert_same(&[0x23]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x24, 0x00, 0x01, 0x25, 0x00, 0x12]);
+        assert_same(&[0x20, 0x05, 0x24, 0x01]);
+        assert_same(&[0x20, 0x05, 0x25, 0x00]);
+        assert_same(&[
+            0x20, 0x01, 0x20, 0x02, 0x20, 0x03, 0x60, 0x61, 0x62, 0x63, 0x68, 0x69, 0x64, 0x04,
+            0x65, 0x05, 0x66, 0x12,
+        ]);
+        assert_same(&[0x20, 0x01, 0x20, 0x02, 0x68]);
+        assert_same(&[0x20, 0x01, 0x20, 0x02, 0x67, 0x66, 0x12]);
+        assert_same(&[0x20, 0x01, 0x64, 0x01]);
+        assert_same(&[0x20, 0x01, 0x20, 0x02, 0x62, 0x69]);
+    }
+    #[test]
+    fn jumps() {
+        assert_same(&[0x20, 0x05, 0x30, 0x06, 0x20, 0x03, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x01, 0x02, 0x31, 0x02, 0x20, 0x03, 0x12]);
+        assert_same(&[0x20, 0x05, 0x30, 0xF0]);
+        assert_same(&[0x20, 0x00, 0x31]);
+        assert_same(&[0x20, 0x1E, 0x20, 0x01, 0x02, 0x31, 0x02, 0x12]);
+    }
+    #[test]
+    fn captured_output() {
+        // PUSH 2, loop: PRINT, PUSH 1, SUB, JNZ loop, RET
+        let code = [0x20, 0x02, 0x10, 0x20, 0x01, 0x02, 0x31, 0x02, 0x12];
+        assert_same(&code);
+        let mut compiled = Stack::<i64>::new();
+        compiled.captured = Some(String::new());
+        compiled
+            .execute_compiled(&JitProgram::compile(&code).unwrap())
+            .unwrap();
+        assert_eq!(compiled.captured.unwrap(), "2\n1\n");
+    }
+    #[test]
+    fn side_exits() {
+        // PCHAR, underflow and unknown ops are left to the interpreter
+        assert_same(&[0x20, 0x00, 0x20, 0x48, 0x20, 0x69, 0x11, 0x12]);
+        assert_same(&[0x20, 0x05, 0x01]);
+        assert_same(&[0x12]);
+        assert_same(&[0x20, 0x05, 0x42]);
+        // ENTER 1, PUSH 7, STORELOCAL 0, LOADLOCAL 0, LEAVE, RET
+        assert_same(&[0x26, 0x01, 0x20, 0x07, 0x28, 0x00, 0x27, 0x00, 0x29, 0x12]);
+        // TRY 7, PUSH 1, PUSH 0, DIV, handler: RET
+        assert_same(&[0x32, 0x07, 0x20, 0x01, 0x20, 0x00, 0x04, 0x12]);
+        assert_same(&[0x20, 0x07, 0x34]);
+        // SPAWN 5, RECV 0, RET, 5: PUSH 2, SEND 0
+        assert_same(&[0x50, 0x05, 0x53, 0x00, 0x12, 0x20, 0x02, 0x52, 0x00]);
+    }
+    #[test]
+    fn assertions() {
+        assert_same(&[
+            0x20, 0x01, 0x40, 0x20, 0x04, 0x20, 0x04, 0x41, 0x20, 0x09, 0x12,
+        ]);
+        assert_same(&[0x20, 0x00, 0x40]);
+        assert_same(&[0x20, 0x02, 0x20, 0x03, 0x41]);
+        assert_same(&[0x40]);
+    }
+    #[test]
+    fn remainder_by_zero_reaches_interpreter() {
+        assert_same(&[0x20, 0x05, 0x20, 0x00, 0x05]);
+    }
+    #[test]
+    fn unbalanced_loop_falls_back() {
+        // Pushes one more value on every pass through the loop
+        let code: Vec<u8> = vec![0x20, 0x03, 0x23, 0x20, 0x01, 0x02, 0x31, 0x02, 0x12];
+        assert!(matches!(
+            JitProgram::compile(&code),
+            Err(JitError::UnbalancedJoin { idx: 2, .. })
+        ));
+
+        let mut interpreted = Stack::<i64>::new();
+        let mut jitted = Stack::<i64>::new();
+        assert_eq!(
+            jitted.execute_jit(&code).unwrap(),
+            interpreted.execute(&code).unwrap()
+        );
+        assert_eq!(jitted.state, interpreted.state);
+    }
+}
@@ -0,0 +1,229 @@
+/*!Linker, placing objects one after another and patching their relocations
+ *
+ * The first object is the program, it starts at address 0 and runs first.
+ * Exported names share one namespace across all objects.
+ */
+
+use std::collections::HashMap;
+
+use crate::code::{
+    check::Signature,
+    object::{Export, Object, Target},
+    parse_error::ParseError,
+    preprocess::Location,
+};
+
+///Merge objects into a single program
+pub fn link(objects: &[Object]) -> Result<Vec<u8>, ParseError> {
+    let mut bases = Vec::with_capacity(objects.len());
+    let mut size = 0;
+    for object in objects {
+        bases.push(size);
+        size += object.code.len();
+    }
+
+    let mut exported: HashMap<&str, (usize, &Export)> = HashMap::new();
+    for (object, base) in objects.iter().zip(&bases) {
+        for export in &object.exports {
+            if let Some((_, first)) = exported.insert(&export.name, (base + export.address, export))
+            {
+                return Err(ParseError::DuplicateExport {
+                    name: export.name.clone(),
+                    first: first.at.clone(),
+                    at: export.at.clone(),
+                });
+            }
+        }
+    }
+
+    for import in objects.iter().flat_map(|object| &object.imports) {
+        if !exported.contains_key(import.name.as_str()) {
+            return Err(ParseError::UndefinedSymbol {
+                name: import.name.clone(),
+                at: import.at.clone(),
+            });
+        }
+    }
+
+    let mut code = Vec::with_capacity(size);
+    for (object, base) in objects.iter().zip(&bases) {
+        code.extend_from_slice(&object.code);
+        for relocation in &object.relocations {
+            let address = match &relocation.target {
+                Target::Module => *base,
+                Target::Symbol(name) => exported[name.as_str()].0,
+            };
+            let value = address as i64 + relocation.addend;
+            code[base + relocation.offset] =
+                u8::try_from(value).map_err(|_| ParseError::OperandRange {
+                    value,
+                    at: relocation.at.clone(),
+                })?;
+        }
+    }
+    Ok(code)
+}
+
+///Where the code of a linked program came from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    ///Addresses and names of the labels, lowest address first
+    pub labels: Vec<(usize, String)>,
+    ///Source map: the address of every instruction and the file, line and column it was
+    ///assembled from, lowest address first
+    pub lines: Vec<(usize, Location)>,
+    ///Address of every label with a `( in -- out )` annotation, and the annotation
+    pub signatures: Vec<(usize, Signature)>,
+}
+
+impl DebugInfo {
+    ///Source map of a program compiled from the single file `file`, without labels, from the
+    ///address, line and column of every instruction
+    pub fn compiled(
+        file: &str,
+        positions: impl IntoIterator<Item = (usize, usize, usize)>,
+    ) -> Self {
+        let lines = positions
+            .into_iter()
+            .map(|(address, line, column)| {
+                let location = Location {
+                    file: file.to_string(),
+                    line,
+                    column,
+                };
+                (address, location)
+            })
+            .collect();
+        DebugInfo {
+            lines,
+            ..DebugInfo::default()
+        }
+    }
+
+    ///Line the instruction at `address` was assembled from
+    pub fn line(&self, address: usize) -> Option<&Location> {
+        let found = self
+            .lines
+            .binary_search_by_key(&address, |(address, _)| *address);
+        found.ok().map(|index| &self.lines[index].1)
+    }
+}
+
+///Labels and lines of the objects once they are linked
+pub fn debug_info(objects: &[Object]) -> DebugInfo {
+    let mut base = 0;
+    let mut info = DebugInfo::default();
+    for object in objects {
+        info.labels.extend(
+            object
+                .labels
+                .iter()
+                .map(|(name, address)| (base + address, name.clone())),
+        );
+        info.lines.extend(
+            object
+                .lines
+                .iter()
+                .map(|(address, at)| (base + address, at.clone())),
+        );
+        info.signatures.extend(
+            object
+                .signatures
+                .iter()
+                .map(|(address, signature)| (base + address, *signature)),
+        );
+        base += object.code.len();
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::object::{Import, Relocation};
+
+    use super::*;
+
+    fn at(line: usize) -> Location {
+        Location {
+            file: "test.cor".into(),
+            line,
+            column: 1,
+        }
+    }
+
+    fn library(name: &str, address: usize) -> Object {
+        Object {
+            code: vec![0x00, 0x00, 0x12],
+            exports: vec![Export {
+                name: name.into(),
+                address,
+                at: at(1),
+            }],
+            labels: vec![(name.into(), address)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn relocations() {
+        let program = Object {
+            code: vec![0x30, 0x00, 0x31, 0x00, 0xFF],
+            imports: vec![Import {
+                name: "f".into(),
+                at: at(1),
+            }],
+            relocations: vec![
+                Relocation {
+                    offset: 1,
+                    target: Target::Symbol("f".into()),
+                    addend: 0,
+                    at: at(2),
+                },
+                Relocation {
+                    offset: 3,
+                    target: Target::Module,
+                    addend: 4,
+                    at: at(3),
+                },
+            ],
+            ..Default::default()
+        };
+        let objects = [program, library("f", 2)];
+        let code = link(&objects).unwrap();
+        assert_eq!(code, [0x30, 0x07, 0x31, 0x04, 0xFF, 0x00, 0x00, 0x12]);
+        assert_eq!(debug_info(&objects).labels, [(7, String::from("f"))]);
+    }
+    #[test]
+    fn symbol_errors() {
+        let program = Object {
+            imports: vec![Import {
+                name: "g".into(),
+                at: at(4),
+            }],
+            ..Default::default()
+        };
+        assert!(matches!(
+            link(&[program, library("f", 0)]),
+            Err(ParseError::UndefinedSymbol { name, at: Location { line: 4, .. } }) if name == "g"
+        ));
+        assert!(matches!(
+            link(&[library("f", 0), library("f", 1)]),
+            Err(ParseError::DuplicateExport { .. })
+        ));
+
+        let far = Object {
+            code: vec![0x30, 0x00],
+            relocations: vec![Relocation {
+                offset: 1,
+                target: Target::Module,
+                addend: 255,
+                at: at(1),
+            }],
+            ..Default::default()
+        };
+        assert!(matches!(
+            link(&[library("f", 0), far]),
+            Err(ParseError::OperandRange { value: 258, .. })
+        ));
+    }
+}
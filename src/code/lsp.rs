@@ -0,0 +1,764 @@
+/*!Language server for .cor files, over stdio
+ *
+ * Documents are kept in memory and edited incrementally, so everything works
+ * on unsaved buffers. After every change the buffer is assembled and the
+ * error, if any, is published as a diagnostic.
+ *
+ * definition, references  \\ labels, from their `label:` and their `$label` and `.export`/`.import` uses
+ * hover                   \\ the stack effect of a mnemonic as documented in `code`, or a label's address
+ * completion              \\ mnemonics, and labels after `$`
+ * rename                  \\ a label, its definition and every use
+ *
+ * Positions count UTF-16 code units, as the protocol does by default.
+ */
+
+use std::{collections::HashMap, path::PathBuf};
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, CompletionResponse,
+    Diagnostic, DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, Documentation, GotoDefinitionParams, GotoDefinitionResponse, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind,
+    OneOf, Position, PublishDiagnosticsParams, Range, ReferenceParams, RenameParams,
+    ServerCapabilities, TextDocumentContentChangeEvent, TextDocumentSyncCapability,
+    TextDocumentSyncKind, TextEdit, Uri, WorkspaceEdit,
+};
+use serde_json::Value;
+
+use crate::code::{
+    check::EFFECTS,
+    parse::assemble_source,
+    preprocess::{MNEMONICS, is_word, split_comment, split_label, split_word},
+};
+
+///A label defined or used in a document
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Symbol {
+    name: String,
+    line: u32,
+    ///Byte offsets of the name in its line
+    start: usize,
+    end: usize,
+    definition: bool,
+}
+
+#[derive(Debug, Default)]
+struct Document {
+    text: String,
+    ///Addresses of the labels, as of the last time the document assembled
+    labels: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<Uri, Document>,
+}
+
+///Run the language server on stdin and stdout until the client shuts it down
+pub fn serve() -> anyhow::Result<()> {
+    let (connection, io_threads) = Connection::stdio();
+    run(&connection)?;
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+///Initialize, then answer the client on `connection` until it shuts the server down
+pub fn run(connection: &Connection) -> anyhow::Result<()> {
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    let mut server = Server::default();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                connection.sender.send(server.request(request).into())?;
+            }
+            Message::Notification(notification) => {
+                for notification in server.notify(notification) {
+                    connection.sender.send(notification.into())?;
+                }
+            }
+            Message::Response(_) => (),
+        }
+    }
+    Ok(())
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(
+            TextDocumentSyncKind::INCREMENTAL,
+        )),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![String::from("$")]),
+            ..CompletionOptions::default()
+        }),
+        rename_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    }
+}
+
+impl Server {
+    fn request(&mut self, request: Request) -> Response {
+        let id = request.id.clone();
+        match self.answer(request) {
+            Ok(result) => Response::new_ok(id, result),
+            Err((code, message)) => Response::new_err(id, code as i32, message),
+        }
+    }
+
+    fn answer(&mut self, request: Request) -> Result<Value, (ErrorCode, String)> {
+        let invalid = |error: serde_json::Error| (ErrorCode::InvalidParams, error.to_string());
+        let params = request.params;
+        let result = match request.method.as_str() {
+            "textDocument/definition" => {
+                let params: GotoDefinitionParams =
+                    serde_json::from_value(params).map_err(invalid)?;
+                let at = params.text_document_position_params;
+                let document = self.document(&at.text_document.uri)?;
+                let definition = document.symbol_at(at.position).and_then(|symbol| {
+                    document
+                        .symbols()
+                        .into_iter()
+                        .find(|other| other.definition && other.name == symbol.name)
+                });
+                serde_json::to_value(definition.map(|definition| {
+                    GotoDefinitionResponse::Scalar(
+                        document.location(&at.text_document.uri, &definition),
+                    )
+                }))
+            }
+            "textDocument/references" => {
+                let params: ReferenceParams = serde_json::from_value(params).map_err(invalid)?;
+                let at = params.text_document_position;
+                let document = self.document(&at.text_document.uri)?;
+                let references = document.symbol_at(at.position).map(|symbol| {
+                    document
+                        .symbols()
+                        .into_iter()
+                        .filter(|other| other.name == symbol.name)
+                        .filter(|other| params.context.include_declaration || !other.definition)
+                        .map(|other| document.location(&at.text_document.uri, &other))
+                        .collect::<Vec<_>>()
+                });
+                serde_json::to_value(references)
+            }
+            "textDocument/hover" => {
+                let params: HoverParams = serde_json::from_value(params).map_err(invalid)?;
+                let at = params.text_document_position_params;
+                let document = self.document(&at.text_document.uri)?;
+                serde_json::to_value(document.hover(at.position))
+            }
+            "textDocument/completion" => {
+                let params: CompletionParams = serde_json::from_value(params).map_err(invalid)?;
+                let at = params.text_document_position;
+                let document = self.document(&at.text_document.uri)?;
+                serde_json::to_value(CompletionResponse::Array(document.completion(at.position)))
+            }
+            "textDocument/rename" => {
+                let params: RenameParams = serde_json::from_value(params).map_err(invalid)?;
+                let at = params.text_document_position;
+                let document = self.document(&at.text_document.uri)?;
+                if !is_word(&params.new_name) {
+                    return Err((
+                        ErrorCode::InvalidParams,
+                        format!("`{}` is not a valid label", params.new_name),
+                    ));
+                }
+                let edit = document.symbol_at(at.position).map(|symbol| {
+                    let edits = document
+                        .symbols()
+                        .into_iter()
+                        .filter(|other| other.name == symbol.name)
+                        .map(|other| TextEdit {
+                            range: document.location(&at.text_document.uri, &other).range,
+                            new_text: params.new_name.clone(),
+                        })
+                        .collect();
+                    WorkspaceEdit {
+                        changes: Some(HashMap::from([(at.text_document.uri.clone(), edits)])),
+                        ..WorkspaceEdit::default()
+                    }
+                });
+                serde_json::to_value(edit)
+            }
+            method => {
+                return Err((
+                    ErrorCode::MethodNotFound,
+                    format!("Unsupported request {method}"),
+                ));
+            }
+        };
+        result.map_err(|error| (ErrorCode::InternalError, error.to_string()))
+    }
+
+    fn document(&self, uri: &Uri) -> Result<&Document, (ErrorCode, String)> {
+        self.documents.get(uri).ok_or_else(|| {
+            (
+                ErrorCode::InvalidParams,
+                format!("{} is not open", uri.as_str()),
+            )
+        })
+    }
+
+    ///Update the documents, returning the diagnostics to publish
+    fn notify(&mut self, notification: Notification) -> Vec<Notification> {
+        let uri = match notification.method.as_str() {
+            "textDocument/didOpen" => {
+                let Ok(params) =
+                    serde_json::from_value::<DidOpenTextDocumentParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+                let document = Document {
+                    text: params.text_document.text,
+                    labels: HashMap::new(),
+                };
+                self.documents
+                    .insert(params.text_document.uri.clone(), document);
+                params.text_document.uri
+            }
+            "textDocument/didChange" => {
+                let Ok(params) =
+                    serde_json::from_value::<DidChangeTextDocumentParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+                let Some(document) = self.documents.get_mut(&params.text_document.uri) else {
+                    return Vec::new();
+                };
+                for change in params.content_changes {
+                    document.apply(change);
+                }
+                params.text_document.uri
+            }
+            "textDocument/didClose" => {
+                let Ok(params) =
+                    serde_json::from_value::<DidCloseTextDocumentParams>(notification.params)
+                else {
+                    return Vec::new();
+                };
+                self.documents.remove(&params.text_document.uri);
+                return vec![publish(params.text_document.uri, Vec::new())];
+            }
+            _ => return Vec::new(),
+        };
+        let document = self.documents.get_mut(&uri).unwrap();
+        let diagnostics = document.check(&path(&uri));
+        vec![publish(uri, diagnostics)]
+    }
+}
+
+fn publish(uri: Uri, diagnostics: Vec<Diagnostic>) -> Notification {
+    Notification::new(
+        String::from("textDocument/publishDiagnostics"),
+        PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        },
+    )
+}
+
+///File a document is saved to, which includes are relative to
+fn path(uri: &Uri) -> PathBuf {
+    let text = uri.as_str();
+    let Some(path) = text.strip_prefix("file://") else {
+        return PathBuf::from(text);
+    };
+    // Undo the percent encoding of spaces and other reserved characters
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let decoded = after
+            .get(..2)
+            .filter(|_| byte == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &after[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+///Byte offset in `line` of the UTF-16 column `character`
+fn byte_of(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (index, c) in line.char_indices() {
+        if units >= character as usize {
+            return index;
+        }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+///UTF-16 column of the byte offset `byte` in `line`
+fn character_of(line: &str, byte: usize) -> u32 {
+    line[..byte].encode_utf16().count() as u32
+}
+
+impl Document {
+    fn line(&self, line: u32) -> &str {
+        self.text.lines().nth(line as usize).unwrap_or("")
+    }
+
+    ///Byte offset of `position` in the text
+    fn offset(&self, position: Position) -> usize {
+        let mut start = 0;
+        for _ in 0..position.line {
+            match self.text[start..].find('\n') {
+                Some(newline) => start += newline + 1,
+                None => return self.text.len(),
+            }
+        }
+        let end = self.text[start..]
+            .find('\n')
+            .map_or(self.text.len(), |newline| start + newline);
+        start + byte_of(&self.text[start..end], position.character)
+    }
+
+    fn apply(&mut self, change: TextDocumentContentChangeEvent) {
+        match change.range {
+            Some(range) => {
+                let start = self.offset(range.start);
+                let end = self.offset(range.end).max(start);
+                self.text.replace_range(start..end, &change.text);
+            }
+            None => self.text = change.text,
+        }
+    }
+
+    ///Assemble the document, keeping its label addresses when it assembles
+    fn check(&mut self, path: &std::path::Path) -> Vec<Diagnostic> {
+        let name = path.display().to_string();
+        let error = match assemble_source::<i64>(&name, &self.text, &[]) {
+            Ok(object) => {
+                self.labels = object.labels.into_iter().collect();
+                return Vec::new();
+            }
+            Err(error) => error,
+        };
+        let message = error.to_string();
+        // Errors in included files are shown on the first line
+        let (line, message) = match error.at() {
+            Some(at) if at.file == name => (
+                at.line.saturating_sub(1) as u32,
+                message
+                    .strip_prefix(&format!("{at}: "))
+                    .unwrap_or(&message)
+                    .to_string(),
+            ),
+            _ => (0, message),
+        };
+        let end = character_of(self.line(line), self.line(line).len());
+        vec![Diagnostic {
+            range: Range::new(Position::new(line, 0), Position::new(line, end)),
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some(String::from("corrode")),
+            message,
+            ..Diagnostic::default()
+        }]
+    }
+
+    ///Every label definition and use, in order
+    fn symbols(&self) -> Vec<Symbol> {
+        let mut symbols = Vec::new();
+        for (number, text) in self.text.lines().enumerate() {
+            let line = number as u32;
+            let mut push = |start: usize, name: &str, definition: bool| {
+                symbols.push(Symbol {
+                    name: name.to_string(),
+                    line,
+                    start,
+                    end: start + name.len(),
+                    definition,
+                })
+            };
+            // Trimmed, so every part split off below is a suffix of it
+            let code = split_comment(text).0.trim_end();
+            let (label, rest) = split_label(code);
+            if let Some(label) = label {
+                push(code.len() - code.trim_start().len(), label, true);
+            }
+
+            let rest_start = code.len() - rest.len();
+            let (directive, names) = split_word(rest);
+            if matches!(directive.to_lowercase().as_str(), ".export" | ".import") {
+                let mut start = code.len() - names.len();
+                for piece in names.split(',') {
+                    let name = piece.trim();
+                    if is_word(name) {
+                        push(start + piece.len() - piece.trim_start().len(), name, false);
+                    }
+                    start += piece.len() + 1;
+                }
+                continue;
+            }
+
+            // $label operands, skipping character literals
+            let mut quoted = false;
+            let mut chars = rest.char_indices().peekable();
+            while let Some((index, c)) = chars.next() {
+                match c {
+                    '\'' => quoted = !quoted,
+                    '\\' if quoted => {
+                        chars.next();
+                    }
+                    '$' if !quoted => {
+                        let name: String = rest[index + 1..]
+                            .chars()
+                            .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                            .collect();
+                        if !name.is_empty() {
+                            push(rest_start + index + 1, &name, false);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        symbols
+    }
+
+    ///Label defined or used at `position`
+    fn symbol_at(&self, position: Position) -> Option<Symbol> {
+        let byte = byte_of(self.line(position.line), position.character);
+        self.symbols().into_iter().find(|symbol| {
+            symbol.line == position.line && symbol.start <= byte && byte <= symbol.end
+        })
+    }
+
+    fn location(&self, uri: &Uri, symbol: &Symbol) -> Location {
+        let line = self.line(symbol.line);
+        Location::new(
+            uri.clone(),
+            Range::new(
+                Position::new(symbol.line, character_of(line, symbol.start)),
+                Position::new(symbol.line, character_of(line, symbol.end)),
+            ),
+        )
+    }
+
+    fn hover(&self, position: Position) -> Option<Hover> {
+        let value = match self.symbol_at(position) {
+            Some(symbol) => {
+                let definition = self
+                    .symbols()
+                    .into_iter()
+                    .find(|other| other.definition && other.name == symbol.name);
+                match (self.labels.get(&symbol.name), definition) {
+                    (Some(address), _) => format!("label `{}` at address {address}", symbol.name),
+                    (None, Some(definition)) => {
+                        format!("label `{}` on line {}", symbol.name, definition.line + 1)
+                    }
+                    (None, None) => format!("label `{}` is not defined here", symbol.name),
+                }
+            }
+            None => {
+                let line = self.line(position.line);
+                let byte = byte_of(line, position.character);
+                let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+                let start = line[..byte]
+                    .rfind(|c: char| !is_word(c))
+                    .map_or(0, |index| index + 1);
+                let end = line[byte..]
+                    .find(|c: char| !is_word(c))
+                    .map_or(line.len(), |index| byte + index);
+                let (effect, description) = documented(&line[start..end].to_lowercase())?;
+                match description {
+                    Some(description) => format!("```\n{effect}\n```\n\n---\n\n{description}"),
+                    None => format!("```\n{effect}\n```"),
+                }
+            }
+        };
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value,
+            }),
+            range: None,
+        })
+    }
+
+    ///Labels after a `$`, mnemonics otherwise
+    fn completion(&self, position: Position) -> Vec<CompletionItem> {
+        let line = self.line(position.line);
+        let before = &line[..byte_of(line, position.character)];
+        let word = before.trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_');
+        if word.ends_with('$') {
+            let mut labels: Vec<String> = self
+                .symbols()
+                .into_iter()
+                .filter(|symbol| symbol.definition)
+                .map(|symbol| symbol.name)
+                .collect();
+            labels.dedup();
+            return labels
+                .into_iter()
+                .map(|label| CompletionItem {
+                    label,
+                    kind: Some(CompletionItemKind::REFERENCE),
+                    ..CompletionItem::default()
+                })
+                .collect();
+        }
+        MNEMONICS
+            .iter()
+            .map(|mnemonic| {
+                let (effect, description) = documented(mnemonic).unzip();
+                CompletionItem {
+                    label: mnemonic.to_string(),
+                    kind: Some(CompletionItemKind::KEYWORD),
+                    detail: effect.map(str::to_string),
+                    documentation: description
+                        .flatten()
+                        .map(|description| Documentation::String(description.to_string())),
+                    ..CompletionItem::default()
+                }
+            })
+            .collect()
+    }
+}
+
+///Stack effect of a mnemonic, and the description after ` \\ ` if its documentation has one
+fn documented(mnemonic: &str) -> Option<(&'static str, Option<&'static str>)> {
+    let line = EFFECTS.get(mnemonic)?;
+    Some(match line.split_once(" \\\\ ") {
+        Some((effect, description)) => (effect, Some(description)),
+        None => (line, None),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use lsp_server::RequestId;
+    use lsp_types::InitializeParams;
+    use serde_json::json;
+
+    use super::*;
+
+    const SOURCE: &str = ".export start\nstart: push 3 ; count '$x'\nloop:  push 1\n       sub\n       jnz $loop\n       jmp $start + 0\n";
+
+    fn document(text: &str) -> Document {
+        Document {
+            text: text.to_string(),
+            labels: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn symbols() {
+        let symbols = document(SOURCE).symbols();
+        let found: Vec<(&str, u32, usize, bool)> = symbols
+            .iter()
+            .map(|symbol| {
+                (
+                    symbol.name.as_str(),
+                    symbol.line,
+                    symbol.start,
+                    symbol.definition,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("start", 0, 8, false),
+                ("start", 1, 0, true),
+                ("loop", 2, 0, true),
+                ("loop", 4, 12, false),
+                ("start", 5, 12, false),
+            ]
+        );
+        let document = document(SOURCE);
+        assert_eq!(
+            document.symbol_at(Position::new(4, 14)).unwrap().name,
+            "loop"
+        );
+        assert_eq!(document.symbol_at(Position::new(3, 8)), None);
+    }
+    #[test]
+    fn edits_and_positions() {
+        let mut document = document("push 1\nadd\n");
+        document.apply(TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(0, 5), Position::new(0, 6))),
+            range_length: None,
+            text: String::from("2\npush 3"),
+        });
+        assert_eq!(document.text, "push 2\npush 3\nadd\n");
+        // Columns count UTF-16 units, é is one and 𝄞 two
+        let mut document = self::document("push 'é' ; 𝄞 x\n");
+        document.apply(TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(0, 14), Position::new(0, 15))),
+            range_length: None,
+            text: String::from("y"),
+        });
+        assert_eq!(document.text, "push 'é' ; 𝄞 y\n");
+        assert_eq!(
+            path(&Uri::from_str("file:///tmp/my%20programs/a.cor").unwrap()),
+            PathBuf::from("/tmp/my programs/a.cor")
+        );
+    }
+    #[test]
+    fn hover_and_completion() {
+        let hover = |document: &Document, line, character| {
+            let Some(Hover {
+                contents: HoverContents::Markup(markup),
+                ..
+            }) = document.hover(Position::new(line, character))
+            else {
+                return None;
+            };
+            Some(markup.value)
+        };
+        let mut document = document(SOURCE);
+        assert_eq!(
+            hover(&document, 1, 9).unwrap(),
+            "```\nPUSH A => ( -- A )\n```"
+        );
+        assert_eq!(
+            hover(&document, 4, 8).unwrap(),
+            "```\nJNZ => ( a -- a )\n```\n\n---\n\ngo to address (%int) or label ($string) IF stack top is NOT == 0"
+        );
+        assert_eq!(hover(&document, 4, 13).unwrap(), "label `loop` on line 3");
+        assert!(document.check(&PathBuf::from("main.cor")).is_empty());
+        assert_eq!(
+            hover(&document, 4, 13).unwrap(),
+            "label `loop` at address 2"
+        );
+        assert_eq!(EFFECTS.len(), MNEMONICS.len());
+
+        let labels: Vec<String> = document
+            .completion(Position::new(4, 12))
+            .into_iter()
+            .map(|item| item.label)
+            .collect();
+        assert_eq!(labels, ["start", "loop"]);
+        let mnemonics = document.completion(Position::new(3, 8));
+        assert_eq!(mnemonics.len(), MNEMONICS.len());
+        assert_eq!(
+            mnemonics[2].detail.as_deref(),
+            Some("SUB => ( a b -- a - b )")
+        );
+        let jmp = mnemonics.iter().find(|item| item.label == "jmp").unwrap();
+        assert_eq!(jmp.detail.as_deref(), Some("JMP => ()"));
+        assert_eq!(
+            jmp.documentation,
+            Some(Documentation::String(
+                "go to address (%int) or label ($string)".to_string()
+            ))
+        );
+    }
+    #[test]
+    fn protocol() {
+        let (server, client) = Connection::memory();
+        let thread = std::thread::spawn(move || run(&server).unwrap());
+        let mut next_id = 0;
+        let mut request = |method: &str, params: Value| {
+            next_id += 1;
+            let request = Request::new(RequestId::from(next_id), method.to_string(), params);
+            client.sender.send(request.into()).unwrap();
+            match client.receiver.recv().unwrap() {
+                Message::Response(response) => response,
+                message => panic!("expected a response, got {message:?}"),
+            }
+        };
+        let initialized = request(
+            "initialize",
+            serde_json::to_value(InitializeParams::default()).unwrap(),
+        );
+        assert!(initialized.result.unwrap()["capabilities"]["renameProvider"] == json!(true));
+
+        let notify = |method: &str, params: Value| {
+            let notification = Notification::new(method.to_string(), params);
+            client.sender.send(notification.into()).unwrap();
+        };
+        let diagnostics = || match client.receiver.recv().unwrap() {
+            Message::Notification(notification) => notification.params["diagnostics"].clone(),
+            message => panic!("expected diagnostics, got {message:?}"),
+        };
+        notify("initialized", json!({}));
+
+        let uri = "file:///tmp/unsaved.cor";
+        let document = json!({"uri": uri});
+        notify(
+            "textDocument/didOpen",
+            json!({"textDocument": {"uri": uri, "languageId": "corrode", "version": 1, "text": "push 3*100\n"}}),
+        );
+        let published = diagnostics();
+        assert_eq!(
+            published[0]["message"],
+            "Operand 300 does not fit in a byte"
+        );
+        assert_eq!(
+            published[0]["range"]["end"],
+            json!({"line": 0, "character": 10})
+        );
+
+        let edit = |line, start, end, text: &str| {
+            json!({
+                "textDocument": {"uri": uri, "version": 2},
+                "contentChanges": [{"range": {
+                    "start": {"line": line, "character": start},
+                    "end": {"line": line, "character": end},
+                }, "text": text}],
+            })
+        };
+        notify("textDocument/didChange", edit(0, 5, 10, "3"));
+        assert_eq!(diagnostics(), json!([]));
+        notify(
+            "textDocument/didChange",
+            edit(1, 0, 0, "loop: push 1\njnz $loop\n"),
+        );
+        assert_eq!(diagnostics(), json!([]));
+
+        let at = |line, character| json!({"textDocument": document, "position": {"line": line, "character": character}});
+        let definition = request("textDocument/definition", at(2, 6)).result.unwrap();
+        assert_eq!(
+            definition["range"],
+            json!({"start": {"line": 1, "character": 0}, "end": {"line": 1, "character": 4}})
+        );
+        let mut references = at(1, 2);
+        references["context"] = json!({"includeDeclaration": false});
+        let references = request("textDocument/references", references)
+            .result
+            .unwrap();
+        assert_eq!(references.as_array().unwrap().len(), 1);
+        let hover = request("textDocument/hover", at(0, 1)).result.unwrap();
+        assert_eq!(hover["contents"]["value"], "```\nPUSH A => ( -- A )\n```");
+
+        let mut rename = at(1, 2);
+        rename["newName"] = json!("again");
+        let renamed = request("textDocument/rename", rename.clone())
+            .result
+            .unwrap();
+        let edits = renamed["changes"][uri].as_array().unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(
+            edits[1]["range"]["start"],
+            json!({"line": 2, "character": 5})
+        );
+        rename["newName"] = json!("not a label");
+        assert!(request("textDocument/rename", rename).error.is_some());
+
+        assert!(request("shutdown", Value::Null).error.is_none());
+        notify("exit", Value::Null);
+        thread.join().unwrap();
+    }
+}
@@ -7,23 +7,82 @@
  * MUL => ( a b -- a * b )
  * DIV => ( a b -- a / b )
  * MOD => ( a b -- a % b )
+ * LT => ( a b -- a < b ) \\ 1 when a is less than b, 0 otherwise
  *
  * SWP => ( a b -- b a )
  * POP => ( a -- )
  * DUP => ( a -- a a )
+ * OVER => ( a b -- a b a )
+ * ROT => ( a b c -- b c a )
+ * NIP => ( a b -- b )
+ * TUCK => ( a b -- b a b )
+ * 2DUP => ( a b -- a b a b )
+ * 2SWAP => ( a b c d -- c d a b )
+ * DEPTH => ( -- n ) \\ number of values on the stack, locals included
+ * CLEAR => ( ... -- ) \\ drop everything above the locals of the innermost frame
  *
- * PRINT => ( a --> println! ) \\ println! top of stack
- * PCHAR => ( ... a -> println! ) \\ println! stack as UTF-8 until 0
- * RET => ( -- ) \\ return top of stack
+ * PRINT => ( a -- a ) \\ println! top of stack
+ * PCHAR => ( 0 c ... -- c ... ) \\ println! the values above the topmost 0 as UTF-8, dropping the 0
+ * RET => ( a -- a ) \\ return top of stack
+ * LEAVE => ( locals ... -- ... ) \\ drop the locals of the innermost frame, keeping what is above them
+ * ENDTRY => () \\ remove the handler of the innermost TRY
+ * THROW => ( ... code -- code ) \\ unwind to the innermost TRY and go to its handler
+ * ASSERT => ( a -- ) \\ fail unless a is not 0
+ * ASSERTEQ => ( a b -- ) \\ fail unless a equals b
+ * YIELD => () \\ let the next coroutine that can run go on
  *
  * EXIT => () \\ stop execution
  *
  * #2 byte Instructions.
  * PUSH A => ( -- A )
+ * LOAD S => ( -- x ) \\ copy of stack slot S, counted from the bottom
+ * STORE S => ( x -- ) \\ pop into stack slot S, counted from the bottom
+ * PICK N => ( xN ... x0 -- xN ... x0 xN ) \\ copy of the value N below the top, PICK 0 is DUP
+ * ROLL N => ( xN ... x0 -- ... x0 xN ) \\ move the value N below the top onto it, ROLL 1 is SWP
+ * ENTER N => ( -- 0 ... ) \\ open a frame of N locals, all 0
+ * LOADLOCAL I => ( -- x ) \\ copy of local I of the innermost frame
+ * STORELOCAL I => ( x -- ) \\ pop into local I of the innermost frame
  * JMP => () \\ go to address (%int) or label ($string)
- * JNZ => ( -- ) \\ go to address (%int) or label ($string) IF stack top is NOT == 0
+ * JNZ => ( a -- a ) \\ go to address (%int) or label ($string) IF stack top is NOT == 0
+ * TRY => () \\ until ENDTRY, send THROWs and faults to the handler at address (%int) or label ($string)
+ * SPAWN => () \\ queue a coroutine with an empty stack at address (%int) or label ($string)
+ * SEND C => ( x -- ) \\ pop onto channel C
+ * RECV C => ( -- x ) \\ oldest value on channel C, waiting for one while it is empty
+ *
+ * Operands are constant expressions evaluated by the assembler, with `+ - * / %`,
+ * prefix `-`, parentheses, numbers, characters such as 'a', label addresses
+ * ($string) and constants defined earlier with `.equ NAME expr`. They have to
+ * come out between 0 and 255. PUSH also takes a number literal of any length,
+ * such as `push -12345678901234567890`, built up from its bytes with PUSH, DUP,
+ * MUL and ADD. `.local NAME` names the next local of the frame opened by the
+ * last ENTER, counting from 0.
+ *
+ * A handler gets the stack and frames as they were at its TRY, with the error
+ * code on top. Faults have the codes of `StackError::code`.
+ *
+ * Coroutines take turns round-robin on YIELD, on a RECV that has to wait and
+ * when one other than the first finishes with RET, EXIT or the end of the code.
+ * The first one finishing ends the program. When every coroutine waits on a
+ * RECV, that is a deadlock.
  */
 
+pub mod c_backend;
+pub mod check;
+pub mod check_error;
 pub mod code_execution;
-pub mod labels;
+pub mod coverage;
+pub mod debugger;
+pub mod format;
+pub mod instruction;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+pub mod jit;
+pub mod link;
+pub mod lsp;
+pub mod object;
 pub mod parse;
+pub mod parse_error;
+pub mod preprocess;
+pub mod profiler;
+pub mod test_runner;
+pub mod threaded;
+pub mod wasm_backend;
@@ -0,0 +1,60 @@
+/*!Relocatable object code, assembled from a single .cor file
+ *
+ * .export name, other   \\ make labels of this module visible to other modules
+ * .import name          \\ use a label exported by another module as $name
+ *
+ * Labels are local to their module unless they are exported. Where a module
+ * ends up is only known once the linker has placed it, so the assembler leaves
+ * a 0 in every operand that depends on a label and records a relocation for it.
+ */
+
+use crate::code::{check::Signature, preprocess::Location};
+
+#[derive(Debug, Default)]
+pub struct Object {
+    pub code: Vec<u8>,
+    pub exports: Vec<Export>,
+    pub imports: Vec<Import>,
+    pub relocations: Vec<Relocation>,
+    ///Every label of the module, exported or not, with its offset in the code
+    pub labels: Vec<(String, usize)>,
+    ///Offset of every instruction in the code and the line and column it was assembled from
+    pub lines: Vec<(usize, Location)>,
+    ///Offset of every label with a `( in -- out )` annotation, and the annotation
+    pub signatures: Vec<(usize, Signature)>,
+}
+
+///A label other modules can import
+#[derive(Debug, Clone)]
+pub struct Export {
+    pub name: String,
+    ///Offset of the label in the code of its module
+    pub address: usize,
+    pub at: Location,
+}
+
+///A label this module expects another module to export
+#[derive(Debug, Clone)]
+pub struct Import {
+    pub name: String,
+    pub at: Location,
+}
+
+///What the address in a relocated operand is counted from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    ///The start of the module the operand is in
+    Module,
+    ///An imported label
+    Symbol(String),
+}
+
+///An operand byte to patch once the target address is known
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    ///Offset of the operand in the code of its module
+    pub offset: usize,
+    pub target: Target,
+    pub addend: i64,
+    pub at: Location,
+}
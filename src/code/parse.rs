@@ -1,88 +1,589 @@
-use std::{collections::HashMap, fs, io};
+use std::{collections::HashMap, path::Path, sync::LazyLock};
 
-use pest::Parser;
+use num::{
+    BigInt,
+    bigint::Sign,
+    traits::{CheckedAdd, CheckedMul, CheckedSub},
+};
+use pest::{
+    Parser,
+    iterators::Pair,
+    pratt_parser::{Assoc, Op, PrattParser},
+};
 use pest_derive::Parser;
 
-use crate::code::labels::process_labels;
+use crate::code::{
+    check::Signature,
+    link::{DebugInfo, debug_info, link},
+    object::{Export, Import, Object, Relocation, Target},
+    parse_error::ParseError,
+    preprocess::{Line, Location, preprocess, preprocess_source},
+};
 
 #[derive(Parser)]
 #[grammar = "./code/grammar.pest"]
 pub struct InputParser;
 
-pub fn parse_code(input_file: &str) -> Result<Vec<u8>, io::Error> {
-    let input = fs::read_to_string(input_file)?;
+///Multiplication binds tighter than addition, negation tightest
+static PRATT: LazyLock<PrattParser<Rule>> = LazyLock::new(|| {
+    PrattParser::new()
+        .op(Op::infix(Rule::plus, Assoc::Left) | Op::infix(Rule::minus, Assoc::Left))
+        .op(Op::infix(Rule::times, Assoc::Left)
+            | Op::infix(Rule::divided, Assoc::Left)
+            | Op::infix(Rule::remainder, Assoc::Left))
+        .op(Op::prefix(Rule::negate))
+});
+
+///Value of an operand expression, counted from `base` when it has one
+#[derive(Debug, Clone)]
+struct Value {
+    offset: i64,
+    base: Option<Target>,
+}
+
+impl Value {
+    fn absolute(offset: i64) -> Self {
+        Value { offset, base: None }
+    }
+}
+
+///Names an operand expression can refer to
+struct Symbols<'a> {
+    ///`.equ` constants defined so far
+    constants: HashMap<String, Value>,
+    ///`.local` names, by index in the frame they were declared for
+    locals: HashMap<String, i64>,
+    ///Index the next `.local` gets, restarting at 0 after every ENTER
+    next_local: i64,
+    ///Labels of this module, by offset in its code
+    labels: HashMap<String, usize>,
+    imports: HashMap<String, Location>,
+    lines: &'a [Line],
+}
+
+///Assemble a .cor file into a program. `defines` drive its `.if`, `.ifdef` and `.ifndef` directives.
+pub fn parse_code(input_file: &str, defines: &[(&str, i64)]) -> Result<Vec<u8>, ParseError> {
+    link_files(&[input_file], defines)
+}
+
+///Assemble .cor files and link them, the first one is the program
+pub fn link_files(input_files: &[&str], defines: &[(&str, i64)]) -> Result<Vec<u8>, ParseError> {
+    Ok(link_files_debug::<i64>(input_files, defines)?.0)
+}
+
+///Like `link_files`, also returning where the labels and instructions of the program came from.
+///PUSH literals must fit in the stack values `T` it is going to run on.
+pub fn link_files_debug<T>(
+    input_files: &[&str],
+    defines: &[(&str, i64)],
+) -> Result<(Vec<u8>, DebugInfo), ParseError>
+where
+    T: From<u8> + CheckedAdd + CheckedSub + CheckedMul,
+{
+    let objects = input_files
+        .iter()
+        .map(|input_file| assemble::<T>(input_file, defines))
+        .collect::<Result<Vec<Object>, ParseError>>()?;
+    Ok((link(&objects)?, debug_info(&objects)))
+}
+
+///Assemble a .cor file into relocatable object code for stack values of type `T`
+pub fn assemble<T>(input_file: &str, defines: &[(&str, i64)]) -> Result<Object, ParseError>
+where
+    T: From<u8> + CheckedAdd + CheckedSub + CheckedMul,
+{
+    assemble_lines::<T>(preprocess(Path::new(input_file), defines)?)
+}
+
+///Like `assemble`, with the text of the file given, such as an unsaved editor buffer
+pub fn assemble_source<T>(
+    input_file: &str,
+    source: &str,
+    defines: &[(&str, i64)],
+) -> Result<Object, ParseError>
+where
+    T: From<u8> + CheckedAdd + CheckedSub + CheckedMul,
+{
+    assemble_lines::<T>(preprocess_source(Path::new(input_file), source, defines)?)
+}
+
+fn assemble_lines<T>(lines: Vec<Line>) -> Result<Object, ParseError>
+where
+    T: From<u8> + CheckedAdd + CheckedSub + CheckedMul,
+{
+    let input: String = lines
+        .iter()
+        .map(|line| format!("{}\n", line.text))
+        .collect();
     let parsed = InputParser::parse(Rule::file, &input)
-        .expect("Could not parse file")
+        .map_err(|error| {
+            let position = match error.line_col {
+                pest::error::LineColLocation::Pos(position)
+                | pest::error::LineColLocation::Span(position, _) => position,
+            };
+            ParseError::Syntax {
+                message: error.variant.message().into_owned(),
+                at: location(&lines, position),
+            }
+        })?
         .next()
         .unwrap();
-    let mut code: Vec<u8> = Vec::new();
-
-    let mut labels: HashMap<String, usize> = HashMap::new();
-    process_labels(&mut labels, parsed.clone());
+    let mut object = Object::default();
+    let mut symbols = Symbols::new(parsed.clone(), &lines, &mut object)?;
+    let code = &mut object.code;
 
     for line in parsed.into_inner() {
-        match line.as_rule() {
+        let (offset, rule, at) = (code.len(), line.as_rule(), symbols.at(&line));
+        match rule {
+            Rule::equ => symbols.define(line)?,
+            Rule::local => symbols.declare_local(line)?,
+            Rule::export | Rule::import => (),
             Rule::nop => code.push(0x00),
             Rule::add => code.push(0x01),
             Rule::sub => code.push(0x02),
             Rule::mul => code.push(0x03),
             Rule::div => code.push(0x04),
             Rule::modulus => code.push(0x05),
+            Rule::lt => code.push(0x06),
             Rule::print => code.push(0x10),
             Rule::pchar => code.push(0x11),
             Rule::ret => code.push(0x12),
-            Rule::valu8 => {
-                code.push(0x20);
-                let value: u8 = line.into_inner().as_str().parse::<u8>().unwrap();
-                code.push(value)
-            }
+            Rule::valu8 => match wide_literal(&line) {
+                Some(value) if !fits::<T>(&value) => {
+                    return Err(ParseError::LiteralRange { value, at });
+                }
+                Some(value) => push_wide(&value, code),
+                None => {
+                    code.push(0x20);
+                    symbols.operand(line, code, &mut object.relocations)?
+                }
+            },
             Rule::swp => code.push(0x21),
             Rule::pop => code.push(0x22),
             Rule::dup => code.push(0x23),
+            Rule::over => code.push(0x60),
+            Rule::rot => code.push(0x61),
+            Rule::nip => code.push(0x62),
+            Rule::tuck => code.push(0x63),
+            Rule::pick => {
+                code.push(0x64);
+                symbols.operand(line, code, &mut object.relocations)?
+            }
+            Rule::roll => {
+                code.push(0x65);
+                symbols.operand(line, code, &mut object.relocations)?
+            }
+            Rule::depth => code.push(0x66),
+            Rule::clear => code.push(0x67),
+            Rule::twodup => code.push(0x68),
+            Rule::twoswap => code.push(0x69),
+            Rule::load => {
+                code.push(0x24);
+                symbols.operand(line, code, &mut object.relocations)?
+            }
+            Rule::store => {
+                code.push(0x25);
+                symbols.operand(line, code, &mut object.relocations)?
+            }
+            Rule::enter => {
+                code.push(0x26);
+                symbols.operand(line, code, &mut object.relocations)?;
+                symbols.next_local = 0;
+            }
+            Rule::loadlocal => {
+                code.push(0x27);
+                symbols.operand(line, code, &mut object.relocations)?
+            }
+            Rule::storelocal => {
+                code.push(0x28);
+                symbols.operand(line, code, &mut object.relocations)?
+            }
+            Rule::leave => code.push(0x29),
             Rule::jmp => {
-                let inner_rule = line.into_inner().next().unwrap();
                 code.push(0x30);
-
-                let value: u8 = match inner_rule.as_rule() {
-                    Rule::word => {
-                        if let Some(address) = labels.get(inner_rule.as_str()) {
-                            *address as u8
-                        } else {
-                            panic!()
-                        }
-                    }
-                    Rule::address => inner_rule.as_str().parse::<u8>().unwrap(),
-                    _ => unreachable!(),
-                };
-                code.push(value);
+                symbols.operand(line, code, &mut object.relocations)?;
             }
 
             Rule::jnz => {
-                let inner_rule = line.into_inner().next().unwrap();
                 code.push(0x31);
+                symbols.operand(line, code, &mut object.relocations)?;
+            }
 
-                let value: u8 = match inner_rule.as_rule() {
-                    Rule::word => {
-                        if let Some(address) = labels.get(inner_rule.as_str()) {
-                            *address as u8
-                        } else {
-                            panic!()
-                        }
-                    }
-                    Rule::address => inner_rule.as_str().parse::<u8>().unwrap(),
-                    _ => unreachable!(),
-                };
-                code.push(value);
+            Rule::begintry => {
+                code.push(0x32);
+                symbols.operand(line, code, &mut object.relocations)?;
+            }
+            Rule::endtry => code.push(0x33),
+            Rule::throw => code.push(0x34),
+            Rule::assert => code.push(0x40),
+            Rule::asserteq => code.push(0x41),
+
+            Rule::spawn => {
+                code.push(0x50);
+                symbols.operand(line, code, &mut object.relocations)?;
+            }
+            Rule::yielding => code.push(0x51),
+            Rule::send => {
+                code.push(0x52);
+                symbols.operand(line, code, &mut object.relocations)?
+            }
+            Rule::recv => {
+                code.push(0x53);
+                symbols.operand(line, code, &mut object.relocations)?
             }
 
-            Rule::label => (),
+            Rule::label | Rule::COMMENT => (),
+            Rule::signature => object.signatures.push((offset, signature(line))),
 
             Rule::EOI | Rule::exit => code.push(0xFF),
 
             _ => unreachable!(),
         }
+        if code.len() > offset && rule != Rule::EOI {
+            object.lines.push((offset, at));
+        }
+    }
+    Ok(object)
+}
+
+///Where a line and column of the preprocessed input came from. Lines keep their indentation
+///through the preprocessor, so the column is the same in the source.
+fn location(lines: &[Line], (line, column): (usize, usize)) -> Location {
+    match lines.get(line.saturating_sub(1)).or(lines.last()) {
+        Some(line) => Location {
+            column,
+            ..line.at.clone()
+        },
+        None => Location {
+            file: String::new(),
+            line,
+            column,
+        },
+    }
+}
+
+///Number of values on either side of a `( in -- out )` annotation
+fn signature(signature: Pair<Rule>) -> Signature {
+    let mut sides = signature.into_inner().map(|side| side.into_inner().count());
+    Signature {
+        inputs: sides.next().unwrap(),
+        outputs: sides.next().unwrap(),
+    }
+}
+
+///Operand of a PUSH that is a literal, possibly negated, outside of a byte
+fn wide_literal(push: &Pair<Rule>) -> Option<BigInt> {
+    let expr = push.clone().into_inner().next()?;
+    let inner: Vec<Pair<Rule>> = expr
+        .into_inner()
+        .filter(|pair| pair.as_rule() != Rule::COMMENT)
+        .collect();
+    let value = match inner.as_slice() {
+        [number] if number.as_rule() == Rule::number => number.as_str().parse::<BigInt>().ok()?,
+        [negate, number]
+            if negate.as_rule() == Rule::negate && number.as_rule() == Rule::number =>
+        {
+            -number.as_str().parse::<BigInt>().ok()?
+        }
+        _ => return None,
+    };
+    (value < BigInt::ZERO || value > BigInt::from(u8::MAX)).then_some(value)
+}
+
+///Build `value` up from its bytes, most significant first, multiplying by 16 * 16 before
+///adding each next one. A negative value subtracts its bytes from 0 instead, so that the
+///most negative value of a type is built without overflowing on the way.
+fn push_wide(value: &BigInt, code: &mut Vec<u8>) {
+    let (sign, bytes) = value.to_bytes_be();
+    let op = if sign == Sign::Minus { 0x02 } else { 0x01 };
+    match sign {
+        Sign::Minus => code.extend([0x20, 0, 0x20, bytes[0], 0x02]),
+        _ => code.extend([0x20, bytes[0]]),
+    }
+    for &byte in &bytes[1..] {
+        code.extend([0x20, 16, 0x23, 0x03, 0x03, 0x20, byte, op]);
+    }
+}
+
+///Whether the code `push_wide` gives runs on stack values of type `T` without overflowing
+fn fits<T>(value: &BigInt) -> bool
+where
+    T: From<u8> + CheckedAdd + CheckedSub + CheckedMul,
+{
+    let (sign, bytes) = value.to_bytes_be();
+    let Some(base) = T::from(16).checked_mul(&T::from(16)) else {
+        return false;
+    };
+    bytes
+        .iter()
+        .try_fold(T::from(0), |built, &byte| {
+            let built = built.checked_mul(&base)?;
+            match sign {
+                Sign::Minus => built.checked_sub(&T::from(byte)),
+                _ => built.checked_add(&T::from(byte)),
+            }
+        })
+        .is_some()
+}
+
+///Bytes of code a line of the parsed file assembles to
+fn size(line: &Pair<Rule>) -> usize {
+    match line.as_rule() {
+        Rule::valu8 => wide_literal(line).map_or(2, |value| {
+            let mut code = Vec::new();
+            push_wide(&value, &mut code);
+            code.len()
+        }),
+        Rule::load
+        | Rule::store
+        | Rule::enter
+        | Rule::loadlocal
+        | Rule::storelocal
+        | Rule::jmp
+        | Rule::jnz
+        | Rule::begintry
+        | Rule::spawn
+        | Rule::send
+        | Rule::recv
+        | Rule::pick
+        | Rule::roll => 2,
+        Rule::equ
+        | Rule::local
+        | Rule::export
+        | Rule::import
+        | Rule::label
+        | Rule::signature
+        | Rule::COMMENT
+        | Rule::EOI => 0,
+        _ => 1,
+    }
+}
+
+impl<'a> Symbols<'a> {
+    ///Lay out the labels and collect the imports and exports of `object`
+    fn new(file: Pair<Rule>, lines: &'a [Line], object: &mut Object) -> Result<Self, ParseError> {
+        let mut symbols = Symbols {
+            constants: HashMap::new(),
+            locals: HashMap::new(),
+            next_local: 0,
+            labels: HashMap::new(),
+            imports: HashMap::new(),
+            lines,
+        };
+        let mut exports: Vec<(String, Location)> = Vec::new();
+        let mut address = 0;
+        for pair in file.into_inner() {
+            let at = symbols.at(&pair);
+            match pair.as_rule() {
+                Rule::label => {
+                    let name = pair.into_inner().as_str();
+                    if symbols.labels.insert(name.into(), address).is_some() {
+                        return Err(ParseError::DuplicateSymbol {
+                            name: name.into(),
+                            at,
+                        });
+                    }
+                }
+                Rule::import => {
+                    for name in pair.into_inner() {
+                        symbols.imports.insert(name.as_str().into(), at.clone());
+                        object.imports.push(Import {
+                            name: name.as_str().into(),
+                            at: at.clone(),
+                        });
+                    }
+                }
+                Rule::export => exports.extend(
+                    pair.into_inner()
+                        .map(|name| (name.as_str().into(), at.clone())),
+                ),
+                _ => address += size(&pair),
+            }
+        }
+
+        if let Some(import) = object
+            .imports
+            .iter()
+            .find(|import| symbols.labels.contains_key(&import.name))
+        {
+            return Err(ParseError::DuplicateSymbol {
+                name: import.name.clone(),
+                at: import.at.clone(),
+            });
+        }
+        for (name, at) in exports {
+            let Some(&address) = symbols.labels.get(&name) else {
+                return Err(ParseError::UndefinedSymbol { name, at });
+            };
+            object.exports.push(Export { name, address, at });
+        }
+        object.labels = symbols
+            .labels
+            .iter()
+            .map(|(name, &address)| (name.clone(), address))
+            .collect();
+        object.labels.sort_by_key(|&(_, address)| address);
+        Ok(symbols)
+    }
+
+    fn at(&self, pair: &Pair<Rule>) -> Location {
+        location(self.lines, pair.line_col())
+    }
+
+    ///Evaluate a `.equ NAME expr` line
+    fn define(&mut self, equ: Pair<Rule>) -> Result<(), ParseError> {
+        let at = self.at(&equ);
+        let mut inner = equ.into_inner();
+        let name = inner.next().unwrap().as_str();
+        let value = self.evaluate(inner.next().unwrap())?;
+        if self.locals.contains_key(name)
+            || self.constants.insert(name.to_string(), value).is_some()
+        {
+            return Err(ParseError::DuplicateSymbol {
+                name: name.to_string(),
+                at,
+            });
+        }
+        Ok(())
+    }
+
+    ///Name the next local of the frame opened by the last ENTER.
+    ///A later `.local` may reuse the name for another frame.
+    fn declare_local(&mut self, local: Pair<Rule>) -> Result<(), ParseError> {
+        let at = self.at(&local);
+        let name = local.into_inner().as_str();
+        if self.constants.contains_key(name) {
+            return Err(ParseError::DuplicateSymbol {
+                name: name.to_string(),
+                at,
+            });
+        }
+        self.locals.insert(name.to_string(), self.next_local);
+        self.next_local += 1;
+        Ok(())
+    }
+
+    ///Append the operand of an instruction, which has to fit in a byte once it is linked
+    fn operand(
+        &self,
+        instruction: Pair<Rule>,
+        code: &mut Vec<u8>,
+        relocations: &mut Vec<Relocation>,
+    ) -> Result<(), ParseError> {
+        let at = self.at(&instruction);
+        let value = self.evaluate(instruction.into_inner().next().unwrap())?;
+        match value.base {
+            Some(target) => {
+                relocations.push(Relocation {
+                    offset: code.len(),
+                    target,
+                    addend: value.offset,
+                    at,
+                });
+                code.push(0);
+            }
+            None => {
+                code.push(
+                    u8::try_from(value.offset).map_err(|_| ParseError::OperandRange {
+                        value: value.offset,
+                        at,
+                    })?,
+                )
+            }
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self, pair: Pair<Rule>) -> Result<Value, ParseError> {
+        let at = self.at(&pair);
+        match pair.as_rule() {
+            Rule::number => pair
+                .as_str()
+                .parse::<i64>()
+                .map(Value::absolute)
+                .map_err(|_| ParseError::Overflow { at }),
+            Rule::character => Ok(Value::absolute(character(pair.as_str()) as i64)),
+            Rule::word => match (
+                self.labels.get(pair.as_str()),
+                self.imports.contains_key(pair.as_str()),
+            ) {
+                (Some(&address), _) => Ok(Value {
+                    offset: address as i64,
+                    base: Some(Target::Module),
+                }),
+                (None, true) => Ok(Value {
+                    offset: 0,
+                    base: Some(Target::Symbol(pair.as_str().to_string())),
+                }),
+                (None, false) => Err(ParseError::UndefinedSymbol {
+                    name: format!("${}", pair.as_str()),
+                    at,
+                }),
+            },
+            Rule::constant => match (
+                self.locals.get(pair.as_str()),
+                self.constants.get(pair.as_str()),
+            ) {
+                (Some(&local), _) => Ok(Value::absolute(local)),
+                (None, Some(value)) => Ok(value.clone()),
+                (None, None) => Err(ParseError::UndefinedSymbol {
+                    name: pair.as_str().to_string(),
+                    at,
+                }),
+            },
+            Rule::expr => PRATT
+                .map_primary(|primary| self.evaluate(primary))
+                .map_prefix(|_, operand| {
+                    let operand = operand?;
+                    if operand.base.is_some() {
+                        return Err(ParseError::NotRelocatable { at: at.clone() });
+                    }
+                    operand
+                        .offset
+                        .checked_neg()
+                        .map(Value::absolute)
+                        .ok_or(ParseError::Overflow { at: at.clone() })
+                })
+                .map_infix(|lhs, op, rhs| {
+                    let (lhs, rhs) = (lhs?, rhs?);
+                    // A label plus or minus a constant, or the distance between two labels
+                    let base = match (op.as_rule(), lhs.base, rhs.base) {
+                        (_, None, None) => None,
+                        (Rule::plus, base, None) | (Rule::plus, None, base) => base,
+                        (Rule::minus, base, None) => base,
+                        (Rule::minus, Some(lhs), Some(rhs)) if lhs == rhs => None,
+                        _ => return Err(ParseError::NotRelocatable { at: at.clone() }),
+                    };
+                    let (lhs, rhs) = (lhs.offset, rhs.offset);
+                    if matches!(op.as_rule(), Rule::divided | Rule::remainder) && rhs == 0 {
+                        return Err(ParseError::DivisionByZero { at: at.clone() });
+                    }
+                    match op.as_rule() {
+                        Rule::plus => lhs.checked_add(rhs),
+                        Rule::minus => lhs.checked_sub(rhs),
+                        Rule::times => lhs.checked_mul(rhs),
+                        Rule::divided => lhs.checked_div(rhs),
+                        _ => lhs.checked_rem(rhs),
+                    }
+                    .map(|offset| Value { offset, base })
+                    .ok_or(ParseError::Overflow { at: at.clone() })
+                })
+                .parse(pair.into_inner()),
+            _ => unreachable!(),
+        }
+    }
+}
+
+///Code point of a quoted character literal such as `'a'` or `'\n'`
+fn character(literal: &str) -> u32 {
+    let mut chars = literal[1..literal.len() - 1].chars();
+    match (chars.next(), chars.next()) {
+        (Some('\\'), Some('n')) => '\n' as u32,
+        (Some('\\'), Some('t')) => '\t' as u32,
+        (Some('\\'), Some('0')) => 0,
+        (Some('\\'), Some(escaped)) | (Some(escaped), _) => escaped as u32,
+        (None, _) => unreachable!(),
     }
-    Ok(code)
 }
 
 #[cfg(test)]
@@ -90,10 +591,228 @@ mod tests {
     use super::*;
     #[test]
     fn parse_testfile() {
-        std::fs::write("./testfiles/testfile.cor", "push 10\npush 20\nadd\nret\n").unwrap();
-
-        let retval = parse_code("./testfiles/testfile.cor").unwrap();
+        let retval = parse_code("./testfiles/testfile.cor", &[]).unwrap();
 
         assert_eq!(retval, [0x20, 0x0a, 0x20, 0x14, 0x01, 0x12, 0xFF])
     }
+    ///Assemble and link `source` in memory, as the single file test.cor
+    fn build<T>(source: &str) -> Result<Vec<u8>, ParseError>
+    where
+        T: From<u8> + CheckedAdd + CheckedSub + CheckedMul,
+    {
+        link(&[assemble_source::<T>("test.cor", source, &[])?])
+    }
+    #[test]
+    fn constants() {
+        let assemble = build::<i64>;
+
+        let source = ".equ WIDTH 4\n.equ HEIGHT WIDTH - 1\npush WIDTH*HEIGHT\nloop: push 'a'-1\njmp $loop+2\npush -(2 - 3) + 7 % 4\npush '\\n'\n";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0x20, 12, 0x20, 96, 0x30, 4, 0x20, 4, 0x20, 10, 0xFF]
+        );
+        assert!(matches!(
+            assemble("\npush 200+56\n"),
+            Err(ParseError::OperandRange {
+                value: 256,
+                at: Location { line: 2, .. }
+            })
+        ));
+        assert!(matches!(
+            assemble("push 1-2\n"),
+            Err(ParseError::OperandRange { value: -1, .. })
+        ));
+        assert!(matches!(
+            assemble("push 9223372036854775807 + 1\n"),
+            Err(ParseError::Overflow { .. })
+        ));
+        assert!(matches!(
+            assemble("load 99999999999999999999\n"),
+            Err(ParseError::Overflow { .. })
+        ));
+        assert!(matches!(
+            assemble("push 1 % (2 - 2)\n"),
+            Err(ParseError::DivisionByZero { .. })
+        ));
+        assert!(matches!(
+            assemble("push N\n.equ N 1\n"),
+            Err(ParseError::UndefinedSymbol { .. })
+        ));
+        assert!(matches!(
+            assemble(".equ N 1\n.equ N 2\n"),
+            Err(ParseError::DuplicateSymbol { .. })
+        ));
+        assert!(matches!(
+            assemble("jmp $nowhere\n"),
+            Err(ParseError::UndefinedSymbol { .. })
+        ));
+        assert!(matches!(
+            assemble("l: push $l * 2\n"),
+            Err(ParseError::NotRelocatable { .. })
+        ));
+        assert_eq!(
+            assemble("a: nop\nb: push $b - $a\n").unwrap(),
+            [0x00, 0x20, 0x01, 0xFF]
+        );
+    }
+    #[test]
+    fn locals() {
+        let source = "push 9\nenter 2\n.local count\n.local total\npush 4\nstorelocal total\nloadlocal total\nloadlocal count\nadd\nleave\nenter 1\n.local total\nloadlocal total\nret\n";
+        let code = build::<i64>(source).unwrap();
+        assert_eq!(
+            code,
+            [
+                0x20, 9, 0x26, 2, 0x20, 4, 0x28, 1, 0x27, 1, 0x27, 0, 0x01, 0x29, 0x26, 1, 0x27, 0,
+                0x12, 0xFF
+            ]
+        );
+
+        assert!(matches!(
+            build::<i64>(".equ count 1\n.local count\n"),
+            Err(ParseError::DuplicateSymbol { .. })
+        ));
+    }
+    #[test]
+    fn try_throw() {
+        let source = "try $caught\npush 1\npush 0\ndiv\nendtry\ncaught: push 4\nsub\njnz $other\npush 9\nthrow\nother: ret\n";
+        let code = build::<i64>(source).unwrap();
+        assert_eq!(
+            code,
+            [
+                0x32, 8, 0x20, 1, 0x20, 0, 0x04, 0x33, 0x20, 4, 0x02, 0x31, 16, 0x20, 9, 0x34,
+                0x12, 0xFF
+            ]
+        );
+        let mut stack = crate::stack::Stack::<i64>::new();
+        assert!(matches!(
+            stack.execute(&code),
+            Err(crate::stack::stack_error::StackError::Uncaught { ref code, .. }) if code == "9"
+        ));
+    }
+    #[test]
+    fn coroutines() {
+        let source = ".equ OUT 1\nspawn $worker\nrecv OUT\nret\nworker: push 7\nyield\nsend OUT\n";
+        let code = build::<i64>(source).unwrap();
+        assert_eq!(code, [0x50, 5, 0x53, 1, 0x12, 0x20, 7, 0x51, 0x52, 1, 0xFF]);
+        let mut stack = crate::stack::Stack::<i64>::new();
+        assert_eq!(stack.execute(&code).unwrap(), 7);
+    }
+    #[test]
+    fn stack_words() {
+        let source = ".equ N 2\npush 1\npush 2\npush 3\nOVER\nrot\nnip\ntuck\n2dup\n2SWAP\npick N\nroll N+1\ndepth\nclear\nend: jmp $end\n";
+        let code = build::<i64>(source).unwrap();
+        assert_eq!(
+            code,
+            [
+                0x20, 1, 0x20, 2, 0x20, 3, 0x60, 0x61, 0x62, 0x63, 0x68, 0x69, 0x64, 2, 0x65, 3,
+                0x66, 0x67, 0x30, 18, 0xFF
+            ]
+        );
+    }
+    #[test]
+    fn structured_blocks() {
+        // Print the odd numbers below 6 and the even ones as 0, then 3 stars
+        let source = "push 5\n.while\n  dup\n  push 2\n  mod\n  .ifnz\n    pop\n    print\n  .else\n    print\n    pop\n  .endif\n  push 1\n  sub\n.endwhile\n.loop 3\n  push 0\n  push '*'\n  pchar\n  pop\n.endloop\nret\n";
+        let code = build::<i64>(source).unwrap();
+        let mut stack = crate::stack::Stack::<i64>::new();
+        stack.captured = Some(String::new());
+        assert_eq!(stack.execute(&code).unwrap(), 0);
+        assert_eq!(stack.captured.unwrap(), "5\n0\n3\n0\n1\n*\n*\n*\n");
+        assert_eq!(stack.state, [0]);
+    }
+    #[test]
+    fn wide_literals() {
+        let code = build::<i64>("push 300\npush -1\nloop: jmp $loop\n").unwrap();
+        assert_eq!(
+            code,
+            [
+                0x20, 1, 0x20, 16, 0x23, 0x03, 0x03, 0x20, 44, 0x01, 0x20, 0, 0x20, 1, 0x02, 0x30,
+                15, 0xFF
+            ]
+        );
+
+        let source = "push 123456789012345678901234567890\nret\n";
+        assert!(matches!(
+            build::<i64>(source),
+            Err(ParseError::LiteralRange {
+                at: Location { line: 1, .. },
+                ..
+            })
+        ));
+        let code = build::<BigInt>(source).unwrap();
+        let mut stack = crate::stack::Stack::<BigInt>::new();
+        assert_eq!(
+            stack.execute(&code).unwrap().to_string(),
+            "123456789012345678901234567890"
+        );
+
+        let code =
+            build::<i64>("push 9223372036854775807\npush -9223372036854775808\nret\n").unwrap();
+        let mut stack = crate::stack::Stack::<i64>::new();
+        assert_eq!(stack.execute(&code).unwrap(), i64::MIN);
+        assert_eq!(stack.state, [i64::MAX, i64::MIN]);
+        assert!(matches!(
+            build::<i64>("push 9223372036854775808\n"),
+            Err(ParseError::LiteralRange { .. })
+        ));
+    }
+    #[test]
+    fn modules() {
+        let files = ["main.cor", "lib.cor"];
+        let objects = [
+            ".import double\npush 3\njmp $double\n.export back\nback: ret\n",
+            ".export double\nloop: nop\ndouble: dup\nadd\n.import back\njmp $back\n",
+        ]
+        .iter()
+        .zip(files)
+        .map(|(source, file)| assemble_source::<i64>(file, source, &[]).unwrap())
+        .collect::<Vec<Object>>();
+
+        assert_eq!(objects[1].exports[0].address, 1);
+        assert_eq!(
+            objects[1].relocations[0].target,
+            Target::Symbol("back".into())
+        );
+        let code = link(&objects).unwrap();
+        assert_eq!(
+            code,
+            [
+                0x20, 3, 0x30, 7, 0x12, 0xFF, 0x00, 0x23, 0x01, 0x30, 4, 0xFF
+            ]
+        );
+        let mut stack = crate::stack::Stack::<i64>::new();
+        assert_eq!(stack.execute(&code).unwrap(), 6);
+
+        let info = debug_info(&objects);
+        let labels: Vec<(usize, &str)> = info
+            .labels
+            .iter()
+            .map(|(address, name)| (*address, name.as_str()))
+            .collect();
+        assert_eq!(labels, [(4, "back"), (6, "loop"), (7, "double")]);
+        let lines: Vec<(usize, usize)> = info
+            .lines
+            .iter()
+            .map(|(address, at)| (*address, at.line))
+            .collect();
+        assert_eq!(
+            lines,
+            [(0, 2), (2, 3), (4, 5), (6, 2), (7, 3), (8, 4), (9, 6)]
+        );
+        assert_eq!(info.line(7).unwrap().file, files[1]);
+        assert_eq!(info.line(5), None);
+
+        assert!(matches!(
+            link(&objects[..1]),
+            Err(ParseError::UndefinedSymbol { name, .. }) if name == "double"
+        ));
+        assert!(matches!(
+            build::<i64>(".export nothing\n"),
+            Err(ParseError::UndefinedSymbol { name, .. }) if name == "nothing"
+        ));
+        assert!(matches!(
+            build::<i64>(".import x\nx: nop\n"),
+            Err(ParseError::DuplicateSymbol { .. })
+        ));
+    }
 }
@@ -0,0 +1,99 @@
+use std::io;
+
+use num::BigInt;
+use thiserror::Error;
+
+use crate::code::preprocess::Location;
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("Could not read {path}: {source}")]
+    Io { path: String, source: io::Error },
+    #[error("{at}: {message}")]
+    Syntax { message: String, at: Location },
+    #[error("{at}: Unknown directive `{directive}`")]
+    UnknownDirective { directive: String, at: Location },
+    #[error("{at}: Malformed `{directive}`")]
+    Malformed {
+        directive: &'static str,
+        at: Location,
+    },
+    #[error(
+        "{at}: `.if` needs a name or number, a block that tests the top of the stack at run time is `.ifnz`"
+    )]
+    IfWithoutOperand { at: Location },
+    #[error("{at}: `{directive}` is never closed")]
+    Unterminated {
+        directive: &'static str,
+        at: Location,
+    },
+    #[error("{at}: `{directive}` without a matching opening directive")]
+    Unmatched {
+        directive: &'static str,
+        at: Location,
+    },
+    #[error("{at}: Include cycle {chain}")]
+    IncludeCycle { chain: String, at: Location },
+    #[error("{at}: Macro `{name}` is already defined or names an instruction")]
+    InvalidMacro { name: String, at: Location },
+    #[error("{at}: Macro `{name}` takes {expected} arguments but {found} were given")]
+    MacroArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+        at: Location,
+    },
+    #[error("{at}: Macro `{name}` is nested too deeply")]
+    MacroDepth { name: String, at: Location },
+    #[error("{at}: Undefined symbol `{name}`")]
+    UndefinedSymbol { name: String, at: Location },
+    #[error("{at}: Symbol `{name}` is already defined")]
+    DuplicateSymbol { name: String, at: Location },
+    #[error("{at}: Symbol `{name}` is already exported at {first}")]
+    DuplicateExport {
+        name: String,
+        first: Location,
+        at: Location,
+    },
+    #[error("{at}: Labels can only be offset by a constant or subtracted from each other")]
+    NotRelocatable { at: Location },
+    #[error("{at}: Constant expression overflows")]
+    Overflow { at: Location },
+    #[error("{at}: Division by zero in constant expression")]
+    DivisionByZero { at: Location },
+    #[error("{at}: Operand {value} does not fit in a byte")]
+    OperandRange { value: i64, at: Location },
+    #[error("{at}: Literal {value} does not fit in a stack value, it needs --bignum")]
+    LiteralRange { value: BigInt, at: Location },
+}
+
+impl ParseError {
+    pub fn unmatched(directive: &'static str, at: Location) -> Self {
+        ParseError::Unmatched { directive, at }
+    }
+
+    ///Where in the source the error is, `None` for files that could not be read
+    pub fn at(&self) -> Option<&Location> {
+        match self {
+            ParseError::Io { .. } => None,
+            ParseError::Syntax { at, .. }
+            | ParseError::UnknownDirective { at, .. }
+            | ParseError::Malformed { at, .. }
+            | ParseError::IfWithoutOperand { at }
+            | ParseError::Unterminated { at, .. }
+            | ParseError::Unmatched { at, .. }
+            | ParseError::IncludeCycle { at, .. }
+            | ParseError::InvalidMacro { at, .. }
+            | ParseError::MacroArguments { at, .. }
+            | ParseError::MacroDepth { at, .. }
+            | ParseError::UndefinedSymbol { at, .. }
+            | ParseError::DuplicateSymbol { at, .. }
+            | ParseError::DuplicateExport { at, .. }
+            | ParseError::NotRelocatable { at }
+            | ParseError::Overflow { at }
+            | ParseError::DivisionByZero { at }
+            | ParseError::OperandRange { at, .. }
+            | ParseError::LiteralRange { at, .. } => Some(at),
+        }
+    }
+}
@@ -0,0 +1,850 @@
+/*!Assembler preprocessor, run on the lines of a .cor file before they are parsed
+ *
+ * .include "file.cor"   \\ insert another file, relative to this one
+ * .macro name a b       \\ define a macro with parameters a and b, used as \a and \b
+ * .endm                 \\ end of the macro body
+ * name 1, 2             \\ expand a macro
+ *
+ * .ifdef NAME / .ifndef NAME / .if NAME or number
+ * .else
+ * .endif                \\ keep lines depending on the defines passed to parse_code
+ *
+ * .ifnz
+ * .else
+ * .endif                \\ run the first part when the top of the stack is not 0, else the second
+ * .while / .endwhile    \\ run the body for as long as the top of the stack is not 0
+ * .loop n / .endloop    \\ run the body n times, with the count of runs left on top
+ *
+ * ; comment             \\ from a `;` outside quotes to the end of the line
+ *
+ * Labels defined in a macro body are renamed to `label__n` in its n-th
+ * expansion, so every expansion gets labels of its own.
+ *
+ * `.ifnz`, `.while` and `.loop` are expanded into JMP and JNZ to labels named
+ * after the n-th such block, such as `__while3_body`. The runtime `.if` is
+ * spelled `.ifnz`, so a `.if` whose operand is missing, as when a macro argument
+ * is left blank, is an error pointing at `.ifnz` rather than a test at run time.
+ * Like JNZ,
+ * they test the top of the stack without popping it. The count of a `.loop`
+ * sits on top of the stack while the body runs, which has to leave it there,
+ * and is popped once it reaches 0.
+ */
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::code::parse_error::ParseError;
+
+///Macros may expand other macros down to this depth
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+pub(crate) const MNEMONICS: [&str; 42] = [
+    "nop",
+    "add",
+    "sub",
+    "mul",
+    "div",
+    "mod",
+    "lt",
+    "print",
+    "pchar",
+    "ret",
+    "push",
+    "swp",
+    "pop",
+    "dup",
+    "over",
+    "rot",
+    "nip",
+    "tuck",
+    "pick",
+    "roll",
+    "depth",
+    "clear",
+    "2dup",
+    "2swap",
+    "load",
+    "store",
+    "enter",
+    "loadlocal",
+    "storelocal",
+    "leave",
+    "exit",
+    "jmp",
+    "jnz",
+    "try",
+    "endtry",
+    "throw",
+    "assert",
+    "asserteq",
+    "spawn",
+    "yield",
+    "send",
+    "recv",
+];
+
+///File and line a line of assembly comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    ///Column of the instruction or word in the line, counted in characters from 1. Whole lines
+    ///are at column 1.
+    pub column: usize,
+}
+
+impl Location {
+    ///`file:line:column`, where messages only give `file:line`
+    pub fn with_column(&self) -> String {
+        format!("{self}:{}", self.column)
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub text: String,
+    pub at: Location,
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Line>,
+}
+
+///An open .if, .ifdef or .ifndef
+struct Condition {
+    ///Whether the lines around it are kept
+    enabled: bool,
+    holds: bool,
+    in_else: bool,
+    at: Location,
+}
+
+impl Condition {
+    fn active(&self) -> bool {
+        self.enabled && self.holds != self.in_else
+    }
+}
+
+///An open block directive
+enum Block {
+    Condition(Condition),
+    ///A `.ifnz`, `.while` or `.loop`, whose labels are numbered `n`
+    Runtime {
+        directive: &'static str,
+        n: usize,
+        in_else: bool,
+        at: Location,
+    },
+}
+
+impl Block {
+    fn active(&self) -> bool {
+        match self {
+            Block::Condition(condition) => condition.active(),
+            Block::Runtime { .. } => true,
+        }
+    }
+
+    fn directive(&self) -> &'static str {
+        match self {
+            Block::Condition(_) => ".if",
+            Block::Runtime { directive, .. } => directive,
+        }
+    }
+
+    fn at(&self) -> &Location {
+        match self {
+            Block::Condition(condition) => &condition.at,
+            Block::Runtime { at, .. } => at,
+        }
+    }
+}
+
+struct Preprocessor {
+    defines: HashMap<String, i64>,
+    macros: HashMap<String, Macro>,
+    ///Files being included, outermost first
+    includes: Vec<PathBuf>,
+    expansions: usize,
+    ///Runtime blocks numbered so far
+    blocks: usize,
+    out: Vec<Line>,
+}
+
+///Expand includes, macros and conditionals of a file into plain assembly lines
+pub fn preprocess(input_file: &Path, defines: &[(&str, i64)]) -> Result<Vec<Line>, ParseError> {
+    let mut preprocessor = Preprocessor::new(defines);
+    preprocessor.file(input_file, None)?;
+    Ok(preprocessor.out)
+}
+
+///Like `preprocess`, with the text of the file given, such as an unsaved editor buffer
+pub fn preprocess_source(
+    input_file: &Path,
+    source: &str,
+    defines: &[(&str, i64)],
+) -> Result<Vec<Line>, ParseError> {
+    let mut preprocessor = Preprocessor::new(defines);
+    preprocessor.source(input_file, source, None)?;
+    Ok(preprocessor.out)
+}
+
+///Whether `text` is a label or macro name
+pub(crate) fn is_word(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+///Split a line at the `;` starting its comment, skipping those in character literals and strings
+pub(crate) fn split_comment(text: &str) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => (),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ';') => return (&text[..index], Some(&text[index..])),
+            (None, _) => (),
+        }
+    }
+    (text, None)
+}
+
+///Split off the first whitespace separated word
+pub(crate) fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    match text.split_once(char::is_whitespace) {
+        Some((head, rest)) => (head, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+///Split a leading `label:` off a line
+pub(crate) fn split_label(text: &str) -> (Option<&str>, &str) {
+    match text.split_once(':') {
+        Some((label, rest)) if is_word(label.trim()) => (Some(label.trim()), rest.trim()),
+        _ => (None, text.trim()),
+    }
+}
+
+///Replace whole-word occurrences of `from`, which starts with `\` or `$`, by `to`
+fn replace_word(text: &str, from: &str, to: &str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(found) = rest.find(from) {
+        let after = &rest[found + from.len()..];
+        replaced.push_str(&rest[..found]);
+        if after.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') {
+            replaced.push_str(from);
+        } else {
+            replaced.push_str(to);
+        }
+        rest = after;
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+impl Preprocessor {
+    fn new(defines: &[(&str, i64)]) -> Self {
+        Preprocessor {
+            defines: defines
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value))
+                .collect(),
+            macros: HashMap::new(),
+            includes: Vec::new(),
+            expansions: 0,
+            blocks: 0,
+            out: Vec::new(),
+        }
+    }
+
+    fn file(&mut self, path: &Path, included_at: Option<&Location>) -> Result<(), ParseError> {
+        let source = fs::read_to_string(path).map_err(|source| ParseError::Io {
+            path: path.display().to_string(),
+            source,
+        })?;
+        self.source(path, &source, included_at)
+    }
+
+    fn source(
+        &mut self,
+        path: &Path,
+        source: &str,
+        included_at: Option<&Location>,
+    ) -> Result<(), ParseError> {
+        let name = path.display().to_string();
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        if let Some(start) = self.includes.iter().position(|file| file == &canonical) {
+            let chain: Vec<String> = self.includes[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|file| file.display().to_string())
+                .collect();
+            return Err(ParseError::IncludeCycle {
+                at: included_at.cloned().unwrap(),
+                chain: chain.join(" -> "),
+            });
+        }
+
+        let lines = source
+            .lines()
+            .enumerate()
+            .map(|(index, text)| Line {
+                text: split_comment(text).0.trim_end().to_string(),
+                at: Location {
+                    file: name.clone(),
+                    line: index + 1,
+                    column: 1,
+                },
+            })
+            .collect();
+
+        self.includes.push(canonical);
+        self.lines(lines, 0)?;
+        self.includes.pop();
+        Ok(())
+    }
+
+    fn lines(&mut self, lines: Vec<Line>, depth: usize) -> Result<(), ParseError> {
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut lines = lines.into_iter();
+
+        while let Some(line) = lines.next() {
+            let (head, operand) = split_word(&line.text);
+            let active = blocks.iter().all(Block::active);
+
+            match head {
+                ".ifnz" | ".while" | ".loop" if operand.is_empty() == (head != ".loop") => {
+                    let directive = match head {
+                        ".ifnz" => ".ifnz",
+                        ".while" => ".while",
+                        _ => ".loop",
+                    };
+                    let n = self.blocks;
+                    self.blocks += 1;
+                    if active {
+                        let opening = match directive {
+                            ".ifnz" => vec![
+                                format!("jnz $__if{n}_then"),
+                                format!("jmp $__if{n}_else"),
+                                format!("__if{n}_then:"),
+                            ],
+                            ".while" => {
+                                vec![format!("jmp $__while{n}_test"), format!("__while{n}_body:")]
+                            }
+                            _ => vec![
+                                format!("push {operand}"),
+                                format!("jmp $__loop{n}_test"),
+                                format!("__loop{n}_body:"),
+                            ],
+                        };
+                        self.generated(opening, &line);
+                    }
+                    blocks.push(Block::Runtime {
+                        directive,
+                        n,
+                        in_else: false,
+                        at: line.at,
+                    });
+                }
+                ".ifnz" | ".while" | ".loop" => {
+                    return Err(ParseError::Malformed {
+                        directive: match head {
+                            ".ifnz" => ".ifnz",
+                            ".while" => ".while",
+                            _ => ".loop",
+                        },
+                        at: line.at,
+                    });
+                }
+                ".if" | ".ifdef" | ".ifndef" => {
+                    let holds = active && self.condition(head, operand, &line.at)?;
+                    blocks.push(Block::Condition(Condition {
+                        enabled: active,
+                        holds,
+                        in_else: false,
+                        at: line.at,
+                    }));
+                }
+                ".else" => match blocks.last_mut() {
+                    Some(Block::Condition(condition)) if !condition.in_else => {
+                        condition.in_else = true
+                    }
+                    Some(Block::Runtime {
+                        directive: ".ifnz",
+                        n,
+                        in_else,
+                        ..
+                    }) if !*in_else => {
+                        *in_else = true;
+                        let n = *n;
+                        if active {
+                            let lines = vec![format!("jmp $__if{n}_end"), format!("__if{n}_else:")];
+                            self.generated(lines, &line);
+                        }
+                    }
+                    _ => return Err(ParseError::unmatched(".else", line.at)),
+                },
+                ".endif" | ".endwhile" | ".endloop" => {
+                    let (directive, opening) = match head {
+                        ".endif" => (".endif", ".ifnz"),
+                        ".endwhile" => (".endwhile", ".while"),
+                        _ => (".endloop", ".loop"),
+                    };
+                    let closing = match blocks.pop() {
+                        Some(Block::Condition(_)) if directive == ".endif" => Vec::new(),
+                        Some(Block::Runtime {
+                            directive: open,
+                            n,
+                            in_else,
+                            ..
+                        }) if open == opening => match open {
+                            ".ifnz" if in_else => vec![format!("__if{n}_end:")],
+                            ".ifnz" => vec![format!("__if{n}_else:")],
+                            ".while" => vec![format!("__while{n}_test: jnz $__while{n}_body")],
+                            _ => vec![
+                                String::from("push 1"),
+                                String::from("sub"),
+                                format!("__loop{n}_test: jnz $__loop{n}_body"),
+                                String::from("pop"),
+                            ],
+                        },
+                        _ => return Err(ParseError::unmatched(directive, line.at)),
+                    };
+                    // The block is popped, so whether it is assembled depends on the ones around it
+                    if blocks.iter().all(Block::active) {
+                        self.generated(closing, &line);
+                    }
+                }
+                _ if !active => {
+                    // Skipped macro bodies still have to be skipped as a whole
+                    if head == ".macro" {
+                        self.macro_body(&mut lines, &line.at)?;
+                    }
+                }
+                ".macro" => {
+                    let body = self.macro_body(&mut lines, &line.at)?;
+                    self.define_macro(operand, body, &line.at)?;
+                }
+                ".endm" => return Err(ParseError::unmatched(".endm", line.at)),
+                // Constants and symbols are handled by the assembler, once labels are known
+                ".equ" | ".local" | ".export" | ".import" => self.out.push(line),
+                ".include" => {
+                    let Some(included) = operand
+                        .strip_prefix('"')
+                        .and_then(|path| path.strip_suffix('"'))
+                    else {
+                        return Err(ParseError::Malformed {
+                            directive: ".include",
+                            at: line.at,
+                        });
+                    };
+                    let path = Path::new(&line.at.file)
+                        .parent()
+                        .unwrap_or(Path::new(""))
+                        .join(included);
+                    self.file(&path, Some(&line.at))?;
+                }
+                _ if head.starts_with('.') => {
+                    return Err(ParseError::UnknownDirective {
+                        directive: head.to_string(),
+                        at: line.at,
+                    });
+                }
+                _ => self.instruction(line, depth)?,
+            }
+        }
+
+        match blocks.pop() {
+            Some(block) => Err(ParseError::Unterminated {
+                directive: block.directive(),
+                at: block.at().clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    ///Add the lines a runtime block directive expands into, indented like it so their columns
+    ///point at the directive
+    fn generated(&mut self, lines: Vec<String>, directive: &Line) {
+        let indent = &directive.text[..directive.text.len() - directive.text.trim_start().len()];
+        self.out.extend(lines.into_iter().map(|text| Line {
+            text: format!("{indent}{text}"),
+            at: directive.at.clone(),
+        }));
+    }
+
+    fn condition(&self, directive: &str, operand: &str, at: &Location) -> Result<bool, ParseError> {
+        if directive == ".if" && operand.is_empty() {
+            return Err(ParseError::IfWithoutOperand { at: at.clone() });
+        }
+        if !is_word(operand) {
+            return Err(ParseError::Malformed {
+                directive: match directive {
+                    ".ifdef" => ".ifdef",
+                    ".ifndef" => ".ifndef",
+                    _ => ".if",
+                },
+                at: at.clone(),
+            });
+        }
+        Ok(match directive {
+            ".ifdef" => self.defines.contains_key(operand),
+            ".ifndef" => !self.defines.contains_key(operand),
+            _ => match (operand.parse::<i64>(), self.defines.get(operand)) {
+                (Ok(value), _) | (_, Some(&value)) => value != 0,
+                _ => {
+                    return Err(ParseError::UndefinedSymbol {
+                        name: operand.to_string(),
+                        at: at.clone(),
+                    });
+                }
+            },
+        })
+    }
+
+    ///Collect the lines up to the `.endm` closing the `.macro` at `at`
+    fn macro_body(
+        &self,
+        lines: &mut impl Iterator<Item = Line>,
+        at: &Location,
+    ) -> Result<Vec<Line>, ParseError> {
+        let mut body = Vec::new();
+        for line in lines.by_ref() {
+            match split_word(&line.text).0 {
+                ".endm" => return Ok(body),
+                ".macro" => {
+                    return Err(ParseError::Malformed {
+                        directive: ".macro",
+                        at: line.at,
+                    });
+                }
+                _ => body.push(line),
+            }
+        }
+        Err(ParseError::Unterminated {
+            directive: ".macro",
+            at: at.clone(),
+        })
+    }
+
+    fn define_macro(
+        &mut self,
+        operand: &str,
+        body: Vec<Line>,
+        at: &Location,
+    ) -> Result<(), ParseError> {
+        let mut words = operand.split(|c: char| c.is_whitespace() || c == ',');
+        let name = words.next().unwrap_or_default();
+        let parameters: Vec<String> = words
+            .filter(|word| !word.is_empty())
+            .map(String::from)
+            .collect();
+
+        if !is_word(name) || !parameters.iter().all(|parameter| is_word(parameter)) {
+            return Err(ParseError::Malformed {
+                directive: ".macro",
+                at: at.clone(),
+            });
+        }
+        if MNEMONICS.contains(&name.to_lowercase().as_str()) || self.macros.contains_key(name) {
+            return Err(ParseError::InvalidMacro {
+                name: name.to_string(),
+                at: at.clone(),
+            });
+        }
+        self.macros
+            .insert(name.to_string(), Macro { parameters, body });
+        Ok(())
+    }
+
+    ///Pass an instruction line through, expanding it when it names a macro
+    fn instruction(&mut self, line: Line, depth: usize) -> Result<(), ParseError> {
+        let (label, rest) = split_label(&line.text);
+        let (name, arguments) = split_word(rest);
+        let Some(called) = self.macros.get(name) else {
+            self.out.push(line);
+            return Ok(());
+        };
+
+        let arguments: Vec<&str> = if arguments.contains(',') {
+            arguments.split(',').map(str::trim).collect()
+        } else {
+            arguments.split_whitespace().collect()
+        };
+        if arguments.len() != called.parameters.len() {
+            return Err(ParseError::MacroArguments {
+                name: name.to_string(),
+                expected: called.parameters.len(),
+                found: arguments.len(),
+                at: line.at,
+            });
+        }
+        if depth == MAX_EXPANSION_DEPTH {
+            return Err(ParseError::MacroDepth {
+                name: name.to_string(),
+                at: line.at,
+            });
+        }
+
+        let expansion = self.expansions;
+        self.expansions += 1;
+        let locals: Vec<&str> = called
+            .body
+            .iter()
+            .filter_map(|body_line| split_label(&body_line.text).0)
+            .collect();
+
+        let mut parameters: Vec<(&String, &str)> =
+            called.parameters.iter().zip(arguments).collect();
+        // Longer names first, so \ab is not replaced as \a followed by b
+        parameters.sort_by_key(|(parameter, _)| std::cmp::Reverse(parameter.len()));
+
+        let expanded: Vec<Line> = called
+            .body
+            .iter()
+            .map(|body_line| {
+                let mut text = body_line.text.clone();
+                for (parameter, argument) in &parameters {
+                    text = replace_word(&text, &format!("\\{parameter}"), argument);
+                }
+                for local in &locals {
+                    let renamed = format!("{local}__{expansion}");
+                    text = replace_word(&text, &format!("${local}"), &format!("${renamed}"));
+                    if let (Some(defined), rest) = split_label(&text)
+                        && defined == *local
+                    {
+                        text = format!("{renamed}: {rest}");
+                    }
+                }
+                Line {
+                    text,
+                    at: body_line.at.clone(),
+                }
+            })
+            .collect();
+
+        if let Some(label) = label {
+            self.out.push(Line {
+                text: format!("{label}:"),
+                at: line.at.clone(),
+            });
+        }
+        self.lines(expanded, depth + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Preprocess `source` in memory, as the file main.cor
+    fn lines(source: &str, defines: &[(&str, i64)]) -> Result<Vec<String>, ParseError> {
+        Ok(preprocess_source(Path::new("main.cor"), source, defines)?
+            .into_iter()
+            .map(|line| line.text.trim().to_string())
+            .collect())
+    }
+
+    #[test]
+    fn macros() {
+        let source = ".macro countdown n\n    push \\n\nloop: push 1\n    sub\n    jnz $loop\n.endm\nstart: countdown 3\ncountdown 5\nret\n";
+        assert_eq!(
+            lines(source, &[]).unwrap(),
+            [
+                "start:",
+                "push 3",
+                "loop__0: push 1",
+                "sub",
+                "jnz $loop__0",
+                "push 5",
+                "loop__1: push 1",
+                "sub",
+                "jnz $loop__1",
+                "ret"
+            ]
+        );
+    }
+    #[test]
+    fn comments() {
+        let source = "; countdown\npush ';' ; a semicolon\n.include \"a;b.cor\" ; quoted\n";
+        assert!(matches!(
+            lines(source, &[]),
+            Err(ParseError::Io { path, .. }) if path.ends_with("a;b.cor")
+        ));
+        assert_eq!(
+            lines(
+                ".ifdef A ; only with A\npush '\\'' ;;\n.endif\n",
+                &[("A", 1)]
+            )
+            .unwrap(),
+            ["push '\\''"]
+        );
+        assert_eq!(split_comment("push 1;;x"), ("push 1", Some(";;x")));
+    }
+    #[test]
+    fn conditionals() {
+        let source = ".ifdef DEBUG\npush 1\n.if LEVEL\npush 2\n.else\npush 3\n.endif\n.else\npush 4\n.endif\n.ifndef DEBUG\n.macro skipped\n.endm\n.endif\n";
+        assert_eq!(lines(source, &[]).unwrap(), ["push 4"]);
+        assert_eq!(
+            lines(source, &[("DEBUG", 1), ("LEVEL", 0)]).unwrap(),
+            ["push 1", "push 3"]
+        );
+        assert!(matches!(
+            lines(".if LEVEL\n.endif\n", &[]),
+            Err(ParseError::UndefinedSymbol { .. })
+        ));
+        assert!(matches!(
+            lines("push 1\n.ifdef A\n", &[]),
+            Err(ParseError::Unterminated {
+                directive: ".if",
+                at: Location { line: 2, .. }
+            })
+        ));
+        assert!(matches!(
+            lines(".endif\n", &[]),
+            Err(ParseError::Unmatched { .. })
+        ));
+    }
+    #[test]
+    fn runtime_blocks() {
+        let source = ".while\n.ifnz\npush 1\n.else\n.loop 2\n.endloop\n.endif\n.endwhile\n";
+        assert_eq!(
+            lines(source, &[]).unwrap(),
+            [
+                "jmp $__while0_test",
+                "__while0_body:",
+                "jnz $__if1_then",
+                "jmp $__if1_else",
+                "__if1_then:",
+                "push 1",
+                "jmp $__if1_end",
+                "__if1_else:",
+                "push 2",
+                "jmp $__loop2_test",
+                "__loop2_body:",
+                "push 1",
+                "sub",
+                "__loop2_test: jnz $__loop2_body",
+                "pop",
+                "__if1_end:",
+                "__while0_test: jnz $__while0_body",
+            ]
+        );
+        // Blocks in dropped lines still pair up, but expand to nothing
+        let source = ".ifdef A\n.ifnz\n.else\n.endif\n.endif\n.ifnz\n.endif\n";
+        assert_eq!(
+            lines(source, &[]).unwrap(),
+            [
+                "jnz $__if1_then",
+                "jmp $__if1_else",
+                "__if1_then:",
+                "__if1_else:"
+            ]
+        );
+
+        assert!(matches!(
+            lines(".while\n.ifdef A\n.endwhile\n.endif\n", &[]),
+            Err(ParseError::Unmatched {
+                directive: ".endwhile",
+                at: Location { line: 3, .. }
+            })
+        ));
+        assert!(matches!(
+            lines(".loop 3\n.else\n.endloop\n", &[]),
+            Err(ParseError::Unmatched {
+                directive: ".else",
+                ..
+            })
+        ));
+        assert!(matches!(
+            lines("push 1\n.ifnz\n.while\n.endwhile\n", &[]),
+            Err(ParseError::Unterminated {
+                directive: ".ifnz",
+                at: Location { line: 2, .. }
+            })
+        ));
+        assert!(matches!(
+            lines(".loop\n.endloop\n", &[]),
+            Err(ParseError::Malformed {
+                directive: ".loop",
+                ..
+            })
+        ));
+        // A `.if` that lost its operand is not taken for a runtime block
+        let error = lines(".if\n.endif\n", &[]).unwrap_err();
+        assert!(matches!(error, ParseError::IfWithoutOperand { .. }));
+        assert!(error.to_string().ends_with("at run time is `.ifnz`"));
+        let source = ".macro m a b\n.if \\b\npush \\a\n.endif\n.endm\nm 1,\n";
+        assert!(matches!(
+            lines(source, &[]),
+            Err(ParseError::IfWithoutOperand {
+                at: Location { line: 2, .. }
+            })
+        ));
+        assert!(matches!(
+            lines(".ifnz 1\n.endif\n", &[]),
+            Err(ParseError::Malformed {
+                directive: ".ifnz",
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn macro_errors() {
+        assert!(matches!(
+            lines(".macro m a\n.endm\nm\n", &[]),
+            Err(ParseError::MacroArguments {
+                expected: 1,
+                found: 0,
+                ..
+            })
+        ));
+        assert!(matches!(
+            lines(".macro m\nm\n.endm\nm\n", &[]),
+            Err(ParseError::MacroDepth { .. })
+        ));
+        assert!(matches!(
+            lines(".macro push\n.endm\n", &[]),
+            Err(ParseError::InvalidMacro { .. })
+        ));
+        assert!(matches!(
+            lines(".macro m\npush 1\n", &[]),
+            Err(ParseError::Unterminated {
+                directive: ".macro",
+                ..
+            })
+        ));
+    }
+    #[test]
+    fn includes() {
+        let included = preprocess(Path::new("./testfiles/include/main.cor"), &[]).unwrap();
+        let texts: Vec<&str> = included.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["push 1", "push 2", "ret"]);
+        assert!(included[1].at.file.ends_with("b.cor"));
+
+        let Err(ParseError::IncludeCycle { chain, at }) =
+            preprocess(Path::new("./testfiles/include/cycle.cor"), &[])
+        else {
+            panic!()
+        };
+        assert!(chain.ends_with("cycle_a.cor"));
+        assert_eq!(chain.split(" -> ").count(), 3);
+        assert_eq!(at.line, 1);
+    }
+}
@@ -0,0 +1,356 @@
+/*!Profiler, executing code one instruction at a time and counting what runs
+ *
+ * A `Profile` counts the executions of every offset and opcode, the deepest
+ * the stack got around every offset and how often every backward jump was
+ * taken. Reports attribute an offset to the last label at or before it, code
+ * before the first label to `(start)`:
+ *
+ * report     \\ executions, share and stack depth of every label and opcode, and the hottest loops
+ * listing    \\ the disassembly with the executions and stack depth of every instruction
+ * collapsed  \\ `label;OPCODE count` lines, as flamegraph tools read them
+ */
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fmt::{Debug, Display, Write},
+    path::Path,
+};
+
+use num::{
+    Integer, ToPrimitive,
+    traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, NumOps},
+};
+
+use crate::{
+    code::{code_execution::compile_debug, instruction::Instruction, link::DebugInfo},
+    stack::{Stack, stack_error::StackError},
+};
+
+///Name reports give code before the first label
+const START: &str = "(start)";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    ///Executions of the instruction at each offset
+    pub counts: Vec<u64>,
+    ///Executions of each opcode
+    pub opcodes: BTreeMap<u8, u64>,
+    ///Deepest the stack of the running coroutine was before or after the instruction at each offset
+    pub depths: Vec<usize>,
+    ///Times each backward JMP or JNZ was taken, by the offsets of the jump and its target
+    pub back_edges: BTreeMap<(usize, usize), u64>,
+    ///Times each JNZ fell through and jumped, by its offset
+    pub branches: BTreeMap<usize, [u64; 2]>,
+}
+
+impl<T> Stack<T>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    ///Execute like `execute`, counting every instruction in `profile`
+    pub fn execute_profiled(
+        &mut self,
+        code: &[u8],
+        profile: &mut Profile,
+    ) -> Result<T, StackError> {
+        profile.grow(code.len());
+        loop {
+            // Past the end of a coroutine, go on to the next one without executing anything
+            if let Some(result) = self.execute_for(code, 0)? {
+                return Ok(result);
+            }
+            let (idx, current) = (self.idx, self.scheduler.current);
+            let depth = self.state.len();
+
+            let result = self.execute_for(code, 1);
+
+            let op = code[idx];
+            profile.counts[idx] += 1;
+            *profile.opcodes.entry(op).or_default() += 1;
+            let same = self.scheduler.current == current;
+            let depth = if same {
+                depth.max(self.state.len())
+            } else {
+                depth
+            };
+            profile.depths[idx] = profile.depths[idx].max(depth);
+            let target = code.get(idx + 1).map(|&target| target as usize);
+            if same && matches!(op, 0x30 | 0x31) && target == Some(self.idx) && self.idx <= idx {
+                *profile.back_edges.entry((idx, self.idx)).or_default() += 1;
+            }
+            if same && op == 0x31 {
+                let outcomes = profile.branches.entry(idx).or_default();
+                if target == Some(self.idx) {
+                    outcomes[1] += 1;
+                } else if self.idx == idx + 2 {
+                    outcomes[0] += 1;
+                }
+            }
+
+            if let Some(result) = result? {
+                return Ok(result);
+            }
+        }
+    }
+}
+
+impl Profile {
+    pub fn new(size: usize) -> Self {
+        let mut profile = Profile::default();
+        profile.grow(size);
+        profile
+    }
+
+    ///Make room for code of `size` bytes
+    fn grow(&mut self, size: usize) {
+        if self.counts.len() < size {
+            self.counts.resize(size, 0);
+            self.depths.resize(size, 0);
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    ///Ranked executions and stack depth of every label and opcode, then of the loops
+    pub fn report(&self, labels: &[(usize, String)]) -> String {
+        let total = self.total();
+        let max_depth = self.depths.iter().max().copied().unwrap_or(0);
+        let percent = |count: u64| 100.0 * count as f64 / total.max(1) as f64;
+        let mut out = format!("{total} instructions executed, stack depth up to {max_depth}\n");
+
+        let mut by_label: BTreeMap<&str, (u64, usize)> = BTreeMap::new();
+        for (idx, (&count, &depth)) in self.counts.iter().zip(&self.depths).enumerate() {
+            if count > 0 {
+                let entry = by_label.entry(label_of(labels, idx)).or_default();
+                entry.0 += count;
+                entry.1 = entry.1.max(depth);
+            }
+        }
+        let mut by_label: Vec<_> = by_label.into_iter().collect();
+        by_label.sort_by_key(|&(_, (count, _))| Reverse(count));
+        out.push_str("\nexecutions       %  depth  label\n");
+        for (label, (count, depth)) in by_label {
+            writeln!(
+                out,
+                "{count:>10} {:>6.2} {depth:>6}  {label}",
+                percent(count)
+            )
+            .unwrap();
+        }
+
+        let mut by_opcode: Vec<_> = self.opcodes.iter().collect();
+        by_opcode.sort_by_key(|&(_, &count)| Reverse(count));
+        out.push_str("\nexecutions       %  opcode\n");
+        for (&op, &count) in by_opcode {
+            let name = Instruction::mnemonic(op);
+            writeln!(out, "{count:>10} {:>6.2}  {name}", percent(count)).unwrap();
+        }
+
+        let mut loops: Vec<_> = self
+            .back_edges
+            .iter()
+            .map(|(&(jump, target), &iterations)| {
+                let executions: u64 = self.counts[target..=jump].iter().sum();
+                (iterations, executions, jump, target)
+            })
+            .collect();
+        loops.sort_by(|a, b| b.1.cmp(&a.1).then(b.0.cmp(&a.0)));
+        if !loops.is_empty() {
+            out.push_str("\niterations  executions  loop\n");
+        }
+        for (iterations, executions, jump, target) in loops {
+            let label = label_of(labels, target);
+            writeln!(
+                out,
+                "{iterations:>10} {executions:>11}  %{target}..%{jump} in {label}"
+            )
+            .unwrap();
+        }
+        out
+    }
+
+    ///Disassembly of `code` with labels, and the executions and stack depth of every instruction
+    ///and where the source map says it came from
+    pub fn listing(&self, code: &[u8], info: &DebugInfo) -> String {
+        let mut out = String::from("executions  depth  idx\n");
+        let mut labels = info.labels.iter().peekable();
+        let mut idx = 0;
+        while let Some(instruction) = Instruction::decode(code, idx) {
+            while let Some((_, label)) = labels.next_if(|(address, _)| *address <= idx) {
+                writeln!(out, "{label}:").unwrap();
+            }
+            match self.counts.get(idx).copied().unwrap_or(0) {
+                0 => write!(out, "{:>10} {:>6}", "-", "-").unwrap(),
+                count => write!(out, "{count:>10} {:>6}", self.depths[idx]).unwrap(),
+            }
+            match info.line(idx) {
+                Some(at) => writeln!(out, " {idx:>4}  {instruction}  ; {}", at.with_column()),
+                None => writeln!(out, " {idx:>4}  {instruction}"),
+            }
+            .unwrap();
+            idx += instruction.size();
+        }
+        for (_, label) in labels {
+            writeln!(out, "{label}:").unwrap();
+        }
+        out
+    }
+
+    ///Executions by label and opcode in the collapsed stack format of flamegraph tools
+    pub fn collapsed(&self, code: &[u8], labels: &[(usize, String)]) -> String {
+        let mut stacks: BTreeMap<(&str, &str), u64> = BTreeMap::new();
+        for (idx, &count) in self.counts.iter().enumerate() {
+            if count > 0 {
+                let name = Instruction::mnemonic(code[idx]);
+                *stacks.entry((label_of(labels, idx), name)).or_default() += count;
+            }
+        }
+        stacks
+            .into_iter()
+            .map(|((label, name), count)| format!("{label};{name} {count}\n"))
+            .collect()
+    }
+}
+
+///Name of the last label at or before `idx`
+fn label_of(labels: &[(usize, String)], idx: usize) -> &str {
+    match labels.partition_point(|(address, _)| *address <= idx) {
+        0 => START,
+        after => &labels[after - 1].1,
+    }
+}
+
+///Run a program under the profiler, printing the report and the annotated listing, and writing
+///collapsed stacks beside the first file with a `.folded` extension
+pub fn profile<T>(input_files: &[&str], defines: &[(&str, i64)]) -> anyhow::Result<T>
+where
+    T: Debug
+        + ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let (code, info) = compile_debug::<T>(input_files, defines)?;
+    let mut profile = Profile::new(code.len());
+    let result = Stack::<T>::new().execute_profiled(&code, &mut profile);
+
+    println!("{}", profile.report(&info.labels));
+    print!("{}", profile.listing(&code, &info));
+    let folded = Path::new(input_files[0]).with_extension("folded");
+    std::fs::write(&folded, profile.collapsed(&code, &info.labels))?;
+    println!("Collapsed stacks written to {}", folded.display());
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PUSH 3, loop: PUSH 1, SUB, JNZ loop, done: RET
+    const COUNTDOWN: [u8; 8] = [0x20, 0x03, 0x20, 0x01, 0x02, 0x31, 0x02, 0x12];
+
+    fn labels() -> Vec<(usize, String)> {
+        vec![(2, String::from("loop")), (7, String::from("done"))]
+    }
+
+    fn countdown() -> Profile {
+        let code = COUNTDOWN;
+        let mut profile = Profile::new(code.len());
+        let result = Stack::<i64>::new().execute_profiled(&code, &mut profile);
+        assert_eq!(result.unwrap(), 0);
+        profile
+    }
+
+    #[test]
+    fn counts() {
+        let profile = countdown();
+        assert_eq!(profile.counts, [1, 0, 3, 0, 3, 3, 0, 1]);
+        assert_eq!(profile.depths, [1, 0, 2, 0, 2, 1, 0, 1]);
+        assert_eq!(profile.opcodes[&0x20], 4);
+        assert_eq!(profile.total(), 11);
+        assert_eq!(profile.back_edges, BTreeMap::from([((5, 2), 2)]));
+        assert_eq!(profile.branches, BTreeMap::from([(5, [1, 2])]));
+    }
+    #[test]
+    fn coroutines() {
+        // The coroutine at 6 runs past the end of its code, the first one waits for it
+        let code = [0x50, 0x06, 0x53, 0x01, 0x12, 0x00, 0x20, 0x07, 0x52, 0x01];
+        let mut profile = Profile::default();
+        let result = Stack::<i64>::new().execute_profiled(&code, &mut profile);
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(profile.counts, [1, 0, 2, 0, 1, 0, 1, 0, 1, 0]);
+        assert_eq!(profile.total(), 6);
+    }
+    #[test]
+    fn reports() {
+        let profile = countdown();
+        let report = profile.report(&labels());
+        assert_eq!(
+            report,
+            "11 instructions executed, stack depth up to 2\n\
+             \n\
+             executions       %  depth  label\n\
+             \x20        9  81.82      2  loop\n\
+             \x20        1   9.09      1  (start)\n\
+             \x20        1   9.09      1  done\n\
+             \n\
+             executions       %  opcode\n\
+             \x20        4  36.36  PUSH\n\
+             \x20        3  27.27  SUB\n\
+             \x20        3  27.27  JNZ\n\
+             \x20        1   9.09  RET\n\
+             \n\
+             iterations  executions  loop\n\
+             \x20        2           9  %2..%5 in loop\n"
+        );
+
+        let code = COUNTDOWN;
+        let info = DebugInfo {
+            labels: labels(),
+            lines: vec![(
+                5,
+                crate::code::preprocess::Location {
+                    file: String::from("countdown.cor"),
+                    line: 4,
+                    column: 3,
+                },
+            )],
+            ..DebugInfo::default()
+        };
+        assert_eq!(
+            profile.listing(&code, &info),
+            "executions  depth  idx\n\
+             \x20        1      1    0  PUSH 3\n\
+             loop:\n\
+             \x20        3      2    2  PUSH 1\n\
+             \x20        3      2    4  SUB\n\
+             \x20        3      1    5  JNZ %2  ; countdown.cor:4:3\n\
+             done:\n\
+             \x20        1      1    7  RET\n"
+        );
+        assert_eq!(
+            profile.collapsed(&code, &labels()),
+            "(start);PUSH 1\ndone;RET 1\nloop;JNZ 3\nloop;PUSH 3\nloop;SUB 3\n"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+/*!Test runner of `corrode test`
+ *
+ * Every label starting with `test_` is a test. Each one runs from its label in
+ * a fresh `Stack` until RET or EXIT, and fails on an error, such as an ASSERT
+ * or ASSERTEQ that does not hold. A program without such labels is a single
+ * test named `main`, run from the start.
+ *
+ * The output of PRINT and PCHAR is captured. A test with a golden file also
+ * fails unless its output and result match the file, which holds the output
+ * followed by a `result N` line. The golden file of `test_name` in `prog.cor`
+ * is `prog.test_name.golden` beside it; `corrode test --update` writes them
+ * from the results of the tests that pass.
+ */
+
+use std::{
+    fmt::{Debug, Display},
+    path::{Path, PathBuf},
+};
+
+use num::{
+    Integer, ToPrimitive,
+    traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, NumOps},
+};
+
+use crate::{
+    code::{
+        code_execution::{compile_debug, failed_at},
+        link::DebugInfo,
+    },
+    stack::{Stack, stack_error::StackError},
+};
+
+///Name of the test a program without `test_` labels is
+const MAIN: &str = "main";
+
+#[derive(Debug)]
+pub struct TestOutcome<T> {
+    pub name: String,
+    pub result: Result<T, StackError>,
+    ///What PRINT and PCHAR wrote
+    pub output: String,
+}
+
+impl<T: Display> TestOutcome<T> {
+    ///Output and result as a golden file holds them, `None` when the test failed
+    pub fn golden(&self) -> Option<String> {
+        let value = self.result.as_ref().ok()?;
+        Some(format!("{}result {value}\n", self.output))
+    }
+}
+
+///Run every test of `code`, each in a fresh stack
+pub fn run_tests<T>(code: &[u8], info: &DebugInfo) -> Vec<TestOutcome<T>>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let mut tests: Vec<(&str, usize)> = info
+        .labels
+        .iter()
+        .filter(|(_, name)| name.starts_with("test_"))
+        .map(|(address, name)| (name.as_str(), *address))
+        .collect();
+    if tests.is_empty() {
+        tests.push((MAIN, 0));
+    }
+    tests
+        .into_iter()
+        .map(|(name, address)| {
+            let mut stack = Stack::new();
+            stack.idx = address;
+            stack.captured = Some(String::new());
+            let result = stack.execute(code);
+            TestOutcome {
+                name: name.to_string(),
+                result,
+                output: stack.captured.unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+///Golden file of the test `name` in the program `main_file`
+pub fn golden_path(main_file: &str, name: &str) -> PathBuf {
+    Path::new(main_file).with_extension(format!("{name}.golden"))
+}
+
+///Run the tests of a program, comparing them with their golden files or writing those when
+///`update` is set, and report every test and a summary. Fails when a test does.
+pub fn test<T>(input_files: &[&str], defines: &[(&str, i64)], update: bool) -> anyhow::Result<()>
+where
+    T: Debug
+        + ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    let (code, info) = compile_debug::<T>(input_files, defines)?;
+    let outcomes = run_tests::<T>(&code, &info);
+
+    let mut failures = Vec::new();
+    for outcome in &outcomes {
+        let path = golden_path(input_files[0], &outcome.name);
+        let failure = match (&outcome.result, outcome.golden()) {
+            (Err(error), _) => {
+                let at = failed_at(&code, error)
+                    .and_then(|idx| info.line(idx))
+                    .map(|at| format!(" at {}", at.with_column()))
+                    .unwrap_or_default();
+                Some(format!("{error}{at}"))
+            }
+            (Ok(_), Some(golden)) if update => {
+                std::fs::write(&path, golden)?;
+                None
+            }
+            (Ok(_), Some(golden)) => match std::fs::read_to_string(&path) {
+                Ok(expected) if expected != golden => Some(format!(
+                    "does not match {}\nexpected:\n{expected}found:\n{golden}",
+                    path.display()
+                )),
+                _ => None,
+            },
+            (Ok(_), None) => None,
+        };
+        let status = if failure.is_some() { "FAILED" } else { "ok" };
+        println!("test {} ... {status}", outcome.name);
+        failures.extend(failure.map(|failure| (&outcome.name, failure)));
+    }
+
+    for (name, failure) in &failures {
+        println!("\n{name}: {failure}");
+    }
+    let passed = outcomes.len() - failures.len();
+    let status = if failures.is_empty() { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {status}. {passed} passed; {} failed",
+        failures.len()
+    );
+    if !failures.is_empty() {
+        anyhow::bail!("{} of {} tests failed", failures.len(), outcomes.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::parse::link_files_debug;
+
+    use super::*;
+
+    const SOURCE: &str = "\
+push 1
+ret
+test_sum: push 2
+push 3
+add
+dup
+print
+push 5
+asserteq
+exit
+test_fails: push 2
+push 3
+asserteq
+ret
+test_nonzero: push 0
+assert
+";
+
+    fn outcomes(dir: &Path) -> (String, Vec<TestOutcome<i64>>) {
+        std::fs::create_dir_all(dir).unwrap();
+        let file = dir.join("prog.cor");
+        std::fs::write(&file, SOURCE).unwrap();
+        let file = file.to_str().unwrap().to_string();
+        let (code, info) = link_files_debug::<i64>(&[&file], &[]).unwrap();
+        (file, run_tests(&code, &info))
+    }
+
+    #[test]
+    fn labelled_tests() {
+        let dir = std::env::temp_dir().join(format!("corrode_tests_{}", std::process::id()));
+        let (file, outcomes) = outcomes(&dir);
+        let names: Vec<&str> = outcomes
+            .iter()
+            .map(|outcome| outcome.name.as_str())
+            .collect();
+        assert_eq!(names, ["test_sum", "test_fails", "test_nonzero"]);
+
+        assert_eq!(outcomes[0].result.as_ref().unwrap(), &0xFF);
+        assert_eq!(outcomes[0].golden().unwrap(), "5\nresult 255\n");
+        assert!(matches!(
+            outcomes[1].result,
+            Err(StackError::NotEqual {
+                idx: 18,
+                ref left,
+                ref right,
+                ..
+            }) if left == "2" && right == "3"
+        ));
+        assert!(matches!(
+            outcomes[2].result,
+            Err(StackError::AssertionFailed { idx: 22, .. })
+        ));
+        assert_eq!(outcomes[2].golden(), None);
+
+        // The whole program is the test when no label starts with test_
+        std::fs::write(&file, "push 4\nprint\nret\n").unwrap();
+        let (code, info) = link_files_debug::<i64>(&[&file], &[]).unwrap();
+        let outcomes = run_tests::<i64>(&code, &info);
+        assert_eq!(outcomes[0].name, "main");
+        assert_eq!(outcomes[0].golden().unwrap(), "4\nresult 4\n");
+        assert_eq!(golden_path(&file, "main"), dir.join("prog.main.golden"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn golden_files() {
+        let dir = std::env::temp_dir().join(format!("corrode_golden_{}", std::process::id()));
+        let (file, _) = outcomes(&dir);
+        let passing = "test_sum: push 4\nprint\nret\n";
+        std::fs::write(&file, passing).unwrap();
+
+        // Without a golden file only errors fail, --update writes it and then it is compared
+        assert!(test::<i64>(&[&file], &[], false).is_ok());
+        assert!(test::<i64>(&[&file], &[], true).is_ok());
+        let golden = golden_path(&file, "test_sum");
+        assert_eq!(std::fs::read_to_string(&golden).unwrap(), "4\nresult 4\n");
+        assert!(test::<i64>(&[&file], &[], false).is_ok());
+        std::fs::write(&file, "test_sum: push 5\nprint\nret\n").unwrap();
+        assert!(test::<i64>(&[&file], &[], false).is_err());
+
+        std::fs::write(&file, SOURCE).unwrap();
+        assert!(test::<i64>(&[&file], &[], false).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
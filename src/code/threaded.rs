@@ -0,0 +1,896 @@
+/*!Threaded execution engine
+ *
+ * The bytecode is decoded once into a table of closures, one per instruction,
+ * with every jump already resolved to an index into that table. Straight-line
+ * code is closure threaded: each closure calls its successor itself, and only
+ * jumps return to the dispatch loop. Arithmetic is fused with a PUSH feeding it
+ * and a JNZ testing its result, and the top of the stack is kept in a register
+ * beside the rest of the stack.
+ *
+ * Results, final stack state, output, `Stack::captured` included, and `StackError`s
+ * are the same as `Stack::execute`.
+ */
+
+use std::{cmp::Ordering, collections::HashMap, fmt::Display, mem};
+
+use num::{
+    Integer, ToPrimitive,
+    traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, NumOps},
+};
+
+use crate::{
+    code::{
+        code_execution::{arithmetic, floor, from_count, shuffle, write_line},
+        instruction::{Instruction, reachable},
+    },
+    stack::{Frame, Handler, Stack, stack_error::StackError},
+};
+
+///Data stack with the top element cached outside the vector
+struct Registers<T> {
+    tos: Option<T>,
+    rest: Vec<T>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    ///`Stack::captured` of the stack being run
+    captured: Option<String>,
+    halt: Option<Halt<T>>,
+}
+
+impl<T> Registers<T> {
+    fn new(mut state: Vec<T>) -> Self {
+        let tos = state.pop();
+        Registers {
+            tos,
+            rest: state,
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            captured: None,
+            halt: None,
+        }
+    }
+
+    ///Stop the dispatch loop, returning the index that tells it so
+    fn stop(&mut self, halt: Halt<T>) -> usize {
+        self.halt = Some(halt);
+        HALT
+    }
+
+    fn push(&mut self, item: T) {
+        if let Some(old) = self.tos.replace(item) {
+            self.rest.push(old)
+        }
+    }
+
+    fn pop(&mut self) -> Option<T> {
+        let top = self.tos.take()?;
+        self.tos = self.rest.pop();
+        Some(top)
+    }
+
+    ///Slot counted from the bottom of the stack, the cached top included
+    fn slot_mut(&mut self, slot: usize) -> Option<&mut T> {
+        match slot.cmp(&self.rest.len()) {
+            Ordering::Less => self.rest.get_mut(slot),
+            Ordering::Equal => self.tos.as_mut(),
+            Ordering::Greater => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.rest.len() + usize::from(self.tos.is_some())
+    }
+
+    ///Local of the innermost frame, or the fault LOADLOCAL and STORELOCAL stop with
+    fn local_mut(&mut self, local: u8, idx: usize, op: u8) -> Result<&mut T, Halt<T>> {
+        let Some(&frame) = self.frames.last() else {
+            return Err(fault(StackError::NoFrame { idx, op }, idx, op));
+        };
+        let out_of_range = || {
+            let local = local as usize;
+            fault(StackError::LocalOutOfRange { idx, op, local }, idx, op)
+        };
+        if local as usize >= frame.size {
+            return Err(out_of_range());
+        }
+        self.slot_mut(frame.base + local as usize)
+            .ok_or_else(out_of_range)
+    }
+
+    ///Drop the locals of the innermost frame, like `Stack::leave`
+    fn leave(&mut self, idx: usize, op: u8) -> Result<(), Halt<T>> {
+        let Some(&frame) = self.frames.last() else {
+            return Err(fault(StackError::NoFrame { idx, op }, idx, op));
+        };
+        if self.len() < frame.base + frame.size {
+            return Err(empty(idx, op));
+        }
+        self.rest.extend(self.tos.take());
+        self.rest.drain(frame.base..frame.base + frame.size);
+        self.tos = self.rest.pop();
+        self.frames.pop();
+        Ok(())
+    }
+
+    fn into_state(mut self) -> Vec<T> {
+        self.rest.extend(self.tos);
+        self.rest
+    }
+}
+
+///Why the dispatch loop stopped, with the `idx` and `op` the interpreter would be left at
+enum Halt<T> {
+    Return {
+        value: T,
+        idx: usize,
+        op: u8,
+    },
+    End {
+        idx: usize,
+        op: u8,
+    },
+    Fault {
+        error: StackError,
+        idx: usize,
+        op: u8,
+    },
+    Throw {
+        value: T,
+        idx: usize,
+        op: u8,
+    },
+    ///SPAWN, YIELD, SEND or RECV, left to the scheduler of the `Stack`
+    Schedule {
+        instruction: Instruction,
+        idx: usize,
+        op: u8,
+    },
+}
+
+///Compiled instruction. Returns the index of the next op to run, or `HALT` once
+///`Registers::halt` is set
+struct Op<T>(Box<OpFn<T>>);
+
+type OpFn<T> = dyn Fn(&mut Registers<T>, &[Op<T>]) -> usize;
+
+impl<T> Op<T> {
+    fn new(body: impl Fn(&mut Registers<T>, &[Op<T>]) -> usize + 'static) -> Self {
+        Op(Box::new(body))
+    }
+}
+
+///Fallthrough successor of an instruction
+#[derive(Clone, Copy)]
+struct Next {
+    index: usize,
+    ///Call the successor straight from this op instead of returning to the dispatch loop
+    direct: bool,
+}
+
+impl Next {
+    fn new(from: usize, to: usize, index: usize) -> Self {
+        Next {
+            index,
+            direct: from / DIRECT_RUN == to / DIRECT_RUN,
+        }
+    }
+
+    ///Continue with the successor. Calling it directly gives every op its own
+    ///indirect branch, which predicts far better than the single one in the loop.
+    #[inline(always)]
+    fn go<T>(self, regs: &mut Registers<T>, ops: &[Op<T>]) -> usize {
+        if self.direct {
+            (ops[self.index].0)(regs, ops)
+        } else {
+            self.index
+        }
+    }
+}
+
+///Direct calls nest, so runs of them are broken up at this many bytes of code
+const DIRECT_RUN: usize = 32;
+
+const HALT: usize = usize::MAX;
+
+fn empty<T>(idx: usize, op: u8) -> Halt<T> {
+    Halt::Fault {
+        error: StackError::EmptyStack { idx, op },
+        idx,
+        op,
+    }
+}
+
+fn fault<T>(error: StackError, idx: usize, op: u8) -> Halt<T> {
+    Halt::Fault { error, idx, op }
+}
+
+fn out_of_range<T>(slot: u8, idx: usize, op: u8) -> Halt<T> {
+    Halt::Fault {
+        error: StackError::SlotOutOfRange {
+            idx,
+            op,
+            slot: slot as usize,
+        },
+        idx,
+        op,
+    }
+}
+
+pub struct ThreadedCode<T> {
+    ops: Vec<Op<T>>,
+    ///Index into `ops` of the instruction starting at each byte offset
+    entries: Vec<Option<usize>>,
+    code: Vec<u8>,
+}
+
+impl<T> ThreadedCode<T>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv
+        + 'static,
+{
+    pub fn new(code: &[u8]) -> Self {
+        let decoded: Vec<(usize, Instruction)> = reachable(code).into_iter().collect();
+        let mut entries: Vec<Option<usize>> = vec![None; code.len()];
+        for (index, &(offset, _)) in decoded.iter().enumerate() {
+            entries[offset] = Some(index);
+        }
+
+        // Offsets outside the code end execution; each gets a stub recording where it stopped.
+        let mut stubs: HashMap<(usize, u8), usize> = HashMap::new();
+        let mut resolve = |offset: usize, op: u8| match entries.get(offset).copied().flatten() {
+            Some(index) => index,
+            None => {
+                let next_stub = decoded.len() + stubs.len();
+                *stubs.entry((offset, op)).or_insert(next_stub)
+            }
+        };
+
+        let fetch = |offset: usize| {
+            entries
+                .get(offset)
+                .copied()
+                .flatten()
+                .map(|index| decoded[index].1)
+        };
+
+        let mut ops: Vec<Op<T>> = Vec::with_capacity(decoded.len());
+        for &(offset, instruction) in &decoded {
+            let op = instruction.opcode();
+            let next_offset = offset + instruction.size();
+            let next = Next::new(offset, next_offset, resolve(next_offset, op));
+
+            // Arithmetic absorbs a PUSH feeding it and a JNZ testing its result, so the
+            // common `PUSH n, SUB, JNZ loop` costs a single dispatch
+            let arithmetic = match (instruction, fetch(next_offset)) {
+                (Instruction::Push(value), Some(following)) if following.is_arithmetic() => {
+                    Some((next_offset, following, Some(value)))
+                }
+                _ if instruction.is_arithmetic() => Some((offset, instruction, None)),
+                _ => None,
+            };
+            if let Some((arith_offset, arith, immediate)) = arithmetic {
+                let after = arith_offset + 1;
+                let fused = match fetch(after) {
+                    Some(Instruction::Jnz(target)) => Fused {
+                        offset: arith_offset,
+                        op: arith.opcode(),
+                        immediate,
+                        branch: Some(resolve(target, 0x31)),
+                        next: Next::new(offset, after + 2, resolve(after + 2, 0x31)),
+                    },
+                    _ => Fused {
+                        offset: arith_offset,
+                        op: arith.opcode(),
+                        immediate,
+                        branch: None,
+                        next: Next::new(offset, after, resolve(after, arith.opcode())),
+                    },
+                };
+                ops.push(fused.compile());
+                continue;
+            }
+
+            let compiled: Op<T> = match instruction {
+                Instruction::Nop => Op::new(move |regs, ops| next.go(regs, ops)),
+                Instruction::Add
+                | Instruction::Sub
+                | Instruction::Mul
+                | Instruction::Div
+                | Instruction::Mod
+                | Instruction::Lt => unreachable!(),
+                Instruction::Print => {
+                    Op::new(move |regs: &mut Registers<T>, ops| match &regs.tos {
+                        Some(top) => {
+                            write_line(&mut regs.captured, &top.to_string());
+                            next.go(regs, ops)
+                        }
+                        None => regs.stop(empty(offset, op)),
+                    })
+                }
+                Instruction::PChar => Op::new(move |regs: &mut Registers<T>, ops| {
+                    let mut scratch = Stack::new();
+                    scratch.state = mem::take(&mut regs.rest);
+                    scratch.state.extend(regs.tos.take());
+                    scratch.captured = regs.captured.take();
+                    let result = scratch.print_chars();
+                    regs.captured = scratch.captured;
+                    regs.rest = scratch.state;
+                    regs.tos = regs.rest.pop();
+                    match result {
+                        Ok(()) => next.go(regs, ops),
+                        Err(error) => regs.stop(Halt::Fault {
+                            error,
+                            idx: offset,
+                            op,
+                        }),
+                    }
+                }),
+                Instruction::Ret => {
+                    Op::new(move |regs: &mut Registers<T>, _| match regs.tos.clone() {
+                        Some(value) => regs.stop(Halt::Return {
+                            value,
+                            idx: offset,
+                            op,
+                        }),
+                        None => regs.stop(empty(offset, op)),
+                    })
+                }
+                Instruction::Push(value) => Op::new(move |regs, ops| {
+                    regs.push(value.into());
+                    next.go(regs, ops)
+                }),
+                // SWP and DUP report the offset after themselves, like the interpreter
+                Instruction::Swp => {
+                    Op::new(
+                        move |regs, ops| match (regs.tos.as_mut(), regs.rest.last_mut()) {
+                            (Some(top), Some(under)) => {
+                                mem::swap(top, under);
+                                next.go(regs, ops)
+                            }
+                            _ => {
+                                regs.tos = None;
+                                regs.stop(empty(offset + 1, op))
+                            }
+                        },
+                    )
+                }
+                Instruction::Pop => Op::new(move |regs, ops| {
+                    regs.pop();
+                    next.go(regs, ops)
+                }),
+                Instruction::Dup => Op::new(move |regs, ops| match regs.tos.clone() {
+                    Some(top) => {
+                        regs.rest.push(top);
+                        next.go(regs, ops)
+                    }
+                    None => regs.stop(empty(offset + 1, op)),
+                }),
+                Instruction::Load(slot) => {
+                    Op::new(
+                        move |regs, ops| match regs.slot_mut(slot as usize).cloned() {
+                            Some(item) => {
+                                regs.push(item);
+                                next.go(regs, ops)
+                            }
+                            None => regs.stop(out_of_range(slot, offset, op)),
+                        },
+                    )
+                }
+                Instruction::Store(slot) => Op::new(move |regs, ops| {
+                    let Some(item) = regs.pop() else {
+                        return regs.stop(empty(offset, op));
+                    };
+                    match regs.slot_mut(slot as usize) {
+                        Some(target) => {
+                            *target = item;
+                            next.go(regs, ops)
+                        }
+                        None => regs.stop(out_of_range(slot, offset, op)),
+                    }
+                }),
+                Instruction::Enter(size) => Op::new(move |regs, ops| {
+                    let base = regs.len();
+                    regs.frames.push(Frame {
+                        base,
+                        size: size as usize,
+                    });
+                    for _ in 0..size {
+                        regs.push(0.into());
+                    }
+                    next.go(regs, ops)
+                }),
+                Instruction::LoadLocal(local) => Op::new(move |regs: &mut Registers<T>, ops| {
+                    match regs.local_mut(local, offset, op).map(|item| item.clone()) {
+                        Ok(item) => {
+                            regs.push(item);
+                            next.go(regs, ops)
+                        }
+                        Err(halt) => regs.stop(halt),
+                    }
+                }),
+                Instruction::StoreLocal(local) => Op::new(move |regs, ops| {
+                    let Some(item) = regs.pop() else {
+                        return regs.stop(empty(offset, op));
+                    };
+                    match regs.local_mut(local, offset, op) {
+                        Ok(target) => {
+                            *target = item;
+                            next.go(regs, ops)
+                        }
+                        Err(halt) => regs.stop(halt),
+                    }
+                }),
+                Instruction::Leave => Op::new(move |regs, ops| match regs.leave(offset, op) {
+                    Ok(()) => next.go(regs, ops),
+                    Err(halt) => regs.stop(halt),
+                }),
+                Instruction::Jmp(target) => {
+                    let target = resolve(target, op);
+                    Op::new(move |_, _| target)
+                }
+                Instruction::Jnz(target) => {
+                    let target = resolve(target, op);
+                    Op::new(move |regs: &mut Registers<T>, ops| match &regs.tos {
+                        Some(top) if !top.is_zero() => target,
+                        _ => next.go(regs, ops),
+                    })
+                }
+                Instruction::Try(target) => Op::new(move |regs, ops| {
+                    let handler = Handler {
+                        address: target,
+                        depth: regs.len(),
+                        frames: regs.frames.len(),
+                    };
+                    regs.handlers.push(handler);
+                    next.go(regs, ops)
+                }),
+                Instruction::EndTry => Op::new(move |regs, ops| match regs.handlers.pop() {
+                    Some(_) => next.go(regs, ops),
+                    None => regs.stop(fault(StackError::NoHandler { idx: offset, op }, offset, op)),
+                }),
+                Instruction::Throw => Op::new(move |regs, _| match regs.pop() {
+                    Some(value) => regs.stop(Halt::Throw {
+                        value,
+                        idx: offset,
+                        op,
+                    }),
+                    None => regs.stop(empty(offset, op)),
+                }),
+                Instruction::Assert => {
+                    Op::new(move |regs: &mut Registers<T>, ops| match regs.pop() {
+                        Some(value) if value.is_zero() => {
+                            let error = StackError::AssertionFailed { idx: offset, op };
+                            regs.stop(fault(error, offset, op))
+                        }
+                        Some(_) => next.go(regs, ops),
+                        None => regs.stop(empty(offset, op)),
+                    })
+                }
+                Instruction::AssertEq => Op::new(move |regs: &mut Registers<T>, ops| {
+                    let (Some(rhs), Some(lhs)) = (regs.pop(), regs.pop()) else {
+                        return regs.stop(empty(offset, op));
+                    };
+                    if lhs == rhs {
+                        return next.go(regs, ops);
+                    }
+                    let error = StackError::NotEqual {
+                        idx: offset,
+                        op,
+                        left: lhs.to_string(),
+                        right: rhs.to_string(),
+                    };
+                    regs.stop(fault(error, offset, op))
+                }),
+                Instruction::Spawn(_)
+                | Instruction::Yield
+                | Instruction::Send(_)
+                | Instruction::Recv(_) => Op::new(move |regs: &mut Registers<T>, _| {
+                    regs.stop(Halt::Schedule {
+                        instruction,
+                        idx: offset,
+                        op,
+                    })
+                }),
+                Instruction::Over
+                | Instruction::Rot
+                | Instruction::Nip
+                | Instruction::Tuck
+                | Instruction::Pick(_)
+                | Instruction::Roll(_)
+                | Instruction::TwoDup
+                | Instruction::TwoSwap => Op::new(move |regs: &mut Registers<T>, ops| {
+                    regs.rest.extend(regs.tos.take());
+                    let result = shuffle(&mut regs.rest, instruction, offset);
+                    regs.tos = regs.rest.pop();
+                    match result {
+                        Ok(()) => next.go(regs, ops),
+                        Err(error) => regs.stop(fault(error, offset, op)),
+                    }
+                }),
+                Instruction::Depth => Op::new(move |regs: &mut Registers<T>, ops| {
+                    regs.push(from_count(regs.len()));
+                    next.go(regs, ops)
+                }),
+                Instruction::Clear => Op::new(move |regs: &mut Registers<T>, ops| {
+                    regs.rest.extend(regs.tos.take());
+                    regs.rest.truncate(floor(&regs.frames));
+                    regs.tos = regs.rest.pop();
+                    next.go(regs, ops)
+                }),
+                Instruction::Exit => Op::new(move |regs: &mut Registers<T>, _| {
+                    regs.stop(Halt::Return {
+                        value: 0xFF.into(),
+                        idx: offset,
+                        op,
+                    })
+                }),
+                Instruction::Truncated(_) => {
+                    let end = code.len();
+                    Op::new(move |regs: &mut Registers<T>, _| {
+                        let idx = match &regs.tos {
+                            Some(top) if op == 0x31 && top.is_zero() => end + 1,
+                            None if op == 0x31 => end + 1,
+                            _ => end,
+                        };
+                        regs.stop(Halt::End { idx, op })
+                    })
+                }
+                Instruction::Unknown(_) => Op::new(move |regs: &mut Registers<T>, _| {
+                    regs.stop(Halt::Fault {
+                        error: StackError::UnknownOp {
+                            idx: offset,
+                            byte: op,
+                        },
+                        idx: offset,
+                        op,
+                    })
+                }),
+            };
+            ops.push(compiled);
+        }
+
+        let mut stubs: Vec<((usize, u8), usize)> = stubs.into_iter().collect();
+        stubs.sort_by_key(|&(_, index)| index);
+        for ((idx, op), _) in stubs {
+            ops.push(Op::new(move |regs, _| regs.stop(Halt::End { idx, op })));
+        }
+
+        ThreadedCode {
+            ops,
+            entries,
+            code: code.to_vec(),
+        }
+    }
+}
+
+///ADD, SUB, MUL, DIV, MOD or LT, with the operand of a PUSH before it and a JNZ after it
+struct Fused {
+    offset: usize,
+    op: u8,
+    immediate: Option<u8>,
+    branch: Option<usize>,
+    next: Next,
+}
+
+impl Fused {
+    fn compile<T>(self) -> Op<T>
+    where
+        T: Integer + From<u8> + CheckedAdd + CheckedSub + CheckedMul + CheckedDiv + 'static,
+    {
+        let Fused {
+            offset,
+            op,
+            immediate,
+            branch,
+            next,
+        } = self;
+        Op::new(move |regs: &mut Registers<T>, ops| {
+            let (lhs, rhs) = match (immediate, regs.tos.take()) {
+                (Some(rhs), Some(lhs)) => (lhs, rhs.into()),
+                (None, Some(rhs)) => match regs.rest.pop() {
+                    Some(lhs) => (lhs, rhs),
+                    None => return regs.stop(empty(offset, op)),
+                },
+                (_, None) => return regs.stop(empty(offset, op)),
+            };
+            let result = match arithmetic(op, lhs, rhs, offset) {
+                Ok(result) => result,
+                Err(error) => {
+                    regs.tos = regs.rest.pop();
+                    return regs.stop(fault(error, offset, op));
+                }
+            };
+            let taken = branch.is_some() && !result.is_zero();
+            regs.tos = Some(result);
+            match branch {
+                Some(target) if taken => target,
+                _ => next.go(regs, ops),
+            }
+        })
+    }
+}
+
+impl<T> Stack<T>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv
+        + 'static,
+{
+    ///Run a coroutine instruction at `idx` like `execute` does
+    fn schedule(&mut self, instruction: Instruction) -> Result<(), StackError> {
+        let next = self.idx + instruction.size();
+        match instruction {
+            Instruction::Spawn(address) => {
+                self.spawn(address);
+                self.idx = next;
+            }
+            Instruction::Yield => {
+                self.idx = next;
+                self.reschedule(None, false)?;
+            }
+            Instruction::Send(channel) => {
+                self.send(channel)?;
+                self.idx = next;
+            }
+            Instruction::Recv(channel) => self.receive(channel, next)?,
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    ///Run predecoded code from the current `idx`, behaving exactly like `execute`
+    pub fn execute_threaded(&mut self, program: &ThreadedCode<T>) -> Result<T, StackError> {
+        loop {
+            if self.idx >= program.code.len() {
+                if self.scheduler.current == 0 {
+                    return Ok(0.into());
+                }
+                self.reschedule(None, true)?;
+                continue;
+            }
+            // Entering in the middle of an instruction is left to the byte interpreter
+            let Some(mut pc) = program.entries[self.idx] else {
+                return self.execute(&program.code);
+            };
+
+            let mut regs = Registers::new(mem::take(&mut self.state));
+            regs.frames = mem::take(&mut self.frames);
+            regs.handlers = mem::take(&mut self.handlers);
+            regs.captured = self.captured.take();
+            while pc != HALT {
+                pc = (program.ops[pc].0)(&mut regs, &program.ops);
+            }
+            let halt = regs.halt.take().unwrap();
+            self.frames = mem::take(&mut regs.frames);
+            self.handlers = mem::take(&mut regs.handlers);
+            self.captured = regs.captured.take();
+            self.state = regs.into_state();
+
+            // Caught faults and THROWs carry on from the handler, and coroutines other than
+            // the first from wherever the scheduler takes them
+            match halt {
+                Halt::Return { idx, op, .. } | Halt::End { idx, op }
+                    if self.scheduler.current != 0 =>
+                {
+                    self.idx = idx;
+                    self.op = op;
+                    self.reschedule(None, true)?;
+                }
+                Halt::Return { value, idx, op } => {
+                    self.idx = idx;
+                    self.op = op;
+                    return Ok(value);
+                }
+                Halt::End { idx, op } => {
+                    self.idx = idx;
+                    self.op = op;
+                    return Ok(0.into());
+                }
+                Halt::Schedule {
+                    instruction,
+                    idx,
+                    op,
+                } => {
+                    self.idx = idx;
+                    self.op = op;
+                    if let Err(error) = self.schedule(instruction) {
+                        self.catch(error)?;
+                    }
+                }
+                Halt::Fault { error, idx, op } => {
+                    self.idx = idx;
+                    self.op = op;
+                    self.catch(error)?;
+                }
+                Halt::Throw { value, idx, op } => {
+                    self.idx = idx;
+                    self.op = op;
+                    self.throw(value)?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(code: &[u8]) {
+        let mut interpreted = Stack::<i64>::new();
+        interpreted.captured = Some(String::new());
+        let expected = interpreted.execute(code);
+
+        let mut threaded = Stack::<i64>::new();
+        threaded.captured = Some(String::new());
+        let retval = threaded.execute_threaded(&ThreadedCode::new(code));
+
+        assert_eq!(format!("{:?}", retval), format!("{:?}", expected));
+        assert_eq!(threaded.state, interpreted.state);
+        assert_eq!(threaded.idx, interpreted.idx);
+        assert_eq!(threaded.op, interpreted.op);
+        assert_eq!(threaded.frames, interpreted.frames);
+        assert_eq!(threaded.handlers, interpreted.handlers);
+        assert_eq!(threaded.captured, interpreted.captured);
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x01, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x02, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x03, 0x12]);
+        assert_same(&[0x20, 0x06, 0x20, 0x03, 0x04, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x03, 0x05, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x06, 0x12]);
+        assert_same(&[0x20, 0x06, 0x20, 0x05, 0x06, 0x31, 0x00, 0x12]);
+        assert_same(&[
+            0x20, 0x10, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x12,
+        ]);
+        assert_same(&[
+            0x20, 0x10, 0x23, 0x03, 0x23, 0x03, 0x23, 0x03, 0x23, 0x20, 0x02, 0x04, 0x21, 0x20,
+            0x00, 0x21, 0x02, 0x03, 0x20, 0x00, 0x20, 0x01, 0x02, 0x05, 0x12,
+        ]);
+    }
+    #[test]
+    fn stack_words() {
+        assert_same(&[0x20, 0x01, 0x20, 0x02, 0x21, 0x23, 0x22, 0xFF]);
+        assert_same(&[0x22, 0x20, 0x01, 0x21]);
+        assert_same(&[0x23]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x24, 0x00, 0x01, 0x25, 0x00, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x24, 0x01, 0x25, 0x00, 0x12]);
+        assert_same(&[0x20, 0x05, 0x24, 0x01]);
+        assert_same(&[0x20, 0x05, 0x25, 0x00]);
+        assert_same(&[0x25, 0x00]);
+        assert_same(&[0x20, 0x05, 0x24]);
+        assert_same(&[
+            0x20, 0x01, 0x20, 0x02, 0x20, 0x03, 0x60, 0x61, 0x62, 0x63, 0x68, 0x69, 0x64, 0x04,
+            0x65, 0x05, 0x66, 0x12,
+        ]);
+        assert_same(&[0x20, 0x01, 0x64, 0x01]);
+        assert_same(&[0x20, 0x01, 0x65, 0x03]);
+        assert_same(&[0x20, 0x01, 0x69]);
+        assert_same(&[0x20, 0x01, 0x26, 0x01, 0x20, 0x02, 0x67, 0x66, 0x12]);
+        assert_same(&[0x20, 0x01, 0x64]);
+    }
+    #[test]
+    fn frames() {
+        assert_same(&[
+            0x20, 0x09, 0x26, 0x02, 0x20, 0x04, 0x28, 0x01, 0x27, 0x01, 0x27, 0x00, 0x01, 0x29,
+            0x12,
+        ]);
+        // PCHAR in a frame keeps it
+        assert_same(&[0x26, 0x01, 0x20, 0x41, 0x11, 0x27, 0x00, 0x12]);
+        assert_same(&[0x26, 0x01, 0x27, 0x01]);
+        assert_same(&[0x26, 0x02, 0x22, 0x29]);
+        assert_same(&[0x26, 0x02, 0x22, 0x28, 0x01]);
+        assert_same(&[0x27, 0x00]);
+        assert_same(&[0x29]);
+        assert_same(&[0x26]);
+    }
+    #[test]
+    fn try_throw() {
+        assert_same(&[
+            0x20, 0x01, 0x32, 0x09, 0x20, 0x02, 0x20, 0x07, 0x34, 0x20, 0x03, 0x01, 0x12,
+        ]);
+        // Faults in fused arithmetic, caught and uncaught
+        assert_same(&[0x32, 0x09, 0x26, 0x01, 0x20, 0x01, 0x20, 0x00, 0x04, 0x12]);
+        assert_same(&[0x20, 0x04, 0x20, 0x00, 0x05, 0x12]);
+        assert_same(&[0x20, 0x04, 0x20, 0x00, 0x04, 0x31, 0x00]);
+        // Handler inside the operand of the TRY
+        assert_same(&[0x32, 0x01, 0x34]);
+        assert_same(&[0x32, 0xF0, 0x34]);
+        assert_same(&[0x32, 0x03, 0x33, 0x12]);
+        assert_same(&[0x20, 0x07, 0x34]);
+        assert_same(&[0x33]);
+    }
+    #[test]
+    fn assertions() {
+        assert_same(&[
+            0x20, 0x01, 0x40, 0x20, 0x04, 0x20, 0x04, 0x41, 0x20, 0x09, 0x12,
+        ]);
+        assert_same(&[0x20, 0x00, 0x40]);
+        assert_same(&[0x20, 0x02, 0x20, 0x03, 0x41]);
+        assert_same(&[0x40]);
+        assert_same(&[0x32, 0x07, 0x20, 0x02, 0x20, 0x03, 0x41, 0x12]);
+    }
+    #[test]
+    fn coroutines() {
+        assert_same(&[
+            0x50, 0x0A, 0x50, 0x0F, 0x53, 0x02, 0x53, 0x02, 0x01, 0x12, 0x20, 0x03, 0x52, 0x01,
+            0xFF, 0x53, 0x01, 0x23, 0x51, 0x52, 0x02, 0x52, 0x02,
+        ]);
+        // A fault in a coroutine caught by its own handler
+        assert_same(&[
+            0x50, 0x04, 0x30, 0x0C, 0x32, 0x09, 0x22, 0x51, 0x01, 0x52, 0x01, 0xFF, 0x53, 0x01,
+            0x12,
+        ]);
+        assert_same(&[0x50, 0x04, 0x53, 0x01, 0x53, 0x02]);
+        assert_same(&[0x52, 0x01]);
+    }
+    #[test]
+    fn jumps() {
+        assert_same(&[0x20, 0x05, 0x30, 0x06, 0x20, 0x03, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x01, 0x02, 0x31, 0x02, 0x20, 0x03, 0x12]);
+        assert_same(&[0x20, 0x05, 0x30, 0xF0]);
+        assert_same(&[0x20, 0x00, 0x31]);
+    }
+    #[test]
+    fn countdown() {
+        // PUSH 30, loop: PUSH 1, SUB, JNZ loop, RET
+        assert_same(&[0x20, 0x1E, 0x20, 0x01, 0x02, 0x31, 0x02, 0x12]);
+    }
+    #[test]
+    fn captured_output() {
+        // PUSH 0, PUSH 'H', PUSH 'i', PCHAR, PUSH 2, loop: PRINT, PUSH 1, SUB, JNZ loop, RET
+        let code = [
+            0x20, 0x00, 0x20, 0x48, 0x20, 0x69, 0x11, 0x20, 0x02, 0x10, 0x20, 0x01, 0x02, 0x31,
+            0x09, 0x12,
+        ];
+        assert_same(&code);
+        let mut threaded = Stack::<i64>::new();
+        threaded.captured = Some(String::new());
+        threaded
+            .execute_threaded(&ThreadedCode::new(&code))
+            .unwrap();
+        assert_eq!(threaded.captured.unwrap(), "Hi\n2\n1\n");
+    }
+    #[test]
+    fn fused_arithmetic() {
+        assert_same(&[0x20, 0x01, 0x02]);
+        assert_same(&[0x20, 0x03, 0x20, 0x01, 0x02, 0x31, 0x02]);
+        assert_same(&[0x20, 0x00, 0x20, 0x01, 0x02, 0x31, 0xF0]);
+        // Jumping straight to the SUB skips the PUSH it is fused with
+        assert_same(&[0x20, 0x09, 0x20, 0x04, 0x30, 0x08, 0x20, 0x01, 0x02, 0x12]);
+    }
+    #[test]
+    fn jump_into_operand() {
+        // The JMP lands on the operand of the PUSH, which decodes as RET
+        assert_same(&[0x20, 0x12, 0x30, 0x01, 0xFF]);
+    }
+    #[test]
+    fn errors() {
+        assert_same(&[0x20, 0x05, 0x01]);
+        assert_same(&[0x12]);
+        assert_same(&[0x10]);
+        assert_same(&[0x20, 0x05, 0x42]);
+    }
+}
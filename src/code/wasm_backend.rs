@@ -0,0 +1,2220 @@
+/*!Compiler from bytecode to a WebAssembly module
+ *
+ * The module imports two host functions: `env.print(i64)` for PRINT and
+ * `env.pchar(ptr: i32, len: i32)` for PCHAR, which gets the collected bytes in
+ * the exported `memory` and decides how to show them. It exports `run() -> i64`,
+ * which returns the value of RET/EXIT, or 0 when the code runs off its end.
+ *
+ * The stack lives at the start of `memory` as up to `STACK_SIZE` `i64` cells,
+ * with its depth in the exported `sp` global. Frames opened by ENTER are pairs
+ * of `i32` base and size after the PCHAR scratch area, counted by the exported
+ * `frames` global, and the handlers installed by TRY follow them, counted by
+ * `handlers`. Jumps go through a dispatch loop over the basic blocks of the
+ * code. A runtime fault stores its kind, index and opcode in the `fault_kind`,
+ * `fault_idx` and `fault_op` globals, the slot of a LOAD or STORE or the local
+ * of a LOADLOCAL or STORELOCAL in `fault_slot` and the code of an uncaught THROW
+ * in `fault_code`, or the reach of a PICK or ROLL and the depth it failed at in
+ * those two, then traps, so the embedder can turn it back into a
+ * `StackError` with [`stack_error`].
+ *
+ * Wasm cannot unwind out of a helper function, so code that uses TRY checks
+ * every instruction for the faults it could raise before running it, while a
+ * handler is installed, and branches to the handler instead.
+ *
+ * Channels are ring buffers of `CHANNEL_SIZE` cells after the handlers. A
+ * coroutine that is switched out has its stack, frames and handlers copied to
+ * a save area of its own, up to `MAX_COROUTINES` of them, and goes on at the
+ * dispatch arm it was left at when it is copied back in. A deadlock leaves the
+ * number of blocked coroutines in `fault_slot` and their ids and channels in
+ * `memory`, and an ASSERTEQ that fails leaves the two values it compared in the
+ * same place, which [`stack_error`] reads back.
+ */
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, EntityType, ExportKind, ExportSection, Function,
+    FunctionSection, GlobalSection, GlobalType, ImportSection, InstructionSink, MemArg,
+    MemorySection, MemoryType, Module, TypeSection, ValType,
+};
+
+use crate::{
+    code::instruction::{Instruction, reachable},
+    stack::stack_error::StackError,
+};
+
+///Number of `i64` cells available to the stack
+pub const STACK_SIZE: i32 = 4096;
+///End of the scratch area PCHAR collects its bytes in, right after the stack
+const TEXT_END: i32 = 65536;
+///Frames opened by ENTER start right after the scratch area
+const FRAMES_START: u64 = TEXT_END as u64;
+pub const MAX_FRAMES: i32 = 256;
+///Handlers installed by TRY start right after the frames, each an `i32` arm of
+///the dispatch loop, stack depth and frame count
+const HANDLERS_START: u64 = FRAMES_START + MAX_FRAMES as u64 * 8;
+pub const MAX_HANDLERS: i32 = 256;
+///Head and count of each channel start right after the handlers, as `i32` pairs
+const CHANNELS_START: u64 = HANDLERS_START + MAX_HANDLERS as u64 * 16;
+pub const CHANNEL_SIZE: i32 = 64;
+///Ring buffers of the channels, `CHANNEL_SIZE` cells for each of the 256
+const CHANNEL_CELLS_START: u64 = CHANNELS_START + 256 * 8;
+///Save areas of the waiting coroutines, by position in the queue, as `i32`s
+const QUEUE_START: u64 = CHANNEL_CELLS_START + 256 * CHANNEL_SIZE as u64 * 8;
+pub const MAX_COROUTINES: i32 = 16;
+///Id and channel of each coroutine in a deadlock, as `i32` pairs, or the values of a failed ASSERTEQ
+const BLOCKED_START: u64 = QUEUE_START + MAX_COROUTINES as u64 * 4;
+///Save areas of the coroutines that are not running
+const SAVED_START: u64 = BLOCKED_START + MAX_COROUTINES as u64 * 8;
+///Id, dispatch arm, depth, frames, handlers, channel it waits on, whether it is in use and the
+///address it was switched out at, then copies of the stack, frames and handlers
+const CONTEXT_SIZE: i32 = 32 + STACK_SIZE * 8 + MAX_FRAMES * 8 + MAX_HANDLERS * 16;
+const SAVED_STACK: i32 = 32;
+const SAVED_FRAMES: i32 = SAVED_STACK + STACK_SIZE * 8;
+const SAVED_HANDLERS: i32 = SAVED_FRAMES + MAX_FRAMES * 8;
+const MEMORY_PAGES: u64 = (SAVED_START + (MAX_COROUTINES * CONTEXT_SIZE) as u64).div_ceil(65536);
+
+// Fault kinds of the `StackError`s double as the error codes TRY handlers receive
+pub const FAULT_EMPTY_STACK: i32 = 1;
+pub const FAULT_UNKNOWN_OP: i32 = 2;
+pub const FAULT_FULL_STACK: i32 = 3;
+pub const FAULT_DIVISION_BY_ZERO: i32 = 4;
+pub const FAULT_SLOT_OUT_OF_RANGE: i32 = 5;
+pub const FAULT_NO_FRAME: i32 = 6;
+pub const FAULT_LOCAL_OUT_OF_RANGE: i32 = 7;
+pub const FAULT_NO_HANDLER: i32 = 8;
+pub const FAULT_UNCAUGHT: i32 = 9;
+pub const FAULT_DEADLOCK: i32 = 10;
+pub const FAULT_ASSERTION: i32 = 11;
+pub const FAULT_OUT_OF_REACH: i32 = 12;
+pub const FAULT_OVERFLOW: i32 = 13;
+
+// Function indices, the imports come first
+const PRINT: u32 = 0;
+const PCHAR: u32 = 1;
+const FAULT: u32 = 2;
+const POP: u32 = 3;
+const PUSH: u32 = 4;
+const TOP: u32 = 5;
+const TRUTHY: u32 = 6;
+const CHECK_DIVISOR: u32 = 7;
+const PRINT_CHARS: u32 = 8;
+const ENTER: u32 = 9;
+const LOCAL: u32 = 10;
+const LEAVE: u32 = 11;
+const TRY_HANDLER: u32 = 12;
+const END_TRY: u32 = 13;
+const CATCH: u32 = 14;
+const SPAWN: u32 = 15;
+const SEND: u32 = 16;
+const RECEIVE: u32 = 17;
+const RESCHEDULE: u32 = 18;
+const OVERFLOWS: u32 = 19;
+const RUN: u32 = 20;
+
+// Global indices
+const SP: u32 = 0;
+const FAULT_KIND: u32 = 1;
+const FAULT_IDX: u32 = 2;
+const FAULT_OP: u32 = 3;
+const FAULT_SLOT: u32 = 4;
+const FRAMES: u32 = 5;
+const HANDLERS: u32 = 6;
+const FAULT_CODE: u32 = 7;
+const CURRENT: u32 = 8;
+const NEXT_ID: u32 = 9;
+const WAITING: u32 = 10;
+
+const CELL: MemArg = MemArg {
+    offset: 0,
+    align: 3,
+    memory_index: 0,
+};
+const BYTE: MemArg = MemArg {
+    offset: 0,
+    align: 0,
+    memory_index: 0,
+};
+///Base of the frame at the address, relative to `FRAMES_START`
+const FRAME_BASE: MemArg = MemArg {
+    offset: FRAMES_START,
+    align: 2,
+    memory_index: 0,
+};
+const FRAME_SIZE: MemArg = MemArg {
+    offset: FRAMES_START + 4,
+    align: 2,
+    memory_index: 0,
+};
+///Dispatch arm of the handler at the address, relative to `HANDLERS_START`
+const HANDLER_ARM: MemArg = MemArg {
+    offset: HANDLERS_START,
+    align: 2,
+    memory_index: 0,
+};
+const HANDLER_DEPTH: MemArg = MemArg {
+    offset: HANDLERS_START + 4,
+    align: 2,
+    memory_index: 0,
+};
+const HANDLER_FRAMES: MemArg = MemArg {
+    offset: HANDLERS_START + 8,
+    align: 2,
+    memory_index: 0,
+};
+///Head of the channel at the address, relative to `CHANNELS_START`
+const CHANNEL_HEAD: MemArg = word(CHANNELS_START);
+const CHANNEL_COUNT: MemArg = word(CHANNELS_START + 4);
+const CHANNEL_CELL: MemArg = MemArg {
+    offset: CHANNEL_CELLS_START,
+    align: 3,
+    memory_index: 0,
+};
+const QUEUE: MemArg = word(QUEUE_START);
+const BLOCKED_ID: MemArg = word(BLOCKED_START);
+const BLOCKED_CHANNEL: MemArg = word(BLOCKED_START + 4);
+const NOT_EQUAL_LEFT: MemArg = MemArg {
+    offset: BLOCKED_START,
+    align: 3,
+    memory_index: 0,
+};
+const NOT_EQUAL_RIGHT: MemArg = MemArg {
+    offset: BLOCKED_START + 8,
+    align: 3,
+    memory_index: 0,
+};
+///Fields of the save area at the address, relative to `SAVED_START`
+const SAVED_ID: MemArg = word(SAVED_START);
+const SAVED_ARM: MemArg = word(SAVED_START + 4);
+const SAVED_DEPTH: MemArg = word(SAVED_START + 8);
+const SAVED_FRAME_COUNT: MemArg = word(SAVED_START + 12);
+const SAVED_HANDLER_COUNT: MemArg = word(SAVED_START + 16);
+const SAVED_BLOCKED: MemArg = word(SAVED_START + 20);
+const SAVED_USED: MemArg = word(SAVED_START + 24);
+const SAVED_IDX: MemArg = word(SAVED_START + 28);
+
+const fn word(offset: u64) -> MemArg {
+    MemArg {
+        offset,
+        align: 2,
+        memory_index: 0,
+    }
+}
+
+///Rebuild the `StackError` a trapped module recorded in its fault globals and `memory`.
+///Returns `None` for faults the interpreter has no error for.
+pub fn stack_error(
+    kind: i32,
+    idx: i32,
+    op: i32,
+    slot: i32,
+    code: i64,
+    memory: &[u8],
+) -> Option<StackError> {
+    match kind {
+        FAULT_EMPTY_STACK => Some(StackError::EmptyStack {
+            idx: idx as usize,
+            op: op as u8,
+        }),
+        FAULT_UNKNOWN_OP => Some(StackError::UnknownOp {
+            idx: idx as usize,
+            byte: op as u8,
+        }),
+        FAULT_SLOT_OUT_OF_RANGE => Some(StackError::SlotOutOfRange {
+            idx: idx as usize,
+            op: op as u8,
+            slot: slot as usize,
+        }),
+        FAULT_NO_FRAME => Some(StackError::NoFrame {
+            idx: idx as usize,
+            op: op as u8,
+        }),
+        FAULT_LOCAL_OUT_OF_RANGE => Some(StackError::LocalOutOfRange {
+            idx: idx as usize,
+            op: op as u8,
+            local: slot as usize,
+        }),
+        FAULT_DIVISION_BY_ZERO => Some(StackError::DivisionByZero {
+            idx: idx as usize,
+            op: op as u8,
+        }),
+        FAULT_NO_HANDLER => Some(StackError::NoHandler {
+            idx: idx as usize,
+            op: op as u8,
+        }),
+        FAULT_UNCAUGHT => Some(StackError::Uncaught {
+            idx: idx as usize,
+            op: op as u8,
+            code: code.to_string(),
+        }),
+        FAULT_DEADLOCK => {
+            let word = |address: usize| {
+                i32::from_le_bytes(memory[address..address + 4].try_into().unwrap())
+            };
+            let mut blocked: Vec<(usize, u8)> = (0..slot as usize)
+                .map(|position| {
+                    let address = BLOCKED_START as usize + position * 8;
+                    (word(address) as usize, word(address + 4) as u8)
+                })
+                .collect();
+            blocked.sort_unstable();
+            Some(StackError::Deadlock {
+                idx: idx as usize,
+                op: op as u8,
+                blocked,
+            })
+        }
+        FAULT_ASSERTION if op == 0x41 => {
+            let cell = |address: usize| {
+                i64::from_le_bytes(memory[address..address + 8].try_into().unwrap())
+            };
+            Some(StackError::NotEqual {
+                idx: idx as usize,
+                op: op as u8,
+                left: cell(BLOCKED_START as usize).to_string(),
+                right: cell(BLOCKED_START as usize + 8).to_string(),
+            })
+        }
+        FAULT_ASSERTION => Some(StackError::AssertionFailed {
+            idx: idx as usize,
+            op: op as u8,
+        }),
+        FAULT_OUT_OF_REACH => Some(StackError::OutOfReach {
+            idx: idx as usize,
+            op: op as u8,
+            n: slot as usize,
+            depth: code as usize,
+        }),
+        FAULT_OVERFLOW => Some(StackError::Overflow {
+            idx: idx as usize,
+            op: op as u8,
+        }),
+        _ => None,
+    }
+}
+
+///Compile bytecode into the binary encoding of a WebAssembly module
+pub fn compile_wasm(code: &[u8]) -> Vec<u8> {
+    let mut types = TypeSection::new();
+    let signatures: [(&[ValType], &[ValType]); 13] = [
+        (&[ValType::I64], &[]),
+        (&[ValType::I32, ValType::I32], &[]),
+        (&[ValType::I32, ValType::I32, ValType::I32], &[]),
+        (&[ValType::I32, ValType::I32], &[ValType::I64]),
+        (&[ValType::I64, ValType::I32, ValType::I32], &[]),
+        (&[], &[ValType::I32]),
+        (
+            &[ValType::I64, ValType::I64, ValType::I32, ValType::I32],
+            &[],
+        ),
+        (&[], &[ValType::I64]),
+        (&[ValType::I32, ValType::I32, ValType::I32], &[ValType::I32]),
+        (&[ValType::I64, ValType::I32, ValType::I32], &[ValType::I32]),
+        (
+            &[ValType::I64, ValType::I32, ValType::I32, ValType::I32],
+            &[],
+        ),
+        (&[ValType::I32; 5], &[ValType::I32]),
+        (&[ValType::I64, ValType::I64, ValType::I32], &[ValType::I32]),
+    ];
+    for (params, results) in signatures {
+        types
+            .ty()
+            .function(params.iter().copied(), results.iter().copied());
+    }
+
+    let mut imports = ImportSection::new();
+    imports.import("env", "print", EntityType::Function(0));
+    imports.import("env", "pchar", EntityType::Function(1));
+
+    let mut functions = FunctionSection::new();
+    // fault, pop, push, top, truthy, check_divisor, print_chars, enter, local, leave,
+    // try_handler, end_try, catch, spawn, send, receive, reschedule, overflows, run
+    for ty in [2, 3, 4, 3, 5, 6, 1, 2, 8, 1, 2, 1, 9, 2, 10, 8, 11, 12, 7] {
+        functions.function(ty);
+    }
+
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: MEMORY_PAGES,
+        maximum: None,
+        memory64: false,
+        shared: false,
+        page_size_log2: None,
+    });
+
+    let mut globals = GlobalSection::new();
+    for _ in [
+        SP, FAULT_KIND, FAULT_IDX, FAULT_OP, FAULT_SLOT, FRAMES, HANDLERS,
+    ] {
+        globals.global(
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+                shared: false,
+            },
+            &ConstExpr::i32_const(0),
+        );
+    }
+    globals.global(
+        GlobalType {
+            val_type: ValType::I64,
+            mutable: true,
+            shared: false,
+        },
+        &ConstExpr::i64_const(0),
+    );
+    for initial in [0, 1, 0] {
+        globals.global(
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+                shared: false,
+            },
+            &ConstExpr::i32_const(initial),
+        );
+    }
+
+    let mut exports = ExportSection::new();
+    exports.export("run", ExportKind::Func, RUN);
+    exports.export("memory", ExportKind::Memory, 0);
+    exports.export("sp", ExportKind::Global, SP);
+    exports.export("fault_kind", ExportKind::Global, FAULT_KIND);
+    exports.export("fault_idx", ExportKind::Global, FAULT_IDX);
+    exports.export("fault_op", ExportKind::Global, FAULT_OP);
+    exports.export("fault_slot", ExportKind::Global, FAULT_SLOT);
+    exports.export("frames", ExportKind::Global, FRAMES);
+    exports.export("handlers", ExportKind::Global, HANDLERS);
+    exports.export("fault_code", ExportKind::Global, FAULT_CODE);
+
+    let mut codes = CodeSection::new();
+    codes.function(&fault());
+    codes.function(&pop());
+    codes.function(&push());
+    codes.function(&top());
+    codes.function(&truthy());
+    codes.function(&check_divisor());
+    codes.function(&print_chars());
+    codes.function(&enter());
+    codes.function(&local());
+    codes.function(&leave());
+    codes.function(&try_handler());
+    codes.function(&end_try());
+    codes.function(&catch());
+    codes.function(&spawn());
+    codes.function(&send());
+    codes.function(&receive());
+    codes.function(&reschedule());
+    codes.function(&overflows());
+    codes.function(&run(code));
+
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&imports)
+        .section(&functions)
+        .section(&memories)
+        .section(&globals)
+        .section(&exports)
+        .section(&codes);
+    module.finish()
+}
+
+///Fault with the kind, index and opcode in its parameters
+fn fault() -> Function {
+    let mut f = Function::new([]);
+    f.instructions()
+        .local_get(0)
+        .global_set(FAULT_KIND)
+        .local_get(1)
+        .global_set(FAULT_IDX)
+        .local_get(2)
+        .global_set(FAULT_OP)
+        .unreachable()
+        .end();
+    f
+}
+
+///Emit a fault of `kind` when the condition on top of the wasm stack holds,
+///with the index and opcode in locals 0 and 1 of the current function
+fn fault_if(sink: &mut InstructionSink, kind: i32, idx: u32, op: u32) {
+    sink.if_(BlockType::Empty)
+        .i32_const(kind)
+        .local_get(idx)
+        .local_get(op)
+        .call(FAULT)
+        .end();
+}
+
+///Address of the cell `below` cells under the top of the stack
+fn cell_address(sink: &mut InstructionSink, below: i32) {
+    sink.global_get(SP)
+        .i32_const(below)
+        .i32_sub()
+        .i32_const(8)
+        .i32_mul();
+}
+
+fn pop() -> Function {
+    let mut f = Function::new([]);
+    let mut sink = f.instructions();
+    sink.global_get(SP).i32_eqz();
+    fault_if(&mut sink, FAULT_EMPTY_STACK, 0, 1);
+    sink.global_get(SP).i32_const(1).i32_sub().global_set(SP);
+    cell_address(&mut sink, 0);
+    sink.i64_load(CELL).end();
+    f
+}
+
+fn push() -> Function {
+    let mut f = Function::new([]);
+    let mut sink = f.instructions();
+    sink.global_get(SP).i32_const(STACK_SIZE).i32_eq();
+    fault_if(&mut sink, FAULT_FULL_STACK, 1, 2);
+    cell_address(&mut sink, 0);
+    sink.local_get(0)
+        .i64_store(CELL)
+        .global_get(SP)
+        .i32_const(1)
+        .i32_add()
+        .global_set(SP)
+        .end();
+    f
+}
+
+fn top() -> Function {
+    let mut f = Function::new([]);
+    let mut sink = f.instructions();
+    sink.global_get(SP).i32_eqz();
+    fault_if(&mut sink, FAULT_EMPTY_STACK, 0, 1);
+    cell_address(&mut sink, 1);
+    sink.i64_load(CELL).end();
+    f
+}
+
+///Whether JNZ jumps: the stack is not empty and its top is not 0
+fn truthy() -> Function {
+    let mut f = Function::new([]);
+    let mut sink = f.instructions();
+    sink.global_get(SP).if_(BlockType::Result(ValType::I32));
+    cell_address(&mut sink, 1);
+    sink.i64_load(CELL)
+        .i64_const(0)
+        .i64_ne()
+        .else_()
+        .i32_const(0)
+        .end()
+        .end();
+    f
+}
+
+///Fault on division by zero and on division overflow, which wasm would either trap on
+///without a fault kind or, for the remainder, not trap on at all
+fn check_divisor() -> Function {
+    let mut f = Function::new([]);
+    let mut sink = f.instructions();
+    sink.local_get(1).i64_eqz();
+    fault_if(&mut sink, FAULT_DIVISION_BY_ZERO, 2, 3);
+    sink.local_get(0)
+        .i64_const(i64::MIN)
+        .i64_eq()
+        .local_get(1)
+        .i64_const(-1)
+        .i64_eq()
+        .i32_and();
+    fault_if(&mut sink, FAULT_OVERFLOW, 2, 3);
+    sink.end();
+    f
+}
+
+///Whether ADD, SUB or MUL, told apart by their opcode, overflows with `lhs` and `rhs`, which
+///wasm would wrap around
+fn overflows() -> Function {
+    let (lhs, rhs, op, result) = (0, 1, 2, 3);
+    let mut f = Function::new([(1, ValType::I64)]);
+    let mut sink = f.instructions();
+    // The sum overflows when it has the sign of neither operand, the difference when the
+    // operands differ in sign and it has the sign of the right one
+    sink.local_get(op)
+        .i32_const(0x01)
+        .i32_eq()
+        .if_(BlockType::Result(ValType::I32))
+        .local_get(lhs)
+        .local_get(rhs)
+        .i64_add()
+        .local_set(result)
+        .local_get(lhs)
+        .local_get(result)
+        .i64_xor()
+        .local_get(rhs)
+        .local_get(result)
+        .i64_xor()
+        .i64_and()
+        .i64_const(0)
+        .i64_lt_s()
+        .else_()
+        .local_get(op)
+        .i32_const(0x02)
+        .i32_eq()
+        .if_(BlockType::Result(ValType::I32))
+        .local_get(lhs)
+        .local_get(rhs)
+        .i64_sub()
+        .local_set(result)
+        .local_get(lhs)
+        .local_get(rhs)
+        .i64_xor()
+        .local_get(lhs)
+        .local_get(result)
+        .i64_xor()
+        .i64_and()
+        .i64_const(0)
+        .i64_lt_s()
+        .else_();
+    // The product overflows when dividing it by `lhs` does not give `rhs` back, except for
+    // -1 * MIN, where that division would trap
+    sink.local_get(lhs)
+        .i64_const(-1)
+        .i64_eq()
+        .local_get(rhs)
+        .i64_const(i64::MIN)
+        .i64_eq()
+        .i32_and()
+        .if_(BlockType::Result(ValType::I32))
+        .i32_const(1)
+        .else_()
+        .local_get(lhs)
+        .i64_eqz()
+        .if_(BlockType::Result(ValType::I32))
+        .i32_const(0)
+        .else_()
+        .local_get(lhs)
+        .local_get(rhs)
+        .i64_mul()
+        .local_get(lhs)
+        .i64_div_s()
+        .local_get(rhs)
+        .i64_ne()
+        .end()
+        .end()
+        .end()
+        .end()
+        .end();
+    f
+}
+
+///Pop bytes down to the next 0, hand them to the host and push them back
+fn print_chars() -> Function {
+    // Locals after idx and op: length, character, counter
+    let (length, character, counter) = (2, 3, 4);
+    let mut f = Function::new([(1, ValType::I32), (1, ValType::I64), (1, ValType::I32)]);
+    let mut sink = f.instructions();
+
+    // The bytes are written backwards from TEXT_END, so they end up in order
+    sink.block(BlockType::Empty)
+        .loop_(BlockType::Empty)
+        .global_get(SP)
+        .i32_eqz()
+        .br_if(1)
+        .global_get(SP)
+        .i32_const(1)
+        .i32_sub()
+        .global_set(SP);
+    cell_address(&mut sink, 0);
+    sink.i64_load(CELL)
+        .local_tee(character)
+        .i64_eqz()
+        .br_if(1)
+        .local_get(character)
+        .i64_const(0xFF)
+        .i64_le_u()
+        .if_(BlockType::Empty)
+        .i32_const(TEXT_END - 1)
+        .local_get(length)
+        .i32_sub()
+        .local_get(character)
+        .i64_store8(BYTE)
+        .local_get(length)
+        .i32_const(1)
+        .i32_add()
+        .local_set(length)
+        .end()
+        .br(0)
+        .end()
+        .end();
+
+    sink.i32_const(TEXT_END)
+        .local_get(length)
+        .i32_sub()
+        .local_get(length)
+        .call(PCHAR);
+
+    sink.block(BlockType::Empty)
+        .loop_(BlockType::Empty)
+        .local_get(counter)
+        .local_get(length)
+        .i32_ge_u()
+        .br_if(1)
+        .i32_const(TEXT_END)
+        .local_get(length)
+        .i32_sub()
+        .local_get(counter)
+        .i32_add()
+        .i64_load8_u(BYTE)
+        .local_get(0)
+        .local_get(1)
+        .call(PUSH)
+        .local_get(counter)
+        .i32_const(1)
+        .i32_add()
+        .local_set(counter)
+        .br(0)
+        .end()
+        .end()
+        .end();
+    f
+}
+
+///Address of the innermost frame, relative to `FRAMES_START`
+fn frame_address(sink: &mut InstructionSink) {
+    sink.global_get(FRAMES)
+        .i32_const(1)
+        .i32_sub()
+        .i32_const(8)
+        .i32_mul();
+}
+
+///Open a frame of the size in local 0 and push that many zeros
+fn enter() -> Function {
+    let counter = 3;
+    let mut f = Function::new([(1, ValType::I32)]);
+    let mut sink = f.instructions();
+    sink.global_get(FRAMES).i32_const(MAX_FRAMES).i32_eq();
+    fault_if(&mut sink, FAULT_FULL_STACK, 1, 2);
+    sink.global_get(FRAMES)
+        .i32_const(1)
+        .i32_add()
+        .global_set(FRAMES);
+    frame_address(&mut sink);
+    sink.global_get(SP).i32_store(FRAME_BASE);
+    frame_address(&mut sink);
+    sink.local_get(0).i32_store(FRAME_SIZE);
+
+    sink.block(BlockType::Empty)
+        .loop_(BlockType::Empty)
+        .local_get(counter)
+        .local_get(0)
+        .i32_ge_u()
+        .br_if(1)
+        .i64_const(0)
+        .local_get(1)
+        .local_get(2)
+        .call(PUSH)
+        .local_get(counter)
+        .i32_const(1)
+        .i32_add()
+        .local_set(counter)
+        .br(0)
+        .end()
+        .end()
+        .end();
+    f
+}
+
+///Memory address of the local in local 0, faulting like `Stack::local`
+fn local() -> Function {
+    let base = 3;
+    let mut f = Function::new([(1, ValType::I32)]);
+    let mut sink = f.instructions();
+    sink.global_get(FRAMES).i32_eqz();
+    fault_if(&mut sink, FAULT_NO_FRAME, 1, 2);
+    frame_address(&mut sink);
+    sink.i32_load(FRAME_BASE).local_set(base).local_get(0);
+    frame_address(&mut sink);
+    sink.i32_load(FRAME_SIZE)
+        .i32_ge_u()
+        .local_get(base)
+        .local_get(0)
+        .i32_add()
+        .global_get(SP)
+        .i32_ge_u()
+        .i32_or()
+        .if_(BlockType::Empty)
+        .local_get(0)
+        .global_set(FAULT_SLOT)
+        .i32_const(FAULT_LOCAL_OUT_OF_RANGE)
+        .local_get(1)
+        .local_get(2)
+        .call(FAULT)
+        .end()
+        .local_get(base)
+        .local_get(0)
+        .i32_add()
+        .i32_const(8)
+        .i32_mul()
+        .end();
+    f
+}
+
+///Drop the locals of the innermost frame, moving what was pushed above them down
+fn leave() -> Function {
+    let (base, size, from) = (2, 3, 4);
+    let mut f = Function::new([(3, ValType::I32)]);
+    let mut sink = f.instructions();
+    sink.global_get(FRAMES).i32_eqz();
+    fault_if(&mut sink, FAULT_NO_FRAME, 0, 1);
+    frame_address(&mut sink);
+    sink.i32_load(FRAME_BASE).local_set(base);
+    frame_address(&mut sink);
+    sink.i32_load(FRAME_SIZE)
+        .local_set(size)
+        .global_get(SP)
+        .local_get(base)
+        .local_get(size)
+        .i32_add()
+        .local_tee(from)
+        .i32_lt_u();
+    fault_if(&mut sink, FAULT_EMPTY_STACK, 0, 1);
+
+    sink.block(BlockType::Empty)
+        .loop_(BlockType::Empty)
+        .local_get(from)
+        .global_get(SP)
+        .i32_ge_u()
+        .br_if(1)
+        .local_get(from)
+        .local_get(size)
+        .i32_sub()
+        .i32_const(8)
+        .i32_mul()
+        .local_get(from)
+        .i32_const(8)
+        .i32_mul()
+        .i64_load(CELL)
+        .i64_store(CELL)
+        .local_get(from)
+        .i32_const(1)
+        .i32_add()
+        .local_set(from)
+        .br(0)
+        .end()
+        .end()
+        .global_get(SP)
+        .local_get(size)
+        .i32_sub()
+        .global_set(SP)
+        .global_get(FRAMES)
+        .i32_const(1)
+        .i32_sub()
+        .global_set(FRAMES)
+        .end();
+    f
+}
+
+///Address of the innermost handler, relative to `HANDLERS_START`
+fn handler_address(sink: &mut InstructionSink) {
+    sink.global_get(HANDLERS)
+        .i32_const(1)
+        .i32_sub()
+        .i32_const(16)
+        .i32_mul();
+}
+
+///Install a handler at the dispatch arm in local 0, saving the stack depth and frame count
+fn try_handler() -> Function {
+    let mut f = Function::new([]);
+    let mut sink = f.instructions();
+    sink.global_get(HANDLERS).i32_const(MAX_HANDLERS).i32_eq();
+    fault_if(&mut sink, FAULT_FULL_STACK, 1, 2);
+    sink.global_get(HANDLERS)
+        .i32_const(1)
+        .i32_add()
+        .global_set(HANDLERS);
+    handler_address(&mut sink);
+    sink.local_get(0).i32_store(HANDLER_ARM);
+    handler_address(&mut sink);
+    sink.global_get(SP).i32_store(HANDLER_DEPTH);
+    handler_address(&mut sink);
+    sink.global_get(FRAMES).i32_store(HANDLER_FRAMES).end();
+    f
+}
+
+fn end_try() -> Function {
+    let mut f = Function::new([]);
+    let mut sink = f.instructions();
+    sink.global_get(HANDLERS).i32_eqz();
+    fault_if(&mut sink, FAULT_NO_HANDLER, 0, 1);
+    sink.global_get(HANDLERS)
+        .i32_const(1)
+        .i32_sub()
+        .global_set(HANDLERS)
+        .end();
+    f
+}
+
+///Remove the innermost handler, unwind the stack and frames to what they were at its
+///TRY and push the error code in local 0. Returns the dispatch arm of the handler.
+fn catch() -> Function {
+    let (address, saved) = (3, 4);
+    let mut f = Function::new([(2, ValType::I32)]);
+    let mut sink = f.instructions();
+    handler_address(&mut sink);
+    sink.local_set(address)
+        .global_get(HANDLERS)
+        .i32_const(1)
+        .i32_sub()
+        .global_set(HANDLERS);
+    for (field, global) in [(HANDLER_DEPTH, SP), (HANDLER_FRAMES, FRAMES)] {
+        sink.local_get(address)
+            .i32_load(field)
+            .local_tee(saved)
+            .global_get(global)
+            .local_get(saved)
+            .global_get(global)
+            .i32_lt_u()
+            .select()
+            .global_set(global);
+    }
+    sink.local_get(0)
+        .local_get(1)
+        .local_get(2)
+        .call(PUSH)
+        .local_get(address)
+        .i32_load(HANDLER_ARM)
+        .end();
+    f
+}
+
+///Address of the save area in `slot`, relative to `SAVED_START`
+fn context_address(sink: &mut InstructionSink, slot: u32) {
+    sink.local_get(slot).i32_const(CONTEXT_SIZE).i32_mul();
+}
+
+///Leave the first save area that is not in use in `slot`
+fn free_context(sink: &mut InstructionSink, slot: u32) {
+    sink.i32_const(0)
+        .local_set(slot)
+        .block(BlockType::Empty)
+        .loop_(BlockType::Empty);
+    context_address(sink, slot);
+    sink.i32_load(SAVED_USED)
+        .i32_eqz()
+        .br_if(1)
+        .local_get(slot)
+        .i32_const(1)
+        .i32_add()
+        .local_set(slot)
+        .br(0)
+        .end()
+        .end();
+}
+
+///Append the save area in `slot` to the queue
+fn enqueue(sink: &mut InstructionSink, slot: u32) {
+    sink.global_get(WAITING)
+        .i32_const(4)
+        .i32_mul()
+        .local_get(slot)
+        .i32_store(QUEUE)
+        .global_get(WAITING)
+        .i32_const(1)
+        .i32_add()
+        .global_set(WAITING);
+}
+
+///Copy the stack, frames and handlers to the save area in `slot`, or back from it
+fn copy_context(sink: &mut InstructionSink, slot: u32, save: bool) {
+    for (saved, live, global, size) in [
+        (SAVED_STACK, 0, SP, 8),
+        (SAVED_FRAMES, FRAMES_START as i32, FRAMES, 8),
+        (SAVED_HANDLERS, HANDLERS_START as i32, HANDLERS, 16),
+    ] {
+        let area = |sink: &mut InstructionSink| {
+            context_address(sink, slot);
+            sink.i32_const(SAVED_START as i32 + saved).i32_add();
+        };
+        if save {
+            area(sink);
+            sink.i32_const(live);
+        } else {
+            sink.i32_const(live);
+            area(sink);
+        }
+        sink.global_get(global)
+            .i32_const(size)
+            .i32_mul()
+            .memory_copy(0, 0);
+    }
+}
+
+///Queue a coroutine starting at the dispatch arm in local 0 with an empty stack
+fn spawn() -> Function {
+    let slot = 3;
+    let mut f = Function::new([(1, ValType::I32)]);
+    let mut sink = f.instructions();
+    sink.global_get(WAITING)
+        .i32_const(MAX_COROUTINES - 1)
+        .i32_eq();
+    fault_if(&mut sink, FAULT_FULL_STACK, 1, 2);
+    free_context(&mut sink, slot);
+    context_address(&mut sink, slot);
+    sink.global_get(NEXT_ID)
+        .i32_store(SAVED_ID)
+        .global_get(NEXT_ID)
+        .i32_const(1)
+        .i32_add()
+        .global_set(NEXT_ID);
+    for (field, value) in [
+        (SAVED_USED, 1),
+        (SAVED_DEPTH, 0),
+        (SAVED_FRAME_COUNT, 0),
+        (SAVED_HANDLER_COUNT, 0),
+        (SAVED_BLOCKED, -1),
+    ] {
+        context_address(&mut sink, slot);
+        sink.i32_const(value).i32_store(field);
+    }
+    context_address(&mut sink, slot);
+    sink.local_get(0).i32_store(SAVED_ARM);
+    enqueue(&mut sink, slot);
+    sink.end();
+    f
+}
+
+///Address of the channel in `channel`, relative to `CHANNELS_START`
+fn channel_address(sink: &mut InstructionSink, channel: u32) {
+    sink.local_get(channel).i32_const(8).i32_mul();
+}
+
+///Turn the position on top of the wasm stack into the address of its cell in the ring
+///buffer of the channel in `channel`, relative to `CHANNEL_CELLS_START`
+fn channel_cell(sink: &mut InstructionSink, channel: u32) {
+    sink.i32_const(CHANNEL_SIZE)
+        .i32_rem_u()
+        .local_get(channel)
+        .i32_const(CHANNEL_SIZE)
+        .i32_mul()
+        .i32_add()
+        .i32_const(8)
+        .i32_mul();
+}
+
+///Append the value in local 0 to the channel in local 1
+fn send() -> Function {
+    let count = 4;
+    let mut f = Function::new([(1, ValType::I32)]);
+    let mut sink = f.instructions();
+    channel_address(&mut sink, 1);
+    sink.i32_load(CHANNEL_COUNT)
+        .local_tee(count)
+        .i32_const(CHANNEL_SIZE)
+        .i32_eq();
+    fault_if(&mut sink, FAULT_FULL_STACK, 2, 3);
+    channel_address(&mut sink, 1);
+    sink.i32_load(CHANNEL_HEAD).local_get(count).i32_add();
+    channel_cell(&mut sink, 1);
+    sink.local_get(0).i64_store(CHANNEL_CELL);
+    channel_address(&mut sink, 1);
+    sink.local_get(count)
+        .i32_const(1)
+        .i32_add()
+        .i32_store(CHANNEL_COUNT)
+        .end();
+    f
+}
+
+///Push the oldest value of the channel in local 0. Returns 0 when there is none.
+fn receive() -> Function {
+    let head = 3;
+    let mut f = Function::new([(1, ValType::I32)]);
+    let mut sink = f.instructions();
+    channel_address(&mut sink, 0);
+    sink.i32_load(CHANNEL_COUNT)
+        .i32_eqz()
+        .if_(BlockType::Empty)
+        .i32_const(0)
+        .return_()
+        .end();
+    channel_address(&mut sink, 0);
+    sink.i32_load(CHANNEL_HEAD).local_tee(head);
+    channel_cell(&mut sink, 0);
+    sink.i64_load(CHANNEL_CELL)
+        .local_get(1)
+        .local_get(2)
+        .call(PUSH);
+    channel_address(&mut sink, 0);
+    sink.local_get(head)
+        .i32_const(1)
+        .i32_add()
+        .i32_const(CHANNEL_SIZE)
+        .i32_rem_u()
+        .i32_store(CHANNEL_HEAD);
+    channel_address(&mut sink, 0);
+    channel_address(&mut sink, 0);
+    sink.i32_load(CHANNEL_COUNT)
+        .i32_const(1)
+        .i32_sub()
+        .i32_store(CHANNEL_COUNT)
+        .i32_const(1)
+        .end();
+    f
+}
+
+///Swap in the first waiting coroutine that can run, queueing the current one to go on at
+///the dispatch arm in local 0 unless local 2 says it has finished. Local 1 is the channel
+///it waits to receive from, or -1. Returns the dispatch arm to go on at, or -2 when the
+///current one only yields and no other can run.
+fn reschedule() -> Function {
+    let (resume, blocked, finished, idx, op) = (0, 1, 2, 3, 4);
+    let (position, slot, next, on, count) = (5, 6, 7, 8, 9);
+    let mut f = Function::new([(5, ValType::I32)]);
+    let mut sink = f.instructions();
+    let queued = |sink: &mut InstructionSink, position: u32| {
+        sink.local_get(position)
+            .i32_const(4)
+            .i32_mul()
+            .i32_load(QUEUE);
+    };
+
+    sink.block(BlockType::Empty)
+        .loop_(BlockType::Empty)
+        .local_get(position)
+        .global_get(WAITING)
+        .i32_ge_u()
+        .br_if(1);
+    queued(&mut sink, position);
+    sink.local_set(slot);
+    context_address(&mut sink, slot);
+    sink.i32_load(SAVED_BLOCKED)
+        .local_tee(on)
+        .i32_const(0)
+        .i32_lt_s()
+        .br_if(1)
+        .local_get(on)
+        .i32_const(8)
+        .i32_mul()
+        .i32_load(CHANNEL_COUNT)
+        .br_if(1)
+        .local_get(position)
+        .i32_const(1)
+        .i32_add()
+        .local_set(position)
+        .br(0)
+        .end()
+        .end();
+
+    // No coroutine can run
+    sink.local_get(position)
+        .global_get(WAITING)
+        .i32_eq()
+        .if_(BlockType::Empty)
+        .local_get(blocked)
+        .i32_const(0)
+        .i32_lt_s()
+        .local_get(finished)
+        .i32_eqz()
+        .i32_and()
+        .if_(BlockType::Empty)
+        .i32_const(-2)
+        .return_()
+        .end()
+        // A finished coroutine is not waiting, so the RECV the first waiting one runs again is
+        // reported instead
+        .local_get(blocked)
+        .i32_const(0)
+        .i32_lt_s()
+        .if_(BlockType::Empty)
+        .i32_const(0)
+        .i32_load(QUEUE)
+        .i32_const(CONTEXT_SIZE)
+        .i32_mul()
+        .i32_load(SAVED_IDX)
+        .local_set(idx)
+        .i32_const(0x53)
+        .local_set(op)
+        .end()
+        .global_get(HANDLERS)
+        .if_(BlockType::Empty)
+        .i64_const(FAULT_DEADLOCK as i64)
+        .local_get(idx)
+        .local_get(op)
+        .call(CATCH)
+        .return_()
+        .end()
+        .local_get(blocked)
+        .i32_const(0)
+        .i32_ge_s()
+        .if_(BlockType::Empty)
+        .i32_const(0)
+        .global_get(CURRENT)
+        .i32_store(BLOCKED_ID)
+        .i32_const(0)
+        .local_get(blocked)
+        .i32_store(BLOCKED_CHANNEL)
+        .i32_const(1)
+        .local_set(count)
+        .end()
+        .i32_const(0)
+        .local_set(position)
+        .block(BlockType::Empty)
+        .loop_(BlockType::Empty)
+        .local_get(position)
+        .global_get(WAITING)
+        .i32_ge_u()
+        .br_if(1);
+    queued(&mut sink, position);
+    sink.local_set(slot);
+    for (field, into) in [(SAVED_ID, BLOCKED_ID), (SAVED_BLOCKED, BLOCKED_CHANNEL)] {
+        sink.local_get(count).i32_const(8).i32_mul();
+        context_address(&mut sink, slot);
+        sink.i32_load(field).i32_store(into);
+    }
+    sink.local_get(count)
+        .i32_const(1)
+        .i32_add()
+        .local_set(count)
+        .local_get(position)
+        .i32_const(1)
+        .i32_add()
+        .local_set(position)
+        .br(0)
+        .end()
+        .end()
+        .local_get(count)
+        .global_set(FAULT_SLOT)
+        .i32_const(FAULT_DEADLOCK)
+        .local_get(idx)
+        .local_get(op)
+        .call(FAULT)
+        .end();
+
+    // Take the next one out of the queue
+    queued(&mut sink, position);
+    sink.local_set(next)
+        .block(BlockType::Empty)
+        .loop_(BlockType::Empty)
+        .local_get(position)
+        .i32_const(1)
+        .i32_add()
+        .global_get(WAITING)
+        .i32_ge_u()
+        .br_if(1)
+        .local_get(position)
+        .i32_const(4)
+        .i32_mul()
+        .local_get(position)
+        .i32_const(1)
+        .i32_add()
+        .local_tee(position);
+    sink.i32_const(4)
+        .i32_mul()
+        .i32_load(QUEUE)
+        .i32_store(QUEUE)
+        .br(0)
+        .end()
+        .end()
+        .global_get(WAITING)
+        .i32_const(1)
+        .i32_sub()
+        .global_set(WAITING);
+
+    sink.local_get(finished).i32_eqz().if_(BlockType::Empty);
+    free_context(&mut sink, slot);
+    for (field, value) in [
+        (SAVED_USED, None),
+        (SAVED_ARM, Some(resume)),
+        (SAVED_BLOCKED, Some(blocked)),
+        (SAVED_IDX, Some(idx)),
+    ] {
+        context_address(&mut sink, slot);
+        match value {
+            Some(local) => sink.local_get(local),
+            None => sink.i32_const(1),
+        };
+        sink.i32_store(field);
+    }
+    for (field, global) in [
+        (SAVED_ID, CURRENT),
+        (SAVED_DEPTH, SP),
+        (SAVED_FRAME_COUNT, FRAMES),
+        (SAVED_HANDLER_COUNT, HANDLERS),
+    ] {
+        context_address(&mut sink, slot);
+        sink.global_get(global).i32_store(field);
+    }
+    copy_context(&mut sink, slot, true);
+    enqueue(&mut sink, slot);
+    sink.end();
+
+    for (field, global) in [
+        (SAVED_ID, CURRENT),
+        (SAVED_DEPTH, SP),
+        (SAVED_FRAME_COUNT, FRAMES),
+        (SAVED_HANDLER_COUNT, HANDLERS),
+    ] {
+        context_address(&mut sink, next);
+        sink.i32_load(field).global_set(global);
+    }
+    copy_context(&mut sink, next, false);
+    context_address(&mut sink, next);
+    sink.i32_const(0).i32_store(SAVED_USED);
+    context_address(&mut sink, next);
+    sink.i32_load(SAVED_ARM).end();
+    f
+}
+
+///Emit the checks for the faults `instruction` could raise, in the order the interpreter
+///finds them. `raise` gets each condition on top of the wasm stack with its fault kind and
+///the number of cells the interpreter pops before it notices the fault.
+fn guard(
+    sink: &mut InstructionSink,
+    instruction: Instruction,
+    raise: &dyn Fn(&mut InstructionSink, i32, i32),
+) {
+    let below = |sink: &mut InstructionSink, cells: i32| {
+        sink.global_get(SP).i32_const(cells).i32_lt_u();
+    };
+    let full = |sink: &mut InstructionSink, cells: i32| {
+        sink.global_get(SP).i32_const(STACK_SIZE - cells).i32_gt_s();
+    };
+    // Local out of range, with `popped` cells taken off the stack first
+    let local_range = |sink: &mut InstructionSink, local: u8, popped: i32| {
+        sink.i32_const(local as i32);
+        frame_address(sink);
+        sink.i32_load(FRAME_SIZE).i32_ge_u();
+        frame_address(sink);
+        sink.i32_load(FRAME_BASE)
+            .i32_const(local as i32 + popped)
+            .i32_add()
+            .global_get(SP)
+            .i32_ge_u()
+            .i32_or();
+    };
+    let no_frame = |sink: &mut InstructionSink| {
+        sink.global_get(FRAMES).i32_eqz();
+    };
+    match instruction {
+        Instruction::Add | Instruction::Sub | Instruction::Mul => {
+            below(sink, 2);
+            raise(sink, FAULT_EMPTY_STACK, 2);
+            cell_address(sink, 2);
+            sink.i64_load(CELL);
+            cell_address(sink, 1);
+            sink.i64_load(CELL)
+                .i32_const(instruction.opcode() as i32)
+                .call(OVERFLOWS);
+            raise(sink, FAULT_OVERFLOW, 2);
+        }
+        Instruction::Lt | Instruction::Swp => {
+            below(sink, 2);
+            raise(sink, FAULT_EMPTY_STACK, 2);
+        }
+        Instruction::Div | Instruction::Mod => {
+            below(sink, 2);
+            raise(sink, FAULT_EMPTY_STACK, 2);
+            cell_address(sink, 1);
+            sink.i64_load(CELL).i64_eqz();
+            raise(sink, FAULT_DIVISION_BY_ZERO, 2);
+            cell_address(sink, 2);
+            sink.i64_load(CELL).i64_const(i64::MIN).i64_eq();
+            cell_address(sink, 1);
+            sink.i64_load(CELL).i64_const(-1).i64_eq().i32_and();
+            raise(sink, FAULT_OVERFLOW, 2);
+        }
+        Instruction::Print | Instruction::Ret | Instruction::Throw => {
+            below(sink, 1);
+            raise(sink, FAULT_EMPTY_STACK, 1);
+        }
+        Instruction::Assert => {
+            below(sink, 1);
+            raise(sink, FAULT_EMPTY_STACK, 1);
+            cell_address(sink, 1);
+            sink.i64_load(CELL).i64_eqz();
+            raise(sink, FAULT_ASSERTION, 1);
+        }
+        Instruction::AssertEq => {
+            below(sink, 2);
+            raise(sink, FAULT_EMPTY_STACK, 2);
+            cell_address(sink, 2);
+            sink.i64_load(CELL);
+            cell_address(sink, 1);
+            sink.i64_load(CELL).i64_ne();
+            raise(sink, FAULT_ASSERTION, 2);
+        }
+        Instruction::Dup => {
+            below(sink, 1);
+            raise(sink, FAULT_EMPTY_STACK, 1);
+            full(sink, 1);
+            raise(sink, FAULT_FULL_STACK, 0);
+        }
+        Instruction::Push(_) => {
+            full(sink, 1);
+            raise(sink, FAULT_FULL_STACK, 0);
+        }
+        Instruction::Load(slot) => {
+            below(sink, slot as i32 + 1);
+            raise(sink, FAULT_SLOT_OUT_OF_RANGE, 0);
+            full(sink, 1);
+            raise(sink, FAULT_FULL_STACK, 0);
+        }
+        Instruction::Store(slot) => {
+            below(sink, 1);
+            raise(sink, FAULT_EMPTY_STACK, 1);
+            below(sink, slot as i32 + 2);
+            raise(sink, FAULT_SLOT_OUT_OF_RANGE, 1);
+        }
+        Instruction::Enter(size) => {
+            sink.global_get(FRAMES).i32_const(MAX_FRAMES).i32_eq();
+            raise(sink, FAULT_FULL_STACK, 0);
+            full(sink, size as i32);
+            raise(sink, FAULT_FULL_STACK, 0);
+        }
+        Instruction::LoadLocal(local) => {
+            no_frame(sink);
+            raise(sink, FAULT_NO_FRAME, 0);
+            local_range(sink, local, 0);
+            raise(sink, FAULT_LOCAL_OUT_OF_RANGE, 0);
+            full(sink, 1);
+            raise(sink, FAULT_FULL_STACK, 0);
+        }
+        Instruction::StoreLocal(local) => {
+            below(sink, 1);
+            raise(sink, FAULT_EMPTY_STACK, 1);
+            no_frame(sink);
+            raise(sink, FAULT_NO_FRAME, 1);
+            local_range(sink, local, 1);
+            raise(sink, FAULT_LOCAL_OUT_OF_RANGE, 1);
+        }
+        Instruction::Leave => {
+            no_frame(sink);
+            raise(sink, FAULT_NO_FRAME, 0);
+            frame_address(sink);
+            sink.i32_load(FRAME_BASE);
+            frame_address(sink);
+            sink.i32_load(FRAME_SIZE)
+                .i32_add()
+                .global_get(SP)
+                .i32_gt_u();
+            raise(sink, FAULT_EMPTY_STACK, 0);
+        }
+        Instruction::Spawn(_) => {
+            sink.global_get(WAITING)
+                .i32_const(MAX_COROUTINES - 1)
+                .i32_eq();
+            raise(sink, FAULT_FULL_STACK, 0);
+        }
+        Instruction::Send(_) => {
+            below(sink, 1);
+            raise(sink, FAULT_EMPTY_STACK, 1);
+        }
+        Instruction::Recv(channel) => {
+            full(sink, 1);
+            sink.i32_const(channel as i32 * 8)
+                .i32_load(CHANNEL_COUNT)
+                .i32_const(0)
+                .i32_ne()
+                .i32_and();
+            raise(sink, FAULT_FULL_STACK, 0);
+        }
+        Instruction::Depth => {
+            full(sink, 1);
+            raise(sink, FAULT_FULL_STACK, 0);
+        }
+        Instruction::Unknown(_) => {
+            sink.i32_const(1);
+            raise(sink, FAULT_UNKNOWN_OP, 0);
+        }
+        _ => {
+            if let Some((taken, order)) = instruction.shuffle() {
+                below(sink, taken as i32);
+                match instruction {
+                    Instruction::Pick(_) | Instruction::Roll(_) => {
+                        raise(sink, FAULT_OUT_OF_REACH, 0)
+                    }
+                    _ => raise(sink, FAULT_EMPTY_STACK, 0),
+                }
+                if order.len() > taken {
+                    full(sink, (order.len() - taken) as i32);
+                    raise(sink, FAULT_FULL_STACK, 0);
+                }
+            }
+        }
+    }
+}
+
+///Whether `guard` has any check for the instruction
+fn guarded(instruction: Instruction) -> bool {
+    !matches!(
+        instruction,
+        Instruction::Nop
+            | Instruction::PChar
+            | Instruction::Pop
+            | Instruction::Jmp(_)
+            | Instruction::Jnz(_)
+            | Instruction::Try(_)
+            | Instruction::EndTry
+            | Instruction::Yield
+            | Instruction::Clear
+            | Instruction::Exit
+            | Instruction::Truncated(_)
+    )
+}
+
+///Values a stack word of `Instruction::shuffle` moves, past those it leaves where they are
+fn moved(order: &[usize]) -> usize {
+    let kept = order
+        .iter()
+        .enumerate()
+        .take_while(|&(position, &from)| position == from)
+        .count();
+    order.len() - kept
+}
+
+///Basic blocks of the code, keyed by the offset they start at.
+///Each holds its instructions in order.
+fn basic_blocks(
+    instructions: &BTreeMap<usize, Instruction>,
+) -> BTreeMap<usize, Vec<(usize, Instruction)>> {
+    let mut leaders: BTreeSet<usize> = BTreeSet::new();
+    let mut previous: Option<(usize, Instruction)> = None;
+    let mut following = instructions.keys().skip(1);
+    for (&offset, &instruction) in instructions {
+        let next = following.next().copied();
+        match instruction {
+            Instruction::Jmp(target)
+            | Instruction::Jnz(target)
+            | Instruction::Try(target)
+            | Instruction::Spawn(target) => {
+                leaders.insert(target);
+            }
+            // Where a coroutine that is switched out goes on
+            Instruction::Yield => {
+                leaders.insert(offset + 1);
+            }
+            Instruction::Recv(_) => {
+                leaders.insert(offset);
+            }
+            _ => (),
+        }
+        // Fallthrough into an instruction that was not decoded right after this one, because a
+        // jump lands inside this one's operand
+        if !instruction.ends_block() && next != Some(offset + instruction.size()) {
+            leaders.insert(offset + instruction.size());
+        }
+        let continues = previous.is_some_and(|(at, before)| {
+            !before.ends_block()
+                && !matches!(before, Instruction::Jnz(_))
+                && at + before.size() == offset
+        });
+        if !continues {
+            leaders.insert(offset);
+        }
+        previous = Some((offset, instruction));
+    }
+
+    let mut blocks: BTreeMap<usize, Vec<(usize, Instruction)>> = BTreeMap::new();
+    let mut current = 0;
+    for (&offset, &instruction) in instructions {
+        if leaders.contains(&offset) {
+            current = offset;
+        }
+        blocks
+            .entry(current)
+            .or_default()
+            .push((offset, instruction));
+    }
+    blocks
+}
+
+///The `run` export: a dispatch loop with one `br_table` arm per basic block, and one more
+///for arms past the end of the code, which finishes the running coroutine
+fn run(code: &[u8]) -> Function {
+    let instructions = reachable(code);
+    let blocks = basic_blocks(&instructions);
+    let index: BTreeMap<usize, u32> = blocks
+        .keys()
+        .enumerate()
+        .map(|(position, &offset)| (offset, position as u32))
+        .collect();
+
+    // Locals: block to run next, floor of a CLEAR, operands of binary instructions, and the
+    // values a stack word moves, as many as the one that moves most needs
+    let (pc, floor, lhs, rhs, scratch) = (0, 1, 2, 3, 4);
+    let moving = instructions
+        .values()
+        .filter_map(|instruction| instruction.shuffle())
+        .map(|(_, order)| moved(&order) as u32)
+        .max()
+        .unwrap_or(0);
+    let mut f = Function::new([(2, ValType::I32), (2 + moving, ValType::I64)]);
+    let mut sink = f.instructions();
+    let count = blocks.len() as u32;
+    let uses_try = instructions
+        .values()
+        .any(|instruction| matches!(instruction, Instruction::Try(_)));
+
+    let arm = |target: usize| index.get(&target).map_or(-1, |&arm| arm as i32);
+
+    sink.loop_(BlockType::Empty);
+    for _ in 0..=count {
+        sink.block(BlockType::Empty);
+    }
+    let arms: Vec<u32> = (0..count).collect();
+    sink.local_get(pc).br_table(arms, count).end();
+
+    for (position, block) in blocks.values().enumerate() {
+        // Depth of the dispatch loop from this block's code
+        let depth = count - position as u32;
+        let goto = |sink: &mut InstructionSink, target: usize, extra: u32| {
+            sink.i32_const(arm(target)).local_set(pc).br(depth + extra);
+        };
+        // Finish a coroutine other than the first instead of returning from `run`
+        let finish = |sink: &mut InstructionSink| {
+            sink.global_get(CURRENT)
+                .if_(BlockType::Empty)
+                .i32_const(-1)
+                .local_set(pc)
+                .br(depth + 1)
+                .end();
+        };
+
+        for &(offset, instruction) in block {
+            let op = instruction.opcode() as i32;
+            let at = |sink: &mut InstructionSink| {
+                sink.i32_const(offset as i32).i32_const(op);
+            };
+            // SWP and DUP report the index after themselves, like the interpreter
+            let after = |sink: &mut InstructionSink| {
+                sink.i32_const(offset as i32 + 1).i32_const(op);
+            };
+            // Fault unless the stack is deeper than `slot`
+            let check_slot = |sink: &mut InstructionSink, slot: u8| {
+                sink.global_get(SP)
+                    .i32_const(slot as i32)
+                    .i32_le_u()
+                    .if_(BlockType::Empty)
+                    .i32_const(slot as i32)
+                    .global_set(FAULT_SLOT)
+                    .i32_const(FAULT_SLOT_OUT_OF_RANGE);
+                at(sink);
+                sink.call(FAULT).end();
+            };
+            // Continue at the handler the CATCH helper picks for the error code on top
+            // of the wasm stack, `extra` blocks inside this block's code
+            let catch = |sink: &mut InstructionSink, extra: u32| {
+                at(sink);
+                sink.call(CATCH).local_set(pc).br(depth + extra);
+            };
+            if uses_try && guarded(instruction) {
+                sink.global_get(HANDLERS).if_(BlockType::Empty);
+                guard(&mut sink, instruction, &|sink, kind, popped| {
+                    sink.if_(BlockType::Empty);
+                    if popped > 0 {
+                        // As many of the cells as there are
+                        sink.global_get(SP)
+                            .i32_const(popped)
+                            .i32_sub()
+                            .i32_const(0)
+                            .global_get(SP)
+                            .i32_const(popped)
+                            .i32_ge_u()
+                            .select()
+                            .global_set(SP);
+                    }
+                    sink.i64_const(kind as i64);
+                    catch(sink, 2);
+                    sink.end();
+                });
+                sink.end();
+            }
+            let operands = |sink: &mut InstructionSink| {
+                at(sink);
+                sink.call(POP).local_set(rhs);
+                at(sink);
+                sink.call(POP).local_set(lhs);
+                sink.local_get(lhs).local_get(rhs);
+            };
+
+            match instruction {
+                Instruction::Nop => {}
+                Instruction::Add | Instruction::Sub | Instruction::Mul => {
+                    operands(&mut sink);
+                    sink.i32_const(op)
+                        .call(OVERFLOWS)
+                        .if_(BlockType::Empty)
+                        .i32_const(FAULT_OVERFLOW);
+                    at(&mut sink);
+                    sink.call(FAULT).end();
+                    sink.local_get(lhs).local_get(rhs);
+                    match instruction {
+                        Instruction::Add => sink.i64_add(),
+                        Instruction::Sub => sink.i64_sub(),
+                        _ => sink.i64_mul(),
+                    };
+                    at(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::Div | Instruction::Mod => {
+                    operands(&mut sink);
+                    at(&mut sink);
+                    sink.call(CHECK_DIVISOR);
+                    sink.local_get(lhs).local_get(rhs);
+                    match instruction {
+                        Instruction::Div => sink.i64_div_s(),
+                        _ => sink.i64_rem_s(),
+                    };
+                    at(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::Lt => {
+                    operands(&mut sink);
+                    sink.i64_lt_s().i64_extend_i32_u();
+                    at(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::Print => {
+                    at(&mut sink);
+                    sink.call(TOP).call(PRINT);
+                }
+                Instruction::PChar => {
+                    at(&mut sink);
+                    sink.call(PRINT_CHARS);
+                }
+                Instruction::Ret => {
+                    at(&mut sink);
+                    sink.call(TOP).local_set(lhs);
+                    finish(&mut sink);
+                    sink.local_get(lhs).return_();
+                }
+                Instruction::Push(value) => {
+                    sink.i64_const(value as i64);
+                    at(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::Swp => {
+                    after(&mut sink);
+                    sink.call(POP).local_set(rhs);
+                    after(&mut sink);
+                    sink.call(POP).local_set(lhs);
+                    sink.local_get(rhs);
+                    after(&mut sink);
+                    sink.call(PUSH).local_get(lhs);
+                    after(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::Pop => {
+                    sink.global_get(SP)
+                        .if_(BlockType::Empty)
+                        .global_get(SP)
+                        .i32_const(1)
+                        .i32_sub()
+                        .global_set(SP)
+                        .end();
+                }
+                Instruction::Dup => {
+                    after(&mut sink);
+                    sink.call(TOP);
+                    after(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::Load(slot) => {
+                    check_slot(&mut sink, slot);
+                    sink.i32_const(slot as i32 * 8).i64_load(CELL);
+                    at(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::Store(slot) => {
+                    at(&mut sink);
+                    sink.call(POP).local_set(rhs);
+                    check_slot(&mut sink, slot);
+                    sink.i32_const(slot as i32 * 8)
+                        .local_get(rhs)
+                        .i64_store(CELL);
+                }
+                Instruction::Enter(size) => {
+                    sink.i32_const(size as i32);
+                    at(&mut sink);
+                    sink.call(ENTER);
+                }
+                Instruction::LoadLocal(local) => {
+                    sink.i32_const(local as i32);
+                    at(&mut sink);
+                    sink.call(LOCAL).i64_load(CELL);
+                    at(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::StoreLocal(local) => {
+                    at(&mut sink);
+                    sink.call(POP).local_set(rhs).i32_const(local as i32);
+                    at(&mut sink);
+                    sink.call(LOCAL).local_get(rhs).i64_store(CELL);
+                }
+                Instruction::Leave => {
+                    at(&mut sink);
+                    sink.call(LEAVE);
+                }
+                Instruction::Jmp(target) => goto(&mut sink, target, 0),
+                Instruction::Jnz(target) => {
+                    sink.call(TRUTHY).if_(BlockType::Empty);
+                    goto(&mut sink, target, 1);
+                    sink.end();
+                }
+                Instruction::Try(target) => {
+                    sink.i32_const(arm(target));
+                    at(&mut sink);
+                    sink.call(TRY_HANDLER);
+                }
+                Instruction::EndTry => {
+                    at(&mut sink);
+                    sink.call(END_TRY);
+                }
+                Instruction::Throw => {
+                    at(&mut sink);
+                    sink.call(POP)
+                        .local_set(lhs)
+                        .global_get(HANDLERS)
+                        .i32_eqz()
+                        .if_(BlockType::Empty)
+                        .local_get(lhs)
+                        .global_set(FAULT_CODE)
+                        .i32_const(FAULT_UNCAUGHT);
+                    at(&mut sink);
+                    sink.call(FAULT).end().local_get(lhs);
+                    catch(&mut sink, 0);
+                }
+                Instruction::Assert => {
+                    at(&mut sink);
+                    sink.call(POP).i64_eqz().if_(BlockType::Empty);
+                    sink.i32_const(FAULT_ASSERTION);
+                    at(&mut sink);
+                    sink.call(FAULT).end();
+                }
+                Instruction::AssertEq => {
+                    operands(&mut sink);
+                    sink.i64_ne()
+                        .if_(BlockType::Empty)
+                        .i32_const(0)
+                        .local_get(lhs)
+                        .i64_store(NOT_EQUAL_LEFT)
+                        .i32_const(0)
+                        .local_get(rhs)
+                        .i64_store(NOT_EQUAL_RIGHT)
+                        .i32_const(FAULT_ASSERTION);
+                    at(&mut sink);
+                    sink.call(FAULT).end();
+                }
+                Instruction::Spawn(target) => {
+                    sink.i32_const(arm(target));
+                    at(&mut sink);
+                    sink.call(SPAWN);
+                }
+                Instruction::Yield => {
+                    sink.i32_const(arm(offset + 1)).i32_const(-1).i32_const(0);
+                    at(&mut sink);
+                    sink.call(RESCHEDULE)
+                        .local_tee(pc)
+                        .i32_const(-2)
+                        .i32_ne()
+                        .br_if(depth);
+                }
+                Instruction::Send(channel) => {
+                    at(&mut sink);
+                    sink.call(POP).i32_const(channel as i32);
+                    at(&mut sink);
+                    sink.call(SEND);
+                }
+                Instruction::Recv(channel) => {
+                    sink.i32_const(channel as i32);
+                    at(&mut sink);
+                    sink.call(RECEIVE)
+                        .i32_eqz()
+                        .if_(BlockType::Empty)
+                        .i32_const(arm(offset))
+                        .i32_const(channel as i32)
+                        .i32_const(0);
+                    at(&mut sink);
+                    sink.call(RESCHEDULE).local_set(pc).br(depth + 1).end();
+                }
+                Instruction::Over
+                | Instruction::Rot
+                | Instruction::Nip
+                | Instruction::Tuck
+                | Instruction::Pick(_)
+                | Instruction::Roll(_)
+                | Instruction::TwoDup
+                | Instruction::TwoSwap => {
+                    let (taken, order) = instruction.shuffle().unwrap();
+                    let (taken, count) = (taken as i32, order.len() as i32);
+                    sink.global_get(SP)
+                        .i32_const(taken)
+                        .i32_lt_u()
+                        .if_(BlockType::Empty);
+                    match instruction {
+                        Instruction::Pick(n) | Instruction::Roll(n) => sink
+                            .i32_const(n as i32)
+                            .global_set(FAULT_SLOT)
+                            .global_get(SP)
+                            .i64_extend_i32_u()
+                            .global_set(FAULT_CODE)
+                            .i32_const(FAULT_OUT_OF_REACH),
+                        _ => sink.i32_const(FAULT_EMPTY_STACK),
+                    };
+                    at(&mut sink);
+                    sink.call(FAULT).end();
+                    if count > taken {
+                        sink.global_get(SP)
+                            .i32_const(STACK_SIZE - (count - taken))
+                            .i32_gt_s()
+                            .if_(BlockType::Empty)
+                            .i32_const(FAULT_FULL_STACK);
+                        at(&mut sink);
+                        sink.call(FAULT).end();
+                    }
+                    let kept = order.len() - moved(&order);
+                    for (position, &from) in order.iter().enumerate().skip(kept) {
+                        cell_address(&mut sink, taken - from as i32);
+                        sink.i64_load(CELL)
+                            .local_set(scratch + (position - kept) as u32);
+                    }
+                    for position in kept..order.len() {
+                        cell_address(&mut sink, taken - position as i32);
+                        sink.local_get(scratch + (position - kept) as u32)
+                            .i64_store(CELL);
+                    }
+                    sink.global_get(SP)
+                        .i32_const(count - taken)
+                        .i32_add()
+                        .global_set(SP);
+                }
+                Instruction::Depth => {
+                    sink.global_get(SP).i64_extend_i32_u();
+                    at(&mut sink);
+                    sink.call(PUSH);
+                }
+                Instruction::Clear => {
+                    sink.global_get(FRAMES).if_(BlockType::Result(ValType::I32));
+                    frame_address(&mut sink);
+                    sink.i32_load(FRAME_BASE);
+                    frame_address(&mut sink);
+                    sink.i32_load(FRAME_SIZE)
+                        .i32_add()
+                        .else_()
+                        .i32_const(0)
+                        .end()
+                        .local_tee(floor)
+                        .global_get(SP)
+                        .i32_lt_u()
+                        .if_(BlockType::Empty)
+                        .local_get(floor)
+                        .global_set(SP)
+                        .end();
+                }
+                Instruction::Exit => {
+                    finish(&mut sink);
+                    sink.i64_const(0xFF).return_();
+                }
+                Instruction::Truncated(_) => goto(&mut sink, usize::MAX, 0),
+                Instruction::Unknown(_) => {
+                    sink.i32_const(FAULT_UNKNOWN_OP);
+                    at(&mut sink);
+                    sink.call(FAULT);
+                }
+            }
+        }
+
+        let &(offset, last) = block.last().unwrap();
+        if !last.ends_block() {
+            goto(&mut sink, offset + last.size(), 0);
+        }
+        sink.end();
+    }
+
+    sink.global_get(CURRENT)
+        .i32_eqz()
+        .if_(BlockType::Empty)
+        .i64_const(0)
+        .return_()
+        .end()
+        .i32_const(0)
+        .i32_const(-1)
+        .i32_const(1)
+        .i32_const(code.len() as i32)
+        .i32_const(0)
+        .call(RESCHEDULE)
+        .local_set(pc)
+        .br(0)
+        .end();
+    sink.i64_const(0).end();
+    f
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmi::{Caller, Engine, Extern, Linker, Module, Store};
+
+    use super::*;
+    use crate::{
+        code::{
+            link::link,
+            parse::{assemble_source, parse_code},
+        },
+        stack::Stack,
+    };
+
+    ///Everything a run of the module leaves behind
+    struct Outcome {
+        result: Result<i64, Option<StackError>>,
+        state: Vec<i64>,
+        output: String,
+    }
+
+    ///Run the compiled code in the wasmi interpreter, collecting what the host functions print
+    fn run_wasm(code: &[u8]) -> Outcome {
+        let engine = Engine::default();
+        let module = Module::new(&engine, &compile_wasm(code)[..]).unwrap();
+        let mut store = Store::new(&engine, String::new());
+        let mut linker = <Linker<String>>::new(&engine);
+        linker
+            .func_wrap(
+                "env",
+                "print",
+                |mut caller: Caller<'_, String>, value: i64| {
+                    caller.data_mut().push_str(&format!("{value}\n"));
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "env",
+                "pchar",
+                |mut caller: Caller<'_, String>, ptr: i32, len: i32| {
+                    let memory = caller
+                        .get_export("memory")
+                        .and_then(Extern::into_memory)
+                        .unwrap();
+                    let mut bytes = vec![0; len as usize];
+                    memory.read(&caller, ptr as usize, &mut bytes).unwrap();
+                    let text = String::from_utf8(bytes)
+                        .unwrap_or_else(|_| String::from("Could not parse stack to string."));
+                    caller.data_mut().push_str(&format!("{text}\n"));
+                },
+            )
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+
+        let run = instance.get_typed_func::<(), i64>(&store, "run").unwrap();
+        let global = |store: &Store<String>, name: &str| {
+            instance
+                .get_global(store, name)
+                .unwrap()
+                .get(store)
+                .i32()
+                .unwrap()
+        };
+        let result = run.call(&mut store, ()).map_err(|_| {
+            stack_error(
+                global(&store, "fault_kind"),
+                global(&store, "fault_idx"),
+                global(&store, "fault_op"),
+                global(&store, "fault_slot"),
+                instance
+                    .get_global(&store, "fault_code")
+                    .unwrap()
+                    .get(&store)
+                    .i64()
+                    .unwrap(),
+                instance.get_memory(&store, "memory").unwrap().data(&store),
+            )
+        });
+
+        let depth = global(&store, "sp") as usize;
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        let mut cells = vec![0; depth * 8];
+        memory.read(&store, 0, &mut cells).unwrap();
+        let state = cells
+            .chunks_exact(8)
+            .map(|cell| i64::from_le_bytes(cell.try_into().unwrap()))
+            .collect();
+
+        Outcome {
+            result,
+            state,
+            output: store.into_data(),
+        }
+    }
+
+    ///Check the module returns, or fails, like `Stack::execute` and leaves the same stack
+    fn assert_same(code: &[u8]) -> Outcome {
+        let mut stack: Stack<i64> = Stack::new();
+        let expected = stack.execute(code);
+        let outcome = run_wasm(code);
+        match (&expected, &outcome.result) {
+            (Ok(expected), Ok(actual)) => assert_eq!(expected, actual),
+            (Err(expected), Err(Some(actual))) => {
+                assert_eq!(format!("{expected:?}"), format!("{actual:?}"))
+            }
+            _ => panic!("expected {expected:?}, got {:?}", outcome.result),
+        }
+        assert_eq!(stack.state, outcome.state);
+        outcome
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x01, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x02, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x03, 0x12]);
+        assert_same(&[0x20, 0x06, 0x20, 0x03, 0x04, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x03, 0x05, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x06, 0x12]);
+    }
+    #[test]
+    fn overflow() {
+        let build = |source: &str| {
+            link(&[assemble_source::<i64>("overflow.cor", source, &[]).unwrap()]).unwrap()
+        };
+        let max = "push 9223372036854775807";
+        let min = "push -9223372036854775808";
+        let operations = [
+            format!("{max}\npush 1\nadd"),
+            format!("{min}\npush -1\nadd"),
+            format!("{max}\npush -1\nadd"),
+            format!("{min}\npush 1\nsub"),
+            format!("{max}\npush -1\nsub"),
+            format!("push -1\n{max}\nsub"),
+            String::from("push 4611686018427387904\npush 2\nmul"),
+            format!("push -1\n{min}\nmul"),
+            format!("{min}\npush -1\nmul"),
+            format!("{min}\npush 1\nmul"),
+            format!("push 0\n{min}\nmul"),
+            format!("{min}\npush -1\ndiv"),
+            format!("{min}\npush -1\nmod"),
+        ];
+        for operation in operations {
+            assert_same(&build(&format!("{operation}\nret\n")));
+            // Caught by a handler, which returns the code of the fault
+            assert_same(&build(&format!(
+                "try $caught\n{operation}\nret\ncaught: ret\n"
+            )));
+        }
+    }
+    #[test]
+    fn stack_words() {
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x21, 0x12]);
+        assert_same(&[0x20, 0x05, 0x23, 0x01, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x22, 0x22, 0x22, 0x20, 0x01]);
+        assert_same(&[0x20, 0x05, 0x20, 0x06, 0x24, 0x00, 0x01, 0x25, 0x00, 0x12]);
+        assert_same(&[0x20, 0x05, 0x24, 0x01]);
+        assert_same(&[0x20, 0x05, 0x25, 0x00]);
+        assert_same(&[
+            0x20, 0x01, 0x20, 0x02, 0x20, 0x03, 0x60, 0x61, 0x62, 0x63, 0x68, 0x69, 0x64, 0x04,
+            0x65, 0x05, 0x66, 0x12,
+        ]);
+        assert_same(&[0x20, 0x01, 0x26, 0x01, 0x20, 0x02, 0x67, 0x66, 0x12]);
+        assert_same(&[0x67, 0x66, 0x12]);
+        assert_same(&[0x20, 0x01, 0x64, 0x01]);
+        assert_same(&[0x20, 0x01, 0x69]);
+        assert_same(&[0x32, 0x04, 0x65, 0x00, 0x12]);
+        assert_same(&[0x20, 0x01, 0x32, 0x05, 0x63, 0x12]);
+    }
+    #[test]
+    fn frames() {
+        assert_same(&[
+            0x20, 0x09, 0x26, 0x02, 0x20, 0x04, 0x28, 0x01, 0x27, 0x01, 0x27, 0x00, 0x01, 0x29,
+            0x12,
+        ]);
+        assert_same(&[0x26, 0x01, 0x27, 0x01]);
+        assert_same(&[0x26, 0x02, 0x22, 0x29]);
+        assert_same(&[0x26, 0x02, 0x22, 0x28, 0x01]);
+        assert_same(&[0x27, 0x00]);
+        assert_same(&[0x29]);
+    }
+    #[test]
+    fn jumps() {
+        assert_same(&[0x20, 0x05, 0x30, 0x06, 0x20, 0x03, 0x12]);
+        assert_same(&[0x20, 0x05, 0x20, 0x01, 0x02, 0x31, 0x02, 0x20, 0x03, 0x12]);
+        assert_same(&[0x20, 0x12, 0x30, 0x01, 0xFF]);
+        assert_same(&[0x20, 0x05, 0x30, 0xF0]);
+        assert_same(&[0x31, 0x00, 0x20]);
+        // The JNZ operand decodes as ADD, which runs on into the PUSH after the JNZ
+        assert_same(&[0x31, 0x01, 0x20, 0x02, 0x65]);
+        assert_same(&[0x20, 0x00, 0x31, 0x03, 0x20, 0x20, 0x07, 0x12]);
+        assert_same(&[]);
+    }
+    #[test]
+    fn countdown_output() {
+        // PUSH 3, loop: PRINT, PUSH 1, SUB, JNZ loop, EXIT
+        let outcome = assert_same(&[0x20, 0x03, 0x10, 0x20, 0x01, 0x02, 0x31, 0x02, 0xFF]);
+        assert_eq!(outcome.output, "3\n2\n1\n");
+    }
+    #[test]
+    fn hello_world() {
+        let outcome = assert_same(&parse_code("./hello_world.cor", &[]).unwrap());
+        assert_eq!(outcome.output, "Hello, world!\n");
+    }
+    #[test]
+    fn errors() {
+        assert_same(&[0x20, 0x05, 0x01]);
+        assert_same(&[0x21]);
+        assert_same(&[0x23]);
+        assert_same(&[0x10]);
+        assert_same(&[0x20, 0x05, 0x42]);
+        assert_same(&[0x20, 0x05, 0x20, 0x00, 0x04]);
+    }
+    #[test]
+    fn try_throw() {
+        assert_same(&[
+            0x20, 0x01, 0x32, 0x09, 0x20, 0x02, 0x20, 0x07, 0x34, 0x20, 0x03, 0x01, 0x12,
+        ]);
+        assert_same(&[0x32, 0x09, 0x26, 0x01, 0x20, 0x01, 0x20, 0x00, 0x04, 0x12]);
+        assert_same(&[0x32, 0x06, 0x20, 0x01, 0x42, 0x00, 0x12]);
+        // Faults caught by the checks before each instruction
+        assert_same(&[0x32, 0x04, 0x21, 0x00, 0x12]);
+        assert_same(&[0x20, 0x05, 0x32, 0x07, 0x25, 0x03, 0x00, 0x12]);
+        assert_same(&[0x32, 0x04, 0x27, 0x00, 0x12]);
+        assert_same(&[0x32, 0x06, 0x26, 0x02, 0x27, 0x02, 0x12]);
+        assert_same(&[0x32, 0x06, 0x26, 0x02, 0x28, 0x01, 0x12]);
+        assert_same(&[0x32, 0x06, 0x26, 0x02, 0x22, 0x29, 0x12]);
+        assert_same(&[0x32, 0xF0, 0x34]);
+        // Nested TRYs, the inner one ended before the THROW
+        assert_same(&[
+            0x32, 0x0B, 0x32, 0x0C, 0x33, 0x20, 0x05, 0x34, 0x00, 0x00, 0x00, 0x12, 0x00,
+        ]);
+        assert_same(&[0x20, 0x07, 0x34]);
+        assert_same(&[0x32, 0x03, 0x33, 0x33]);
+        assert_same(&[0x32, 0x03, 0x33, 0x12]);
+    }
+    #[test]
+    fn assertions() {
+        assert_same(&[
+            0x20, 0x01, 0x40, 0x20, 0x04, 0x20, 0x04, 0x41, 0x20, 0x09, 0x12,
+        ]);
+        assert_same(&[0x20, 0x00, 0x40]);
+        assert_same(&[0x20, 0x02, 0x20, 0x03, 0x41]);
+        assert_same(&[0x40]);
+        assert_same(&[0x32, 0x07, 0x20, 0x02, 0x20, 0x03, 0x41, 0x12]);
+        assert_same(&[0x32, 0x05, 0x20, 0x00, 0x40, 0x12]);
+    }
+    #[test]
+    fn coroutines() {
+        assert_same(&[
+            0x50, 0x0A, 0x50, 0x0F, 0x53, 0x02, 0x53, 0x02, 0x01, 0x12, 0x20, 0x03, 0x52, 0x01,
+            0xFF, 0x53, 0x01, 0x23, 0x51, 0x52, 0x02, 0x52, 0x02,
+        ]);
+        // Only the first coroutine is left to yield to
+        assert_same(&[0x50, 0x07, 0x51, 0x51, 0x20, 0x04, 0x12, 0x20, 0x09]);
+        assert_same(&[0x50, 0x04, 0x53, 0x01, 0x53, 0x02]);
+        // The deadlock goes to the handler of the coroutine that waits last, which passes
+        // the code on to the first one
+        assert_same(&[
+            0x50, 0x06, 0x53, 0x01, 0x12, 0x00, 0x32, 0x0A, 0x53, 0x02, 0x52, 0x01,
+        ]);
+        // The spawned coroutine finishes while the first one waits, at a RET or past the end
+        assert_same(&[0x50, 0x05, 0x53, 0x01, 0x12, 0x12]);
+        assert_same(&[0x50, 0x05, 0x53, 0x01, 0x12, 0x20, 0x01]);
+    }
+}
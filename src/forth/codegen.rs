@@ -0,0 +1,488 @@
+/*!Code generation from Forth words to bytecode
+ *
+ * Colon definitions are inlined wherever they are called. The index and limit
+ * of every DO loop live in the locals of a frame entered at the start of the
+ * program, two for each level of nesting, so the data stack only ever holds
+ * the values the program pushes itself.
+ */
+
+use crate::{
+    code::instruction::Instruction,
+    forth::{
+        forth_error::ForthError,
+        parse::{Program, Token},
+    },
+    lang::{
+        ast::{Pos, Positions},
+        codegen::DIFFERS,
+    },
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Item {
+    Op {
+        instruction: Instruction,
+        pos: Pos,
+    },
+    Jmp {
+        label: usize,
+        pos: Pos,
+    },
+    Jnz {
+        label: usize,
+        pos: Pos,
+    },
+    Label(usize),
+    ///DEPTH, less the locals of the loops once the size of the frame is known
+    Depth {
+        pos: Pos,
+    },
+}
+
+///Control word waiting for the word that closes it
+enum Open {
+    If { otherwise: usize },
+    Else { end: usize },
+    Begin { start: usize },
+    Do { again: usize },
+}
+
+struct Codegen<'a> {
+    definitions: &'a [Vec<Token>],
+    items: Vec<Item>,
+    labels: usize,
+    open: Vec<Open>,
+    ///DO loops around the word being compiled
+    loops: usize,
+    max_loops: usize,
+    ///First DO of the program, which the frame for the loops is attributed to
+    first_do: Option<Pos>,
+}
+
+///Compile a parsed program, also returning the source position of every instruction by address
+pub fn generate(program: &Program) -> Result<(Vec<u8>, Positions), ForthError> {
+    let mut codegen = Codegen {
+        definitions: &program.definitions,
+        items: Vec::new(),
+        labels: 0,
+        open: Vec::new(),
+        loops: 0,
+        max_loops: 0,
+        first_do: None,
+    };
+    codegen.body(&program.top)?;
+    codegen.assemble()
+}
+
+impl<'a> Codegen<'a> {
+    fn emit(&mut self, instruction: Instruction, pos: Pos) {
+        self.items.push(Item::Op { instruction, pos });
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.items.push(Item::Label(label));
+    }
+
+    ///Local holding the index of the loop `level` loops out from the innermost, the limit is the next one
+    fn index(&self, level: usize) -> u8 {
+        (2 * (self.loops - 1 - level)) as u8
+    }
+
+    fn body(&mut self, tokens: &'a [Token]) -> Result<(), ForthError> {
+        let mut tokens = tokens.iter().peekable();
+        while let Some(token) = tokens.next() {
+            match token {
+                &Token::Number { value, pos } => match tokens.peek() {
+                    Some(Token::Builtin { name, pos }) if name == "pick" || name == "roll" => {
+                        // The parser made sure the number fits in the operand
+                        let n = value as u8;
+                        let instruction = match name.as_str() {
+                            "pick" => Instruction::Pick(n),
+                            _ => Instruction::Roll(n),
+                        };
+                        self.emit(instruction, *pos);
+                        tokens.next();
+                    }
+                    _ => self.literal(value, pos),
+                },
+                Token::Call { definition, .. } => self.body(&self.definitions[*definition])?,
+                Token::Builtin { name, pos } => self.word(name, *pos)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn word(&mut self, name: &str, pos: Pos) -> Result<(), ForthError> {
+        let simple: &[Instruction] = match name {
+            "+" => &[Instruction::Add],
+            "-" => &[Instruction::Sub],
+            "*" => &[Instruction::Mul],
+            "/" => &[Instruction::Div],
+            "mod" => &[Instruction::Mod],
+            "negate" => &[Instruction::Push(0), Instruction::Swp, Instruction::Sub],
+            "1+" => &[Instruction::Push(1), Instruction::Add],
+            "1-" => &[Instruction::Push(1), Instruction::Sub],
+            "<" => &[Instruction::Lt],
+            ">" => &[Instruction::Swp, Instruction::Lt],
+            "dup" => &[Instruction::Dup],
+            "drop" => &[Instruction::Pop],
+            "swap" => &[Instruction::Swp],
+            "over" => &[Instruction::Over],
+            "rot" => &[Instruction::Rot],
+            "nip" => &[Instruction::Nip],
+            "tuck" => &[Instruction::Tuck],
+            "2dup" => &[Instruction::TwoDup],
+            "2swap" => &[Instruction::TwoSwap],
+            "2drop" => &[Instruction::Pop, Instruction::Pop],
+            "." => &[Instruction::Print, Instruction::Pop],
+            // PCHAR prints the values above a 0 and pushes them back
+            "emit" => &[
+                Instruction::Push(0),
+                Instruction::Swp,
+                Instruction::PChar,
+                Instruction::Pop,
+            ],
+            // Every PRINT and PCHAR already ends its line
+            "cr" => &[],
+            _ => {
+                self.control(name, pos)?;
+                return Ok(());
+            }
+        };
+        for &instruction in simple {
+            self.emit(instruction, pos);
+        }
+        Ok(())
+    }
+
+    fn control(&mut self, name: &str, pos: Pos) -> Result<(), ForthError> {
+        match name {
+            "=" => {
+                self.differs(pos);
+                self.emit(Instruction::Push(1), pos);
+                self.emit(Instruction::Swp, pos);
+                self.emit(Instruction::Sub, pos);
+            }
+            "<>" => self.differs(pos),
+            "0=" => self.zero(pos),
+            "depth" => self.items.push(Item::Depth { pos }),
+            "if" => {
+                let (taken, otherwise) = (self.label(), self.label());
+                self.items.push(Item::Jnz { label: taken, pos });
+                self.emit(Instruction::Pop, pos);
+                self.items.push(Item::Jmp {
+                    label: otherwise,
+                    pos,
+                });
+                self.place(taken);
+                self.emit(Instruction::Pop, pos);
+                self.open.push(Open::If { otherwise });
+            }
+            "else" => {
+                let Some(Open::If { otherwise }) = self.open.pop() else {
+                    unreachable!("the parser pairs ELSE with IF")
+                };
+                let end = self.label();
+                self.items.push(Item::Jmp { label: end, pos });
+                self.place(otherwise);
+                self.open.push(Open::Else { end });
+            }
+            "then" => match self.open.pop() {
+                Some(Open::If { otherwise: label } | Open::Else { end: label }) => {
+                    self.place(label)
+                }
+                _ => unreachable!("the parser pairs THEN with IF"),
+            },
+            "begin" => {
+                let start = self.label();
+                self.place(start);
+                self.open.push(Open::Begin { start });
+            }
+            "until" => {
+                let Some(Open::Begin { start }) = self.open.pop() else {
+                    unreachable!("the parser pairs UNTIL with BEGIN")
+                };
+                let done = self.label();
+                self.items.push(Item::Jnz { label: done, pos });
+                self.emit(Instruction::Pop, pos);
+                self.items.push(Item::Jmp { label: start, pos });
+                self.place(done);
+                self.emit(Instruction::Pop, pos);
+            }
+            "do" => {
+                self.loops += 1;
+                self.max_loops = self.max_loops.max(self.loops);
+                self.first_do.get_or_insert(pos);
+                if self.max_loops > 127 {
+                    return Err(ForthError::LoopsTooDeep { pos });
+                }
+                let index = self.index(0);
+                self.emit(Instruction::StoreLocal(index), pos);
+                self.emit(Instruction::StoreLocal(index + 1), pos);
+                let (again, first) = (self.label(), self.label());
+                self.items.push(Item::Jmp { label: first, pos });
+                // Going round again leaves the flag of the comparison to drop
+                self.place(again);
+                self.emit(Instruction::Pop, pos);
+                self.place(first);
+                self.open.push(Open::Do { again });
+            }
+            "loop" => {
+                let Some(Open::Do { again }) = self.open.pop() else {
+                    unreachable!("the parser pairs LOOP with DO")
+                };
+                let index = self.index(0);
+                self.emit(Instruction::LoadLocal(index), pos);
+                self.emit(Instruction::Push(1), pos);
+                self.emit(Instruction::Add, pos);
+                self.emit(Instruction::Dup, pos);
+                self.emit(Instruction::StoreLocal(index), pos);
+                self.emit(Instruction::LoadLocal(index + 1), pos);
+                self.emit(Instruction::Lt, pos);
+                self.items.push(Item::Jnz { label: again, pos });
+                self.emit(Instruction::Pop, pos);
+                self.loops -= 1;
+            }
+            "i" => self.emit(Instruction::LoadLocal(self.index(0)), pos),
+            "j" => self.emit(Instruction::LoadLocal(self.index(1)), pos),
+            _ => unreachable!("the parser only lets through known words, not `{name}`"),
+        }
+        Ok(())
+    }
+
+    fn literal(&mut self, value: i64, pos: Pos) {
+        if value >= 0 {
+            self.number(value as u64, pos);
+            return;
+        }
+        // The magnitude of i64::MIN is not an i64, so it is built as -(2^63 - 1) - 1
+        let min = value == i64::MIN;
+        self.emit(Instruction::Push(0), pos);
+        self.number(value.unsigned_abs() - u64::from(min), pos);
+        self.emit(Instruction::Sub, pos);
+        if min {
+            self.emit(Instruction::Push(1), pos);
+            self.emit(Instruction::Sub, pos);
+        }
+    }
+
+    ///Push a non-negative number, building the ones PUSH cannot hold in base 255
+    fn number(&mut self, value: u64, pos: Pos) {
+        match u8::try_from(value) {
+            Ok(byte) => self.emit(Instruction::Push(byte), pos),
+            Err(_) => {
+                self.number(value / 255, pos);
+                self.emit(Instruction::Push(255), pos);
+                self.emit(Instruction::Mul, pos);
+                if !value.is_multiple_of(255) {
+                    self.emit(Instruction::Push((value % 255) as u8), pos);
+                    self.emit(Instruction::Add, pos);
+                }
+            }
+        }
+    }
+
+    ///Replace the two values on top of the stack with 0 if they are equal, and with 1 otherwise
+    fn differs(&mut self, pos: Pos) {
+        for instruction in DIFFERS {
+            self.emit(instruction, pos);
+        }
+    }
+
+    ///Replace the top of the stack with 1 if it is 0, and with 0 otherwise
+    fn zero(&mut self, pos: Pos) {
+        let (nonzero, end) = (self.label(), self.label());
+        self.items.push(Item::Jnz {
+            label: nonzero,
+            pos,
+        });
+        self.emit(Instruction::Pop, pos);
+        self.emit(Instruction::Push(1), pos);
+        self.items.push(Item::Jmp { label: end, pos });
+        self.place(nonzero);
+        self.emit(Instruction::Pop, pos);
+        self.emit(Instruction::Push(0), pos);
+        self.place(end);
+    }
+
+    ///Lay the code out behind the frame for the loops and resolve the labels to addresses
+    fn assemble(&self) -> Result<(Vec<u8>, Positions), ForthError> {
+        let locals = 2 * self.max_loops as u8;
+        let mut items = Vec::with_capacity(self.items.len() + 1);
+        if let Some(pos) = self.first_do {
+            items.push(Item::Op {
+                instruction: Instruction::Enter(locals),
+                pos,
+            });
+        }
+        for &item in &self.items {
+            match item {
+                Item::Depth { pos } if locals > 0 => {
+                    items.push(Item::Op {
+                        instruction: Instruction::Depth,
+                        pos,
+                    });
+                    items.push(Item::Op {
+                        instruction: Instruction::Push(locals),
+                        pos,
+                    });
+                    items.push(Item::Op {
+                        instruction: Instruction::Sub,
+                        pos,
+                    });
+                }
+                Item::Depth { pos } => items.push(Item::Op {
+                    instruction: Instruction::Depth,
+                    pos,
+                }),
+                _ => items.push(item),
+            }
+        }
+
+        let mut addresses: Vec<usize> = vec![0; self.labels];
+        let mut size = 0;
+        for item in &items {
+            match item {
+                Item::Op { instruction, .. } => size += instruction.size(),
+                Item::Jmp { .. } | Item::Jnz { .. } => size += 2,
+                Item::Label(label) => addresses[*label] = size,
+                Item::Depth { .. } => unreachable!("DEPTH is expanded above"),
+            }
+        }
+
+        let mut code: Vec<u8> = Vec::with_capacity(size);
+        let mut lines = Vec::new();
+        for item in &items {
+            let (instruction, pos) = match *item {
+                Item::Op { instruction, pos } => (instruction, pos),
+                Item::Jmp { label, pos } | Item::Jnz { label, pos } => {
+                    let target = addresses[label];
+                    if target > u8::MAX as usize {
+                        return Err(ForthError::ProgramTooLarge { size, pos });
+                    }
+                    match item {
+                        Item::Jmp { .. } => (Instruction::Jmp(target), pos),
+                        _ => (Instruction::Jnz(target), pos),
+                    }
+                }
+                _ => continue,
+            };
+            lines.push((code.len(), pos));
+            instruction.encode(&mut code);
+        }
+        Ok((code, lines))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{forth::compile, lang::SourceError, stack::Stack};
+
+    ///Lines the program prints and the stack it leaves, without the locals of its loops
+    fn run(source: &str) -> (String, Vec<i64>) {
+        let code = compile(source).unwrap();
+        let mut stack = Stack::<i64>::new();
+        stack.captured = Some(String::new());
+        stack.execute(&code).unwrap();
+        let locals = stack.frames.last().map_or(0, |frame| frame.size);
+        (stack.captured.unwrap(), stack.state[locals..].to_vec())
+    }
+
+    fn error(source: &str) -> String {
+        compile(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(run("1 2 3 * + 4 -").1, [3]);
+        assert_eq!(run("-7 2 / 7 3 MOD").1, [-3, 1]);
+        assert_eq!(run("5 negate 1+ 1- 1000000").1, [-5, 1000000]);
+        assert_eq!(
+            run("9223372036854775807 -9223372036854775808").1,
+            [i64::MAX, i64::MIN]
+        );
+        assert_eq!(
+            run("3 4 < 4 3 < 4 3 > 4 4 = 4 5 <> 0 0= 7 0=").1,
+            [1, 0, 1, 1, 1, 1, 0]
+        );
+        // Their difference does not fit in an i64
+        assert_eq!(
+            run("-9000000000000000000 9000000000000000000 = -9000000000000000000 9000000000000000000 <>").1,
+            [0, 1]
+        );
+    }
+    #[test]
+    fn stack_words() {
+        assert_eq!(run("1 2 swap over rot dup drop").1, [1, 2, 2]);
+        assert_eq!(run("1 2 3 nip tuck 2dup 2swap 2drop").1, [3, 1, 3]);
+        assert_eq!(run("1 2 3 2 pick 2 roll depth").1, [1, 3, 1, 2, 4]);
+        assert_eq!(run("1 2 3 3 0 do depth loop").1, [1, 2, 3, 3, 4, 5]);
+    }
+    #[test]
+    fn definitions() {
+        let source = "
+            : square ( n -- n*n ) dup * ;
+            : square square 1+ ; \\ uses the square before it
+            3 square . 72 emit cr
+        ";
+        assert_eq!(run(source), (String::from("10\nH\n"), vec![]));
+    }
+    #[test]
+    fn control_flow() {
+        let sign = ": sign dup 0 < if drop -1 else 0 > if 1 else 0 then then ;";
+        assert_eq!(run(&format!("{sign} -5 sign 0 sign 9 sign")).1, [-1, 0, 1]);
+        assert_eq!(run("1 0 if 2 then 1 if 3 then").1, [1, 3]);
+        assert_eq!(run("10 begin 1- dup 0= until").1, [0]);
+    }
+    #[test]
+    fn loops() {
+        assert_eq!(run("4 1 do i . loop").0, "1\n2\n3\n");
+        assert_eq!(run("0 3 0 do 2 0 do j 10 * i + + loop loop").1, [63]);
+        // The body runs once even when the limit is already reached
+        assert_eq!(run("0 0 do i loop").1, [0]);
+        let source = ": row ( n -- ) 3 0 do dup i * swap loop drop ; 3 1 do i row loop";
+        assert_eq!(run(source).1, [0, 1, 2, 0, 2, 4]);
+    }
+    #[test]
+    fn errors() {
+        assert_eq!(error("1 2 frob"), "1:5: undefined word `frob`");
+        assert_eq!(
+            error("99999999999999999999"),
+            "1:1: number `99999999999999999999` is too large"
+        );
+        assert_eq!(error("1 then"), "1:3: `then` without a matching `if`");
+        assert_eq!(error(": f begin 1 ;"), "1:5: `begin` is never closed");
+        assert_eq!(error(": f 1"), "1:1: `:` is never closed");
+        assert_eq!(error("1 ;"), "1:3: `;` without a matching `:`");
+        assert_eq!(error(":"), "1:1: `:` needs the name of the word it defines");
+        assert_eq!(
+            error(": f : g ;"),
+            "1:5: `:` inside the definition of another word"
+        );
+        assert_eq!(error(": IF 1 ;"), "1:3: `IF` cannot be redefined");
+        assert_eq!(error("2 0 do j loop"), "1:8: `j` outside of a DO loop");
+        assert_eq!(
+            error("1 dup pick"),
+            "1:7: `pick` needs a number from 0 to 255 right before it"
+        );
+        let source = format!("1 {} 1 if then", "dup ".repeat(300));
+        assert_eq!(
+            error(&source),
+            "1:1206: the program is 310 bytes, but a jump can only reach byte 255"
+        );
+        assert_eq!(
+            compile("1\n: f dup ; f 2 swop")
+                .unwrap_err()
+                .render("test.fs", "1\n: f dup ; f 2 swop"),
+            format!(
+                "test.fs:2:15: undefined word `swop`\n: f dup ; f 2 swop\n{}^",
+                " ".repeat(14)
+            )
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use thiserror::Error;
+
+use crate::lang::{SourceError, ast::Pos};
+
+#[derive(Debug, Error)]
+pub enum ForthError {
+    #[error("{pos}: undefined word `{word}`")]
+    UndefinedWord { word: String, pos: Pos },
+    #[error("{pos}: number `{literal}` is too large")]
+    NumberTooLarge { literal: String, pos: Pos },
+    #[error("{pos}: `{word}` without a matching `{expected}`")]
+    Unmatched {
+        word: String,
+        expected: &'static str,
+        pos: Pos,
+    },
+    #[error("{pos}: `{word}` is never closed")]
+    Unclosed { word: String, pos: Pos },
+    #[error("{pos}: `:` needs the name of the word it defines")]
+    MissingName { pos: Pos },
+    #[error("{pos}: `:` inside the definition of another word")]
+    NestedDefinition { pos: Pos },
+    #[error("{pos}: `{word}` cannot be redefined")]
+    Reserved { word: String, pos: Pos },
+    #[error("{pos}: `{word}` outside of a DO loop")]
+    OutsideLoop { word: String, pos: Pos },
+    #[error("{pos}: `{word}` needs a number from 0 to 255 right before it")]
+    NeedsLiteral { word: String, pos: Pos },
+    #[error("{pos}: DO loops nest too deep, a frame only holds the parameters of 127")]
+    LoopsTooDeep { pos: Pos },
+    #[error("{pos}: the program is {size} bytes, but a jump can only reach byte 255")]
+    ProgramTooLarge { size: usize, pos: Pos },
+}
+
+impl SourceError for ForthError {
+    fn pos(&self) -> Option<Pos> {
+        match self {
+            ForthError::UndefinedWord { pos, .. }
+            | ForthError::NumberTooLarge { pos, .. }
+            | ForthError::Unmatched { pos, .. }
+            | ForthError::Unclosed { pos, .. }
+            | ForthError::MissingName { pos }
+            | ForthError::NestedDefinition { pos }
+            | ForthError::Reserved { pos, .. }
+            | ForthError::OutsideLoop { pos, .. }
+            | ForthError::NeedsLiteral { pos, .. }
+            | ForthError::LoopsTooDeep { pos }
+            | ForthError::ProgramTooLarge { pos, .. } => Some(*pos),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+/*!A subset of Forth compiled to corrode bytecode, in `.fs` and `.fth` files
+ *
+ * ```text
+ * \ Squares of 1 to 10, then their sum
+ * : square ( n -- n*n ) dup * ;
+ * : squares ( -- ) 11 1 do i square . loop ;
+ *
+ * squares
+ * 0 11 1 do i square + loop .
+ * ```
+ *
+ * Words are separated by whitespace and are not case sensitive. `\` comments
+ * run to the end of the line and `( )` comments to the closing parenthesis.
+ *
+ * - Numbers are 64 bit integers and push themselves.
+ * - `+ - * / mod negate 1+ 1-` do arithmetic.
+ * - `< > = <> 0=` compare, giving 1 for true rather than -1. IF and UNTIL take
+ *   any value that is not 0 as true.
+ * - `dup drop swap over rot nip tuck 2dup 2swap 2drop depth` move values
+ *   around, as do `N pick` and `N roll` with N a literal from 0 to 255.
+ * - `.` prints the number on top, `emit` the character with the code on top.
+ *   Output is written a line at a time, so each ends its own line and `cr`
+ *   does nothing.
+ * - `: name ... ;` defines a word. Definitions are inlined where they are
+ *   used, and a name in the body refers to the word it named before the
+ *   definition, so words cannot recurse.
+ * - `IF ... ELSE ... THEN`, `BEGIN ... UNTIL` and `limit start DO ... LOOP`
+ *   with `I` and `J` nest in definitions and at the top level. The body of a
+ *   DO loop runs at least once.
+ *
+ * Compile errors point at the word in the source, and the instructions of
+ * every word keep the line they came from for the debugger, profiler and
+ * coverage. Control flow limits the size of a program the same way as in
+ * [`crate::lang`].
+ */
+
+use crate::{code::link::DebugInfo, forth::forth_error::ForthError, lang::compile_source_file};
+
+pub mod codegen;
+pub mod forth_error;
+pub mod parse;
+
+///Compile source text to bytecode
+pub fn compile(source: &str) -> Result<Vec<u8>, ForthError> {
+    Ok(codegen::generate(&parse::parse_program(source)?)?.0)
+}
+
+///Compile a source file, rendering errors with the line they point at, and keep the line of
+///every instruction
+pub fn compile_file(input_file: &str) -> anyhow::Result<(Vec<u8>, DebugInfo)> {
+    compile_source_file(input_file, |source| {
+        codegen::generate(&parse::parse_program(source)?)
+    })
+}
+
+///Whether the file holds Forth rather than assembly
+pub fn is_forth_file(file: &str) -> bool {
+    file.ends_with(".fs") || file.ends_with(".fth")
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use crate::{forth::forth_error::ForthError, lang::ast::Pos};
+
+///Words that structure the code, which colon definitions cannot take the name of
+const CONTROL: [&str; 11] = [
+    ":", ";", "if", "else", "then", "begin", "until", "do", "loop", "i", "j",
+];
+
+///Words compiled straight to bytecode
+const BUILTINS: [&str; 29] = [
+    "+", "-", "*", "/", "mod", "negate", "1+", "1-", "<", ">", "=", "<>", "0=", "dup", "drop",
+    "swap", "over", "rot", "nip", "tuck", "pick", "roll", "2dup", "2swap", "2drop", "depth", ".",
+    "emit", "cr",
+];
+
+///A whitespace separated word of the source and where it starts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Word {
+    pub text: String,
+    pub pos: Pos,
+}
+
+///A word of a definition or of the top level, with what it names already looked up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Number {
+        value: i64,
+        pos: Pos,
+    },
+    ///Builtin or control word, in lower case
+    Builtin {
+        name: String,
+        pos: Pos,
+    },
+    ///Colon definition the word named when it was compiled, by index
+    Call {
+        definition: usize,
+        pos: Pos,
+    },
+}
+
+#[derive(Debug, Default)]
+pub struct Program {
+    ///Bodies of the colon definitions in the order they were made
+    pub definitions: Vec<Vec<Token>>,
+    ///Words outside any definition, run in order
+    pub top: Vec<Token>,
+}
+
+///Split the source into words, leaving out `\` comments to the end of the line and `( )` comments
+pub fn words(source: &str) -> Result<Vec<Word>, ForthError> {
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    // Where the `( )` comment being skipped starts
+    let mut paren: Option<Pos> = None;
+    let mut backslash = false;
+    let mut pos = Pos { line: 1, column: 1 };
+
+    for c in source.chars() {
+        if backslash {
+            backslash = c != '\n';
+        } else if paren.is_some() {
+            if c == ')' {
+                paren = None;
+            }
+        } else if c.is_whitespace() {
+            if let Some(word) = current.take() {
+                match word.text.as_str() {
+                    "\\" => backslash = c != '\n',
+                    "(" => paren = Some(word.pos),
+                    _ => words.push(word),
+                }
+            }
+        } else {
+            current
+                .get_or_insert_with(|| Word {
+                    text: String::new(),
+                    pos,
+                })
+                .text
+                .push(c);
+        }
+
+        if c == '\n' {
+            pos = Pos {
+                line: pos.line + 1,
+                column: 1,
+            };
+        } else {
+            pos.column += 1;
+        }
+    }
+
+    match current {
+        Some(word) if word.text == "(" => paren = Some(word.pos),
+        Some(word) if word.text != "\\" => words.push(word),
+        _ => (),
+    }
+    if let Some(pos) = paren {
+        return Err(ForthError::Unclosed {
+            word: String::from("("),
+            pos,
+        });
+    }
+    Ok(words)
+}
+
+///Split the source into its colon definitions and top level, looking up every word as it comes
+pub fn parse_program(source: &str) -> Result<Program, ForthError> {
+    let mut program = Program::default();
+    let mut dictionary: HashMap<String, usize> = HashMap::new();
+    let mut words = words(source)?.into_iter();
+
+    while let Some(word) = words.next() {
+        match word.text.as_str() {
+            ":" => {
+                let name = words
+                    .next()
+                    .ok_or(ForthError::MissingName { pos: word.pos })?;
+                let lower = name.text.to_lowercase();
+                if CONTROL.contains(&lower.as_str()) {
+                    return Err(ForthError::Reserved {
+                        word: name.text,
+                        pos: name.pos,
+                    });
+                }
+                let mut body = Vec::new();
+                loop {
+                    let Some(next) = words.next() else {
+                        return Err(ForthError::Unclosed {
+                            word: word.text,
+                            pos: word.pos,
+                        });
+                    };
+                    match next.text.as_str() {
+                        ";" => break,
+                        ":" => return Err(ForthError::NestedDefinition { pos: next.pos }),
+                        _ => body.push(token(&next, &dictionary)?),
+                    }
+                }
+                verify(&body)?;
+                // The name only refers to the new definition from here on, so it cannot recurse
+                dictionary.insert(lower, program.definitions.len());
+                program.definitions.push(body);
+            }
+            ";" => {
+                return Err(ForthError::Unmatched {
+                    word: word.text,
+                    expected: ":",
+                    pos: word.pos,
+                });
+            }
+            _ => program.top.push(token(&word, &dictionary)?),
+        }
+    }
+    verify(&program.top)?;
+    Ok(program)
+}
+
+fn token(word: &Word, dictionary: &HashMap<String, usize>) -> Result<Token, ForthError> {
+    let (name, pos) = (word.text.to_lowercase(), word.pos);
+    if CONTROL.contains(&name.as_str()) {
+        return Ok(Token::Builtin { name, pos });
+    }
+    if let Some(&definition) = dictionary.get(&name) {
+        return Ok(Token::Call { definition, pos });
+    }
+    let digits = name.strip_prefix('-').unwrap_or(&name);
+    if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return name
+            .parse()
+            .map(|value| Token::Number { value, pos })
+            .map_err(|_| ForthError::NumberTooLarge {
+                literal: word.text.clone(),
+                pos,
+            });
+    }
+    if BUILTINS.contains(&name.as_str()) {
+        return Ok(Token::Builtin { name, pos });
+    }
+    Err(ForthError::UndefinedWord {
+        word: word.text.clone(),
+        pos,
+    })
+}
+
+///Check that the control words of a definition or of the top level pair up, that I and J are
+///inside enough DO loops and that PICK and ROLL have a literal before them
+fn verify(tokens: &[Token]) -> Result<(), ForthError> {
+    let mut open: Vec<(&str, Pos)> = Vec::new();
+    for (index, token) in tokens.iter().enumerate() {
+        let Token::Builtin { name, pos } = token else {
+            continue;
+        };
+        let unmatched = |expected| ForthError::Unmatched {
+            word: name.clone(),
+            expected,
+            pos: *pos,
+        };
+        let loops = open.iter().filter(|(opener, _)| *opener == "do").count();
+        match name.as_str() {
+            "if" | "begin" | "do" => open.push((name, *pos)),
+            "else" => match open.last_mut() {
+                Some((opener, _)) if *opener == "if" => *opener = "else",
+                _ => return Err(unmatched("if")),
+            },
+            "then" => match open.pop() {
+                Some(("if" | "else", _)) => (),
+                _ => return Err(unmatched("if")),
+            },
+            "until" => match open.pop() {
+                Some(("begin", _)) => (),
+                _ => return Err(unmatched("begin")),
+            },
+            "loop" => match open.pop() {
+                Some(("do", _)) => (),
+                _ => return Err(unmatched("do")),
+            },
+            "i" | "j" if loops < if name == "i" { 1 } else { 2 } => {
+                return Err(ForthError::OutsideLoop {
+                    word: name.clone(),
+                    pos: *pos,
+                });
+            }
+            "pick" | "roll" => {
+                let literal = index
+                    .checked_sub(1)
+                    .and_then(|before| match tokens[before] {
+                        Token::Number { value, .. } => u8::try_from(value).ok(),
+                        _ => None,
+                    });
+                if literal.is_none() {
+                    return Err(ForthError::NeedsLiteral {
+                        word: name.clone(),
+                        pos: *pos,
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+    match open.pop() {
+        Some((opener, pos)) => Err(ForthError::Unclosed {
+            word: opener.to_string(),
+            pos,
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments() {
+        let words = words("1 ( a\ncomment) 2 \\ to the end\n\t3 (").unwrap_err();
+        assert_eq!(words.to_string(), "3:4: `(` is never closed");
+        let words: Vec<(String, usize, usize)> =
+            super::words("1 ( a\ncomment) 2 \\ to the end\n\t3")
+                .unwrap()
+                .into_iter()
+                .map(|word| (word.text, word.pos.line, word.pos.column))
+                .collect();
+        assert_eq!(
+            words,
+            [
+                (String::from("1"), 1, 1),
+                (String::from("2"), 2, 10),
+                (String::from("3"), 3, 2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt::Display;
+
+///Line and column in the source, both counted from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pos {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Pos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+///Source position every instruction was compiled from, by address
+pub type Positions = Vec<(usize, Pos)>;
+
+#[derive(Debug, Default)]
+pub struct Program {
+    pub functions: Vec<Function>,
+    ///Top level statements, run in order
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub body: Vec<Statement>,
+    pub pos: Pos,
+}
+
+#[derive(Debug)]
+pub enum Statement {
+    Let {
+        name: String,
+        value: Expr,
+        pos: Pos,
+    },
+    Assign {
+        name: String,
+        value: Expr,
+        pos: Pos,
+    },
+    If {
+        condition: Expr,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+        pos: Pos,
+    },
+    While {
+        condition: Expr,
+        body: Vec<Statement>,
+        pos: Pos,
+    },
+    Return {
+        value: Expr,
+        pos: Pos,
+    },
+    Print {
+        value: Expr,
+    },
+    PrintText {
+        text: String,
+        pos: Pos,
+    },
+    Expr {
+        value: Expr,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+pub enum Expr {
+    Number {
+        value: u64,
+        pos: Pos,
+    },
+    Variable {
+        name: String,
+        pos: Pos,
+    },
+    Call {
+        name: String,
+        arguments: Vec<Expr>,
+        pos: Pos,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr>,
+        pos: Pos,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        pos: Pos,
+    },
+}
+
+impl Expr {
+    pub fn pos(&self) -> Pos {
+        match self {
+            Expr::Number { pos, .. }
+            | Expr::Variable { pos, .. }
+            | Expr::Call { pos, .. }
+            | Expr::Unary { pos, .. }
+            | Expr::Binary { pos, .. } => *pos,
+        }
+    }
+}
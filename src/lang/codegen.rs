@@ -0,0 +1,639 @@
+/*!Code generation from the syntax tree to bytecode
+ *
+ * Every value lives on the stack. The depth is known at every point of the
+ * program, so a variable is the slot it was pushed to and is read and written
+ * with LOAD and STORE. Calls are inlined: the arguments become the slots of the
+ * parameters and the result replaces them once the body is done.
+ */
+
+use std::collections::HashMap;
+
+use crate::{
+    code::instruction::Instruction,
+    lang::{
+        ast::{BinaryOp, Expr, Function, Pos, Positions, Program, Statement, UnaryOp},
+        lang_error::LangError,
+    },
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Item {
+    Op { instruction: Instruction, pos: Pos },
+    Jmp { label: usize, pos: Pos },
+    Jnz { label: usize, pos: Pos },
+    Label(usize),
+}
+
+///Variables visible in the body being compiled: the top level or one inlined call
+struct Frame {
+    scopes: Vec<Vec<(String, usize)>>,
+    ///Slot the result of the call ends up in, and the label after the call
+    call: Option<(usize, usize)>,
+}
+
+struct Codegen<'a> {
+    functions: HashMap<&'a str, &'a Function>,
+    items: Vec<Item>,
+    labels: usize,
+    depth: usize,
+    frames: Vec<Frame>,
+    ///Functions being inlined, innermost last
+    inlining: Vec<&'a str>,
+    ///Position of the statement or expression being compiled, which its instructions come from
+    pos: Pos,
+}
+
+///Compile a program to bytecode and the position in the source of every instruction
+pub fn generate(program: &Program) -> Result<(Vec<u8>, Positions), LangError> {
+    let mut functions: HashMap<&str, &Function> = HashMap::new();
+    for function in &program.functions {
+        if functions.insert(&function.name, function).is_some() {
+            return Err(LangError::DuplicateFunction {
+                name: function.name.clone(),
+                pos: function.pos,
+            });
+        }
+    }
+
+    let mut codegen = Codegen {
+        functions,
+        items: Vec::new(),
+        labels: 0,
+        depth: 0,
+        frames: vec![Frame {
+            scopes: vec![Vec::new()],
+            call: None,
+        }],
+        inlining: Vec::new(),
+        pos: Pos { line: 1, column: 1 },
+    };
+    for statement in &program.statements {
+        codegen.statement(statement)?;
+    }
+    codegen.assemble()
+}
+
+///Operand of a LOAD or STORE
+fn slot(slot: usize, pos: Pos) -> Result<u8, LangError> {
+    u8::try_from(slot).map_err(|_| LangError::TooManyValues { pos })
+}
+
+impl<'a> Codegen<'a> {
+    fn emit(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Push(_) | Instruction::Dup | Instruction::Load(_) => self.depth += 1,
+            Instruction::Pop | Instruction::Store(_) => self.depth -= 1,
+            Instruction::TwoDup => self.depth += 2,
+            _ if instruction.is_arithmetic() => self.depth -= 1,
+            _ => (),
+        }
+        self.items.push(Item::Op {
+            instruction,
+            pos: self.pos,
+        });
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.items.push(Item::Label(label));
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn lookup(&self, name: &str, pos: Pos) -> Result<usize, LangError> {
+        self.frames
+            .last()
+            .unwrap()
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(declared, _)| declared == name)
+            .map(|&(_, slot)| slot)
+            .ok_or_else(|| LangError::UndefinedVariable {
+                name: name.to_string(),
+                pos,
+            })
+    }
+
+    ///Compile statements in a scope of their own, dropping its variables at the end
+    fn block(&mut self, statements: &'a [Statement]) -> Result<(), LangError> {
+        self.frame().scopes.push(Vec::new());
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        let scope = self.frame().scopes.pop().unwrap();
+        for _ in scope {
+            self.emit(Instruction::Pop);
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &'a Statement) -> Result<(), LangError> {
+        let pos = match statement {
+            Statement::Let { pos, .. }
+            | Statement::Assign { pos, .. }
+            | Statement::If { pos, .. }
+            | Statement::While { pos, .. }
+            | Statement::Return { pos, .. }
+            | Statement::PrintText { pos, .. } => *pos,
+            Statement::Print { value } | Statement::Expr { value } => value.pos(),
+        };
+        let outer = std::mem::replace(&mut self.pos, pos);
+        match statement {
+            Statement::Let { name, value, pos } => {
+                self.expr(value)?;
+                let declared = self.depth - 1;
+                slot(declared, *pos)?;
+                let scope = self.frame().scopes.last_mut().unwrap();
+                scope.push((name.clone(), declared));
+            }
+            Statement::Assign { name, value, pos } => {
+                let target = slot(self.lookup(name, *pos)?, *pos)?;
+                self.expr(value)?;
+                self.emit(Instruction::Store(target));
+            }
+            Statement::If {
+                condition,
+                then,
+                otherwise,
+                pos,
+            } => {
+                // JNZ takes the branch that runs when the condition value is not 0
+                let (fallthrough, taken) = if inverts(condition) {
+                    (then, otherwise)
+                } else {
+                    (otherwise, then)
+                };
+                let (branch, end) = (self.label(), self.label());
+                self.condition(condition)?;
+                self.items.push(Item::Jnz {
+                    label: branch,
+                    pos: *pos,
+                });
+                self.emit(Instruction::Pop);
+                self.block(fallthrough)?;
+                self.items.push(Item::Jmp {
+                    label: end,
+                    pos: *pos,
+                });
+
+                self.place(branch);
+                self.depth += 1;
+                self.emit(Instruction::Pop);
+                self.block(taken)?;
+                self.place(end);
+            }
+            Statement::While {
+                condition,
+                body,
+                pos,
+            } => {
+                let (top, exit) = (self.label(), self.label());
+                if inverts(condition) {
+                    self.place(top);
+                    self.condition(condition)?;
+                    self.items.push(Item::Jnz {
+                        label: exit,
+                        pos: *pos,
+                    });
+                    self.emit(Instruction::Pop);
+                    self.block(body)?;
+                    self.items.push(Item::Jmp {
+                        label: top,
+                        pos: *pos,
+                    });
+                    self.place(exit);
+                    self.depth += 1;
+                    self.emit(Instruction::Pop);
+                } else {
+                    // The check sits after the body, so each pass takes a single jump
+                    let check = exit;
+                    self.items.push(Item::Jmp {
+                        label: check,
+                        pos: *pos,
+                    });
+                    self.place(top);
+                    self.depth += 1;
+                    self.emit(Instruction::Pop);
+                    self.block(body)?;
+                    self.place(check);
+                    self.condition(condition)?;
+                    self.items.push(Item::Jnz {
+                        label: top,
+                        pos: *pos,
+                    });
+                    self.emit(Instruction::Pop);
+                }
+            }
+            Statement::Return { value, pos } => {
+                let depth = self.depth;
+                self.expr(value)?;
+                match self.frames.last().unwrap().call {
+                    Some((result, end)) => {
+                        self.finish_call(result, *pos)?;
+                        self.items.push(Item::Jmp {
+                            label: end,
+                            pos: *pos,
+                        });
+                    }
+                    None => self.emit(Instruction::Ret),
+                }
+                // Nothing after the return runs, so the rest of the block keeps the old depth
+                self.depth = depth;
+            }
+            Statement::Print { value } => {
+                self.expr(value)?;
+                self.emit(Instruction::Print);
+                self.emit(Instruction::Pop);
+            }
+            Statement::PrintText { text, .. } => {
+                self.emit(Instruction::Push(0));
+                for &byte in text.as_bytes() {
+                    self.emit(Instruction::Push(byte));
+                }
+                // PCHAR consumes the 0 and pushes the text back
+                self.emit(Instruction::PChar);
+                self.depth -= 1;
+                for _ in text.as_bytes() {
+                    self.emit(Instruction::Pop);
+                }
+            }
+            Statement::Expr { value } => {
+                self.expr(value)?;
+                self.emit(Instruction::Pop);
+            }
+        }
+        self.pos = outer;
+        Ok(())
+    }
+
+    ///Push the value of a condition. `inverts` tells whether 0 means it holds.
+    fn condition(&mut self, condition: &'a Expr) -> Result<(), LangError> {
+        match condition {
+            Expr::Binary {
+                op: BinaryOp::Eq | BinaryOp::Ne,
+                lhs,
+                rhs,
+                ..
+            } => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                self.differs();
+                Ok(())
+            }
+            Expr::Unary {
+                op: UnaryOp::Not,
+                operand,
+                ..
+            } => self.condition(operand),
+            _ => self.expr(condition),
+        }
+    }
+
+    fn expr(&mut self, expr: &'a Expr) -> Result<(), LangError> {
+        let outer = std::mem::replace(&mut self.pos, expr.pos());
+        match expr {
+            Expr::Number { value, .. } => self.number(*value),
+            Expr::Variable { name, pos } => {
+                let source = slot(self.lookup(name, *pos)?, *pos)?;
+                self.emit(Instruction::Load(source));
+            }
+            Expr::Call {
+                name,
+                arguments,
+                pos,
+            } => self.call(name, arguments, *pos)?,
+            Expr::Unary { op, operand, pos } => match op {
+                UnaryOp::Neg => {
+                    self.emit(Instruction::Push(0));
+                    self.expr(operand)?;
+                    self.emit(Instruction::Sub);
+                }
+                UnaryOp::Not => {
+                    self.expr(operand)?;
+                    self.not(*pos);
+                }
+            },
+            Expr::Binary { op, lhs, rhs, .. } => {
+                self.expr(lhs)?;
+                self.expr(rhs)?;
+                match op {
+                    BinaryOp::Add => self.emit(Instruction::Add),
+                    BinaryOp::Sub => self.emit(Instruction::Sub),
+                    BinaryOp::Mul => self.emit(Instruction::Mul),
+                    BinaryOp::Div => self.emit(Instruction::Div),
+                    BinaryOp::Rem => self.emit(Instruction::Mod),
+                    BinaryOp::Lt => self.emit(Instruction::Lt),
+                    BinaryOp::Gt => {
+                        self.emit(Instruction::Swp);
+                        self.emit(Instruction::Lt);
+                    }
+                    BinaryOp::Le => {
+                        self.emit(Instruction::Swp);
+                        self.emit(Instruction::Lt);
+                        self.flip();
+                    }
+                    BinaryOp::Ge => {
+                        self.emit(Instruction::Lt);
+                        self.flip();
+                    }
+                    BinaryOp::Eq => {
+                        self.differs();
+                        self.flip();
+                    }
+                    BinaryOp::Ne => self.differs(),
+                }
+            }
+        }
+        self.pos = outer;
+        Ok(())
+    }
+
+    ///Push a non-negative number, building the ones PUSH cannot hold in base 255
+    fn number(&mut self, value: u64) {
+        match u8::try_from(value) {
+            Ok(byte) => self.emit(Instruction::Push(byte)),
+            Err(_) => {
+                self.number(value / 255);
+                self.emit(Instruction::Push(255));
+                self.emit(Instruction::Mul);
+                if !value.is_multiple_of(255) {
+                    self.emit(Instruction::Push((value % 255) as u8));
+                    self.emit(Instruction::Add);
+                }
+            }
+        }
+    }
+
+    ///Replace the top of the stack with 1 if it is 0, and with 0 otherwise
+    fn not(&mut self, pos: Pos) {
+        let (nonzero, end) = (self.label(), self.label());
+        self.items.push(Item::Jnz {
+            label: nonzero,
+            pos,
+        });
+        self.emit(Instruction::Pop);
+        self.emit(Instruction::Push(1));
+        self.items.push(Item::Jmp { label: end, pos });
+        self.place(nonzero);
+        self.emit(Instruction::Pop);
+        self.emit(Instruction::Push(0));
+        self.place(end);
+    }
+
+    ///Replace the two values on top of the stack with 0 if they are equal, and with 1 otherwise
+    fn differs(&mut self) {
+        for instruction in DIFFERS {
+            self.emit(instruction);
+        }
+    }
+
+    ///Turn the 0 or 1 on top of the stack into 1 or 0
+    fn flip(&mut self) {
+        self.emit(Instruction::Push(1));
+        self.emit(Instruction::Swp);
+        self.emit(Instruction::Sub);
+    }
+
+    fn call(&mut self, name: &'a str, arguments: &'a [Expr], pos: Pos) -> Result<(), LangError> {
+        let Some(&function) = self.functions.get(name) else {
+            return Err(LangError::UndefinedFunction {
+                name: name.to_string(),
+                pos,
+            });
+        };
+        if arguments.len() != function.parameters.len() {
+            return Err(LangError::ArgumentCount {
+                name: name.to_string(),
+                expected: function.parameters.len(),
+                found: arguments.len(),
+                pos,
+            });
+        }
+        if self.inlining.contains(&name) {
+            return Err(LangError::Recursion {
+                name: name.to_string(),
+                pos,
+            });
+        }
+
+        for argument in arguments {
+            self.expr(argument)?;
+        }
+        let result = self.depth - arguments.len();
+        let parameters = function.parameters.iter().cloned().zip(result..).collect();
+        let end = self.label();
+        self.frames.push(Frame {
+            scopes: vec![parameters],
+            call: Some((result, end)),
+        });
+        self.inlining.push(name);
+
+        for statement in &function.body {
+            self.statement(statement)?;
+        }
+        // Falling off the end of the body returns 0
+        self.emit(Instruction::Push(0));
+        self.finish_call(result, pos)?;
+        self.place(end);
+
+        self.inlining.pop();
+        self.frames.pop();
+        Ok(())
+    }
+
+    ///Move the value on top of the stack down to `result`, dropping everything above it
+    fn finish_call(&mut self, result: usize, pos: Pos) -> Result<(), LangError> {
+        if self.depth - 1 > result {
+            self.emit(Instruction::Store(slot(result, pos)?));
+            while self.depth > result + 1 {
+                self.emit(Instruction::Pop);
+            }
+        }
+        Ok(())
+    }
+
+    ///Lay the code out and resolve the labels to addresses
+    fn assemble(&self) -> Result<(Vec<u8>, Positions), LangError> {
+        let mut addresses: Vec<usize> = vec![0; self.labels];
+        let mut size = 0;
+        for item in &self.items {
+            match item {
+                Item::Op { instruction, .. } => size += instruction.size(),
+                Item::Jmp { .. } | Item::Jnz { .. } => size += 2,
+                Item::Label(label) => addresses[*label] = size,
+            }
+        }
+
+        let mut code: Vec<u8> = Vec::with_capacity(size);
+        let mut lines = Vec::new();
+        for item in &self.items {
+            let (instruction, pos) = match *item {
+                Item::Op { instruction, pos } => (instruction, pos),
+                Item::Jmp { label, pos } | Item::Jnz { label, pos } => {
+                    let target = addresses[label];
+                    if target > u8::MAX as usize {
+                        return Err(LangError::ProgramTooLarge { size, pos });
+                    }
+                    match item {
+                        Item::Jmp { .. } => (Instruction::Jmp(target), pos),
+                        _ => (Instruction::Jnz(target), pos),
+                    }
+                }
+                Item::Label(_) => continue,
+            };
+            lines.push((code.len(), pos));
+            instruction.encode(&mut code);
+        }
+        Ok((code, lines))
+    }
+}
+
+///Replaces the two values on top of the stack with 0 if they are equal, and with 1 otherwise.
+///Their difference could overflow, so they are compared with LT both ways instead.
+pub(crate) const DIFFERS: [Instruction; 7] = [
+    Instruction::TwoDup,
+    Instruction::Lt,
+    Instruction::Rot,
+    Instruction::Rot,
+    Instruction::Swp,
+    Instruction::Lt,
+    Instruction::Add,
+];
+
+///Whether the value `Codegen::condition` pushes for this condition is 0 when it holds
+fn inverts(condition: &Expr) -> bool {
+    match condition {
+        Expr::Binary {
+            op: BinaryOp::Eq, ..
+        } => true,
+        Expr::Unary {
+            op: UnaryOp::Not,
+            operand,
+            ..
+        } => !inverts(operand),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        lang::{SourceError, compile},
+        stack::Stack,
+    };
+
+    fn run(source: &str) -> i64 {
+        let code = compile(source).unwrap();
+        Stack::<i64>::new().execute(&code).unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(run("return 1 + 2 * 3 - 4;"), 3);
+        assert_eq!(run("return (7 - 10) / 2 % 5;"), -1);
+        assert_eq!(run("return -(3 * 4);"), -12);
+        assert_eq!(run("return 1000000;"), 1000000);
+        assert_eq!(run("return 9223372036854775807;"), i64::MAX);
+    }
+    #[test]
+    fn comparisons() {
+        let cases = [
+            ("3 < 4", 1),
+            ("4 < 4", 0),
+            ("4 <= 4", 1),
+            ("5 <= 4", 0),
+            ("5 > 4", 1),
+            ("4 >= 5", 0),
+            ("4 == 4", 1),
+            ("4 == 5", 0),
+            ("4 != 5", 1),
+            ("4 != 4", 0),
+            ("!0", 1),
+            ("!7", 0),
+        ];
+        for (expression, expected) in cases {
+            assert_eq!(
+                run(&format!("return {expression};")),
+                expected,
+                "{expression}"
+            );
+        }
+
+        // Operands whose difference does not fit in an i64
+        let source = "let a = 0 - 9000000000000000000;\nlet b = 9000000000000000000;\nlet n = 0;\nif a == b { n = n + 1; }\nif a != b { n = n + 10; }\nwhile a == b { n = n + 100; }\nreturn n + (a == b) * 1000 + (a != b) * 10000;";
+        assert_eq!(run(source), 10010);
+    }
+    #[test]
+    fn variables_and_scopes() {
+        let source = "let x = 2;\nlet y = x * 10;\nif y == 20 { let z = 1; x = x + z; }\nx = x + y;\nreturn x;";
+        assert_eq!(run(source), 23);
+    }
+    #[test]
+    fn control_flow() {
+        let source = "let i = 0;\nlet n = 0;\nwhile i < 10 {\n    if i % 2 == 0 { n = n + i; } else if i == 5 { n = n + 100; } else { n = n - 1; }\n    i = i + 1;\n}\nreturn n;";
+        assert_eq!(run(source), 20 + 100 - 4);
+
+        let source =
+            "let i = 3;\nwhile !(i == 0) { i = i - 1; }\nwhile i != 5 { i = i + 1; }\nreturn i;";
+        assert_eq!(run(source), 5);
+    }
+    #[test]
+    fn functions() {
+        let source = "fn square(x) { return x * x; }\nfn clamp(x, low, high) {\n    if x < low { return low; }\n    if x > high { return high; }\n    return x;\n}\nfn nothing() { let unused = 1; }\nreturn square(clamp(9, 1, 5)) + 1 - nothing();";
+        assert_eq!(run(source), 26);
+    }
+    #[test]
+    fn documented_example() {
+        let mut stack = Stack::<i64>::new();
+        let code = compile(
+            "fn square(x) {\n    return x * x;\n}\n\nlet sum = 0;\nlet i = 1;\nwhile i <= 10 {\n    sum = sum + square(i);\n    i = i + 1;\n}\nprint \"sum:\";\nprint sum;\nreturn sum;",
+        )
+        .unwrap();
+        assert_eq!(stack.execute(&code).unwrap(), 385);
+    }
+    #[test]
+    fn errors() {
+        let error = |source: &str| compile(source).unwrap_err().to_string();
+        assert_eq!(error("let x = 1;\nprint y;"), "2:7: undefined variable `y`");
+        assert_eq!(error("print f(1);"), "1:7: undefined function `f`");
+        assert_eq!(
+            error("fn f(a) { return a; }\nprint f(1, 2);"),
+            "2:7: `f` takes 1 arguments but 2 were given"
+        );
+        assert_eq!(
+            error("fn f(a) { return g(a); }\nfn g(a) { return f(a); }\nprint f(1);"),
+            "2:18: `f` calls itself, recursion is not supported"
+        );
+        assert_eq!(
+            error("fn f() { return 1; }\nfn f() { return 2; }"),
+            "2:1: function `f` is already defined"
+        );
+        assert_eq!(
+            error("fn f() { return x; }\nlet x = 1;\nprint f();"),
+            "1:17: undefined variable `x`"
+        );
+
+        let long = "print \"a long line of text to print\";\n".repeat(4);
+        assert_eq!(
+            error(&format!("{long}while 1 {{ }}")),
+            "5:1: the program is 356 bytes, but a jump can only reach byte 255"
+        );
+    }
+    #[test]
+    fn render() {
+        let source = "let x = 1;\nprint x + y;";
+        let error = compile(source).unwrap_err();
+        assert_eq!(
+            error.render("test.crd", source),
+            "test.crd:2:11: undefined variable `y`\nprint x + y;\n          ^"
+        );
+    }
+}
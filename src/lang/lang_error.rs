@@ -0,0 +1,54 @@
+use thiserror::Error;
+
+use crate::lang::{SourceError, ast::Pos, parse::Rule, render_at};
+
+#[derive(Debug, Error)]
+pub enum LangError {
+    #[error("{0}")]
+    Syntax(Box<pest::error::Error<Rule>>),
+    #[error("{pos}: integer literal `{literal}` is too large")]
+    NumberTooLarge { literal: String, pos: Pos },
+    #[error("{pos}: undefined variable `{name}`")]
+    UndefinedVariable { name: String, pos: Pos },
+    #[error("{pos}: undefined function `{name}`")]
+    UndefinedFunction { name: String, pos: Pos },
+    #[error("{pos}: function `{name}` is already defined")]
+    DuplicateFunction { name: String, pos: Pos },
+    #[error("{pos}: `{name}` takes {expected} arguments but {found} were given")]
+    ArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+        pos: Pos,
+    },
+    #[error("{pos}: `{name}` calls itself, recursion is not supported")]
+    Recursion { name: String, pos: Pos },
+    #[error("{pos}: too many values on the stack, only the first 256 can be addressed")]
+    TooManyValues { pos: Pos },
+    #[error("{pos}: the program is {size} bytes, but a jump can only reach byte 255")]
+    ProgramTooLarge { size: usize, pos: Pos },
+}
+
+impl SourceError for LangError {
+    fn pos(&self) -> Option<Pos> {
+        match self {
+            LangError::Syntax(_) => None,
+            LangError::NumberTooLarge { pos, .. }
+            | LangError::UndefinedVariable { pos, .. }
+            | LangError::UndefinedFunction { pos, .. }
+            | LangError::DuplicateFunction { pos, .. }
+            | LangError::ArgumentCount { pos, .. }
+            | LangError::Recursion { pos, .. }
+            | LangError::TooManyValues { pos }
+            | LangError::ProgramTooLarge { pos, .. } => Some(*pos),
+        }
+    }
+
+    ///Pest's own rendering for syntax errors, which already shows the line
+    fn render(&self, path: &str, source: &str) -> String {
+        match self {
+            LangError::Syntax(error) => error.clone().with_path(path).to_string(),
+            _ => render_at(path, self, self.pos(), source),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+/*!A small structured language compiled to corrode bytecode, in `.crd` files
+ *
+ * ```text
+ * // Sum of the first ten squares
+ * fn square(x) {
+ *     return x * x;
+ * }
+ *
+ * let sum = 0;
+ * let i = 1;
+ * while i <= 10 {
+ *     sum = sum + square(i);
+ *     i = i + 1;
+ * }
+ * print "sum:";
+ * print sum;
+ * return sum;
+ * ```
+ *
+ * Values are 64 bit integers. Expressions have `+ - * / %`, comparisons
+ * `== != < <= > >=` giving 1 or 0, prefix `-` and `!`, and calls. Conditions
+ * hold when they are not 0.
+ *
+ * `let` declares a variable for the rest of the enclosing block. Functions only
+ * see their parameters and their own variables, return 0 when they end without
+ * `return`, and are inlined at every call, so they cannot be recursive. A
+ * `return` at the top level ends the program with its value.
+ *
+ * Jumps address a single byte, so a program with loops or conditions has to fit
+ * in the first 256 bytes of code.
+ */
+
+use std::{fmt::Display, fs};
+
+use anyhow::anyhow;
+
+use crate::{
+    code::link::DebugInfo,
+    lang::{
+        ast::{Pos, Positions},
+        lang_error::LangError,
+    },
+};
+
+pub mod ast;
+pub mod codegen;
+pub mod lang_error;
+pub mod parse;
+
+///Compile source text to bytecode
+pub fn compile(source: &str) -> Result<Vec<u8>, LangError> {
+    Ok(codegen::generate(&parse::parse_program(source)?)?.0)
+}
+
+///Compile a source file, rendering errors with the line they point at, and keep the line of
+///every instruction
+pub fn compile_file(input_file: &str) -> anyhow::Result<(Vec<u8>, DebugInfo)> {
+    compile_source_file(input_file, |source| {
+        codegen::generate(&parse::parse_program(source)?)
+    })
+}
+
+///Compile error of a front end, pointing at a position in the source
+pub trait SourceError: Display {
+    fn pos(&self) -> Option<Pos>;
+
+    ///Message prefixed with the file name and followed by the offending source line
+    fn render(&self, path: &str, source: &str) -> String {
+        render_at(path, self, self.pos(), source)
+    }
+}
+
+///`message` prefixed with the file name and followed by the source line at `pos` with a caret
+///under the column
+pub(crate) fn render_at(
+    path: &str,
+    message: &(impl Display + ?Sized),
+    pos: Option<Pos>,
+    source: &str,
+) -> String {
+    let mut rendered = format!("{path}:{message}");
+    if let Some(pos) = pos
+        && let Some(line) = source.lines().nth(pos.line - 1)
+    {
+        rendered.push_str(&format!("\n{line}\n{:>1$}", "^", pos.column));
+    }
+    rendered
+}
+
+///Read `input_file` and `compile` it, rendering errors with the line they point at, and keep
+///the line of every instruction. Both front ends compile their files this way.
+pub(crate) fn compile_source_file<E: SourceError>(
+    input_file: &str,
+    compile: impl FnOnce(&str) -> Result<(Vec<u8>, Positions), E>,
+) -> anyhow::Result<(Vec<u8>, DebugInfo)> {
+    let source = fs::read_to_string(input_file)?;
+    let (code, positions) =
+        compile(&source).map_err(|error| anyhow!(error.render(input_file, &source)))?;
+    let positions = positions
+        .into_iter()
+        .map(|(address, pos)| (address, pos.line, pos.column));
+    Ok((code, DebugInfo::compiled(input_file, positions)))
+}
@@ -0,0 +1,298 @@
+use std::sync::LazyLock;
+
+use pest::{
+    Parser,
+    iterators::{Pair, Pairs},
+    pratt_parser::{Assoc, Op, PrattParser},
+};
+use pest_derive::Parser;
+
+use crate::lang::{
+    ast::{BinaryOp, Expr, Function, Pos, Program, Statement, UnaryOp},
+    lang_error::LangError,
+};
+
+#[derive(Parser)]
+#[grammar = "./lang/grammar.pest"]
+pub struct LangParser;
+
+///Comparisons bind loosest, then addition, then multiplication, then prefix operators
+static PRATT: LazyLock<PrattParser<Rule>> = LazyLock::new(|| {
+    PrattParser::new()
+        .op(Op::infix(Rule::eq, Assoc::Left)
+            | Op::infix(Rule::ne, Assoc::Left)
+            | Op::infix(Rule::lt, Assoc::Left)
+            | Op::infix(Rule::le, Assoc::Left)
+            | Op::infix(Rule::gt, Assoc::Left)
+            | Op::infix(Rule::ge, Assoc::Left))
+        .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::sub, Assoc::Left))
+        .op(Op::infix(Rule::mul, Assoc::Left)
+            | Op::infix(Rule::div, Assoc::Left)
+            | Op::infix(Rule::rem, Assoc::Left))
+        .op(Op::prefix(Rule::neg) | Op::prefix(Rule::not))
+});
+
+pub fn parse_program(source: &str) -> Result<Program, LangError> {
+    let parsed = LangParser::parse(Rule::program, source)
+        .map_err(|error| LangError::Syntax(Box::new(error)))?
+        .next()
+        .unwrap();
+
+    let mut program = Program::default();
+    for item in parsed.into_inner() {
+        match item.as_rule() {
+            Rule::function => program.functions.push(function(item)?),
+            Rule::EOI => (),
+            _ => program.statements.push(statement(item)?),
+        }
+    }
+    Ok(program)
+}
+
+fn pos(pair: &Pair<Rule>) -> Pos {
+    let (line, column) = pair.line_col();
+    Pos { line, column }
+}
+
+fn function(pair: Pair<Rule>) -> Result<Function, LangError> {
+    let pos = pos(&pair);
+    let mut inner = pair.into_inner();
+    let name = inner.next().unwrap().as_str().to_string();
+    let parameters = inner
+        .next()
+        .unwrap()
+        .into_inner()
+        .map(|parameter| parameter.as_str().to_string())
+        .collect();
+    let body = block(inner.next().unwrap())?;
+    Ok(Function {
+        name,
+        parameters,
+        body,
+        pos,
+    })
+}
+
+fn block(pair: Pair<Rule>) -> Result<Vec<Statement>, LangError> {
+    pair.into_inner().map(statement).collect()
+}
+
+fn statement(pair: Pair<Rule>) -> Result<Statement, LangError> {
+    let pos = pos(&pair);
+    let rule = pair.as_rule();
+    let mut inner = pair.into_inner();
+
+    Ok(match rule {
+        Rule::let_statement | Rule::assignment => {
+            let name = inner.next().unwrap().as_str().to_string();
+            let value = expression(inner.next().unwrap())?;
+            if rule == Rule::let_statement {
+                Statement::Let { name, value, pos }
+            } else {
+                Statement::Assign { name, value, pos }
+            }
+        }
+        Rule::if_statement => {
+            let condition = expression(inner.next().unwrap())?;
+            let then = block(inner.next().unwrap())?;
+            let otherwise = match inner.next() {
+                Some(chained) if chained.as_rule() == Rule::if_statement => {
+                    vec![statement(chained)?]
+                }
+                Some(otherwise) => block(otherwise)?,
+                None => Vec::new(),
+            };
+            Statement::If {
+                condition,
+                then,
+                otherwise,
+                pos,
+            }
+        }
+        Rule::while_statement => Statement::While {
+            condition: expression(inner.next().unwrap())?,
+            body: block(inner.next().unwrap())?,
+            pos,
+        },
+        Rule::return_statement => Statement::Return {
+            value: expression(inner.next().unwrap())?,
+            pos,
+        },
+        Rule::print_statement => {
+            let argument = inner.next().unwrap();
+            if argument.as_rule() == Rule::string {
+                Statement::PrintText {
+                    text: unescape(argument.into_inner().as_str()),
+                    pos,
+                }
+            } else {
+                Statement::Print {
+                    value: expression(argument)?,
+                }
+            }
+        }
+        Rule::expression_statement => Statement::Expr {
+            value: expression(inner.next().unwrap())?,
+        },
+        _ => unreachable!(),
+    })
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some(escaped) => unescaped.push(escaped),
+                None => (),
+            },
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+fn expression(pair: Pair<Rule>) -> Result<Expr, LangError> {
+    operators(pair.into_inner())
+}
+
+fn operators(pairs: Pairs<Rule>) -> Result<Expr, LangError> {
+    PRATT
+        .map_primary(primary)
+        .map_prefix(|op, operand| {
+            Ok(Expr::Unary {
+                op: match op.as_rule() {
+                    Rule::neg => UnaryOp::Neg,
+                    _ => UnaryOp::Not,
+                },
+                operand: Box::new(operand?),
+                pos: pos(&op),
+            })
+        })
+        .map_infix(|lhs, op, rhs| {
+            Ok(Expr::Binary {
+                op: match op.as_rule() {
+                    Rule::add => BinaryOp::Add,
+                    Rule::sub => BinaryOp::Sub,
+                    Rule::mul => BinaryOp::Mul,
+                    Rule::div => BinaryOp::Div,
+                    Rule::rem => BinaryOp::Rem,
+                    Rule::eq => BinaryOp::Eq,
+                    Rule::ne => BinaryOp::Ne,
+                    Rule::lt => BinaryOp::Lt,
+                    Rule::le => BinaryOp::Le,
+                    Rule::gt => BinaryOp::Gt,
+                    _ => BinaryOp::Ge,
+                },
+                lhs: Box::new(lhs?),
+                rhs: Box::new(rhs?),
+                pos: pos(&op),
+            })
+        })
+        .parse(pairs)
+}
+
+fn primary(pair: Pair<Rule>) -> Result<Expr, LangError> {
+    let pos = pos(&pair);
+    match pair.as_rule() {
+        Rule::number => {
+            let literal = pair.as_str();
+            match literal.parse::<i64>() {
+                Ok(value) => Ok(Expr::Number {
+                    value: value as u64,
+                    pos,
+                }),
+                Err(_) => Err(LangError::NumberTooLarge {
+                    literal: literal.to_string(),
+                    pos,
+                }),
+            }
+        }
+        Rule::identifier => Ok(Expr::Variable {
+            name: pair.as_str().to_string(),
+            pos,
+        }),
+        Rule::call => {
+            let mut inner = pair.into_inner();
+            let name = inner.next().unwrap().as_str().to_string();
+            Ok(Expr::Call {
+                name,
+                arguments: inner.map(expression).collect::<Result<_, _>>()?,
+                pos,
+            })
+        }
+        Rule::expression => expression(pair),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn precedence() {
+        let program = parse_program("print 1 + 2 * 3 < -x;").unwrap();
+        let Statement::Print { value } = &program.statements[0] else {
+            panic!()
+        };
+        let Expr::Binary { op, lhs, rhs, pos } = value else {
+            panic!()
+        };
+        assert_eq!(*op, BinaryOp::Lt);
+        assert_eq!(
+            *pos,
+            Pos {
+                line: 1,
+                column: 17
+            }
+        );
+        assert!(matches!(
+            **lhs,
+            Expr::Binary {
+                op: BinaryOp::Add,
+                ..
+            }
+        ));
+        assert!(matches!(
+            **rhs,
+            Expr::Unary {
+                op: UnaryOp::Neg,
+                ..
+            }
+        ));
+    }
+    #[test]
+    fn statements() {
+        let source = "fn f(a, b) {\n    return a;\n}\n// comment\nlet x = f(1, 2);\nif x == 1 { x = 2; } else if x { print \"a\\\"b\"; }\nwhile x { x = x - 1; }\n";
+        let program = parse_program(source).unwrap();
+        assert_eq!(program.functions[0].parameters, ["a", "b"]);
+        assert_eq!(program.statements.len(), 3);
+        let Statement::If { otherwise, .. } = &program.statements[1] else {
+            panic!()
+        };
+        let Statement::If { then, .. } = &otherwise[0] else {
+            panic!()
+        };
+        assert!(matches!(&then[0], Statement::PrintText { text, .. } if text == "a\"b"));
+    }
+    #[test]
+    fn errors() {
+        let Err(LangError::Syntax(error)) = parse_program("let x = ;") else {
+            panic!()
+        };
+        assert_eq!(error.line_col, pest::error::LineColLocation::Pos((1, 9)));
+        assert!(matches!(
+            parse_program("let while = 1;"),
+            Err(LangError::Syntax(_))
+        ));
+        assert!(matches!(
+            parse_program("\nprint 99999999999999999999;"),
+            Err(LangError::NumberTooLarge {
+                pos: Pos { line: 2, column: 7 },
+                ..
+            })
+        ));
+    }
+}
@@ -1,2 +1,5 @@
 pub mod code;
+pub mod forth;
+pub mod lang;
 pub mod stack;
+pub mod vm;
@@ -1,20 +1,116 @@
 /*!A stack based language loosely inspired by Java Bytecode and Forth
 !*/
 
-use corrode::code::code_execution::run;
+use corrode::code::{
+    c_backend::transpile_c,
+    check::check_files,
+    code_execution::{resume, run_files},
+    coverage::coverage,
+    debugger::debug,
+    format::format_files,
+    lsp::serve,
+    parse::link_files,
+    profiler::profile,
+    test_runner::test,
+    wasm_backend::compile_wasm,
+};
 
 use anyhow::Result;
-use std::error::Error;
+use num::{
+    BigInt, Integer, ToPrimitive,
+    traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, NumOps},
+};
+use std::{
+    error::Error,
+    fmt::{Debug, Display},
+    path::Path,
+};
 
 fn main() -> Result<(), Box<dyn Error>> {
-    run::<i64>("hello_world.cor")?;
+    // -DNAME or -DNAME=VALUE define a name for conditional assembly
+    let mut defines: Vec<(String, i64)> = Vec::new();
+    let mut args: Vec<String> = Vec::new();
+    // --bignum runs on arbitrary-precision cells instead of i64
+    let mut bignum = false;
+    for arg in std::env::args().skip(1) {
+        if arg == "--bignum" {
+            bignum = true;
+            continue;
+        }
+        match arg.strip_prefix("-D") {
+            Some(define) => defines.push(match define.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.parse()?),
+                None => (define.to_string(), 1),
+            }),
+            None => args.push(arg),
+        }
+    }
+    let defines: Vec<(&str, i64)> = defines
+        .iter()
+        .map(|(name, value)| (name.as_str(), *value))
+        .collect();
 
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if bignum {
+        command::<BigInt>(&args, &defines)
+    } else {
+        command::<i64>(&args, &defines)
+    }
+}
+
+///Carry out the command in `args` with stack cells of type `T`
+fn command<T>(args: &[&str], defines: &[(&str, i64)]) -> Result<(), Box<dyn Error>>
+where
+    T: Debug
+        + ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    match args {
+        ["c", files @ ..] if !files.is_empty() => {
+            print!("{}", transpile_c(&link_files(files, defines)?))
+        }
+        ["wasm", files @ ..] if !files.is_empty() => std::fs::write(
+            Path::new(files[0]).with_extension("wasm"),
+            compile_wasm(&link_files(files, defines)?),
+        )?,
+        ["coverage", files @ ..] if !files.is_empty() => {
+            coverage::<T>(files, defines)?;
+        }
+        ["profile", files @ ..] if !files.is_empty() => {
+            profile::<T>(files, defines)?;
+        }
+        ["check", files @ ..] if !files.is_empty() => check_files::<T>(files, defines)?,
+        ["lsp"] => serve()?,
+        ["fmt", "--check", files @ ..] if !files.is_empty() => format_files(files, true)?,
+        ["fmt", files @ ..] if !files.is_empty() => format_files(files, false)?,
+        ["test", "--update", files @ ..] if !files.is_empty() => test::<T>(files, defines, true)?,
+        ["test", files @ ..] if !files.is_empty() => test::<T>(files, defines, false)?,
+        ["debug", files @ ..] if !files.is_empty() => debug::<T>(files, defines)?,
+        ["resume", snapshot, files @ ..] if !files.is_empty() => {
+            resume::<T>(snapshot, files, defines)?;
+        }
+        [] => {
+            run_files::<T>(&["hello_world.cor"], defines)?;
+        }
+        files => {
+            run_files::<T>(files, defines)?;
+        }
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use corrode::code::code_execution::run;
     #[test]
     fn run_test() {
         let retval: u8 = run("./testfiles/testfile.cor").unwrap();
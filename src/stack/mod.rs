@@ -5,13 +5,44 @@ use std::fmt::Display;
 
 use num::{Integer, ToPrimitive, traits::NumOps};
 
+use crate::stack::scheduler::Scheduler;
+
+pub mod scheduler;
+pub mod snapshot;
+pub mod snapshot_error;
 pub mod stack_error;
 pub mod stack_operations;
 pub mod stack_trace;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Stack<T: ToPrimitive + Integer + NumOps + Display + From<u8> + PartialEq + PartialOrd> {
     pub state: Vec<T>,
     pub idx: usize,
     pub op: u8,
+    ///Frames opened by ENTER, innermost last
+    pub frames: Vec<Frame>,
+    ///Handlers installed by TRY, innermost last
+    pub handlers: Vec<Handler>,
+    ///Coroutines started by SPAWN and the channels between them
+    pub scheduler: Scheduler<T>,
+    ///Lines PRINT and PCHAR write, collected here instead of printed while it is `Some`
+    pub captured: Option<String>,
+}
+
+///Local slots allocated by ENTER
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    ///Frame pointer: index in `state` of local 0
+    pub base: usize,
+    pub size: usize,
+}
+
+///Where a TRY sends errors, and what it unwinds to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handler {
+    pub address: usize,
+    ///Stack depth at the TRY
+    pub depth: usize,
+    ///Number of frames open at the TRY
+    pub frames: usize,
 }
@@ -0,0 +1,161 @@
+/*!Cooperative coroutines sharing one `Stack`
+ *
+ * The coroutine that is running lives in the `Stack` itself. The others wait in
+ * the scheduler's queue with their own state, `idx`, frames and handlers, and
+ * are swapped in round-robin by YIELD, by a RECV on an empty channel and when
+ * a coroutine other than the first one finishes. Channels are numbered queues
+ * of values, shared by all coroutines.
+ */
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    mem,
+};
+
+use num::{Integer, ToPrimitive, traits::NumOps};
+
+use crate::stack::{Frame, Handler, Stack, stack_error::StackError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheduler<T> {
+    ///Id of the running coroutine, 0 for the one `execute` started with
+    pub current: usize,
+    ///Coroutines waiting for their turn, the next one first
+    pub queue: VecDeque<Coroutine<T>>,
+    ///Values sent and not received yet, by channel
+    pub channels: BTreeMap<u8, VecDeque<T>>,
+    ///Id the next SPAWN gives its coroutine
+    pub next_id: usize,
+}
+
+impl<T> Default for Scheduler<T> {
+    fn default() -> Self {
+        Scheduler {
+            current: 0,
+            queue: VecDeque::new(),
+            channels: BTreeMap::new(),
+            next_id: 1,
+        }
+    }
+}
+
+///Coroutine that is not running
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coroutine<T> {
+    pub id: usize,
+    pub state: Vec<T>,
+    pub idx: usize,
+    pub frames: Vec<Frame>,
+    pub handlers: Vec<Handler>,
+    ///Channel it waits to receive from
+    pub blocked: Option<u8>,
+}
+
+impl<T> Scheduler<T> {
+    fn runnable(&self, coroutine: &Coroutine<T>) -> bool {
+        coroutine.blocked.is_none_or(|channel| {
+            self.channels
+                .get(&channel)
+                .is_some_and(|queue| !queue.is_empty())
+        })
+    }
+}
+
+impl<T: Clone + ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
+    ///SPAWN: queue a coroutine starting at `address` with an empty stack
+    pub(crate) fn spawn(&mut self, address: usize) {
+        let id = self.scheduler.next_id;
+        self.scheduler.next_id += 1;
+        self.scheduler.queue.push_back(Coroutine {
+            id,
+            state: Vec::new(),
+            idx: address,
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            blocked: None,
+        });
+    }
+
+    ///SEND: pop a value onto `channel`
+    pub(crate) fn send(&mut self, channel: u8) -> Result<(), StackError> {
+        let value = self.pop()?;
+        self.scheduler
+            .channels
+            .entry(channel)
+            .or_default()
+            .push_back(value);
+        Ok(())
+    }
+
+    ///RECV: push the oldest value on `channel` and go on at `next`, or wait for one
+    pub(crate) fn receive(&mut self, channel: u8, next: usize) -> Result<(), StackError> {
+        let value = self
+            .scheduler
+            .channels
+            .get_mut(&channel)
+            .and_then(VecDeque::pop_front);
+        match value {
+            Some(value) => {
+                self.idx = next;
+                self.push(value)
+            }
+            // The RECV runs again once the coroutine is back
+            None => self.reschedule(Some(channel), false).map(drop),
+        }
+    }
+
+    ///Swap in the first coroutine of the queue that can run, queueing the current one unless it
+    ///has `finished`, in which case it is given back. A coroutine that only yields keeps running
+    ///when no other can; otherwise every coroutine is waiting to receive, which is a deadlock at
+    ///the RECV of the current one, or of the first in the queue when the current one finished.
+    pub(crate) fn reschedule(
+        &mut self,
+        blocked: Option<u8>,
+        finished: bool,
+    ) -> Result<Option<Coroutine<T>>, StackError> {
+        let scheduler = &self.scheduler;
+        let next = scheduler
+            .queue
+            .iter()
+            .position(|coroutine| scheduler.runnable(coroutine));
+        let Some(next) = next else {
+            if blocked.is_none() && !finished {
+                return Ok(None);
+            }
+            let mut waiting: Vec<(usize, u8)> = scheduler
+                .queue
+                .iter()
+                .filter_map(|coroutine| Some((coroutine.id, coroutine.blocked?)))
+                .chain(blocked.map(|channel| (scheduler.current, channel)))
+                .collect();
+            waiting.sort_unstable();
+            // A coroutine that finished is not waiting, so the RECV of the one that has waited
+            // longest is reported instead
+            let (idx, op) = match (blocked, scheduler.queue.front()) {
+                (None, Some(longest)) => (longest.idx, 0x53),
+                _ => (self.idx, self.op),
+            };
+            return Err(StackError::Deadlock {
+                idx,
+                op,
+                blocked: waiting,
+            });
+        };
+
+        let next = self.scheduler.queue.remove(next).unwrap();
+        let previous = Coroutine {
+            id: mem::replace(&mut self.scheduler.current, next.id),
+            state: mem::replace(&mut self.state, next.state),
+            idx: mem::replace(&mut self.idx, next.idx),
+            frames: mem::replace(&mut self.frames, next.frames),
+            handlers: mem::replace(&mut self.handlers, next.handlers),
+            blocked,
+        };
+        if finished {
+            return Ok(Some(previous));
+        }
+        self.scheduler.queue.push_back(previous);
+        Ok(None)
+    }
+}
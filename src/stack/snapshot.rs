@@ -0,0 +1,268 @@
+/*!Snapshots of the complete state of a `Stack`, to carry on executing later
+ *
+ * A snapshot is plain text, one field per line, so a core dump can be read
+ * as is:
+ *
+ * corrode snapshot 1
+ * program 9f86d0...   \\ SHA-256 of the code, a snapshot only restores on the same program
+ * current 0           \\ id of the running coroutine
+ * next-id 2
+ * idx 12
+ * op 83
+ * state 1 2 3         \\ bottom first
+ * frame 0 2           \\ base and size, one line for each, innermost last
+ * handler 10 0 0      \\ address, depth and frame count, one line for each
+ * coroutine 1         \\ the lines up to the next coroutine describe this waiting one
+ * idx 20
+ * blocked 4           \\ channel it waits to receive from
+ * state
+ * channel 4 7 8       \\ values sent on the channel and not received yet, oldest first
+ */
+
+use std::{
+    collections::VecDeque,
+    fmt::{Display, Write},
+};
+
+use num::{Integer, ToPrimitive, traits::NumOps};
+use sha2::{Digest, Sha256};
+
+use crate::stack::{Frame, Handler, Stack, scheduler::Coroutine, snapshot_error::SnapshotError};
+
+const VERSION: u32 = 1;
+
+///Hex SHA-256 of the code, naming the program a snapshot belongs to
+pub fn program_hash(code: &[u8]) -> String {
+    Sha256::digest(code)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+impl<T: Clone + ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
+    ///Everything needed to carry on executing `code` from where this stack is
+    pub fn snapshot(&self, code: &[u8]) -> String {
+        let mut out = format!("corrode snapshot {VERSION}\n");
+        writeln!(out, "program {}", program_hash(code)).unwrap();
+        writeln!(out, "current {}", self.scheduler.current).unwrap();
+        writeln!(out, "next-id {}", self.scheduler.next_id).unwrap();
+        writeln!(out, "idx {}", self.idx).unwrap();
+        writeln!(out, "op {}", self.op).unwrap();
+        write_context(&mut out, &self.state, &self.frames, &self.handlers);
+        for coroutine in &self.scheduler.queue {
+            writeln!(out, "coroutine {}", coroutine.id).unwrap();
+            writeln!(out, "idx {}", coroutine.idx).unwrap();
+            if let Some(channel) = coroutine.blocked {
+                writeln!(out, "blocked {channel}").unwrap();
+            }
+            write_context(
+                &mut out,
+                &coroutine.state,
+                &coroutine.frames,
+                &coroutine.handlers,
+            );
+        }
+        for (channel, values) in &self.scheduler.channels {
+            write!(out, "channel {channel}").unwrap();
+            for value in values {
+                write!(out, " {value}").unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    ///Rebuild the stack a snapshot was taken of, so executing `code` carries on from there.
+    ///Fails unless the snapshot was taken running the same `code`.
+    pub fn restore(snapshot: &str, code: &[u8]) -> Result<Self, SnapshotError> {
+        let mut lines = snapshot.lines().enumerate();
+        if lines.next().map(|(_, line)| line) != Some(&format!("corrode snapshot {VERSION}")) {
+            return Err(SnapshotError::Version { version: VERSION });
+        }
+
+        let mut stack = Stack::new();
+        let mut program = None;
+        // Waiting coroutine the lines describe, or the running one before the first
+        let mut coroutine: Option<Coroutine<T>> = None;
+        for (number, line) in lines {
+            let malformed = || SnapshotError::Malformed {
+                line: number + 1,
+                text: line.to_string(),
+            };
+            let (key, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            let numbers = || {
+                fields
+                    .iter()
+                    .map(|field| field.parse::<usize>().map_err(|_| malformed()))
+                    .collect::<Result<Vec<usize>, SnapshotError>>()
+            };
+            let values = |fields: &[&str]| {
+                fields
+                    .iter()
+                    .map(|field| T::from_str_radix(field, 10).map_err(|_| malformed()))
+                    .collect::<Result<Vec<T>, SnapshotError>>()
+            };
+            let (state, frames, handlers) = match coroutine.as_mut() {
+                Some(coroutine) => (
+                    &mut coroutine.state,
+                    &mut coroutine.frames,
+                    &mut coroutine.handlers,
+                ),
+                None => (&mut stack.state, &mut stack.frames, &mut stack.handlers),
+            };
+
+            match (key, numbers().as_deref()) {
+                ("program", _) => program = Some(rest),
+                ("state", _) => *state = values(&fields)?,
+                ("frame", Ok(&[base, size])) => frames.push(Frame { base, size }),
+                ("handler", Ok(&[address, depth, frames])) => handlers.push(Handler {
+                    address,
+                    depth,
+                    frames,
+                }),
+                ("channel", _) => {
+                    let (channel, sent) = fields.split_first().ok_or_else(malformed)?;
+                    let channel = channel.parse::<u8>().map_err(|_| malformed())?;
+                    let sent = values(sent)?;
+                    stack
+                        .scheduler
+                        .channels
+                        .insert(channel, VecDeque::from(sent));
+                }
+                ("coroutine", Ok(&[id])) => {
+                    stack.scheduler.queue.extend(coroutine.take());
+                    coroutine = Some(Coroutine {
+                        id,
+                        state: Vec::new(),
+                        idx: 0,
+                        frames: Vec::new(),
+                        handlers: Vec::new(),
+                        blocked: None,
+                    });
+                }
+                ("idx", Ok(&[idx])) => match coroutine.as_mut() {
+                    Some(coroutine) => coroutine.idx = idx,
+                    None => stack.idx = idx,
+                },
+                ("blocked", Ok(&[channel])) => {
+                    let channel = u8::try_from(channel).map_err(|_| malformed())?;
+                    coroutine.as_mut().ok_or_else(malformed)?.blocked = Some(channel);
+                }
+                ("op", Ok(&[op])) if coroutine.is_none() => {
+                    stack.op = u8::try_from(op).map_err(|_| malformed())?
+                }
+                ("current", Ok(&[current])) => stack.scheduler.current = current,
+                ("next-id", Ok(&[next_id])) => stack.scheduler.next_id = next_id,
+                _ => return Err(malformed()),
+            }
+        }
+        stack.scheduler.queue.extend(coroutine);
+
+        let found = program_hash(code);
+        match program {
+            Some(expected) if expected == found => Ok(stack),
+            expected => Err(SnapshotError::ProgramMismatch {
+                expected: expected.unwrap_or_default().to_string(),
+                found,
+            }),
+        }
+    }
+}
+
+///Lines for the stack, frames and handlers of a coroutine
+fn write_context<T: Display>(
+    out: &mut String,
+    state: &[T],
+    frames: &[Frame],
+    handlers: &[Handler],
+) {
+    out.push_str("state");
+    for value in state {
+        write!(out, " {value}").unwrap();
+    }
+    out.push('\n');
+    for frame in frames {
+        writeln!(out, "frame {} {}", frame.base, frame.size).unwrap();
+    }
+    for handler in handlers {
+        writeln!(
+            out,
+            "handler {} {} {}",
+            handler.address, handler.depth, handler.frames
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Snapshot after every number of steps up to the end and check restoring and executing the
+    ///snapshot ends like executing once
+    fn assert_resumes(code: &[u8]) {
+        let expected = Stack::<i64>::new().execute(code);
+        for steps in 0..40 {
+            let mut stack = Stack::<i64>::new();
+            if let Some(result) = stack.execute_for(code, steps).transpose() {
+                assert_eq!(format!("{result:?}"), format!("{expected:?}"));
+                return;
+            }
+            let mut restored = Stack::<i64>::restore(&stack.snapshot(code), code).unwrap();
+            assert_eq!(restored.snapshot(code), stack.snapshot(code));
+            let result = restored.execute(code);
+            assert_eq!(format!("{result:?}"), format!("{expected:?}"));
+        }
+        panic!("the code does not finish in 40 steps");
+    }
+
+    #[test]
+    fn resume_anywhere() {
+        // Frames and a handler catching a division by zero
+        assert_resumes(&[
+            0x20, 0x09, 0x32, 0x0E, 0x26, 0x02, 0x20, 0x04, 0x28, 0x01, 0x20, 0x00, 0x04, 0x12,
+            0x27, 0x01, 0x01, 0x12,
+        ]);
+        // Coroutines waiting on channels
+        assert_resumes(&[
+            0x50, 0x0A, 0x50, 0x0F, 0x53, 0x02, 0x53, 0x02, 0x01, 0x12, 0x20, 0x03, 0x52, 0x01,
+            0xFF, 0x53, 0x01, 0x23, 0x51, 0x52, 0x02, 0x52, 0x02,
+        ]);
+    }
+    #[test]
+    fn snapshot_text() {
+        let code = [
+            0x20, 0x05, 0x26, 0x01, 0x32, 0x09, 0x50, 0x0C, 0x51, 0x52, 0x03,
+        ];
+        let mut stack = Stack::<i64>::new();
+        assert_eq!(stack.execute_for(&code, 4).unwrap(), None);
+        assert_eq!(
+            stack.snapshot(&code),
+            format!(
+                "corrode snapshot 1\nprogram {}\ncurrent 0\nnext-id 2\nidx 8\nop 80\n\
+                 state 5 0\nframe 1 1\nhandler 9 2 1\ncoroutine 1\nidx 12\nstate\n",
+                program_hash(&code)
+            )
+        );
+    }
+    #[test]
+    fn other_program() {
+        let stack = Stack::<i64>::new();
+        let snapshot = stack.snapshot(&[0x20, 0x01]);
+        assert_eq!(
+            Stack::<i64>::restore(&snapshot, &[0x20, 0x02]).unwrap_err(),
+            SnapshotError::ProgramMismatch {
+                expected: program_hash(&[0x20, 0x01]),
+                found: program_hash(&[0x20, 0x02]),
+            }
+        );
+        assert_eq!(
+            Stack::<i64>::restore(&snapshot.replace("idx 0", "idx x"), &[0x20, 0x01]).unwrap_err(),
+            SnapshotError::Malformed {
+                line: 5,
+                text: String::from("idx x"),
+            }
+        );
+    }
+}
@@ -0,0 +1,11 @@
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SnapshotError {
+    #[error("Not a snapshot of version {version}")]
+    Version { version: u32 },
+    #[error("Snapshot was taken running program {expected}, not {found}")]
+    ProgramMismatch { expected: String, found: String },
+    #[error("Line {line} of the snapshot is malformed: {text}")]
+    Malformed { line: usize, text: String },
+}
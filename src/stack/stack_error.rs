@@ -10,6 +10,54 @@ pub enum StackError {
     ReserveError { source: TryReserveError },
     #[error("Unknown operation: {idx} at index: {byte}")]
     UnknownOp { idx: usize, byte: u8 },
+    #[error("Stack slot {slot} is out of range")]
+    SlotOutOfRange { idx: usize, op: u8, slot: usize },
+    #[error("No frame to leave or to hold locals")]
+    NoFrame { idx: usize, op: u8 },
+    #[error("Local {local} is out of range of the current frame")]
+    LocalOutOfRange { idx: usize, op: u8, local: usize },
+    #[error("Division by zero")]
+    DivisionByZero { idx: usize, op: u8 },
+    #[error("ENDTRY without a matching TRY")]
+    NoHandler { idx: usize, op: u8 },
+    #[error("Uncaught exception {code}")]
+    Uncaught { idx: usize, op: u8, code: String },
+    #[error(
+        "Deadlock, every coroutine is waiting to receive: {}",
+        waiting(blocked)
+    )]
+    Deadlock {
+        idx: usize,
+        op: u8,
+        ///Id of each coroutine that is waiting, and the channel it waits on
+        blocked: Vec<(usize, u8)>,
+    },
+    #[error("Assertion failed")]
+    AssertionFailed { idx: usize, op: u8 },
+    #[error("Assertion failed: {left} is not equal to {right}")]
+    NotEqual {
+        idx: usize,
+        op: u8,
+        left: String,
+        right: String,
+    },
+    #[error("Arithmetic overflow")]
+    Overflow { idx: usize, op: u8 },
+    #[error("Cannot reach value {n} below the top of a stack of {depth} values")]
+    OutOfReach {
+        idx: usize,
+        op: u8,
+        n: usize,
+        depth: usize,
+    },
+}
+
+fn waiting(blocked: &[(usize, u8)]) -> String {
+    blocked
+        .iter()
+        .map(|(coroutine, channel)| format!("coroutine {coroutine} on channel {channel}"))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 impl StackError {
@@ -23,6 +71,107 @@ impl StackError {
                 idx: *idx + 1,
                 byte: *byte,
             },
+            StackError::SlotOutOfRange { idx, op, slot } => Self::SlotOutOfRange {
+                idx: *idx,
+                op: *op,
+                slot: *slot,
+            },
+            StackError::NoFrame { idx, op } => Self::NoFrame { idx: *idx, op: *op },
+            StackError::LocalOutOfRange { idx, op, local } => Self::LocalOutOfRange {
+                idx: *idx,
+                op: *op,
+                local: *local,
+            },
+            StackError::DivisionByZero { idx, op } => Self::DivisionByZero { idx: *idx, op: *op },
+            StackError::NoHandler { idx, op } => Self::NoHandler { idx: *idx, op: *op },
+            StackError::Uncaught { idx, op, code } => Self::Uncaught {
+                idx: *idx,
+                op: *op,
+                code: code.clone(),
+            },
+            StackError::Deadlock { idx, op, blocked } => Self::Deadlock {
+                idx: *idx,
+                op: *op,
+                blocked: blocked.clone(),
+            },
+            StackError::AssertionFailed { idx, op } => Self::AssertionFailed { idx: *idx, op: *op },
+            StackError::NotEqual {
+                idx,
+                op,
+                left,
+                right,
+            } => Self::NotEqual {
+                idx: *idx,
+                op: *op,
+                left: left.clone(),
+                right: right.clone(),
+            },
+            StackError::OutOfReach { idx, op, n, depth } => Self::OutOfReach {
+                idx: *idx,
+                op: *op,
+                n: *n,
+                depth: *depth,
+            },
+            StackError::Overflow { idx, op } => Self::Overflow { idx: *idx, op: *op },
+        }
+    }
+
+    ///Error code a TRY handler receives for the fault
+    pub fn code(&self) -> u8 {
+        match self {
+            StackError::EmptyStack { .. } => 1,
+            StackError::UnknownOp { .. } => 2,
+            StackError::ReserveError { .. } => 3,
+            StackError::DivisionByZero { .. } => 4,
+            StackError::SlotOutOfRange { .. } => 5,
+            StackError::NoFrame { .. } => 6,
+            StackError::LocalOutOfRange { .. } => 7,
+            StackError::NoHandler { .. } => 8,
+            StackError::Uncaught { .. } => 9,
+            StackError::Deadlock { .. } => 10,
+            StackError::AssertionFailed { .. } | StackError::NotEqual { .. } => 11,
+            StackError::OutOfReach { .. } => 12,
+            StackError::Overflow { .. } => 13,
+        }
+    }
+
+    ///Index of the instruction that failed
+    pub fn idx(&self) -> Option<usize> {
+        match self {
+            StackError::ReserveError { .. } => None,
+            StackError::EmptyStack { idx, .. }
+            | StackError::UnknownOp { idx, .. }
+            | StackError::SlotOutOfRange { idx, .. }
+            | StackError::NoFrame { idx, .. }
+            | StackError::LocalOutOfRange { idx, .. }
+            | StackError::DivisionByZero { idx, .. }
+            | StackError::NoHandler { idx, .. }
+            | StackError::Uncaught { idx, .. }
+            | StackError::Deadlock { idx, .. }
+            | StackError::AssertionFailed { idx, .. }
+            | StackError::NotEqual { idx, .. }
+            | StackError::OutOfReach { idx, .. }
+            | StackError::Overflow { idx, .. } => Some(*idx),
+        }
+    }
+
+    ///Opcode of the instruction that failed
+    pub fn op(&self) -> Option<u8> {
+        match self {
+            StackError::ReserveError { .. } => None,
+            StackError::UnknownOp { byte, .. } => Some(*byte),
+            StackError::EmptyStack { op, .. }
+            | StackError::SlotOutOfRange { op, .. }
+            | StackError::NoFrame { op, .. }
+            | StackError::LocalOutOfRange { op, .. }
+            | StackError::DivisionByZero { op, .. }
+            | StackError::NoHandler { op, .. }
+            | StackError::Uncaught { op, .. }
+            | StackError::Deadlock { op, .. }
+            | StackError::AssertionFailed { op, .. }
+            | StackError::NotEqual { op, .. }
+            | StackError::OutOfReach { op, .. }
+            | StackError::Overflow { op, .. } => Some(*op),
         }
     }
 }
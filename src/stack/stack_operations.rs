@@ -2,7 +2,7 @@ use std::fmt::Display;
 
 use num::{Integer, ToPrimitive, traits::NumOps};
 
-use crate::stack::{Stack, stack_error::StackError};
+use crate::stack::{Stack, scheduler::Scheduler, stack_error::StackError};
 
 impl<T: Clone + ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
     pub fn new() -> Self {
@@ -10,6 +10,10 @@ impl<T: Clone + ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
             state: Vec::new(),
             idx: 0,
             op: 0,
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            scheduler: Scheduler::default(),
+            captured: None,
         }
     }
     pub fn from(slice: &[T]) -> Self {
@@ -17,6 +21,10 @@ impl<T: Clone + ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
             state: slice.to_vec(),
             idx: 0,
             op: 0,
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            scheduler: Scheduler::default(),
+            captured: None,
         }
     }
 
@@ -55,7 +63,7 @@ mod tests {
     #[test]
     fn test_new_empty_stack() {
         let stack = Stack::<u8>::new();
-        assert_eq!(stack.state, [])
+        assert_eq!(stack.state, [0u8; 0])
     }
     #[test]
     fn test_new_stack_with_values() {
@@ -30,6 +30,9 @@ impl<T: ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
                     0x05 => {
                         format!("{idx:>4}\u{2502}(0x05) \u{2500}\u{2500}\u{2500}  Mod     ").into()
                     }
+                    0x06 => {
+                        format!("{idx:>4}\u{2502}(0x06) \u{2500}\u{2500}\u{2500}  Lt      ").into()
+                    }
                     0x10 => {
                         format!("{idx:>4}\u{2502}(0x10) \u{2500}\u{2500}\u{2500}  Print   ").into()
                     }
@@ -54,6 +57,84 @@ impl<T: ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
                             format!("Expected byte at address {:#04x}", idx + 1).into()
                         }
                     }
+                    0x24 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x24) \u{2500}\u{252C}\u{2500}  LOAD    "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x25 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x25) \u{2500}\u{252C}\u{2500}  STORE   "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x26 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x26) \u{2500}\u{252C}\u{2500}  ENTER   "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x27 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x27) \u{2500}\u{252C}\u{2500}  LOADLOC "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x28 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x28) \u{2500}\u{252C}\u{2500}  STORELOC"
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x29 => {
+                        format!("{idx:>4}\u{2502}(0x29) \u{2500}\u{2500}\u{2500}  Leave   ").into()
+                    }
                     0x30 => {
                         let val: u8;
                         let first_idx = idx;
@@ -84,6 +165,135 @@ impl<T: ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
                             format!("Expected byte at address {:#04x}", idx + 1).into()
                         }
                     }
+                    0x32 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x32) \u{2500}\u{252C}\u{2500}  TRY     "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x33 => {
+                        format!("{idx:>4}\u{2502}(0x33) \u{2500}\u{2500}\u{2500}  EndTry  ").into()
+                    }
+                    0x34 => {
+                        format!("{idx:>4}\u{2502}(0x34) \u{2500}\u{2500}\u{2500}  Throw   ").into()
+                    }
+                    0x40 => {
+                        format!("{idx:>4}\u{2502}(0x40) \u{2500}\u{2500}\u{2500}  Assert  ").into()
+                    }
+                    0x41 => {
+                        format!("{idx:>4}\u{2502}(0x41) \u{2500}\u{2500}\u{2500}  AssertEq").into()
+                    }
+                    0x50 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x50) \u{2500}\u{252C}\u{2500}  SPAWN   "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x51 => {
+                        format!("{idx:>4}\u{2502}(0x51) \u{2500}\u{2500}\u{2500}  Yield   ").into()
+                    }
+                    0x52 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x52) \u{2500}\u{252C}\u{2500}  SEND    "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x53 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x53) \u{2500}\u{252C}\u{2500}  RECV    "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x60 => {
+                        format!("{idx:>4}\u{2502}(0x60) \u{2500}\u{2500}\u{2500}  Over    ").into()
+                    }
+                    0x61 => {
+                        format!("{idx:>4}\u{2502}(0x61) \u{2500}\u{2500}\u{2500}  Rot     ").into()
+                    }
+                    0x62 => {
+                        format!("{idx:>4}\u{2502}(0x62) \u{2500}\u{2500}\u{2500}  Nip     ").into()
+                    }
+                    0x63 => {
+                        format!("{idx:>4}\u{2502}(0x63) \u{2500}\u{2500}\u{2500}  Tuck    ").into()
+                    }
+                    0x64 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x64) \u{2500}\u{252C}\u{2500}  PICK    "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x65 => {
+                        let val: u8;
+                        let first_idx = idx;
+
+                        if let Some((idx, number)) = stack.next() {
+                            println!(
+                                "{first_idx:>4}\u{2502}(0x65) \u{2500}\u{252C}\u{2500}  ROLL    "
+                            );
+                            val = number.to_u8().unwrap();
+                            format!("{idx:>4}\u{2502}({:#04x})  \u{2514}\u{2500}  {}", val, val)
+                                .into()
+                        } else {
+                            format!("Expected byte at address {:#04x}", idx + 1).into()
+                        }
+                    }
+                    0x66 => {
+                        format!("{idx:>4}\u{2502}(0x66) \u{2500}\u{2500}\u{2500}  Depth   ").into()
+                    }
+                    0x67 => {
+                        format!("{idx:>4}\u{2502}(0x67) \u{2500}\u{2500}\u{2500}  Clear   ").into()
+                    }
+                    0x68 => {
+                        format!("{idx:>4}\u{2502}(0x68) \u{2500}\u{2500}\u{2500}  2Dup    ").into()
+                    }
+                    0x69 => {
+                        format!("{idx:>4}\u{2502}(0x69) \u{2500}\u{2500}\u{2500}  2Swap   ").into()
+                    }
 
                     0xFF => {
                         format!("{idx:>4}\u{2502}(0xFF) \u{2500}\u{2500}\u{2500}  Exit    ").into()
@@ -102,4 +312,22 @@ impl<T: ToPrimitive + NumOps + Display + From<u8> + Integer> Stack<T> {
             }
         }
     }
+
+    ///Print the locals of the innermost frame opened by ENTER
+    pub fn trace_frame(&self) {
+        let Some(frame) = self.frames.last() else {
+            return;
+        };
+        println!(
+            "current frame (depth {}, base {})",
+            self.frames.len(),
+            frame.base
+        );
+        for local in 0..frame.size {
+            match self.state.get(frame.base + local) {
+                Some(value) => println!("{local:>4}\u{2502}{value}"),
+                None => println!("{local:>4}\u{2502}{}", ColoredString::from("popped").red()),
+            }
+        }
+    }
 }
@@ -0,0 +1,262 @@
+/*!Assembling and running programs from a host program, without touching the terminal
+ *
+ * ```
+ * use corrode::vm::Vm;
+ *
+ * let outcome = Vm::<i64>::new()
+ *     .define("TWICE", 1)
+ *     .stack([5])
+ *     .run(".if TWICE\npush 2\n.else\npush 1\n.endif\nmul\nprint\nret")
+ *     .unwrap();
+ * assert_eq!(outcome.result, 10);
+ * assert_eq!(outcome.stack, [10]);
+ * assert_eq!(outcome.output, "10\n");
+ * ```
+ *
+ * Sources are assembled like `.cor` files, under the name given by `source_name`, which
+ * `.include` resolves paths against and errors point at. Modules added with `module` are
+ * linked after the program, which starts at the first instruction of the main source.
+ * PRINT and PCHAR write to the `output` of the outcome instead of standard output, and every
+ * error comes back as a `VmError`.
+ */
+
+use std::{fmt::Display, io::Read};
+
+use num::{
+    Integer, ToPrimitive,
+    traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, NumOps},
+};
+
+use crate::{
+    code::{link::link, parse::assemble_source},
+    stack::Stack,
+    vm::vm_error::VmError,
+};
+
+pub mod vm_error;
+
+///Name sources are assembled under unless `source_name` gives another
+const SOURCE_NAME: &str = "<source>";
+
+///Settings to assemble and run programs with
+#[derive(Debug, Clone)]
+pub struct Vm<T> {
+    defines: Vec<(String, i64)>,
+    source_name: String,
+    ///Sources linked after the main one, with their names
+    modules: Vec<(String, String)>,
+    ///Values on the stack when the program starts, bottom first
+    stack: Vec<T>,
+    step_limit: Option<usize>,
+}
+
+///What a program left behind when it returned or exited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome<T> {
+    pub result: T,
+    ///Stack at the end, bottom first
+    pub stack: Vec<T>,
+    ///Lines PRINT and PCHAR wrote, each ending in a newline
+    pub output: String,
+}
+
+impl<T> Vm<T>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    pub fn new() -> Self {
+        Vm {
+            defines: Vec::new(),
+            source_name: String::from(SOURCE_NAME),
+            modules: Vec::new(),
+            stack: Vec::new(),
+            step_limit: None,
+        }
+    }
+
+    ///Define a name for `.if` and `.ifdef`, like `-DNAME=VALUE`
+    pub fn define(mut self, name: &str, value: i64) -> Self {
+        self.defines.push((name.to_string(), value));
+        self
+    }
+
+    ///Name of the main source in errors and the path `.include` is relative to
+    pub fn source_name(mut self, name: &str) -> Self {
+        self.source_name = name.to_string();
+        self
+    }
+
+    ///Link another source after the main one, so it can import its exports
+    pub fn module(mut self, name: &str, source: &str) -> Self {
+        self.modules.push((name.to_string(), source.to_string()));
+        self
+    }
+
+    ///Push values before the program starts, bottom first
+    pub fn stack(mut self, values: impl IntoIterator<Item = T>) -> Self {
+        self.stack.extend(values);
+        self
+    }
+
+    ///Stop with `VmError::StepLimit` after executing this many instructions
+    pub fn step_limit(mut self, steps: usize) -> Self {
+        self.step_limit = Some(steps);
+        self
+    }
+
+    ///Assemble the main source and the modules, and link them to bytecode
+    pub fn assemble(&self, source: &str) -> Result<Vec<u8>, VmError> {
+        let defines: Vec<(&str, i64)> = self
+            .defines
+            .iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        let objects = [(self.source_name.as_str(), source)]
+            .into_iter()
+            .chain(
+                self.modules
+                    .iter()
+                    .map(|(name, source)| (name.as_str(), source.as_str())),
+            )
+            .map(|(name, source)| assemble_source::<T>(name, source, &defines))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|source| VmError::Parse { source })?;
+        link(&objects).map_err(|source| VmError::Parse { source })
+    }
+
+    ///Assemble a main source read to the end from `reader`
+    pub fn assemble_reader(&self, mut reader: impl Read) -> Result<Vec<u8>, VmError> {
+        let mut source = String::new();
+        reader
+            .read_to_string(&mut source)
+            .map_err(|source| VmError::Io { source })?;
+        self.assemble(&source)
+    }
+
+    ///Execute bytecode, such as what `assemble` or `forth::compile` give, on a fresh stack
+    pub fn execute(&self, code: &[u8]) -> Result<Outcome<T>, VmError> {
+        let mut stack = Stack::new();
+        stack.state = self.stack.clone();
+        stack.captured = Some(String::new());
+        let steps = self.step_limit.unwrap_or(usize::MAX);
+        let result = loop {
+            match stack.execute_for(code, steps) {
+                Ok(Some(result)) => break result,
+                Ok(None) if self.step_limit.is_some() => {
+                    return Err(VmError::StepLimit { steps });
+                }
+                Ok(None) => (),
+                Err(source) => return Err(VmError::Runtime { source }),
+            }
+        };
+        Ok(Outcome {
+            result,
+            stack: stack.state,
+            output: stack.captured.unwrap_or_default(),
+        })
+    }
+
+    ///Assemble and execute a main source
+    pub fn run(&self, source: &str) -> Result<Outcome<T>, VmError> {
+        self.execute(&self.assemble(source)?)
+    }
+
+    ///Assemble and execute a main source read to the end from `reader`
+    pub fn run_reader(&self, reader: impl Read) -> Result<Outcome<T>, VmError> {
+        self.execute(&self.assemble_reader(reader)?)
+    }
+}
+
+impl<T> Default for Vm<T>
+where
+    T: ToPrimitive
+        + NumOps
+        + Display
+        + TryInto<u8>
+        + From<u8>
+        + Integer
+        + Clone
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num::BigInt;
+
+    use crate::{code::parse_error::ParseError, stack::stack_error::StackError};
+
+    use super::*;
+
+    #[test]
+    fn run_from_strings() {
+        let vm = Vm::<i64>::new().define("N", 3).stack([4]);
+        let outcome = vm.run(".ifdef N\npush 3\n.endif\nadd\nprint\nret").unwrap();
+        assert_eq!(
+            outcome,
+            Outcome {
+                result: 7,
+                stack: vec![7],
+                output: String::from("7\n"),
+            }
+        );
+
+        let outcome = vm.run_reader("push 0\npush 'h'\npush 'i'\npchar\nret".as_bytes());
+        assert_eq!(outcome.unwrap().output, "hi\n");
+
+        let vm = Vm::<i64>::new().module("lib.cor", ".export double\ndouble:\ndup\nadd\nret");
+        let outcome = vm.run(".import double\npush 21\njmp $double").unwrap();
+        assert_eq!(outcome.result, 42);
+
+        let outcome = Vm::<BigInt>::new()
+            .run("push 9223372036854775807\npush 2\nmul\nret")
+            .unwrap();
+        assert_eq!(outcome.result, BigInt::from(i64::MAX) * 2);
+    }
+
+    #[test]
+    fn errors() {
+        let error = Vm::<i64>::new()
+            .source_name("main.cor")
+            .run("push 1\nfrobnicate")
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            VmError::Parse {
+                source: ParseError::Syntax { at, .. }
+            } if at.file == "main.cor" && at.line == 2
+        ));
+
+        let error = Vm::<i64>::new().run("push 1\npush 0\ndiv").unwrap_err();
+        assert!(matches!(
+            error,
+            VmError::Runtime {
+                source: StackError::DivisionByZero { idx: 4, .. }
+            }
+        ));
+
+        let error = Vm::<i64>::new()
+            .step_limit(100)
+            .run("start:\npush 1\njnz $start")
+            .unwrap_err();
+        assert!(matches!(error, VmError::StepLimit { steps: 100 }));
+        let outcome = Vm::<i64>::new().step_limit(3).run("push 1\npush 2\nret");
+        assert_eq!(outcome.unwrap().result, 2);
+    }
+}
@@ -0,0 +1,17 @@
+use std::io;
+
+use thiserror::Error;
+
+use crate::{code::parse_error::ParseError, stack::stack_error::StackError};
+
+#[derive(Debug, Error)]
+pub enum VmError {
+    #[error("Could not read the source: {source}")]
+    Io { source: io::Error },
+    #[error("{source}")]
+    Parse { source: ParseError },
+    #[error("{source}")]
+    Runtime { source: StackError },
+    #[error("Still running after {steps} steps")]
+    StepLimit { steps: usize },
+}